@@ -0,0 +1,292 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Typed declarations for the configuration keys.
+//!
+//! The configuration file itself only stores strings. Adapters register a
+//! `ConfigKey` for each setting they use so that values can be validated
+//! before being written, defaults are known up front and secrets are never
+//! sent back to clients.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The value used in place of secrets when listing the configuration.
+pub const MASKED_SECRET: &'static str = "********";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigKind {
+    String,
+    Bool,
+    Integer { min: Option<i64>, max: Option<i64> },
+    Float { min: Option<f64>, max: Option<f64> },
+    /// One of a fixed set of strings.
+    Choice(Vec<String>),
+}
+
+impl ConfigKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ConfigKind::String => "string",
+            ConfigKind::Bool => "bool",
+            ConfigKind::Integer { .. } => "integer",
+            ConfigKind::Float { .. } => "float",
+            ConfigKind::Choice(_) => "choice",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    UnknownKey(String, String),
+    InvalidValue {
+        namespace: String,
+        property: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::UnknownKey(ref namespace, ref property) => {
+                write!(f, "Unknown configuration key {}::{}", namespace, property)
+            }
+            ConfigError::InvalidValue { ref namespace, ref property, ref reason } => {
+                write!(f,
+                       "Invalid value for {}::{}: {}",
+                       namespace,
+                       property,
+                       reason)
+            }
+        }
+    }
+}
+
+/// The declaration of a configuration key.
+///
+/// A property ending with `*` declares all the properties starting with the
+/// same prefix, e.g. `token_*` for per-bridge tokens.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigKey {
+    pub namespace: String,
+    pub property: String,
+    pub kind: ConfigKind,
+    pub default: String,
+    pub secret: bool,
+    pub description: String,
+}
+
+impl ConfigKey {
+    pub fn new(namespace: &str, property: &str, kind: ConfigKind, default: &str) -> Self {
+        ConfigKey {
+            namespace: namespace.to_owned(),
+            property: property.to_owned(),
+            kind: kind,
+            default: default.to_owned(),
+            secret: false,
+            description: String::new(),
+        }
+    }
+
+    pub fn string(namespace: &str, property: &str, default: &str) -> Self {
+        ConfigKey::new(namespace, property, ConfigKind::String, default)
+    }
+
+    pub fn boolean(namespace: &str, property: &str, default: bool) -> Self {
+        ConfigKey::new(namespace, property, ConfigKind::Bool, &default.to_string())
+    }
+
+    pub fn integer(namespace: &str,
+                   property: &str,
+                   default: i64,
+                   min: Option<i64>,
+                   max: Option<i64>)
+                   -> Self {
+        ConfigKey::new(namespace,
+                       property,
+                       ConfigKind::Integer { min: min, max: max },
+                       &default.to_string())
+    }
+
+    /// Declares a floating point key. `default` may be empty for settings
+    /// without a sensible default, like a location.
+    pub fn float(namespace: &str,
+                 property: &str,
+                 default: &str,
+                 min: Option<f64>,
+                 max: Option<f64>)
+                 -> Self {
+        ConfigKey::new(namespace,
+                       property,
+                       ConfigKind::Float { min: min, max: max },
+                       default)
+    }
+
+    /// Marks this key as holding a secret, which is masked when listed.
+    pub fn secret(mut self) -> Self {
+        self.secret = true;
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = description.to_owned();
+        self
+    }
+
+    /// Returns true if this key declares `namespace::property`.
+    pub fn matches(&self, namespace: &str, property: &str) -> bool {
+        if self.namespace != namespace {
+            return false;
+        }
+        if self.property.ends_with('*') {
+            property.starts_with(&self.property[..self.property.len() - 1])
+        } else {
+            self.property == property
+        }
+    }
+
+    pub fn validate(&self, property: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = |reason: String| {
+            Err(ConfigError::InvalidValue {
+                namespace: self.namespace.clone(),
+                property: property.to_owned(),
+                reason: reason,
+            })
+        };
+
+        match self.kind {
+            ConfigKind::String => Ok(()),
+            ConfigKind::Bool => {
+                match value {
+                    "true" | "false" => Ok(()),
+                    _ => invalid(format!("expected true or false, got {}", value)),
+                }
+            }
+            ConfigKind::Integer { min, max } => {
+                let number = match value.parse::<i64>() {
+                    Ok(number) => number,
+                    Err(_) => return invalid(format!("expected an integer, got {}", value)),
+                };
+                if let Some(min) = min {
+                    if number < min {
+                        return invalid(format!("{} is lower than {}", number, min));
+                    }
+                }
+                if let Some(max) = max {
+                    if number > max {
+                        return invalid(format!("{} is greater than {}", number, max));
+                    }
+                }
+                Ok(())
+            }
+            ConfigKind::Float { min, max } => {
+                let number = match value.parse::<f64>() {
+                    Ok(number) if number.is_finite() => number,
+                    _ => return invalid(format!("expected a number, got {}", value)),
+                };
+                if let Some(min) = min {
+                    if number < min {
+                        return invalid(format!("{} is lower than {}", number, min));
+                    }
+                }
+                if let Some(max) = max {
+                    if number > max {
+                        return invalid(format!("{} is greater than {}", number, max));
+                    }
+                }
+                Ok(())
+            }
+            ConfigKind::Choice(ref choices) => {
+                if choices.iter().any(|choice| choice == value) {
+                    Ok(())
+                } else {
+                    invalid(format!("expected one of {:?}, got {}", choices, value))
+                }
+            }
+        }
+    }
+}
+
+/// The set of declared configuration keys.
+#[derive(Debug, Default)]
+pub struct ConfigSchema {
+    keys: BTreeMap<(String, String), ConfigKey>,
+}
+
+impl ConfigSchema {
+    pub fn new() -> Self {
+        ConfigSchema::default()
+    }
+
+    /// Declares a key. Declaring the same key twice replaces the previous declaration.
+    pub fn register(&mut self, key: ConfigKey) {
+        self.keys.insert((key.namespace.clone(), key.property.clone()), key);
+    }
+
+    pub fn find(&self, namespace: &str, property: &str) -> Option<&ConfigKey> {
+        if let Some(key) = self.keys.get(&(namespace.to_owned(), property.to_owned())) {
+            return Some(key);
+        }
+        self.keys.values().find(|key| key.matches(namespace, property))
+    }
+
+    pub fn keys(&self) -> Vec<&ConfigKey> {
+        self.keys.values().collect()
+    }
+
+    /// Validates a value against its declaration. Undeclared keys are accepted
+    /// unless `strict` is set.
+    pub fn validate(&self,
+                    namespace: &str,
+                    property: &str,
+                    value: &str,
+                    strict: bool)
+                    -> Result<(), ConfigError> {
+        match self.find(namespace, property) {
+            Some(key) => key.validate(property, value),
+            None if strict => {
+                Err(ConfigError::UnknownKey(namespace.to_owned(), property.to_owned()))
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_secret(&self, namespace: &str, property: &str) -> bool {
+        self.find(namespace, property).map_or(false, |key| key.secret)
+    }
+}
+
+#[cfg(test)]
+describe! config_schema {
+    before_each {
+        let mut schema = ConfigSchema::new();
+        schema.register(ConfigKey::boolean("philips_hue", "nupnp_enabled", true));
+        schema.register(ConfigKey::string("philips_hue", "token_*", "unauthorized").secret());
+        schema.register(ConfigKey::integer("tls", "expiry_warning_days", 14, Some(1), Some(90)));
+        schema.register(ConfigKey::float("clock", "latitude", "", Some(-90.), Some(90.)));
+    }
+
+    it "should validate typed values" {
+        assert!(schema.validate("philips_hue", "nupnp_enabled", "false", true).is_ok());
+        assert!(schema.validate("philips_hue", "nupnp_enabled", "maybe", true).is_err());
+        assert!(schema.validate("tls", "expiry_warning_days", "30", true).is_ok());
+        assert!(schema.validate("tls", "expiry_warning_days", "0", true).is_err());
+        assert!(schema.validate("tls", "expiry_warning_days", "soon", true).is_err());
+        assert!(schema.validate("clock", "latitude", "48.8566", true).is_ok());
+        assert!(schema.validate("clock", "latitude", "-91", true).is_err());
+        assert!(schema.validate("clock", "latitude", "north", true).is_err());
+    }
+
+    it "should match wildcard properties" {
+        assert!(schema.is_secret("philips_hue", "token_001788fffe100491"));
+        assert!(!schema.is_secret("philips_hue", "nupnp_enabled"));
+    }
+
+    it "should only reject unknown keys in strict mode" {
+        assert_eq!(schema.validate("foo", "bar", "baz", true),
+                   Err(ConfigError::UnknownKey("foo".to_owned(), "bar".to_owned())));
+        assert!(schema.validate("foo", "bar", "baz", false).is_ok());
+    }
+}
@@ -2,24 +2,46 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use config_schema::{ConfigError, ConfigKey, ConfigSchema, MASKED_SECRET};
 use serde_json;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
-use std::sync::{Mutex, RwLock};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 type ConfigNameSpace = BTreeMap<String, String>;
 
 type ConfigTree = BTreeMap<String, ConfigNameSpace>;
 
+/// How long the writes are batched before the configuration file is written.
+const WRITE_DELAY_MS: u64 = 200;
+
+/// A change to a configuration value. `value` is None when the property was removed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigChange {
+    pub namespace: String,
+    pub property: String,
+    pub value: Option<String>,
+}
+
+pub type ConfigListener = Fn(&ConfigChange) + Send + Sync;
+
 #[derive(Debug)]
 pub struct ConfigStore {
     file_name: String,
     save_lock: Mutex<()>,
     config: ConfigTree,
     overrides: ConfigTree,
+    // The properties set since the file was last written.
+    pending: BTreeSet<(String, String)>,
+    // Modification time of the file when we last wrote or read it, used to
+    // detect edits made by someone else.
+    last_modified: Option<SystemTime>,
 }
 
 impl ConfigStore {
@@ -29,20 +51,38 @@ impl ConfigStore {
             save_lock: Mutex::new(()),
             config: ConfigStore::load(file_name),
             overrides: ConfigTree::new(),
+            pending: BTreeSet::new(),
+            last_modified: ConfigStore::modified(file_name),
         }
     }
 
     pub fn set(&mut self, namespace: &str, property: &str, value: &str) {
-        debug!("Setting config for {}::{} to {}",
-               namespace,
-               property,
-               value);
-        if !self.config.contains_key(namespace) {
-            self.config.insert(namespace.to_owned(), ConfigNameSpace::new());
+        self.set_many(&[(namespace.to_owned(), property.to_owned(), value.to_owned())]);
+    }
+
+    /// Sets several values. The configuration file is only written by `flush()`,
+    /// or when the store is dropped.
+    pub fn set_many(&mut self, values: &[(String, String, String)]) {
+        for &(ref namespace, ref property, ref value) in values {
+            debug!("Setting config for {}::{} to {}",
+                   namespace,
+                   property,
+                   value);
+            self.config
+                .entry(namespace.to_owned())
+                .or_insert_with(ConfigNameSpace::new)
+                .insert(property.to_owned(), value.to_owned());
+            self.pending.insert((namespace.to_owned(), property.to_owned()));
+        }
+    }
+
+    /// Writes the configuration file if some values were set since it was last written.
+    pub fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
         }
-        self.config.get_mut(namespace).unwrap().insert(property.to_owned(), value.to_owned());
-        // TODO: Should be more intelligent than save on every write
         self.save();
+        self.pending.clear();
     }
 
     pub fn get(&self, namespace: &str, property: &str) -> Option<&String> {
@@ -87,6 +127,77 @@ impl ConfigStore {
         }
     }
 
+    /// Returns all the (namespace, property, value) triples, overrides included.
+    pub fn entries(&self) -> Vec<(String, String, String)> {
+        let mut entries = vec![];
+        for (namespace, properties) in &self.config {
+            for property in properties.keys() {
+                entries.push((namespace.clone(),
+                              property.clone(),
+                              self.get(namespace, property).unwrap().clone()));
+            }
+        }
+        for (namespace, properties) in &self.overrides {
+            for (property, value) in properties {
+                if self.get_no_override(namespace, property).is_none() {
+                    entries.push((namespace.clone(), property.clone(), value.clone()));
+                }
+            }
+        }
+        entries
+    }
+
+    /// Returns true if the configuration file was modified since we last
+    /// read or wrote it.
+    pub fn changed_on_disk(&self) -> bool {
+        ConfigStore::modified(&self.file_name) != self.last_modified
+    }
+
+    /// Reloads the configuration file, returning the changed values.
+    /// Overrides and the values not written yet are kept.
+    pub fn reload(&mut self) -> Vec<ConfigChange> {
+        let mut config = ConfigStore::load(&self.file_name);
+        self.last_modified = ConfigStore::modified(&self.file_name);
+        for &(ref namespace, ref property) in &self.pending {
+            if let Some(value) = self.get_no_override(namespace, property) {
+                config.entry(namespace.clone())
+                    .or_insert_with(ConfigNameSpace::new)
+                    .insert(property.clone(), value.clone());
+            }
+        }
+
+        let mut changes = vec![];
+        for (namespace, properties) in &config {
+            for (property, value) in properties {
+                if self.get_no_override(namespace, property) != Some(value) {
+                    changes.push(ConfigChange {
+                        namespace: namespace.clone(),
+                        property: property.clone(),
+                        value: Some(value.clone()),
+                    });
+                }
+            }
+        }
+        for (namespace, properties) in &self.config {
+            for property in properties.keys() {
+                if config.get(namespace).and_then(|props| props.get(property)).is_none() {
+                    changes.push(ConfigChange {
+                        namespace: namespace.clone(),
+                        property: property.clone(),
+                        value: None,
+                    });
+                }
+            }
+        }
+
+        self.config = config;
+        changes
+    }
+
+    fn modified(file_name: &str) -> Option<SystemTime> {
+        fs::metadata(file_name).and_then(|metadata| metadata.modified()).ok()
+    }
+
     fn load(file_name: &str) -> ConfigTree {
         let empty_config = BTreeMap::new();
         let file = match File::open(&Path::new(file_name)) {
@@ -112,7 +223,9 @@ impl ConfigStore {
         parsed_config
     }
 
-    fn save(&self) {
+    /// Writes the configuration to a temporary file and renames it over the
+    /// configuration file, so that readers never see a partial file.
+    fn save(&mut self) {
         let file_path = Path::new(&self.file_name);
         let mut update_name = self.file_name.clone();
         update_name.push_str(".updated");
@@ -120,28 +233,89 @@ impl ConfigStore {
 
         let conf_as_json = serde_json::to_string_pretty(&self.config).unwrap();
 
-        let _ = self.save_lock.lock().unwrap();
+        let _guard = self.save_lock.lock().unwrap();
         match File::create(update_path)
-            .map(|mut file| file.write_all(conf_as_json.as_bytes()))
-            .and_then(|_| fs::copy(&update_path, &file_path))
-            .and_then(|_| fs::remove_file(&update_path)) {
+            .and_then(|mut file| {
+                try!(file.write_all(conf_as_json.as_bytes()));
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&update_path, &file_path)) {
             Ok(_) => debug!("Wrote configuration file {}", self.file_name),
             Err(error) => {
-                error!("While writing configuration file{}: {}",
+                error!("While writing configuration file {}: {}",
                        self.file_name,
                        error.to_string())
             }
         };
+        self.last_modified = ConfigStore::modified(&self.file_name);
     }
 }
 
+impl Drop for ConfigStore {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// A description of a configuration value, as exposed to clients.
+/// Secret values are masked.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigEntry {
+    pub namespace: String,
+    pub property: String,
+    pub value: Option<String>,
+    pub kind: Option<String>,
+    pub default: Option<String>,
+    pub secret: bool,
+    pub description: String,
+}
+
 pub struct ConfigService {
-    store: RwLock<ConfigStore>,
+    store: Arc<RwLock<ConfigStore>>,
+    schema: RwLock<ConfigSchema>,
+    listeners: RwLock<Vec<Box<ConfigListener>>>,
+    // Wakes up the thread writing the batched changes.
+    writer: Mutex<Sender<()>>,
 }
 
 impl ConfigService {
     pub fn new(file_name: &str) -> Self {
-        ConfigService { store: RwLock::new(ConfigStore::new(file_name)) }
+        let store = Arc::new(RwLock::new(ConfigStore::new(file_name)));
+        let (tx, rx) = channel();
+        let weak_store: Weak<RwLock<ConfigStore>> = Arc::downgrade(&store);
+        thread::Builder::new()
+            .name("ConfigWriter".to_owned())
+            .spawn(move || {
+                // Wait for a first change, then for the rest of the batch.
+                while rx.recv().is_ok() {
+                    thread::sleep(Duration::from_millis(WRITE_DELAY_MS));
+                    while rx.try_recv().is_ok() {}
+                    match weak_store.upgrade() {
+                        Some(store) => store.write().unwrap().flush(),
+                        None => break,
+                    }
+                }
+            })
+            .unwrap();
+
+        ConfigService {
+            store: store,
+            schema: RwLock::new(ConfigSchema::new()),
+            listeners: RwLock::new(vec![]),
+            writer: Mutex::new(tx),
+        }
+    }
+
+    /// Declares a configuration key, and returns its current value.
+    pub fn register(&self, key: ConfigKey) -> String {
+        let (namespace, property, default) =
+            (key.namespace.clone(), key.property.clone(), key.default.clone());
+        self.schema.write().unwrap().register(key);
+        if property.ends_with('*') {
+            default
+        } else {
+            self.get(&namespace, &property).unwrap_or(default)
+        }
     }
 
     pub fn get(&self, namespace: &str, property: &str) -> Option<String> {
@@ -152,6 +326,27 @@ impl ConfigService {
             .map(|value| value.to_owned())
     }
 
+    /// Returns the value of a key, or its declared default.
+    pub fn get_value(&self, key: &ConfigKey) -> String {
+        self.get(&key.namespace, &key.property).unwrap_or_else(|| key.default.clone())
+    }
+
+    pub fn get_bool(&self, key: &ConfigKey) -> bool {
+        self.get_value(key).parse::<bool>().unwrap_or_else(|_| key.default == "true")
+    }
+
+    pub fn get_integer(&self, key: &ConfigKey) -> i64 {
+        match self.get_value(key).parse::<i64>() {
+            Ok(value) => value,
+            Err(_) => key.default.parse::<i64>().unwrap_or(0),
+        }
+    }
+
+    /// Returns None if the key is neither set nor has a default.
+    pub fn get_float(&self, key: &ConfigKey) -> Option<f64> {
+        self.get_value(key).parse::<f64>().ok()
+    }
+
     pub fn get_or_set_default(&self, namespace: &str, property: &str, default: &str) -> String {
         self.get(namespace, property).unwrap_or_else(|| {
             self.set(namespace, property, default);
@@ -159,13 +354,175 @@ impl ConfigService {
         })
     }
 
+    /// Sets a value, logging an error if it doesn't validate against its declaration.
     pub fn set(&self, namespace: &str, property: &str, value: &str) {
-        self.store.write().unwrap().set(namespace, property, value);
+        if let Err(err) = self.try_set(namespace, property, value) {
+            error!("{}", err);
+        }
+    }
+
+    /// Sets the value of a declared key.
+    pub fn set_value(&self, key: &ConfigKey, value: &str) -> Result<(), ConfigError> {
+        self.try_set(&key.namespace, &key.property, value)
+    }
+
+    pub fn try_set(&self, namespace: &str, property: &str, value: &str) -> Result<(), ConfigError> {
+        self.set_many(&[(namespace.to_owned(), property.to_owned(), value.to_owned())], false)
+    }
+
+    /// Validates and sets several values at once. Nothing is set if any value is
+    /// invalid. When `strict` is set, undeclared keys are rejected. The changes
+    /// are written to the configuration file shortly after, in a single batch.
+    pub fn set_many(&self,
+                    values: &[(String, String, String)],
+                    strict: bool)
+                    -> Result<(), ConfigError> {
+        {
+            let schema = self.schema.read().unwrap();
+            for &(ref namespace, ref property, ref value) in values {
+                try!(schema.validate(namespace, property, value, strict));
+            }
+        }
+
+        let changes: Vec<ConfigChange> = {
+            let mut store = self.store.write().unwrap();
+            let changed: Vec<(String, String, String)> = values.iter()
+                .filter(|&&(ref namespace, ref property, ref value)| {
+                    store.get_no_override(namespace, property) != Some(value)
+                })
+                .cloned()
+                .collect();
+            store.set_many(&changed);
+            changed.into_iter()
+                .map(|(namespace, property, value)| {
+                    ConfigChange {
+                        namespace: namespace,
+                        property: property,
+                        value: Some(value),
+                    }
+                })
+                .collect()
+        };
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let _ = self.writer.lock().unwrap().send(());
+        self.notify(&changes);
+        Ok(())
+    }
+
+    /// Writes the changes that are still batched to the configuration file.
+    pub fn flush(&self) {
+        self.store.write().unwrap().flush();
+    }
+
+    pub fn is_secret(&self, namespace: &str, property: &str) -> bool {
+        self.schema.read().unwrap().is_secret(namespace, property)
     }
 
     pub fn set_override(&self, namespace: &str, property: &str, value: &str) {
         self.store.write().unwrap().set_override(namespace, property, value);
     }
+
+    /// Lists the declared keys and the stored values, with secrets masked.
+    pub fn entries(&self) -> Vec<ConfigEntry> {
+        let schema = self.schema.read().unwrap();
+        let store = self.store.read().unwrap();
+
+        let mut entries: BTreeMap<(String, String), ConfigEntry> = BTreeMap::new();
+        for key in schema.keys() {
+            if key.property.ends_with('*') {
+                continue;
+            }
+            entries.insert((key.namespace.clone(), key.property.clone()),
+                           ConfigEntry {
+                               namespace: key.namespace.clone(),
+                               property: key.property.clone(),
+                               value: None,
+                               kind: Some(key.kind.name().to_owned()),
+                               default: Some(key.default.clone()),
+                               secret: key.secret,
+                               description: key.description.clone(),
+                           });
+        }
+        for (namespace, property, value) in store.entries() {
+            let key = schema.find(&namespace, &property);
+            let entry = entries.entry((namespace.clone(), property.clone()))
+                .or_insert_with(|| {
+                    ConfigEntry {
+                        namespace: namespace.clone(),
+                        property: property.clone(),
+                        value: None,
+                        kind: key.map(|key| key.kind.name().to_owned()),
+                        default: key.map(|key| key.default.clone()),
+                        secret: key.map_or(false, |key| key.secret),
+                        description: key.map_or(String::new(), |key| key.description.clone()),
+                    }
+                });
+            entry.value = Some(value);
+        }
+
+        entries.into_iter()
+            .map(|(_, mut entry)| {
+                if entry.secret && entry.value.is_some() {
+                    entry.value = Some(MASKED_SECRET.to_owned());
+                }
+                entry
+            })
+            .collect()
+    }
+
+    /// Registers a function called whenever a configuration value changes,
+    /// either through this service or because the file was edited.
+    pub fn add_listener<F>(&self, listener: F)
+        where F: Fn(&ConfigChange) + Send + Sync + 'static
+    {
+        self.listeners.write().unwrap().push(Box::new(listener));
+    }
+
+    fn notify(&self, changes: &[ConfigChange]) {
+        let listeners = self.listeners.read().unwrap();
+        for change in changes {
+            for listener in listeners.iter() {
+                listener(change);
+            }
+        }
+    }
+
+    /// Reloads the configuration file if it was edited, and notifies listeners.
+    pub fn reload_if_changed(&self) {
+        let changed = self.store.read().unwrap().changed_on_disk();
+        if !changed {
+            return;
+        }
+        info!("Reloading the configuration file");
+        let changes = self.store.write().unwrap().reload();
+        self.notify(&changes);
+    }
+
+    /// Watches the configuration file for edits, until the service is dropped.
+    pub fn watch_file(service: &Arc<ConfigService>, period: Duration) {
+        let service: Weak<ConfigService> = Arc::downgrade(service);
+        thread::Builder::new()
+            .name("ConfigWatcher".to_owned())
+            .spawn(move || {
+                loop {
+                    thread::sleep(period);
+                    match service.upgrade() {
+                        Some(service) => service.reload_if_changed(),
+                        None => break,
+                    }
+                }
+            })
+            .unwrap();
+    }
+}
+
+impl Drop for ConfigService {
+    fn drop(&mut self) {
+        self.flush();
+    }
 }
 
 #[cfg(test)]
@@ -200,6 +557,14 @@ describe! config {
             config.set("foo", "bar", "baz");
             assert_eq!(config.get("foo", "barbar"), None);
         }
+
+        it "should only write the file when flushed" {
+            config.set("foo", "bar", "baz");
+            config.set("foo", "qux", "quux");
+            assert!(fs::metadata(&config_file_name).is_err());
+            config.flush();
+            assert!(fs::metadata(&config_file_name).is_ok());
+        }
     }
 
     describe! config_service {
@@ -231,6 +596,54 @@ describe! config {
             assert_eq!(config.get("foo", "barbar"), None);
         }
 
+        it "should reject invalid values for declared keys" {
+            use config_schema::ConfigKey;
+
+            config.register(ConfigKey::boolean("foo", "enabled", true));
+            assert!(config.try_set("foo", "enabled", "yes").is_err());
+            assert_eq!(config.get("foo", "enabled"), None);
+            assert!(config.try_set("foo", "enabled", "false").is_ok());
+            assert_eq!(config.get_bool(&ConfigKey::boolean("foo", "enabled", true)), false);
+        }
+
+        it "should apply batches atomically" {
+            use config_schema::ConfigKey;
+
+            config.register(ConfigKey::integer("foo", "count", 1, Some(0), None));
+            let batch = vec![("foo".to_owned(), "bar".to_owned(), "baz".to_owned()),
+                             ("foo".to_owned(), "count".to_owned(), "-1".to_owned())];
+            assert!(config.set_many(&batch, false).is_err());
+            assert_eq!(config.get("foo", "bar"), None);
+            assert!(config.set_many(&batch, true).is_err());
+        }
+
+        it "should mask secrets" {
+            use config_schema::{ConfigKey, MASKED_SECRET};
+
+            config.register(ConfigKey::string("foo", "password", "").secret());
+            config.set("foo", "password", "hunter2");
+            let entries = config.entries();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].value, Some(MASKED_SECRET.to_owned()));
+        }
+
+        it "should notify listeners of changes" {
+            use std::sync::{Arc, Mutex};
+
+            let changes = Arc::new(Mutex::new(vec![]));
+            let changes_clone = changes.clone();
+            config.add_listener(move |change| changes_clone.lock().unwrap().push(change.clone()));
+            config.set("foo", "bar", "baz");
+            config.set("foo", "bar", "baz");
+
+            assert_eq!(*changes.lock().unwrap(),
+                       vec![ConfigChange {
+                                namespace: "foo".to_owned(),
+                                property: "bar".to_owned(),
+                                value: Some("baz".to_owned()),
+                            }]);
+        }
+
         it "should accept overrides" {
             config.set("foo", "bar", "baz");
             let foo_bar = config.get("foo", "bar").unwrap();
@@ -269,6 +682,32 @@ describe! config {
             };
         }
 
+        it "ConfigService should reload edited files" {
+            use std::fs::File;
+            use std::io::Write;
+            use std::sync::{Arc, Mutex};
+            use std::thread;
+            use std::time::Duration;
+
+            let config = ConfigService::new(&config_file_name);
+            config.set("foo", "bar", "baz");
+
+            let changes = Arc::new(Mutex::new(vec![]));
+            let changes_clone = changes.clone();
+            config.add_listener(move |change| changes_clone.lock().unwrap().push(change.clone()));
+
+            // Make sure the modification time changes.
+            thread::sleep(Duration::from_millis(1100));
+            File::create(&config_file_name)
+                .unwrap()
+                .write_all(br#"{"foo": {"bar": "edited"}}"#)
+                .unwrap();
+            config.reload_if_changed();
+
+            assert_eq!(config.get("foo", "bar").unwrap(), "edited");
+            assert_eq!(changes.lock().unwrap().len(), 1);
+        }
+
         it "ConfigService should remember things over restarts" {
             // Block to make `config` go out of scope
             {
@@ -17,6 +17,7 @@ extern crate libc;
 
 #[macro_use]
 extern crate log;
+extern crate rusqlite;
 extern crate serde_json;
 
 extern crate tls;
@@ -33,8 +34,12 @@ extern crate xml;
 #[macro_use]
 pub mod utils;
 
+pub mod config_schema;
 pub mod config_store;
 pub mod managed_process;
+pub mod metrics;
+pub mod migrations;
 pub mod profile_service;
+pub mod rate_limit;
 pub mod traits;
 pub mod upnp;
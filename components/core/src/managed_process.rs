@@ -5,6 +5,8 @@
 // Assumes Unix
 use libc::{self, c_int};
 
+use metrics;
+
 use std::thread;
 use std::thread::JoinHandle;
 use std::sync::{Arc, Mutex, RwLock};
@@ -120,8 +122,13 @@ impl ManagedProcess {
                         break;
                     }
 
-                    info!("Starting process. Restarted {} times",
-                          checklock!(backoff.read()).get_restart_count());
+                    let restart_count = checklock!(backoff.read()).get_restart_count();
+                    info!("Starting process. Restarted {} times", restart_count);
+                    if restart_count > 0 {
+                        metrics::global().inc("foxbox_managed_process_restarts_total",
+                                              "Number of times a managed process was restarted.",
+                                              vec![]);
+                    }
                     child_process = spawn().unwrap();
                     *pid = Some(child_process.id());
                 }
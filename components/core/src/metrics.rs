@@ -0,0 +1,249 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A process-wide registry of counters, gauges and histograms, rendered in
+//! the Prometheus text exposition format.
+//!
+//! Components record events as they happen through `metrics::global()`.
+//! Values that are cheaper to read than to track (e.g. the number of
+//! websocket clients) are refreshed by collectors right before rendering.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, Once, ONCE_INIT};
+
+/// The upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.];
+
+/// The labels of a series, e.g. `vec![("adapter", "clock@link.mozilla.org".to_owned())]`.
+pub type Labels = Vec<(&'static str, String)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// The number of observations in each of `BUCKETS` (not cumulated).
+    buckets: [u64; 11],
+    sum: f64,
+    count: u64,
+}
+
+struct Family {
+    kind: Kind,
+    help: &'static str,
+    values: BTreeMap<Labels, f64>,
+    histograms: BTreeMap<Labels, Histogram>,
+}
+
+type Collector = Box<Fn(&Metrics) + Send>;
+
+pub struct Metrics {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+    collectors: Mutex<Vec<Collector>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            families: Mutex::new(BTreeMap::new()),
+            collectors: Mutex::new(vec![]),
+        }
+    }
+
+    fn with_family<F>(&self, name: &'static str, kind: Kind, help: &'static str, cb: F)
+        where F: FnOnce(&mut Family)
+    {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| {
+            Family {
+                kind: kind,
+                help: help,
+                values: BTreeMap::new(),
+                histograms: BTreeMap::new(),
+            }
+        });
+        if family.kind != kind {
+            error!("Metric {} is a {}, not a {}",
+                   name,
+                   family.kind.as_str(),
+                   kind.as_str());
+            return;
+        }
+        cb(family);
+    }
+
+    /// Increments a counter by one.
+    pub fn inc(&self, name: &'static str, help: &'static str, labels: Labels) {
+        self.with_family(name, Kind::Counter, help, |family| {
+            *family.values.entry(labels).or_insert(0.) += 1.;
+        });
+    }
+
+    /// Sets the value of a gauge.
+    pub fn set(&self, name: &'static str, help: &'static str, labels: Labels, value: f64) {
+        self.with_family(name, Kind::Gauge, help, |family| {
+            family.values.insert(labels, value);
+        });
+    }
+
+    /// Replaces all the series of a gauge, dropping the ones that are not in `values`.
+    pub fn set_all(&self, name: &'static str, help: &'static str, values: Vec<(Labels, f64)>) {
+        self.with_family(name, Kind::Gauge, help, |family| {
+            family.values = values.into_iter().collect();
+        });
+    }
+
+    /// Records an observation, typically a duration in seconds, in a histogram.
+    pub fn observe(&self, name: &'static str, help: &'static str, labels: Labels, value: f64) {
+        self.with_family(name, Kind::Histogram, help, |family| {
+            let histogram = family.histograms.entry(labels).or_insert_with(Histogram::default);
+            if let Some(index) = BUCKETS.iter().position(|bound| value <= *bound) {
+                histogram.buckets[index] += 1;
+            }
+            histogram.sum += value;
+            histogram.count += 1;
+        });
+    }
+
+    /// Registers a function called before each rendering, to update gauges.
+    pub fn add_collector<F>(&self, collector: F)
+        where F: Fn(&Metrics) + Send + 'static
+    {
+        self.collectors.lock().unwrap().push(Box::new(collector));
+    }
+
+    /// Renders all the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        for collector in self.collectors.lock().unwrap().iter() {
+            collector(self);
+        }
+
+        let mut out = String::new();
+        let families = self.families.lock().unwrap();
+        for (name, family) in families.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind.as_str());
+            for (labels, value) in &family.values {
+                let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+            }
+            for (labels, histogram) in &family.histograms {
+                let mut cumulated = 0;
+                for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                    cumulated += *count;
+                    let _ = writeln!(out,
+                                     "{}_bucket{} {}",
+                                     name,
+                                     format_labels(labels, Some(&bound.to_string())),
+                                     cumulated);
+                }
+                let _ = writeln!(out,
+                                 "{}_bucket{} {}",
+                                 name,
+                                 format_labels(labels, Some("+Inf")),
+                                 histogram.count);
+                let _ = writeln!(out,
+                                 "{}_sum{} {}",
+                                 name,
+                                 format_labels(labels, None),
+                                 histogram.sum);
+                let _ = writeln!(out,
+                                 "{}_count{} {}",
+                                 name,
+                                 format_labels(labels, None),
+                                 histogram.count);
+            }
+        }
+        out
+    }
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels.iter()
+        .map(|&(key, ref value)| format!("{}=\"{}\"", key, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// The registry shared by the whole process.
+pub fn global() -> &'static Metrics {
+    static INIT: Once = ONCE_INIT;
+    static mut METRICS: *const Metrics = 0 as *const Metrics;
+    unsafe {
+        INIT.call_once(|| {
+            METRICS = Box::into_raw(Box::new(Metrics::new()));
+        });
+        &*METRICS
+    }
+}
+
+#[cfg(test)]
+describe! metrics {
+    before_each {
+        let metrics = Metrics::new();
+    }
+
+    it "should render counters and gauges with their labels" {
+        metrics.inc("requests_total", "Requests", vec![("route", "ping".to_owned())]);
+        metrics.inc("requests_total", "Requests", vec![("route", "ping".to_owned())]);
+        metrics.set("clients", "Clients", vec![], 3.);
+        assert_eq!(metrics.render(),
+                   "# HELP clients Clients\n\
+                    # TYPE clients gauge\n\
+                    clients 3\n\
+                    # HELP requests_total Requests\n\
+                    # TYPE requests_total counter\n\
+                    requests_total{route=\"ping\"} 2\n");
+    }
+
+    it "should cumulate histogram buckets" {
+        metrics.observe("latency", "Latency", vec![], 0.25);
+        metrics.observe("latency", "Latency", vec![], 20.);
+        let rendered = metrics.render();
+        assert!(rendered.contains("latency_bucket{le=\"0.1\"} 0\n"));
+        assert!(rendered.contains("latency_bucket{le=\"0.25\"} 1\n"));
+        assert!(rendered.contains("latency_bucket{le=\"10\"} 1\n"));
+        assert!(rendered.contains("latency_bucket{le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("latency_sum 20.25\n"));
+        assert!(rendered.contains("latency_count 2\n"));
+    }
+
+    it "should refresh gauges with collectors and escape labels" {
+        metrics.add_collector(|metrics| {
+            metrics.set_all("watches", "Watches", vec![(vec![("adapter", "a\"b".to_owned())], 1.)]);
+        });
+        assert!(metrics.render().contains("watches{adapter=\"a\\\"b\"} 1\n"));
+    }
+}
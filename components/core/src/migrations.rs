@@ -0,0 +1,210 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Schema versioning for the `SQLite` databases of the profile.
+//!
+//! Each store declares the ordered list of migrations that build its schema.
+//! The version of a database is kept in its `user_version` pragma, and the
+//! pending migrations are applied in a single transaction when the store is
+//! opened. A database created by a newer version of the box is never touched.
+//!
+//! Databases created before versioning existed have a `user_version` of 0,
+//! so the first migration of a store must accept an already existing schema
+//! (e.g. use `CREATE TABLE IF NOT EXISTS`).
+
+use rusqlite::{self, Connection};
+use std::fmt;
+
+/// A single step in the schema history of a database.
+#[derive(Clone, Debug)]
+pub struct Migration {
+    /// The schema version once this migration is applied. Versions start at 1
+    /// and must be consecutive.
+    pub version: u32,
+    pub description: &'static str,
+    /// The statements to run, separated by semicolons.
+    pub sql: &'static str,
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(rusqlite::Error),
+    /// The database was written by a newer schema than the one we know about.
+    Downgrade { found: u32, supported: u32 },
+    /// The list of migrations itself is malformed.
+    InvalidMigrations(String),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MigrationError::Sqlite(ref err) => write!(f, "Database error: {}", err),
+            MigrationError::Downgrade { found, supported } => {
+                write!(f,
+                       "The database schema version {} is newer than the supported version {}",
+                       found,
+                       supported)
+            }
+            MigrationError::InvalidMigrations(ref reason) => {
+                write!(f, "Invalid migrations: {}", reason)
+            }
+        }
+    }
+}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(err: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(err)
+    }
+}
+
+/// Returns the schema version stored in the database.
+pub fn schema_version(connection: &Connection) -> rusqlite::Result<u32> {
+    connection.query_row("PRAGMA user_version", &[], |row| row.get::<i32, i32>(0) as u32)
+}
+
+fn check_migrations(migrations: &[Migration]) -> Result<(), MigrationError> {
+    for (index, migration) in migrations.iter().enumerate() {
+        if migration.version != index as u32 + 1 {
+            return Err(MigrationError::InvalidMigrations(format!("expected version {}, got {} \
+                                                                  ({})",
+                                                                 index + 1,
+                                                                 migration.version,
+                                                                 migration.description)));
+        }
+    }
+    Ok(())
+}
+
+/// Brings the database up to date with `migrations` and returns the resulting
+/// schema version. `name` is only used for logging.
+///
+/// Either all the pending migrations are applied or none of them is.
+pub fn migrate(connection: &mut Connection,
+               name: &str,
+               migrations: &[Migration])
+               -> Result<u32, MigrationError> {
+    try!(check_migrations(migrations));
+
+    let supported = migrations.len() as u32;
+    let current = try!(schema_version(connection));
+    if current > supported {
+        error!("The {} database has schema version {} but only version {} is supported",
+               name,
+               current,
+               supported);
+        return Err(MigrationError::Downgrade {
+            found: current,
+            supported: supported,
+        });
+    }
+    if current == supported {
+        return Ok(current);
+    }
+
+    let transaction = try!(connection.transaction());
+    for migration in &migrations[current as usize..] {
+        info!("Migrating the {} database to version {}: {}",
+              name,
+              migration.version,
+              migration.description);
+        try!(transaction.execute_batch(migration.sql));
+    }
+    // The pragma doesn't accept bound parameters.
+    try!(transaction.execute_batch(&format!("PRAGMA user_version = {}", supported)));
+    try!(transaction.commit());
+
+    Ok(supported)
+}
+
+#[cfg(test)]
+describe! migrations {
+    before_each {
+        use rusqlite::Connection;
+
+        static MIGRATIONS: [Migration; 2] = [
+            Migration {
+                version: 1,
+                description: "Create the items table",
+                sql: "CREATE TABLE IF NOT EXISTS items (name TEXT NOT NULL)",
+            },
+            Migration {
+                version: 2,
+                description: "Add the created_at column",
+                sql: "ALTER TABLE items ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0",
+            },
+        ];
+
+        let mut connection = Connection::open_in_memory().unwrap();
+    }
+
+    it "should create a fresh database" {
+        assert_eq!(migrate(&mut connection, "test", &MIGRATIONS).unwrap(), 2);
+        assert_eq!(schema_version(&connection).unwrap(), 2);
+        connection.execute("INSERT INTO items (name, created_at) VALUES ('foo', 42)", &[])
+            .unwrap();
+    }
+
+    it "should upgrade an unversioned database and keep its data" {
+        connection.execute_batch("CREATE TABLE items (name TEXT NOT NULL);
+                                  INSERT INTO items VALUES ('legacy');")
+            .unwrap();
+        assert_eq!(schema_version(&connection).unwrap(), 0);
+
+        assert_eq!(migrate(&mut connection, "test", &MIGRATIONS).unwrap(), 2);
+        let created_at: i64 = connection.query_row("SELECT created_at FROM items \
+                                                    WHERE name = 'legacy'",
+                                                   &[],
+                                                   |row| row.get(0))
+            .unwrap();
+        assert_eq!(created_at, 0);
+    }
+
+    it "should only apply pending migrations" {
+        assert_eq!(migrate(&mut connection, "test", &MIGRATIONS[..1]).unwrap(), 1);
+        assert_eq!(migrate(&mut connection, "test", &MIGRATIONS).unwrap(), 2);
+        // Running again is a no-op.
+        assert_eq!(migrate(&mut connection, "test", &MIGRATIONS).unwrap(), 2);
+    }
+
+    it "should refuse to downgrade" {
+        assert_eq!(migrate(&mut connection, "test", &MIGRATIONS).unwrap(), 2);
+        match migrate(&mut connection, "test", &MIGRATIONS[..1]) {
+            Err(MigrationError::Downgrade { found: 2, supported: 1 }) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    it "should roll back when a migration fails" {
+        static BROKEN: [Migration; 2] = [
+            Migration {
+                version: 1,
+                description: "Create the items table",
+                sql: "CREATE TABLE items (name TEXT NOT NULL)",
+            },
+            Migration {
+                version: 2,
+                description: "Broken",
+                sql: "ALTER TABLE missing ADD COLUMN foo TEXT",
+            },
+        ];
+        assert!(migrate(&mut connection, "test", &BROKEN).is_err());
+        assert_eq!(schema_version(&connection).unwrap(), 0);
+        assert!(connection.prepare("SELECT * FROM items").is_err());
+    }
+
+    it "should reject non consecutive versions" {
+        static GAP: [Migration; 1] = [
+            Migration {
+                version: 2,
+                description: "Starts at 2",
+                sql: "",
+            },
+        ];
+        match migrate(&mut connection, "test", &GAP) {
+            Err(MigrationError::InvalidMigrations(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
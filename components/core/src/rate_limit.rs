@@ -0,0 +1,481 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Throttling of the HTTP and websocket clients.
+//!
+//! The box is reachable from the internet through the tunnel, so each client
+//! ip address and each user gets a token bucket refilled at a configurable
+//! rate, all the requests share a global one, and clients that keep failing
+//! to authenticate (wrong password, invalid token) are locked out for an
+//! exponentially growing duration.
+//!
+//! Note that all the clients coming through the tunnel share the address of
+//! the tunnel end point, so they only get the per-user and global limits.
+//! For the same reason, failures are counted against the address of the
+//! client, and the account it tries to log in as from that address, only
+//! when its address is really known. Failed logins from unknown addresses
+//! only slow the account down for a short fixed delay, so that a single
+//! client can't lock out a remote user for long.
+
+use config_schema::ConfigKey;
+use config_store::ConfigService;
+use metrics;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The longest lockout, in seconds.
+const MAX_LOCKOUT_S: u64 = 3600;
+
+/// How long an account is throttled after too many failed logins from
+/// clients whose address is unknown, in seconds.
+const ACCOUNT_DELAY_S: u64 = 5;
+
+/// How long we remember a client that doesn't send any request, in seconds.
+const FORGET_AFTER_S: u64 = 3600;
+
+fn ip_requests_per_minute_config() -> ConfigKey {
+    ConfigKey::integer("rate_limit",
+                       "ip_requests_per_minute",
+                       600,
+                       Some(1),
+                       Some(1_000_000))
+        .description("Requests allowed per minute from a single ip address")
+}
+
+fn user_requests_per_minute_config() -> ConfigKey {
+    ConfigKey::integer("rate_limit",
+                       "user_requests_per_minute",
+                       300,
+                       Some(1),
+                       Some(1_000_000))
+        .description("Requests allowed per minute from a single user")
+}
+
+fn global_requests_per_minute_config() -> ConfigKey {
+    ConfigKey::integer("rate_limit",
+                       "global_requests_per_minute",
+                       3000,
+                       Some(1),
+                       Some(10_000_000))
+        .description("Requests allowed per minute from all the clients together")
+}
+
+fn max_failures_config() -> ConfigKey {
+    ConfigKey::integer("rate_limit", "max_failures", 5, Some(1), Some(1000))
+        .description("Failed authentications from a client or for an account before it gets \
+                      locked out")
+}
+
+fn lockout_config() -> ConfigKey {
+    ConfigKey::integer("rate_limit",
+                       "lockout_s",
+                       30,
+                       Some(1),
+                       Some(MAX_LOCKOUT_S as i64))
+        .description("Seconds of the first lockout, doubled after each further failure")
+}
+
+/// Why a request was refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Blocked {
+    /// Too many requests, try again after the given duration.
+    RateLimited(Duration),
+    /// Too many failed authentications, try again after the given duration.
+    LockedOut(Duration),
+}
+
+impl Blocked {
+    pub fn retry_after(&self) -> Duration {
+        match *self {
+            Blocked::RateLimited(duration) |
+            Blocked::LockedOut(duration) => duration,
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match *self {
+            Blocked::RateLimited(_) => "rate",
+            Blocked::LockedOut(_) => "lockout",
+        }
+    }
+}
+
+/// What failed authentications are counted against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FailureKey {
+    /// A client whose real address is known.
+    Ip(IpAddr),
+    /// An account someone tried to log in as, from a client whose address
+    /// is unknown. Only ever throttled for a short fixed delay.
+    Account(String),
+    /// An account someone tried to log in as, from a client whose real
+    /// address is known.
+    AccountClient(String, IpAddr),
+    /// A client whose real address is known, posting to unknown incoming
+    /// webhooks. Kept apart from `Ip`, as these posts are anonymous.
+    Hook(IpAddr),
+}
+
+/// The address of a client connecting from `remote`, if that is its real
+/// address. The tunnel relays the connections of all the remote clients from
+/// localhost, so their address is unknown.
+fn real_address(remote: &IpAddr) -> Option<IpAddr> {
+    let is_loopback = match *remote {
+        IpAddr::V4(ref ip) => ip.is_loopback(),
+        IpAddr::V6(ref ip) => ip.is_loopback() || ip.to_ipv4().map_or(false, |ip| ip.is_loopback()),
+    };
+    if is_loopback {
+        None
+    } else {
+        Some(*remote)
+    }
+}
+
+impl FailureKey {
+    /// The key of a client connecting from `remote`, if its address is known.
+    pub fn client(remote: &IpAddr) -> Option<FailureKey> {
+        real_address(remote).map(FailureKey::Ip)
+    }
+
+    /// The key of logins as `account` from a client connecting from `remote`.
+    pub fn account(account: &str, remote: &IpAddr) -> FailureKey {
+        match real_address(remote) {
+            Some(ip) => FailureKey::AccountClient(account.to_owned(), ip),
+            None => FailureKey::Account(account.to_owned()),
+        }
+    }
+
+    /// The key of a client posting to incoming webhooks from `remote`, if its
+    /// address is known.
+    pub fn hook_client(remote: &IpAddr) -> Option<FailureKey> {
+        real_address(remote).map(FailureKey::Hook)
+    }
+}
+
+/// The limits, read from the configuration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub ip_requests_per_minute: u64,
+    pub user_requests_per_minute: u64,
+    pub global_requests_per_minute: u64,
+    pub max_failures: u32,
+    pub lockout: Duration,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl Bucket {
+    /// Takes a token out of a bucket holding at most `per_minute` tokens,
+    /// refilled at `per_minute` tokens per minute.
+    fn take(&mut self, per_minute: u64, now: Instant) -> Result<(), Duration> {
+        let capacity = per_minute as f64;
+        let elapsed = now.duration_since(self.last_update);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * capacity / 60.).min(capacity);
+        self.last_update = now;
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            Ok(())
+        } else {
+            let wait = (1. - self.tokens) * 60. / capacity;
+            Err(Duration::from_millis((wait * 1000.).ceil() as u64))
+        }
+    }
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Default)]
+struct State {
+    buckets: HashMap<String, Bucket>,
+    failures: HashMap<FailureKey, Failures>,
+    last_cleanup: Option<Instant>,
+}
+
+impl State {
+    fn check(&mut self,
+             limits: &Limits,
+             ip: &IpAddr,
+             user: Option<&str>,
+             now: Instant)
+             -> Result<(), Blocked> {
+        self.cleanup(now);
+
+        let mut keys = vec![("global".to_owned(), limits.global_requests_per_minute)];
+        // All the clients of the tunnel share its address, they must not
+        // share a bucket too.
+        if let Some(ip) = real_address(ip) {
+            keys.push((format!("ip:{}", ip), limits.ip_requests_per_minute));
+        }
+        if let Some(user) = user {
+            keys.push((format!("user:{}", user), limits.user_requests_per_minute));
+        }
+        for (key, per_minute) in keys {
+            let bucket = self.buckets.entry(key).or_insert_with(|| {
+                Bucket {
+                    tokens: per_minute as f64,
+                    last_update: now,
+                }
+            });
+            try!(bucket.take(per_minute, now).map_err(Blocked::RateLimited));
+        }
+        Ok(())
+    }
+
+    /// Returns the longest lockout of `keys`, if any.
+    fn check_lockout(&self, keys: &[FailureKey], now: Instant) -> Result<(), Blocked> {
+        let until = keys.iter()
+            .filter_map(|key| self.failures.get(key).and_then(|failures| failures.locked_until))
+            .filter(|until| *until > now)
+            .max();
+        match until {
+            Some(until) => Err(Blocked::LockedOut(until.duration_since(now))),
+            None => Ok(()),
+        }
+    }
+
+    fn record_failure(&mut self, limits: &Limits, key: &FailureKey, now: Instant) {
+        let failures = self.failures.entry(key.clone()).or_insert_with(Failures::default);
+        failures.count += 1;
+        if failures.count < limits.max_failures {
+            return;
+        }
+        if let FailureKey::Account(_) = *key {
+            // Anybody can fail to log in as anybody through the tunnel, this
+            // must not keep the owner of the account out.
+            let delay = limits.lockout.as_secs().min(ACCOUNT_DELAY_S);
+            failures.locked_until = Some(now + Duration::from_secs(delay));
+        } else {
+            // Double the lockout for each failure past the limit.
+            let exponent = (failures.count - limits.max_failures).min(16);
+            let lockout = (limits.lockout.as_secs() << exponent).min(MAX_LOCKOUT_S);
+            warn!("Locking {:?} out for {}s after {} failed authentications",
+                  key,
+                  lockout,
+                  failures.count);
+            failures.locked_until = Some(now + Duration::from_secs(lockout));
+        }
+    }
+
+    fn record_success(&mut self, key: &FailureKey) {
+        self.failures.remove(key);
+    }
+
+    /// Forgets the clients we haven't heard of for a while.
+    fn cleanup(&mut self, now: Instant) {
+        let forget_after = Duration::from_secs(FORGET_AFTER_S);
+        match self.last_cleanup {
+            Some(last) if now.duration_since(last) < forget_after => return,
+            _ => self.last_cleanup = Some(now),
+        }
+        self.buckets.retain(|_, bucket| now.duration_since(bucket.last_update) < forget_after);
+        self.failures.retain(|_, failures| {
+            failures.locked_until.map_or(false, |until| until > now)
+        });
+    }
+}
+
+pub struct RateLimiter {
+    config: Arc<ConfigService>,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(config: Arc<ConfigService>) -> Self {
+        config.register(ip_requests_per_minute_config());
+        config.register(user_requests_per_minute_config());
+        config.register(global_requests_per_minute_config());
+        config.register(max_failures_config());
+        config.register(lockout_config());
+        RateLimiter {
+            config: config,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn get_limits(&self) -> Limits {
+        let config = &self.config;
+        Limits {
+            ip_requests_per_minute: config.get_integer(&ip_requests_per_minute_config()) as u64,
+            user_requests_per_minute: config.get_integer(&user_requests_per_minute_config()) as u64,
+            global_requests_per_minute: config.get_integer(&global_requests_per_minute_config()) as
+                                        u64,
+            max_failures: config.get_integer(&max_failures_config()) as u32,
+            lockout: Duration::from_secs(config.get_integer(&lockout_config()) as u64),
+        }
+    }
+
+    /// Accounts for a request from `ip`, on behalf of `user` if known.
+    /// `transport` (e.g. "http" or "ws") labels the metrics of blocked requests.
+    pub fn check(&self,
+                 transport: &'static str,
+                 ip: &IpAddr,
+                 user: Option<&str>)
+                 -> Result<(), Blocked> {
+        let limits = self.get_limits();
+        let result = self.state.lock().unwrap().check(&limits, ip, user, Instant::now());
+        if let Err(blocked) = result {
+            debug!("Blocked a {} request from {}: {:?}", transport, ip, blocked);
+            Self::count_blocked(transport, &blocked);
+        }
+        result
+    }
+
+    /// Refuses the requests of a client if any of `keys` is locked out.
+    pub fn check_lockout(&self,
+                         transport: &'static str,
+                         keys: &[FailureKey])
+                         -> Result<(), Blocked> {
+        let result = self.state.lock().unwrap().check_lockout(keys, Instant::now());
+        if let Err(blocked) = result {
+            debug!("Blocked a {} request for {:?}: {:?}", transport, keys, blocked);
+            Self::count_blocked(transport, &blocked);
+        }
+        result
+    }
+
+    fn count_blocked(transport: &'static str, blocked: &Blocked) {
+        metrics::global().inc("foxbox_rate_limit_blocked_total",
+                              "Number of requests refused by the rate limiter.",
+                              vec![("transport", transport.to_owned()),
+                                   ("reason", blocked.reason().to_owned())]);
+    }
+
+    /// Records a failed authentication, e.g. an invalid token, against each of `keys`.
+    pub fn record_failure(&self, keys: &[FailureKey]) {
+        let limits = self.get_limits();
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            state.record_failure(&limits, key, now);
+        }
+    }
+
+    /// Records a successful authentication, which resets the failures of `keys`.
+    pub fn record_success(&self, keys: &[FailureKey]) {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            state.record_success(key);
+        }
+    }
+}
+
+#[cfg(test)]
+describe! rate_limit {
+    before_each {
+        use std::net::{IpAddr, Ipv4Addr};
+        use std::time::{Duration, Instant};
+
+        let limits = Limits {
+            ip_requests_per_minute: 60,
+            user_requests_per_minute: 2,
+            global_requests_per_minute: 100,
+            max_failures: 3,
+            lockout: Duration::from_secs(30),
+        };
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 4));
+        let client = FailureKey::Ip(ip);
+        let localhost = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let account = FailureKey::account("user@domain.org", &ip);
+        let mut state = State::default();
+        let now = Instant::now();
+    }
+
+    it "should refill the buckets over time" {
+        for _ in 0..60 {
+            assert_eq!(state.check(&limits, &ip, None, now), Ok(()));
+        }
+        assert_eq!(state.check(&limits, &ip, None, now),
+                   Err(Blocked::RateLimited(Duration::from_secs(1))));
+        assert_eq!(state.check(&limits, &ip, None, now + Duration::from_secs(1)), Ok(()));
+    }
+
+    it "should not share a bucket between the clients of the tunnel" {
+        // Only the global limit applies to them.
+        for _ in 0..100 {
+            assert_eq!(state.check(&limits, &localhost, None, now), Ok(()));
+        }
+        assert!(state.check(&limits, &localhost, None, now).is_err());
+        assert!(state.check(&limits, &ip, None, now).is_err());
+    }
+
+    it "should limit each user" {
+        assert_eq!(state.check(&limits, &ip, Some("1"), now), Ok(()));
+        assert_eq!(state.check(&limits, &ip, Some("1"), now), Ok(()));
+        assert!(state.check(&limits, &ip, Some("1"), now).is_err());
+        assert_eq!(state.check(&limits, &ip, Some("2"), now), Ok(()));
+    }
+
+    it "should lock out after too many failures, for longer and longer" {
+        state.record_failure(&limits, &client, now);
+        state.record_failure(&limits, &client, now);
+        assert_eq!(state.check_lockout(&[client.clone()], now), Ok(()));
+
+        state.record_failure(&limits, &client, now);
+        assert_eq!(state.check_lockout(&[client.clone()], now),
+                   Err(Blocked::LockedOut(Duration::from_secs(30))));
+
+        let later = now + Duration::from_secs(30);
+        assert_eq!(state.check_lockout(&[client.clone()], later), Ok(()));
+        state.record_failure(&limits, &client, later);
+        assert_eq!(state.check_lockout(&[client.clone()], later),
+                   Err(Blocked::LockedOut(Duration::from_secs(60))));
+
+        state.record_success(&client);
+        assert_eq!(state.check_lockout(&[client.clone()], later), Ok(()));
+    }
+
+    it "should lock out accounts separately from clients" {
+        for _ in 0..3 {
+            state.record_failure(&limits, &account, now);
+        }
+        assert_eq!(state.check_lockout(&[client.clone()], now), Ok(()));
+        assert_eq!(state.check_lockout(&[client.clone(), account.clone()], now),
+                   Err(Blocked::LockedOut(Duration::from_secs(30))));
+        // Rate limiting doesn't depend on the lockouts.
+        assert_eq!(state.check(&limits, &ip, None, now), Ok(()));
+    }
+
+    it "should only throttle the accounts of unknown clients for a short while" {
+        let account = FailureKey::account("user@domain.org", &localhost);
+        assert_eq!(account, FailureKey::Account("user@domain.org".to_owned()));
+        for _ in 0..10 {
+            state.record_failure(&limits, &account, now);
+        }
+        assert_eq!(state.check_lockout(&[account.clone()], now),
+                   Err(Blocked::LockedOut(Duration::from_secs(ACCOUNT_DELAY_S))));
+        let later = now + Duration::from_secs(ACCOUNT_DELAY_S);
+        assert_eq!(state.check_lockout(&[account.clone()], later), Ok(()));
+        // Failures of known clients don't count against them.
+        assert_eq!(state.check_lockout(&[FailureKey::account("user@domain.org", &ip)], now),
+                   Ok(()));
+    }
+
+    it "should only know the address of clients that don't come through the tunnel" {
+        use std::net::Ipv6Addr;
+
+        assert_eq!(FailureKey::client(&ip), Some(client.clone()));
+        assert_eq!(FailureKey::client(&IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))), None);
+        assert_eq!(FailureKey::client(&IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))),
+                   None);
+    }
+
+    it "should keep the failures of the incoming webhooks apart" {
+        let hook_client = FailureKey::hook_client(&ip).unwrap();
+        for _ in 0..3 {
+            state.record_failure(&limits, &hook_client, now);
+        }
+        assert!(state.check_lockout(&[hook_client.clone()], now).is_err());
+        assert_eq!(state.check_lockout(&[client.clone()], now), Ok(()));
+    }
+}
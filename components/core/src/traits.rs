@@ -5,13 +5,14 @@
 use config_store::ConfigService;
 use foxbox_users::UsersManager;
 use profile_service::ProfileService;
+use rate_limit::RateLimiter;
 use serde_json;
 use std::io;
 use std::net::SocketAddr;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::vec::IntoIter;
-use tls::{CertificateRecord, CertificateManager};
+use tls::{CertificateRecord, CertificateManager, TlsOption};
 use upnp::UpnpManager;
 use ws;
 
@@ -23,8 +24,11 @@ pub trait Controller: Send + Sync + Clone + 'static {
     fn ws_as_addrs(&self) -> Result<IntoIter<SocketAddr>, io::Error>;
 
     fn get_tls_enabled(&self) -> bool;
+    fn get_tls_option(&self) -> TlsOption;
     fn get_certificate_manager(&self) -> CertificateManager;
     fn get_box_certificate(&self) -> io::Result<CertificateRecord>;
+    /// The certificate of the HTTPS server, if it is available yet.
+    fn get_served_certificate(&self) -> Option<CertificateRecord>;
     fn get_hostname(&self) -> String;
     fn get_domain(&self) -> String;
 
@@ -35,5 +39,6 @@ pub trait Controller: Send + Sync + Clone + 'static {
     fn get_config(&self) -> Arc<ConfigService>;
     fn get_upnp_manager(&self) -> Arc<UpnpManager>;
     fn get_users_manager(&self) -> Arc<UsersManager>;
+    fn get_rate_limiter(&self) -> Arc<RateLimiter>;
     fn get_profile(&self) -> &ProfileService;
 }
@@ -191,7 +191,62 @@ pub fn parse_simple_xml<R: Read>(data: R) -> Result<HashMap<String, String>, Str
     Ok(values)
 }
 
-#[allow(dead_code)]
+/// Returns the children of each element whose path ends with `suffix`, as a
+/// map from the local name of each child to its text.
+///
+/// Unlike `parse_simple_xml`, this supports repeated elements, e.g. the
+/// `/serviceList/service` entries of a `UPnP` device description.
+pub fn parse_xml_elements<R: Read>(data: R,
+                                   suffix: &str)
+                                   -> Result<Vec<HashMap<String, String>>, String> {
+    let parser = EventReader::new(data);
+    let mut elements = vec![];
+    let mut path = String::new();
+    // The length of the path of the element being collected, if any.
+    let mut current: Option<(usize, HashMap<String, String>)> = None;
+    let mut child = String::new();
+    for e in parser {
+        match e {
+            Ok(XmlEvent::StartElement { name, .. }) => {
+                path.push('/');
+                path.push_str(name.local_name.as_str());
+                match current {
+                    None if path.ends_with(suffix) => current = Some((path.len(), HashMap::new())),
+                    Some((len, _)) if path[len..].rfind('/') == Some(0) => {
+                        child = name.local_name.clone();
+                    }
+                    _ => {}
+                }
+            }
+            Ok(XmlEvent::EndElement { name, .. }) => {
+                if let Some((len, _)) = current {
+                    if path.len() == len {
+                        elements.push(current.take().unwrap().1);
+                    }
+                }
+                child.clear();
+                match path.rfind('/') {
+                    Some(x) => path.truncate(x),
+                    None => return Err(format!("broken path {} at ending element {}", path, name)),
+                }
+            }
+            Ok(XmlEvent::Characters(x)) |
+            Ok(XmlEvent::CData(x)) => {
+                if let Some((_, ref mut values)) = current {
+                    if !child.is_empty() {
+                        values.entry(child.clone()).or_insert_with(String::new).push_str(&x);
+                    }
+                }
+            }
+            Err(e) => {
+                return Err(format!("parse error {}", e));
+            }
+            _ => {}
+        }
+    }
+    Ok(elements)
+}
+
 pub fn escape(unescaped_string: &str, to_escape: Vec<char>) -> String {
     let mut escaped_string = String::new();
     for chr in unescaped_string.to_owned().chars() {
@@ -241,6 +296,25 @@ pub fn split_escaped(string: &str, separator: char) -> Vec<String> {
     split
 }
 
+/// Turns a name into an id, e.g. "Movie night" into "movie-night". Returns
+/// `fallback` if the name has no alphanumeric character.
+pub fn slug(name: &str, fallback: &str) -> String {
+    let mut slug = String::new();
+    for chr in name.trim().to_lowercase().chars() {
+        if chr.is_alphanumeric() {
+            slug.push(chr);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_right_matches('-').to_owned();
+    if slug.is_empty() {
+        fallback.to_owned()
+    } else {
+        slug
+    }
+}
+
 #[cfg(test)]
 describe! string_escaping {
     it "should escape strings" {
@@ -255,3 +329,45 @@ describe! string_escaping {
         assert_eq!(split_escaped(r#"foo\;foo;bar;"#, ';'), vec!["foo;foo", "bar", ""]);
     }
 }
+
+#[cfg(test)]
+describe! slugs {
+    it "should derive ids from names" {
+        assert_eq!(slug("Movie night", "scene"), "movie-night");
+        assert_eq!(slug("  Good night!! ", "scene"), "good-night");
+        assert_eq!(slug("???", "scene"), "scene");
+    }
+}
+
+#[cfg(test)]
+describe! xml_elements {
+    before_each {
+        let description = r#"<?xml version="1.0"?>
+            <root xmlns="urn:schemas-upnp-org:device-1-0">
+              <device>
+                <friendlyName>Living room</friendlyName>
+                <serviceList>
+                  <service>
+                    <serviceType>urn:schemas-upnp-org:service:AVTransport:1</serviceType>
+                    <controlURL>/AVTransport/control</controlURL>
+                  </service>
+                  <service>
+                    <serviceType>urn:schemas-upnp-org:service:RenderingControl:1</serviceType>
+                    <controlURL>/RenderingControl/control</controlURL>
+                  </service>
+                </serviceList>
+              </device>
+            </root>"#;
+    }
+
+    it "should return repeated elements" {
+        let services = parse_xml_elements(description.as_bytes(), "/serviceList/service").unwrap();
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0]["serviceType"], "urn:schemas-upnp-org:service:AVTransport:1");
+        assert_eq!(services[1]["controlURL"], "/RenderingControl/control");
+    }
+
+    it "should return nothing when there is no match" {
+        assert!(parse_xml_elements(description.as_bytes(), "/iconList/icon").unwrap().is_empty());
+    }
+}
@@ -25,8 +25,6 @@ use std::{error, fmt};
 use std::error::Error as std_error;
 use std::sync::Arc;
 
-use serde_json;
-
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub enum Operation {
     Fetch,
@@ -83,29 +81,103 @@ pub enum Error {
 
     // An error happened while attempting to serialize a value.
     Serializing(SerializeError),
+
+    /// The adapter did not answer before the deadline. The operation may
+    /// still complete later, but its result is lost.
+    Timeout(Id<AdapterId>),
 }
 
-impl ToJSON for Error {
-    fn to_json(&self) -> JSON {
-        use self::Error::*;
+impl Error {
+    /// A stable, machine-readable code for the kind of error, e.g. `no_such_channel`.
+    pub fn code(&self) -> &'static str {
         match *self {
-            OperationNotSupported(ref op, ref id) => {
-                vec![("OperationNotSupported",
-                      vec![("operation", op.to_json()), ("channel", id.to_json())])]
-                    .to_json()
-            }
-            GetterRequiresThresholdForWatching(ref id) => {
-                vec![("GetterRequiresThresholdForWatching", id.to_json())].to_json()
-            }
-            InvalidValue => "InvalidValue".to_json(),
-            Internal(_) => "Internal Error".to_json(), // FIXME: Implement ToJSON for InternalError as well
-            Parsing(ref err) => vec![("ParseError", serde_json::to_value(err))].to_json(),
-            Serializing(ref err) => vec![("SerializeError", serde_json::to_value(err))].to_json(),
-            WrongType(ref err) => vec![("TypeError", serde_json::to_value(err))].to_json(),
+            Error::OperationNotSupported(_, _) => "operation_not_supported",
+            Error::GetterRequiresThresholdForWatching(_) => "threshold_required",
+            Error::WrongType(_) => "wrong_type",
+            Error::InvalidValue => "invalid_value",
+            Error::Internal(ref err) => err.code(),
+            Error::Parsing(ref err) => err.code(),
+            Error::Serializing(_) => "serialize_error",
+            Error::Timeout(_) => "timeout",
+        }
+    }
+
+    /// The HTTP status code matching this error.
+    pub fn status(&self) -> u16 {
+        match *self {
+            Error::OperationNotSupported(_, _) |
+            Error::GetterRequiresThresholdForWatching(_) |
+            Error::WrongType(_) |
+            Error::InvalidValue |
+            Error::Parsing(_) => 400,
+            Error::Internal(ref err) => err.status(),
+            // The adapter or the device sent something we can't make sense of.
+            Error::Serializing(_) => 502,
+            Error::Timeout(_) => 504,
+        }
+    }
+
+    /// The path of the offending value in the JSON request, if known.
+    pub fn path(&self) -> Option<&str> {
+        match *self {
+            Error::Parsing(ref err) => err.path(),
+            _ => None,
+        }
+    }
+
+    /// The offending channel, if known.
+    pub fn channel(&self) -> Option<&Id<Channel>> {
+        match *self {
+            Error::OperationNotSupported(_, ref id) |
+            Error::GetterRequiresThresholdForWatching(ref id) => Some(id),
+            Error::Internal(ref err) => err.channel(),
+            _ => None,
         }
     }
 }
 
+/// The JSON representation of an error: an object with a stable machine-readable
+/// `code`, a human-readable `message` and, when known, the `path` of the offending
+/// value in the JSON request and the offending `channel`.
+fn error_to_json(code: &str,
+                 message: String,
+                 path: Option<&str>,
+                 channel: Option<&Id<Channel>>)
+                 -> JSON {
+    let mut fields = vec![("code", code.to_json()), ("message", message.to_json())];
+    if let Some(path) = path {
+        fields.push(("path", path.to_json()));
+    }
+    if let Some(channel) = channel {
+        fields.push(("channel", channel.to_json()));
+    }
+    fields.to_json()
+}
+
+impl ToJSON for Error {
+    fn to_json(&self) -> JSON {
+        error_to_json(self.code(), self.to_string(), self.path(), self.channel())
+    }
+}
+
+impl ToJSON for ParseError {
+    fn to_json(&self) -> JSON {
+        error_to_json(self.code(), self.to_string(), self.path(), None)
+    }
+}
+
+impl ToJSON for SerializeError {
+    fn to_json(&self) -> JSON {
+        error_to_json("serialize_error", self.to_string(), None, None)
+    }
+}
+
+impl ToJSON for TypeError {
+    fn to_json(&self) -> JSON {
+        error_to_json("wrong_type", self.to_string(), None, None)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -117,9 +189,10 @@ impl fmt::Display for Error {
             }
             Error::WrongType(ref err) => write!(f, "{}: {}", self.description(), err),
             Error::InvalidValue => write!(f, "{}", self.description()),
-            Error::Internal(ref err) => write!(f, "{}: {:?}", self.description(), err), // TODO implement Display for InternalError as well
-            Error::Parsing(ref err) => write!(f, "{}: {:?}", self.description(), err), // TODO implement Display for ParseError as well
-            Error::Serializing(ref err) => write!(f, "{}: {:?}", self.description(), err), // TODO implement Display for ParseError as well
+            Error::Internal(ref err) => write!(f, "{}", err),
+            Error::Parsing(ref err) => write!(f, "{}", err),
+            Error::Serializing(ref err) => write!(f, "{}: {}", self.description(), err),
+            Error::Timeout(ref adapter) => write!(f, "{}: {}", self.description(), adapter),
         }
     }
 }
@@ -135,15 +208,19 @@ impl error::Error for Error {
             }
             Error::WrongType(_) => "Attempting to send a value with a wrong type",
             Error::InvalidValue => "Attempting to send an invalid value",
-            Error::Internal(_) => "Internal Error", // TODO implement Error for InternalError as well
+            Error::Internal(ref err) => err.description(),
             Error::Parsing(ref err) => err.description(),
             Error::Serializing(ref err) => err.description(),
+            Error::Timeout(_) => "The adapter did not answer in time",
         }
     }
 
     fn cause(&self) -> Option<&error::Error> {
         match *self {
             Error::WrongType(ref err) => Some(err),
+            Error::Internal(ref err) => Some(err),
+            Error::Parsing(ref err) => Some(err),
+            Error::Serializing(ref err) => Some(err),
             _ => None,
         }
     }
@@ -179,6 +256,97 @@ pub enum InternalError {
     InvalidInitialService,
 }
 
+impl InternalError {
+    /// A stable, machine-readable code for the kind of error, e.g. `no_such_channel`.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            InternalError::NoSuchChannel(_) => "no_such_channel",
+            InternalError::NoSuchService(_) => "no_such_service",
+            InternalError::NoSuchAdapter(_) => "no_such_adapter",
+            InternalError::DuplicateChannel(_) => "duplicate_channel",
+            InternalError::DuplicateService(_) => "duplicate_service",
+            InternalError::DuplicateAdapter(_) => "duplicate_adapter",
+            InternalError::WrongChannel(_) => "wrong_channel",
+            InternalError::ConflictingAdapter(_, _) => "conflicting_adapter",
+            InternalError::GenericError(_) => "adapter_error",
+            InternalError::InvalidInitialService => "invalid_initial_service",
+        }
+    }
+
+    /// The HTTP status code matching this error.
+    pub fn status(&self) -> u16 {
+        match *self {
+            InternalError::NoSuchChannel(_) |
+            InternalError::NoSuchService(_) |
+            InternalError::NoSuchAdapter(_) => 404,
+            InternalError::DuplicateChannel(_) |
+            InternalError::DuplicateService(_) |
+            InternalError::DuplicateAdapter(_) |
+            InternalError::ConflictingAdapter(_, _) => 409,
+            InternalError::WrongChannel(_) |
+            InternalError::InvalidInitialService => 400,
+            // Adapters report the failures of their devices as generic errors.
+            InternalError::GenericError(_) => 502,
+        }
+    }
+
+    /// The offending channel, if known.
+    pub fn channel(&self) -> Option<&Id<Channel>> {
+        match *self {
+            InternalError::NoSuchChannel(ref id) |
+            InternalError::DuplicateChannel(ref id) |
+            InternalError::WrongChannel(ref id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+impl ToJSON for InternalError {
+    fn to_json(&self) -> JSON {
+        error_to_json(self.code(), self.to_string(), None, self.channel())
+    }
+}
+
+impl fmt::Display for InternalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InternalError::*;
+        match *self {
+            NoSuchChannel(ref id) | DuplicateChannel(ref id) | WrongChannel(ref id) => {
+                write!(f, "{}: {}", self.description(), id)
+            }
+            NoSuchService(ref id) | DuplicateService(ref id) => {
+                write!(f, "{}: {}", self.description(), id)
+            }
+            NoSuchAdapter(ref id) | DuplicateAdapter(ref id) => {
+                write!(f, "{}: {}", self.description(), id)
+            }
+            ConflictingAdapter(ref service, ref channel) => {
+                write!(f, "{}: {} and {}", self.description(), service, channel)
+            }
+            GenericError(ref err) => write!(f, "{}", err),
+            InvalidInitialService => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl error::Error for InternalError {
+    fn description(&self) -> &str {
+        use self::InternalError::*;
+        match *self {
+            NoSuchChannel(_) => "No such channel",
+            NoSuchService(_) => "No such service",
+            NoSuchAdapter(_) => "No such adapter",
+            DuplicateChannel(_) => "A channel with the same id already exists",
+            DuplicateService(_) => "A service with the same id already exists",
+            DuplicateAdapter(_) => "An adapter with the same id already exists",
+            WrongChannel(_) => "Wrong channel",
+            ConflictingAdapter(_, _) => "The channel and its service have different adapters",
+            GenericError(_) => "Error in an adapter",
+            InvalidInitialService => "A service may not have channels when it is added",
+        }
+    }
+}
+
 /// An event during watching.
 #[derive(Debug, Clone)]
 pub enum WatchEvent {
@@ -222,7 +390,7 @@ pub enum WatchEvent {
 
 /// User identifier that will be passed from the REST API handlers to the
 /// adapters.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum User {
     None,
     Id(String),
@@ -241,6 +409,15 @@ impl<P, T> Parser<Targetted<T, Payload>> for Targetted<P, Payload>
     fn description() -> String {
         format!("Targetted<{}, Value>", P::description())
     }
+    fn schema() -> JSON {
+        // The fallback format, an array [select, value], is left undocumented.
+        let properties = vec![("select", <Vec<P> as Parser<Vec<T>>>::schema()),
+                              ("value", Payload::schema())];
+        vec![("type", "object".to_json()),
+             ("required", vec!["select", "value"].to_json()),
+             ("properties", properties.to_json())]
+            .to_json()
+    }
     fn parse(path: Path, source: &JSON) -> Result<Targetted<T, Payload>, ParseError> {
         if source.is_object() {
             // Default format: an object {select, value}.
@@ -278,6 +455,14 @@ impl<P, T> Parser<Targetted<T, Exactly<Payload>>> for Targetted<P, Exactly<Paylo
     fn description() -> String {
         format!("Targetted<{}, range>", P::description())
     }
+    fn schema() -> JSON {
+        let properties = vec![("select", <Vec<P> as Parser<Vec<T>>>::schema()),
+                              ("range", Payload::schema())];
+        vec![("type", "object".to_json()),
+             ("required", vec!["select"].to_json()),
+             ("properties", properties.to_json())]
+            .to_json()
+    }
     fn parse(path: Path, source: &JSON) -> Result<Targetted<T, Exactly<Payload>>, ParseError> {
         let select = try!(path.push("select", |path| Vec::<P>::take(path, source, "select")));
         if let Some(&JSON::String(ref str)) = source.find("range") {
@@ -325,7 +510,8 @@ pub trait API: Send {
     /// the resulting `usize` nevertheless.
     ///
     /// Note that this call is _not live_. In other words, if services
-    /// are added after the call, they will not be affected.
+    /// are added after the call, they will not be affected. See
+    /// `AdapterManager::set_live_service_tag` for tags that follow the services.
     fn add_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize;
 
     /// Remove a set of tags from a set of services.
@@ -377,6 +563,24 @@ pub trait API: Send {
     /// are added after the call, they will not be affected.
     fn remove_channel_tags(&self, selectors: Vec<ChannelSelector>, tags: Vec<Id<TagId>>) -> usize;
 
+    /// Give a name to a set of services, or remove their name if `name` is `None`.
+    ///
+    /// Names are stored in the tags database, so they are restored when the services
+    /// are added again. Returns the number of services matching any of the selectors.
+    fn set_service_name(&self, selectors: Vec<ServiceSelector>, name: Option<String>) -> usize;
+
+    /// Place a set of services in a room, or remove their room if `room` is `None`.
+    ///
+    /// Rooms are stored in the tags database, so they are restored when the services
+    /// are added again. Returns the number of services matching any of the selectors.
+    fn set_service_room(&self, selectors: Vec<ServiceSelector>, room: Option<String>) -> usize;
+
+    /// Give a name to a set of channels, or remove their name if `name` is `None`.
+    ///
+    /// Names are stored in the tags database, so they are restored when the channels
+    /// are added again. Returns the number of channels matching any of the selectors.
+    fn set_channel_name(&self, selectors: Vec<ChannelSelector>, name: Option<String>) -> usize;
+
     /// Read the latest value from a set of channels
     fn fetch_values(&self, Vec<ChannelSelector>, user: User) -> OpResult<(Payload, Arc<Format>)>;
 
@@ -0,0 +1,367 @@
+//! The audit log of state-changing operations.
+//!
+//! Every operation that changes the state of the box (sending values to
+//! channels, changing tags, names and rooms, managing users...) is recorded
+//! with its time, the user who requested it, its targets, its payload and its
+//! result, so that we can tell who unlocked the door.
+//!
+//! Entries are stored in an append-only SQLite table. Once the table holds
+//! more than `max_entries` entries, the oldest ones are dropped.
+
+use api::User;
+use channel::{Channel, FeatureId};
+use parse::ToJSON;
+use services::ServiceId;
+use util::Id;
+
+use foxbox_core::migrations::{self, Migration};
+
+use chrono::{DateTime, TimeZone, UTC};
+use rusqlite::{Connection, Result};
+use rusqlite::types::ToSql;
+use serde_json;
+use serde_json::value::Value as JSON;
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// The number of entries kept by default.
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// What secrets are replaced with in the recorded payloads.
+pub const REDACTED: &'static str = "********";
+
+/// The features whose payloads are always secret.
+const SECRET_FEATURES: [&'static str; 1] = ["security/password"];
+
+/// The keys whose values are secret, wherever they appear in a payload.
+const SECRET_KEYS: [&'static str; 4] = ["password", "passphrase", "secret", "token"];
+
+/// The schema version of the audit database written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The schema history of the audit database.
+static MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    Migration {
+        version: 1,
+        description: "Create the audit table",
+        sql: "CREATE TABLE IF NOT EXISTS audit (
+                  id         INTEGER PRIMARY KEY AUTOINCREMENT,
+                  timestamp  INTEGER NOT NULL,
+                  user       TEXT,
+                  action     TEXT NOT NULL,
+                  service    TEXT,
+                  channel    TEXT,
+                  payload    TEXT NOT NULL,
+                  error      TEXT
+              );
+              CREATE INDEX IF NOT EXISTS audit_timestamp ON audit (timestamp);
+              CREATE TRIGGER IF NOT EXISTS audit_append_only BEFORE UPDATE ON audit
+              BEGIN
+                  SELECT RAISE(ABORT, 'The audit log is append-only');
+              END",
+    },
+];
+
+/// Replaces the secrets of a payload sent to a channel with `feature`:
+/// the whole payload for secret features such as `security/password`, and
+/// the values of keys such as `password` anywhere else.
+pub fn redact(feature: Option<&Id<FeatureId>>, payload: JSON) -> JSON {
+    if let Some(feature) = feature {
+        if SECRET_FEATURES.iter().any(|secret| feature == &Id::new(secret)) {
+            return JSON::String(REDACTED.to_owned());
+        }
+    }
+    redact_keys(payload)
+}
+
+fn redact_keys(payload: JSON) -> JSON {
+    match payload {
+        JSON::Object(map) => {
+            JSON::Object(map.into_iter()
+                .map(|(key, value)| {
+                    let is_secret = {
+                        let key = key.to_lowercase();
+                        SECRET_KEYS.iter().any(|secret| key.contains(secret))
+                    };
+                    if is_secret {
+                        (key, JSON::String(REDACTED.to_owned()))
+                    } else {
+                        (key, redact_keys(value))
+                    }
+                })
+                .collect())
+        }
+        JSON::Array(vec) => JSON::Array(vec.into_iter().map(redact_keys).collect()),
+        other => other,
+    }
+}
+
+/// A state-changing operation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<UTC>,
+    pub user: User,
+
+    /// The operation, e.g. `send_values` or `add_service_tags`.
+    pub action: String,
+    pub service: Option<Id<ServiceId>>,
+    pub channel: Option<Id<Channel>>,
+
+    /// The arguments of the operation, with their secrets redacted.
+    pub payload: JSON,
+    pub result: ::std::result::Result<(), String>,
+}
+
+impl AuditEntry {
+    /// A successful operation without payload, happening now.
+    pub fn new(user: &User, action: &str) -> Self {
+        AuditEntry {
+            timestamp: UTC::now(),
+            user: user.clone(),
+            action: action.to_owned(),
+            service: None,
+            channel: None,
+            payload: JSON::Null,
+            result: Ok(()),
+        }
+    }
+
+    pub fn with_service(self, id: &Id<ServiceId>) -> Self {
+        AuditEntry { service: Some(id.clone()), ..self }
+    }
+
+    pub fn with_channel(self, id: &Id<Channel>) -> Self {
+        AuditEntry { channel: Some(id.clone()), ..self }
+    }
+
+    /// Sets the payload. Secrets are redacted, see `redact`.
+    pub fn with_payload(self, feature: Option<&Id<FeatureId>>, payload: JSON) -> Self {
+        AuditEntry { payload: redact(feature, payload), ..self }
+    }
+
+    pub fn with_error(self, error: String) -> Self {
+        AuditEntry { result: Err(error), ..self }
+    }
+}
+
+impl ToJSON for AuditEntry {
+    fn to_json(&self) -> JSON {
+        let user = match self.user {
+            User::Id(ref id) => Some(id.clone()),
+            User::None => None,
+        };
+        vec![("timestamp", self.timestamp.to_rfc3339().to_json()),
+             ("user", user.to_json()),
+             ("action", self.action.to_json()),
+             ("service", self.service.to_json()),
+             ("channel", self.channel.to_json()),
+             ("payload", self.payload.clone()),
+             ("success", self.result.is_ok().to_json()),
+             ("error", self.result.clone().err().to_json())]
+            .to_json()
+    }
+}
+
+/// Which entries to return from `AuditLog::query`. All the criteria must match.
+#[derive(Clone, Debug, Default)]
+pub struct AuditFilter {
+    /// The id of the user who requested the operation.
+    pub user: Option<String>,
+    pub action: Option<String>,
+
+    /// A service or channel id.
+    pub target: Option<String>,
+    pub since: Option<DateTime<UTC>>,
+    pub until: Option<DateTime<UTC>>,
+
+    /// The maximal number of entries, most recent first.
+    pub limit: Option<u32>,
+}
+
+pub struct AuditLog {
+    db: Mutex<Connection>,
+    max_entries: usize,
+}
+
+impl AuditLog {
+    /// Opens the audit database at `path`, or an in-memory one, e.g. for tests.
+    /// If we fail to open or create the database, this will panic.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let db = match path {
+            Some(ref path) => {
+                debug!("Opening audit database at {}", path.display());
+                Connection::open(path)
+            }
+            None => Connection::open_in_memory(),
+        };
+        let mut db = db.unwrap_or_else(|err| {
+            panic!("Unable to open audit database: {}", err);
+        });
+
+        migrations::migrate(&mut db, "audit", &MIGRATIONS).unwrap_or_else(|err| {
+            panic!("Unable to migrate audit database: {}", err);
+        });
+
+        AuditLog {
+            db: Mutex::new(db),
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+
+    /// Changes how many entries are kept.
+    pub fn with_max_entries(self, max_entries: usize) -> Self {
+        AuditLog { max_entries: max_entries, ..self }
+    }
+
+    /// Appends an entry, dropping the oldest ones if needed. Failures are
+    /// logged, since they shouldn't prevent the operation itself.
+    pub fn record(&self, entry: AuditEntry) {
+        if let Err(err) = self.insert(&entry) {
+            error!("Unable to record {:?} in the audit log: {}", entry, err);
+        }
+    }
+
+    fn insert(&self, entry: &AuditEntry) -> Result<()> {
+        let db = self.db.lock().unwrap();
+        let user = match entry.user {
+            User::Id(ref id) => Some(id.clone()),
+            User::None => None,
+        };
+        let service = entry.service.as_ref().map(|id| id.to_string());
+        let channel = entry.channel.as_ref().map(|id| id.to_string());
+        let payload = serde_json::to_string(&entry.payload).unwrap_or_else(|_| "null".to_owned());
+        let error = entry.result.clone().err();
+        try!(db.execute("INSERT INTO audit (timestamp, user, action, service, channel, payload, \
+                         error) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                        &[&entry.timestamp.timestamp(),
+                          &user,
+                          &entry.action,
+                          &service,
+                          &channel,
+                          &payload,
+                          &error]));
+
+        // Rotation: the ids always increase, so this drops the oldest entries.
+        let last = db.last_insert_rowid();
+        try!(db.execute("DELETE FROM audit WHERE id <= $1",
+                        &[&(last - self.max_entries as i64)]));
+        Ok(())
+    }
+
+    /// Returns the entries matching `filter`, most recent first.
+    pub fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>> {
+        let since = filter.since.map(|date| date.timestamp());
+        let until = filter.until.map(|date| date.timestamp());
+        let limit = filter.limit.map_or(-1, |limit| limit as i64);
+
+        let mut clauses = vec![];
+        let mut params: Vec<&ToSql> = vec![];
+        if let Some(ref user) = filter.user {
+            clauses.push("user = ?");
+            params.push(user);
+        }
+        if let Some(ref action) = filter.action {
+            clauses.push("action = ?");
+            params.push(action);
+        }
+        if let Some(ref target) = filter.target {
+            clauses.push("(service = ? OR channel = ?)");
+            params.push(target);
+            params.push(target);
+        }
+        if let Some(ref since) = since {
+            clauses.push("timestamp >= ?");
+            params.push(since);
+        }
+        if let Some(ref until) = until {
+            clauses.push("timestamp <= ?");
+            params.push(until);
+        }
+        params.push(&limit);
+
+        let mut sql = "SELECT timestamp, user, action, service, channel, payload, error FROM \
+                       audit"
+            .to_owned();
+        if !clauses.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&clauses.join(" AND "));
+        }
+        sql.push_str(" ORDER BY id DESC LIMIT ?");
+
+        let db = self.db.lock().unwrap();
+        let mut stmt = try!(db.prepare(&sql));
+        let mut rows = try!(stmt.query(&params));
+        let mut entries = vec![];
+        while let Some(result_row) = rows.next() {
+            let row = try!(result_row);
+            let user: Option<String> = row.get(1);
+            let service: Option<String> = row.get(3);
+            let channel: Option<String> = row.get(4);
+            let payload: String = row.get(5);
+            let error: Option<String> = row.get(6);
+            entries.push(AuditEntry {
+                timestamp: UTC.timestamp(row.get(0), 0),
+                user: user.map_or(User::None, User::Id),
+                action: row.get(2),
+                service: service.map(|id| Id::new(&id)),
+                channel: channel.map(|id| Id::new(&id)),
+                payload: serde_json::from_str(&payload).unwrap_or(JSON::Null),
+                result: error.map_or(Ok(()), Err),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+#[test]
+fn test_redact() {
+    let payload = serde_json::from_str(r#"{"user": "admin", "password": "hunter2",
+                                           "nested": [{"api_token": "x"}]}"#)
+        .unwrap();
+    let expected: JSON = serde_json::from_str(r#"{"user": "admin", "password": "********",
+                                                  "nested": [{"api_token": "********"}]}"#)
+        .unwrap();
+    assert_eq!(redact(None, payload), expected);
+
+    let feature = Id::new("security/password");
+    assert_eq!(redact(Some(&feature), JSON::String("hunter2".to_owned())),
+               JSON::String(REDACTED.to_owned()));
+}
+
+#[test]
+fn test_audit_log() {
+    let log = AuditLog::new(None).with_max_entries(3);
+    let user = User::Id("1".to_owned());
+    for i in 0..4 {
+        log.record(AuditEntry::new(&user, "send_values")
+            .with_channel(&Id::new(&format!("channel {}", i)))
+            .with_payload(None, JSON::U64(i)));
+    }
+    log.record(AuditEntry::new(&User::None, "add_service_tags")
+        .with_service(&Id::new("service"))
+        .with_error("Oops".to_owned()));
+
+    // Only the last 3 entries are kept, most recent first.
+    let entries = log.query(&AuditFilter::default()).unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].action, "add_service_tags");
+    assert_eq!(entries[0].user, User::None);
+    assert_eq!(entries[0].result, Err("Oops".to_owned()));
+    assert_eq!(entries[1].payload, JSON::U64(3));
+
+    let filter = AuditFilter { user: Some("1".to_owned()), ..AuditFilter::default() };
+    assert_eq!(log.query(&filter).unwrap().len(), 2);
+
+    let filter = AuditFilter { target: Some("channel 3".to_owned()), ..AuditFilter::default() };
+    let entries = log.query(&filter).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].channel, Some(Id::new("channel 3")));
+
+    let filter = AuditFilter { limit: Some(1), ..AuditFilter::default() };
+    assert_eq!(log.query(&filter).unwrap().len(), 1);
+
+    // Entries can't be modified.
+    let db = log.db.lock().unwrap();
+    assert!(db.execute("UPDATE audit SET action = 'nothing'", &[]).is_err());
+}
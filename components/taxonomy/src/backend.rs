@@ -7,8 +7,9 @@ use channel::Channel;
 use io::*;
 use selector::*;
 use services::*;
-use tag_storage::TagStorage;
+use tag_storage::{METADATA_NAME, METADATA_ROOM, TagStorage};
 use transact::InsertInMap;
+use value_cache::ValueCache;
 use values::format;
 
 use sublock::atomlock::*;
@@ -65,8 +66,14 @@ struct ServiceData {
     /// The id, as in a `Service`.
     id: Id<ServiceId>,
 
-    /// Creation time properties.
-    properties: HashMap<String, String>,
+    /// Creation time properties, shared with the channels.
+    properties: Arc<HashMap<String, String>>,
+
+    /// The name, as in `Service`.
+    name: Option<String>,
+
+    /// The room, as in `Service`.
+    room: Option<String>,
 
     /// Information on the channels. Used to build field `channels` of `Service`.
     channels: HashMap<Id<Channel>, Arc<SubCell<ChannelData>>>,
@@ -85,7 +92,9 @@ impl ServiceData {
             tags: Arc::new(SubCell::new(liveness, service.tags)),
             id: service.id,
             adapter: service.adapter,
-            properties: service.properties,
+            properties: Arc::new(service.properties),
+            name: service.name,
+            room: service.room,
             channels: HashMap::new(),
         }
     }
@@ -93,7 +102,9 @@ impl ServiceData {
         Service {
             tags: self.tags.borrow().clone(),
             id: self.id.clone(),
-            properties: self.properties.clone(),
+            properties: (*self.properties).clone(),
+            name: self.name.clone(),
+            room: self.room.clone(),
             adapter: self.adapter.clone(),
             channels: self.channels
                 .iter()
@@ -118,6 +129,15 @@ impl<'a> ServiceLike for ServiceView<'a> {
     fn adapter(&self) -> &Id<AdapterId> {
         &self.data.adapter
     }
+    fn properties(&self) -> &HashMap<String, String> {
+        &self.data.properties
+    }
+    fn name(&self) -> Option<&str> {
+        self.data.name.as_ref().map(|name| name.as_str())
+    }
+    fn room(&self) -> Option<&str> {
+        self.data.room.as_ref().map(|room| room.as_str())
+    }
     fn with_tags<F>(&self, f: F) -> bool
         where F: Fn(&HashSet<Id<TagId>>) -> bool
     {
@@ -197,20 +217,29 @@ struct ChannelData {
     /// The tags of the service.
     service_tags: Arc<SubCell<HashSet<Id<TagId>>>>,
 
+    /// The properties of the service.
+    service_properties: Arc<HashMap<String, String>>,
+
     /// Watchers that currently watch this channel.
     watchers: HashMap<WatchKey, Weak<WatcherData>>,
 }
 impl SelectedBy<ChannelSelector> for ChannelData {
     fn matches(&self, selector: &ChannelSelector) -> bool {
-        selector.matches(&*self.service_tags.borrow(), &self.channel)
+        selector.matches(&*self.service_tags.borrow(),
+                         &self.service_properties,
+                         &self.channel)
     }
 }
 
 impl ChannelData {
-    fn new(channel: Channel, service_tags: Arc<SubCell<HashSet<Id<TagId>>>>) -> Self {
+    fn new(channel: Channel,
+           service_tags: Arc<SubCell<HashSet<Id<TagId>>>>,
+           service_properties: Arc<HashMap<String, String>>)
+           -> Self {
         ChannelData {
             channel: channel,
             service_tags: service_tags.clone(),
+            service_properties: service_properties,
             watchers: HashMap::new(),
         }
     }
@@ -350,6 +379,13 @@ pub struct State {
     /// The database used to persist tags.
     /// The underlying SQlite is opened lazily so we can create one here.
     db: Option<Arc<Mutex<TagStorage>>>,
+
+    /// The last known value of each channel, shared with the `AdapterManager`.
+    values: Arc<ValueCache>,
+
+    /// Tags that are kept on the services matching some selectors, including
+    /// services and channels added later. Not stored in the tags database.
+    live_tags: HashMap<Id<TagId>, Vec<ServiceSelector>>,
 }
 
 impl State {
@@ -533,6 +569,44 @@ impl State {
 
     // fn iter_channels<S, K, V>(selectors: Vec<S>, map: &HashMap<Id<K>, V>) ->
     // Filter<Values<Id<K>, V>, &(Fn(&V) -> bool)>
+    /// Adds or removes the live tags of some services, depending on whether they
+    /// currently match the selectors of each tag. Returns the channels of the services
+    /// that gained a tag, as they may need to be watched.
+    fn aux_update_live_tags(&self, services: Vec<Id<ServiceId>>) -> HashSet<Id<Channel>> {
+        let mut gained = HashSet::new();
+        for id in services {
+            let service = match self.service_by_id.get(&id) {
+                Some(service) => service,
+                None => continue,
+            };
+            for (tag, selectors) in &self.live_tags {
+                let matches = {
+                    let borrow = &*service.borrow();
+                    let view = ServiceView::new(borrow);
+                    selectors.iter().any(|selector| selector.matches(&view))
+                };
+                let service = service.borrow();
+                let has_changed = if matches {
+                    service.tags.borrow_mut().insert(tag.clone())
+                } else {
+                    service.tags.borrow_mut().remove(tag)
+                };
+                if !has_changed {
+                    continue;
+                }
+                if matches {
+                    gained.extend(service.channels.keys().cloned());
+                } else {
+                    for channel in service.channels.values() {
+                        Self::aux_channel_may_need_unregistration(&mut *channel.borrow_mut(),
+                                                                  false);
+                    }
+                }
+            }
+        }
+        gained
+    }
+
     // where V: SelectedBy<S>
     // {
     // let cb : &Fn(&V) -> bool + 'state = |data: &V| {
@@ -547,7 +621,10 @@ impl State {
 }
 
 impl State {
-    pub fn new(liveness: &Arc<Liveness>, db_path: Option<PathBuf>) -> Self {
+    pub fn new(liveness: &Arc<Liveness>,
+               db_path: Option<PathBuf>,
+               values: Arc<ValueCache>)
+               -> Self {
         let db = if let Some(ref path) = db_path {
             Some(Arc::new(Mutex::new(TagStorage::new(path))))
         } else {
@@ -561,9 +638,15 @@ impl State {
             channel_by_id: HashMap::new(),
             watchers: Arc::new(Mutex::new(WatchMap::new(liveness))),
             db: db,
+            values: values,
+            live_tags: HashMap::new(),
         }
     }
 
+    pub fn value_cache(&self) -> Arc<ValueCache> {
+        self.values.clone()
+    }
+
     /// Add an adapter to the system.
     ///
     /// # Errors
@@ -634,7 +717,7 @@ impl State {
         if !service.channels.is_empty() {
             return Err(Error::Internal(InternalError::InvalidInitialService));
         }
-        let service = ServiceData::new(&self.liveness, service);
+        let mut service = ServiceData::new(&self.liveness, service);
         let mut services_for_this_adapter = match self.adapter_by_id.get_mut(&service.adapter) {
             None => {
                 return Err(Error::Internal(InternalError::NoSuchAdapter(service.adapter.clone())))
@@ -643,7 +726,7 @@ impl State {
         };
         let id = service.id.clone();
 
-        // Synchronize the tags with the database.
+        // Synchronize the tags and metadata with the database.
         {
             if let Some(ref mutex) = self.db {
                 // Update the service's tag set with the full set from the database.
@@ -654,9 +737,23 @@ impl State {
                         Ok(tags) => tags,
                     };
 
-                let mut tag_set = service.tags.borrow_mut();
-                for tag in &tags {
-                    let _ = tag_set.insert(tag.clone());
+                {
+                    let mut tag_set = service.tags.borrow_mut();
+                    for tag in &tags {
+                        let _ = tag_set.insert(tag.clone());
+                    }
+                }
+
+                // The name and room given by the user take precedence.
+                let mut metadata = match store.get_metadata_for(&id) {
+                    Err(err) => return Err(Error::Internal(InternalError::GenericError(format!("{}", err)))),
+                    Ok(metadata) => metadata,
+                };
+                if let Some(name) = metadata.remove(METADATA_NAME) {
+                    service.name = Some(name);
+                }
+                if let Some(room) = metadata.remove(METADATA_ROOM) {
+                    service.room = Some(room);
                 }
             }
         }
@@ -677,6 +774,9 @@ impl State {
         // If we haven't bailed out yet, leave all this stuff in the maps and sets.
         insert_in_adapters.commit();
         insert_in_services.commit();
+
+        // The service doesn't have any channel yet, so there is nothing to watch.
+        let _ = self.aux_update_live_tags(vec![id]);
         Ok(())
     }
 
@@ -735,9 +835,15 @@ impl State {
             if let Ok(all_tags) = store.get_tags_for(&channel.id) {
                 channel.insert_tags(&all_tags);
             }
+            if let Ok(mut metadata) = store.get_metadata_for(&channel.id) {
+                if let Some(name) = metadata.remove(METADATA_NAME) {
+                    channel.name = Some(name);
+                }
+            }
         }
 
         let id = channel.id.clone();
+        let service_id = channel.service.clone();
         let channel_data;
         {
             let service = match self.service_by_id.get_mut(&channel.service) {
@@ -756,7 +862,9 @@ impl State {
 
             let channels = &mut service.channels;
             channel_data = Arc::new(SubCell::new(&self.liveness,
-                                                 ChannelData::new(channel, service.tags.clone())));
+                                                 ChannelData::new(channel,
+                                                                  service.tags.clone(),
+                                                                  service.properties.clone())));
 
             let insert_in_service =
                 match InsertInMap::start(channels, vec![(id.clone(), channel_data.clone())]) {
@@ -772,7 +880,11 @@ impl State {
             insert_in_service.commit();
             insert_in_channels.commit();
         }
-        Ok(self.aux_channels_may_need_registration(vec![id]))
+
+        // The new channel may change which live tags the service has.
+        let mut channels = self.aux_update_live_tags(vec![service_id]);
+        channels.insert(id);
+        Ok(self.aux_channels_may_need_registration(channels.into_iter().collect()))
     }
 
     /// Remove a channel previously registered on the system. Typically, called by
@@ -788,10 +900,11 @@ impl State {
             None => return Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
             Some(channel) => channel,
         };
+        self.values.remove(id);
         Self::aux_channel_may_need_unregistration(&mut *channel.borrow_mut(), true);
 
-        let service_id = &channel.borrow().channel.service;
-        match self.service_by_id.get_mut(service_id) {
+        let service_id = channel.borrow().channel.service.clone();
+        let result = match self.service_by_id.get_mut(&service_id) {
             None => Err(Error::Internal(InternalError::NoSuchService(service_id.clone()))),
             Some(service) => {
                 if service.borrow_mut().channels.remove(id).is_none() {
@@ -800,7 +913,12 @@ impl State {
                     Ok(())
                 }
             }
-        }
+        };
+
+        // Removing a channel can only make the service lose live tags, so there is
+        // nothing new to watch.
+        let _ = self.aux_update_live_tags(vec![service_id]);
+        result
     }
 
     pub fn get_services(&self, selectors: Vec<ServiceSelector>) -> Vec<Service> {
@@ -812,15 +930,21 @@ impl State {
         result
     }
 
+    /// Adds tags to a set of services. Returns the number of services matching the
+    /// selectors, along with the watches that their channels now call for, since
+    /// they may now match watchers and live tags.
     pub fn add_service_tags(&mut self,
                             selectors: Vec<ServiceSelector>,
                             tags: Vec<Id<TagId>>)
-                            -> usize {
-        let mut result = 0;
+                            -> (WatchRequest, usize) {
+        let mut services = vec![];
+        let mut channels = HashSet::new();
 
         let mut db = self.db.clone();
         self.with_services(selectors, |service| {
             let service = service.borrow_mut();
+            services.push(service.id.clone());
+            channels.extend(service.channels.keys().cloned());
             let mut tag_set = service.tags.borrow_mut();
 
             if let Some(ref mut storage) = db {
@@ -835,19 +959,24 @@ impl State {
             for tag in &tags {
                 let _ = tag_set.insert(tag.clone());
             }
-            result += 1;
         });
-        result
+        let result = services.len();
+        channels.extend(self.aux_update_live_tags(services));
+        (self.aux_channels_may_need_registration(channels.into_iter().collect()), result)
     }
 
+    /// Removes tags from a set of services. Returns the number of services matching
+    /// the selectors, along with the watches that their channels now call for, since
+    /// the live tags of these services are updated.
     pub fn remove_service_tags(&mut self,
                                selectors: Vec<ServiceSelector>,
                                tags: Vec<Id<TagId>>)
-                               -> usize {
-        let mut result = 0;
+                               -> (WatchRequest, usize) {
+        let mut services = vec![];
         let mut db = self.db.clone();
         self.with_services(selectors, |service| {
             let service = service.borrow_mut();
+            services.push(service.id.clone());
             let mut tag_set = service.tags.borrow_mut();
 
             if let Some(ref mut storage) = db {
@@ -862,15 +991,121 @@ impl State {
             for tag in &tags {
                 let _ = tag_set.remove(tag);
             }
-            result += 1;
         });
-        result
+        let result = services.len();
+        let channels = self.aux_update_live_tags(services);
+        (self.aux_channels_may_need_registration(channels.into_iter().collect()), result)
+    }
+
+    /// Sets the name given by the user to a set of services, or removes it if `name` is `None`.
+    /// Returns the number of services matching the selectors, and the watches that the live
+    /// tags they gained call for.
+    pub fn set_service_name(&mut self,
+                            selectors: Vec<ServiceSelector>,
+                            name: Option<String>)
+                            -> (WatchRequest, usize) {
+        let mut services = vec![];
+        let db = self.db.clone();
+        self.with_services(selectors, |service| {
+            let mut service = service.borrow_mut();
+            Self::aux_store_metadata(&db, &service.id, METADATA_NAME, &name);
+            service.name = name.clone();
+            services.push(service.id.clone());
+        });
+        let result = services.len();
+        let channels = self.aux_update_live_tags(services);
+        (self.aux_channels_may_need_registration(channels.into_iter().collect()), result)
+    }
+
+    /// Sets the room of a set of services, or removes it if `room` is `None`.
+    /// Returns the number of services matching the selectors, and the watches that the live
+    /// tags they gained call for.
+    pub fn set_service_room(&mut self,
+                            selectors: Vec<ServiceSelector>,
+                            room: Option<String>)
+                            -> (WatchRequest, usize) {
+        let mut services = vec![];
+        let db = self.db.clone();
+        self.with_services(selectors, |service| {
+            let mut service = service.borrow_mut();
+            Self::aux_store_metadata(&db, &service.id, METADATA_ROOM, &room);
+            service.room = room.clone();
+            services.push(service.id.clone());
+        });
+        let result = services.len();
+        let channels = self.aux_update_live_tags(services);
+        (self.aux_channels_may_need_registration(channels.into_iter().collect()), result)
+    }
+
+    fn aux_store_metadata<T>(db: &Option<Arc<Mutex<TagStorage>>>,
+                             id: &Id<T>,
+                             key: &str,
+                             value: &Option<String>) {
+        if let Some(ref storage) = *db {
+            storage.lock()
+                .unwrap()
+                .set_metadata(id, key, value.as_ref().map(|value| value.as_str()))
+                .unwrap_or_else(|err| {
+                    error!("Storage set_metadata error: {}", err);
+                });
+        }
+    }
+
+    /// Sets the selectors of a live tag, replacing any previous ones, and updates
+    /// the tags of all the services. Returns the number of services that have the tag.
+    pub fn set_live_service_tag(&mut self,
+                                tag: Id<TagId>,
+                                selectors: Vec<ServiceSelector>)
+                                -> (WatchRequest, usize) {
+        self.live_tags.insert(tag.clone(), selectors);
+        let services = self.service_by_id.keys().cloned().collect();
+        let channels = self.aux_update_live_tags(services);
+        let count = self.service_by_id
+            .values()
+            .filter(|service| service.borrow().tags.borrow().contains(&tag))
+            .count();
+        (self.aux_channels_may_need_registration(channels.into_iter().collect()), count)
+    }
+
+    /// Removes a live tag from all the services. Returns false if there was no such
+    /// live tag.
+    pub fn remove_live_service_tag(&mut self, tag: &Id<TagId>) -> bool {
+        if self.live_tags.remove(tag).is_none() {
+            return false;
+        }
+        for service in self.service_by_id.values() {
+            let service = service.borrow();
+            let has_changed = service.tags.borrow_mut().remove(tag);
+            if has_changed {
+                for channel in service.channels.values() {
+                    Self::aux_channel_may_need_unregistration(&mut *channel.borrow_mut(), false);
+                }
+            }
+        }
+        true
     }
 
     pub fn get_channels(&self, selectors: Vec<ChannelSelector>) -> Vec<Channel> {
         Self::aux_get_channels(selectors, &self.channel_by_id)
     }
 
+    /// The number of active watches on the channels of each adapter.
+    pub fn count_watches(&self) -> HashMap<Id<AdapterId>, usize> {
+        let mut counts = HashMap::new();
+        for data in self.channel_by_id.values() {
+            let data = data.borrow();
+            let active = data.watchers
+                .values()
+                .filter_map(|watcher| watcher.upgrade())
+                .filter(|watcher| !watcher.is_dropped.load(Ordering::Relaxed))
+                .count();
+            if active > 0 {
+                *counts.entry(data.adapter.clone()).or_insert(0) += active;
+            }
+        }
+        counts
+    }
+
     /// Add tags to a channel.
     /// As our in-memory representation stores the same getter both in the Service
     /// and in `self.channel`, we need to update both.
@@ -879,11 +1114,13 @@ impl State {
                             tags: Vec<Id<TagId>>)
                             -> (WatchRequest, usize) {
         let mut size = 0;
-        let mut channels = vec![];
+        let mut channels = HashSet::new();
+        let mut services = vec![];
         {
             let tag_db = self.db.clone();
             Self::with_channels_mut(selectors, &mut self.channel_by_id, |mut data| {
-                // This channel has changed, we may need to update watches and the tags database.
+                // This channel has changed, we may need to update watches, live tags and
+                // the tags database.
                 if data.insert_tags(&tags) {
                     if let Some(ref db) = tag_db {
                         let mut store = db.lock().unwrap();
@@ -893,31 +1130,57 @@ impl State {
                             });
                     }
 
-                    channels.push(data.id.clone());
+                    channels.insert(data.id.clone());
+                    services.push(data.service.clone());
                 }
                 size += 1;
             });
         }
-        (self.aux_channels_may_need_registration(channels), size)
+        channels.extend(self.aux_update_live_tags(services));
+        (self.aux_channels_may_need_registration(channels.into_iter().collect()), size)
     }
 
+    /// Removes tags from a set of channels. Returns the number of channels matching
+    /// the selectors, along with the watches called for by the live tags of their
+    /// services, which are updated.
     pub fn remove_channel_tags(&mut self,
                                selectors: Vec<ChannelSelector>,
                                tags: Vec<Id<TagId>>)
-                               -> usize {
+                               -> (WatchRequest, usize) {
         let mut result = 0;
-        let tag_db = self.db.clone();
-        Self::with_channels_mut(selectors, &mut self.channel_by_id, |mut data| {
-            if data.remove_tags(&tags) {
-                if let Some(ref db) = tag_db {
-                    let mut store = db.lock().unwrap();
-                    store.remove_tags(&data.id, &tags)
-                        .unwrap_or_else(|err| {
-                            error!("Storage remove_tags error: {}", err);
-                        });
+        let mut services = vec![];
+        {
+            let tag_db = self.db.clone();
+            Self::with_channels_mut(selectors, &mut self.channel_by_id, |mut data| {
+                if data.remove_tags(&tags) {
+                    if let Some(ref db) = tag_db {
+                        let mut store = db.lock().unwrap();
+                        store.remove_tags(&data.id, &tags)
+                            .unwrap_or_else(|err| {
+                                error!("Storage remove_tags error: {}", err);
+                            });
+                    }
+                    services.push(data.service.clone());
                 }
-            }
-            Self::aux_channel_may_need_unregistration(&mut data, false);
+                Self::aux_channel_may_need_unregistration(&mut data, false);
+                result += 1;
+            });
+        }
+        let channels = self.aux_update_live_tags(services);
+        (self.aux_channels_may_need_registration(channels.into_iter().collect()), result)
+    }
+
+    /// Sets the name given by the user to a set of channels, or removes it if `name` is `None`.
+    /// Returns the number of channels matching the selectors.
+    pub fn set_channel_name(&mut self,
+                            selectors: Vec<ChannelSelector>,
+                            name: Option<String>)
+                            -> usize {
+        let mut result = 0;
+        let db = self.db.clone();
+        Self::with_channels_mut(selectors, &mut self.channel_by_id, |data| {
+            Self::aux_store_metadata(&db, &data.id, METADATA_NAME, &name);
+            data.channel.name = name.clone();
             result += 1;
         });
         result
@@ -1146,7 +1409,9 @@ impl State {
     }
 
     /// Start watching a set of channels.
-    pub fn start_watch(mut per_adapter: WatchRequest) -> WatchGuardCommit {
+    ///
+    /// The values reported by the adapters are recorded in `values`.
+    pub fn start_watch(mut per_adapter: WatchRequest, values: Arc<ValueCache>) -> WatchGuardCommit {
         // In most cases, stop_watch will take place long after start_watch. It is, however,
         // possible that the `WatchGuard` is dropped before start_watch is processed for this
         // channel. In this case, three events take place:
@@ -1177,12 +1442,21 @@ impl State {
                     Some(watch_data) => watch_data,
                 };
                 let is_dropped = watch_data.is_dropped.clone();
+                let values = values.clone();
                 if is_dropped.load(Ordering::Relaxed) {
                     // The WatchGuard has already been dropped.
                     debug!(target: "Taxonomy-backend", "State::start_watch, the guard has been dropped, is_dropped detected, skipping.");
                     continue;
                 }
                 let on_ok = watch_data.on_event.lock().unwrap().filter_map(move |event| {
+                    // Remember the value even if nobody is listening anymore.
+                    match event {
+                        AdapterWatchEvent::Enter { ref id, value: (ref payload, ref format) } |
+                        AdapterWatchEvent::Exit { ref id, value: (ref payload, ref format) } => {
+                            values.update_watched(id, payload, format)
+                        }
+                        AdapterWatchEvent::Error { .. } => {}
+                    }
                     if is_dropped.load(Ordering::Relaxed) {
                         debug!(target: "Taxonomy-backend", "State::start_watch, the guard has been dropped, is_dropped detected, don't propagate messages.");
 
@@ -45,8 +45,14 @@ impl ToJSON for Signature {
             let spec;
             match *value {
                 Maybe::Nothing => continue,
-                Maybe::Required(ref format) => spec = vec![("requires", format.description())],
-                Maybe::Optional(ref format) => spec = vec![("optional", format.description())],
+                Maybe::Required(ref format) => {
+                    spec = vec![("requires", format.description().to_json()),
+                                ("schema", format.schema())]
+                }
+                Maybe::Optional(ref format) => {
+                    spec = vec![("optional", format.description().to_json()),
+                                ("schema", format.schema())]
+                }
             }
             vec.push((key, spec.to_json()))
         }
@@ -81,6 +87,12 @@ pub struct Channel {
     /// Identifier of the adapter for this channel.
     pub adapter: Id<AdapterId>,
 
+    /// A human-readable name given by the user.
+    ///
+    /// Like tags, names are stored in the tags database, so adapters
+    /// should leave this to `None`.
+    pub name: Option<String>,
+
     /// Description of the feature, in a format designed for discovery by applications.
     ///
     /// By convention, developers should prefix with "x-" for features that are not standardized yet.
@@ -143,10 +155,12 @@ impl ToJSON for Channel {
             ("id", self.id.to_json()),
             ("adapter", self.adapter.to_json()),
             ("tags", self.tags.to_json()),
+            ("name", self.name.to_json()),
             ("service", self.service.to_json()),
             ("feature", self.feature.to_json()),
             ("supports_send", self.supports_send.to_json()),
             ("supports_fetch", self.supports_fetch.to_json()),
+            ("supports_watch", self.supports_watch.to_json()),
         ]
             .to_json()
     }
@@ -15,6 +15,7 @@ use std::collections::hash_map::Entry::*;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::Duration;
 
 /// A tweak sent to the virtual device, to set a value, inject an error, ...
 #[allow(enum_variant_names)]
@@ -25,6 +26,10 @@ pub enum Tweak {
     /// Inject an error in a virtual setter. All operations on this setter will
     /// raise the error until `None` is injected instead.
     InjectSetterError(Id<Channel>, Option<Error>),
+
+    /// Make all fetch and send operations wait before answering, to simulate an
+    /// unresponsive device. `None` removes the delay.
+    InjectDelay(Option<Duration>),
 }
 
 /// Something that happened to the virtual device, e.g. a value was sent.
@@ -64,6 +69,7 @@ pub struct FakeAdapter {
     values: SyncMap<Id<Channel>, Result<Value, Error>>,
     senders: SyncMap<Id<Channel>, Error>,
     watchers: SyncMap<Id<Channel>, Vec<WatcherState>>,
+    delay: Arc<Mutex<Option<Duration>>>,
 }
 
 impl FakeAdapter {
@@ -74,6 +80,7 @@ impl FakeAdapter {
         let (values_main, values_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
         let (senders_main, senders_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
         let (watchers_main, watchers_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
+        let (delay_main, delay_thread) = dup(Arc::new(Mutex::new(None)));
 
         let mutex = Arc::new(Mutex::new(tx));
         let tweak = move |msg| {
@@ -90,6 +97,7 @@ impl FakeAdapter {
             tx_effect: Mutex::new(Box::new(tx_effect)),
             rx_effect: Mutex::new(Some(rx_effect)),
             watchers: watchers_main,
+            delay: delay_main,
         };
 
         thread::spawn(move || {
@@ -154,6 +162,9 @@ impl FakeAdapter {
                     InjectSetterError(id, Some(err)) => {
                         senders_thread.lock().unwrap().insert(id, err);
                     }
+                    InjectDelay(delay) => {
+                        *delay_thread.lock().unwrap() = delay;
+                    }
                 }
                 tx.send(()).unwrap();
             }
@@ -168,6 +179,13 @@ impl FakeAdapter {
     pub fn get_tweak(&self) -> Arc<Fn(Tweak) + Sync + Send> {
         self.tweak.clone()
     }
+
+    fn wait_for_delay(&self) {
+        let delay = *self.delay.lock().unwrap();
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+    }
 }
 
 static VERSION: [u32; 4] = [0, 0, 0, 0];
@@ -198,6 +216,7 @@ impl Adapter for FakeAdapter {
                     mut channels: Vec<Id<Channel>>,
                     _: User)
                     -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        self.wait_for_delay();
         let map = self.values.lock().unwrap();
         channels.drain(..)
             .map(|id| {
@@ -216,6 +235,7 @@ impl Adapter for FakeAdapter {
                    mut values: HashMap<Id<Channel>, Value>,
                    _: User)
                    -> ResultMap<Id<Channel>, (), Error> {
+        self.wait_for_delay();
         let map = self.senders.lock().unwrap();
         values.drain()
             .map(|(id, value)| {
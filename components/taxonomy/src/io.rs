@@ -23,13 +23,14 @@ pub enum SerializeError {
 }
 impl fmt::Display for SerializeError {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        (self as &fmt::Debug).fmt(formatter)
+        match *self {
+            SerializeError::JSON(ref err) => write!(formatter, "Invalid JSON: {}", err),
+        }
     }
 }
 impl StdError for SerializeError {
     fn description(&self) -> &str {
-        // Placeholder
-        ""
+        "Error while serializing a value"
     }
 }
 impl From<SerializeError> for Error {
@@ -82,6 +83,11 @@ impl Parser<Payload> for Payload {
     fn description() -> String {
         "JSON".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("description",
+              "A value, whose format is determined by the feature of the channel")]
+            .to_json()
+    }
     fn parse(_: Path, source: &JSON) -> Result<Self, ParseError> {
         Ok(Payload { json: source.clone() })
     }
@@ -89,6 +95,7 @@ impl Parser<Payload> for Payload {
 
 pub struct Format {
     description: Box<Fn() -> String + Send + Sync>,
+    schema: Box<Fn() -> JSON + Send + Sync>,
     #[allow(type_complexity)]
     parse: Box<Fn(Path, &JSON, &BinarySource) -> Result<Value, Error> + Send + Sync>,
     serialize: Box<Fn(&Value, &BinaryTarget) -> Result<JSON, Error> + Send + Sync>,
@@ -99,8 +106,10 @@ impl Format {
         where T: Data + PartialEq
     {
         let description = || T::description();
+        let schema = || T::schema();
         Format {
             description: Box::new(description),
+            schema: Box::new(schema),
             parse: Box::new(|path, source, binary| T::parse(path, source, binary).map(Value::new)),
             serialize: Box::new(|value, target| {
                 let value: &Value = value;
@@ -117,6 +126,13 @@ impl Format {
         (self.description)()
     }
 
+    /// A machine-readable description of the _type_ of the value.
+    ///
+    /// See `Data::schema()`.
+    pub fn schema(&self) -> JSON {
+        (self.schema)()
+    }
+
     /// Attempt to build a `Value` from a json `source` and `binary` components.
     pub fn parse(&self, path: Path, source: &JSON, binary: &BinarySource) -> Result<Value, Error> {
         (self.parse)(path, source, binary)
@@ -128,6 +144,12 @@ impl Format {
     }
 }
 
+impl ToJSON for Format {
+    fn to_json(&self) -> JSON {
+        vec![("description", self.description().to_json()), ("schema", self.schema())].to_json()
+    }
+}
+
 impl fmt::Debug for Format {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         self.description().fmt(formatter)
@@ -9,6 +9,7 @@
 extern crate lazy_static;
 
 extern crate chrono;
+extern crate foxbox_core;
 extern crate libc;
 #[macro_use]
 extern crate log;
@@ -50,6 +51,9 @@ pub mod util;
 /// The back-end thread, in charge of the heavy lifting of managing adapters.
 mod backend;
 
+/// The threads used to call adapters concurrently.
+mod worker_pool;
+
 /// The manager provides an API for (un)registering adapters, services, channels, and
 /// uses these to implements the taxonomy API.
 pub mod manager;
@@ -67,6 +71,12 @@ pub mod transact;
 /// Implementation of the database storing tags.
 pub mod tag_storage;
 
+/// The last known value of each channel.
+pub mod value_cache;
+
+/// The audit log of state-changing operations.
+pub mod audit;
+
 /// Implementation of a fake adapter, controlled entirely programmatically. Designed to be used
 /// as a component of tests.
 pub mod fake_adapter;
@@ -7,18 +7,27 @@
 pub use adapter::*;
 use api;
 use api::{API, Error, TargetMap, User};
+use audit::{AuditEntry, AuditLog};
 use backend::*;
 use channel::Channel;
 use io::*;
+use parse::ToJSON;
 use selector::*;
 use services::*;
 use util::is_sync;
+use value_cache::{CachedValue, ValueCache, ValueSource};
+use worker_pool::WorkerPool;
+
+use foxbox_core::metrics;
 
 use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex, Weak};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self as std_mpsc, RecvTimeoutError};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use sublock::atomlock::*;
 use transformable_channels::mpsc::*;
@@ -37,8 +46,23 @@ pub struct AdapterManager {
     back_end: Arc<MainLock<State>>,
 
     tx_watch: Arc<Mutex<RawSender<WatchOp>>>,
+
+    /// The threads used to call adapters concurrently during fetch and send.
+    pool: WorkerPool,
+
+    /// How long fetch and send wait for each adapter.
+    adapter_timeout: Mutex<Duration>,
+
+    /// The last known value of each channel, updated by fetches and watches.
+    values: Arc<ValueCache>,
+
+    /// The record of the values sent to channels.
+    audit: Arc<AuditLog>,
 }
 
+/// How long fetch and send wait for an adapter by default, in seconds.
+pub const DEFAULT_ADAPTER_TIMEOUT_S: u64 = 10;
+
 impl AdapterManager {
     /// Create an empty `AdapterManager`.
     /// This function does not attempt to load any state from the disk.
@@ -46,13 +70,241 @@ impl AdapterManager {
         // The code should build only if AdapterManager implements Sync.
         is_sync::<AdapterManager>();
 
-        let state = Arc::new(MainLock::new(|liveness| State::new(liveness, db_path)));
-        let tx_watch = Arc::new(Mutex::new(Self::handle_watches(Arc::downgrade(&state))));
+        let values = Arc::new(ValueCache::new());
+        let state = {
+            let values = values.clone();
+            Arc::new(MainLock::new(move |liveness| State::new(liveness, db_path, values)))
+        };
+        let tx_watch = Arc::new(Mutex::new(Self::handle_watches(Arc::downgrade(&state),
+                                                                values.clone())));
         AdapterManager {
             back_end: state,
             tx_watch: tx_watch,
+            pool: WorkerPool::default(),
+            adapter_timeout: Mutex::new(Duration::from_secs(DEFAULT_ADAPTER_TIMEOUT_S)),
+            values: values,
+            audit: Arc::new(AuditLog::new(None)),
         }
     }
+
+    /// Records the operations in `audit` instead of the default in-memory log.
+    pub fn with_audit_log(self, audit: AuditLog) -> Self {
+        AdapterManager { audit: Arc::new(audit), ..self }
+    }
+
+    /// The log of the state-changing operations, to which other components
+    /// should add the operations they perform on behalf of users.
+    pub fn get_audit_log(&self) -> Arc<AuditLog> {
+        self.audit.clone()
+    }
+
+    /// Returns the last known value of a channel that `user` may see, if any.
+    pub fn get_cached_value(&self, id: &Id<Channel>, user: &User) -> Option<CachedValue> {
+        self.values.get(user, id)
+    }
+
+    /// Read the values of a set of channels, accepting values that are at most
+    /// `max_age` old.
+    ///
+    /// Channels with a recent enough cached value are not queried. Channels that
+    /// can't be fetched (e.g. sensors that only report changes) always get their
+    /// last known value, however old, or `None` if they haven't reported anything
+    /// yet. With no `max_age`, all the channels that support fetching are queried.
+    ///
+    /// Values fetched on behalf of another user are never returned.
+    pub fn fetch_values_cached(&self,
+                               selectors: Vec<ChannelSelector>,
+                               user: User,
+                               max_age: Option<Duration>)
+                               -> OpResult<CachedValue> {
+        let mut results = HashMap::new();
+        let mut to_fetch = vec![];
+        for channel in self.get_channels(selectors) {
+            if channel.supports_fetch.is_none() && channel.supports_watch.is_none() {
+                continue;
+            }
+            let cached = self.values.get(&user, &channel.id);
+            let is_fresh = match (&cached, max_age) {
+                (&Some(ref value), Some(max_age)) => value.is_fresh(max_age),
+                _ => false,
+            };
+            if channel.supports_fetch.is_some() && !is_fresh {
+                to_fetch.push(ChannelSelector::new().with_id(&channel.id));
+            } else {
+                results.insert(channel.id, Ok(cached));
+            }
+        }
+
+        if !to_fetch.is_empty() {
+            for (id, result) in self.fetch_values(to_fetch, user) {
+                let result = result.map(|fetched| {
+                    fetched.map(|(payload, format)| {
+                        CachedValue::new(payload, format, ValueSource::Fetch)
+                    })
+                });
+                results.insert(id, result);
+            }
+        }
+        results
+    }
+
+    /// Labels the services matching any of `selectors` with `tag`, and keeps
+    /// doing so as services and channels come and go: services that start
+    /// matching gain the tag, services that stop matching lose it. Replaces the
+    /// previous selectors of `tag`, if any. Returns the number of services that
+    /// currently have the tag.
+    ///
+    /// Unlike `add_service_tags`, live tags are not stored in the tags database,
+    /// so callers should set them again after a restart, and should use tags that
+    /// are not also set by hand.
+    pub fn set_live_service_tag(&self, tag: &Id<TagId>, selectors: Vec<ServiceSelector>) -> usize {
+        let (request, count) = {
+            // Acquire and release lock asap.
+            self.back_end.write().unwrap().set_live_service_tag(tag.clone(), selectors)
+        };
+        self.register_watches(request);
+        count
+    }
+
+    /// Stops maintaining a live tag and removes it from all the services.
+    /// Returns false if there was no such live tag.
+    pub fn remove_live_service_tag(&self, tag: &Id<TagId>) -> bool {
+        self.back_end.write().unwrap().remove_live_service_tag(tag)
+    }
+
+    /// Changes how long `fetch_values` and `send_values` wait for each adapter.
+    /// The channels of adapters that don't answer in time get an `Error::Timeout`.
+    pub fn set_adapter_timeout(&self, timeout: Duration) {
+        *self.adapter_timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn get_adapter_timeout(&self) -> Duration {
+        *self.adapter_timeout.lock().unwrap()
+    }
+
+    /// The number of active watches on the channels of each adapter.
+    pub fn count_watches(&self) -> HashMap<Id<AdapterId>, usize> {
+        self.back_end.read().unwrap().count_watches()
+    }
+
+    /// Runs `call` for each adapter of `request` on the worker pool, and
+    /// collects the results that arrive before the deadline.
+    ///
+    /// The time each adapter takes is recorded in the metrics, as `operation`.
+    ///
+    /// Only a few calls to the same adapter run at once, so that a hung adapter
+    /// can't take all the workers. Calls still waiting for their turn once the
+    /// deadline has passed are dropped without reaching the adapter. Adapters that call back into the
+    /// `AdapterManager` from `fetch_values` or `send_values` (e.g. scenes or
+    /// groups) are already running on a worker, so their calls run inline
+    /// instead of waiting for another worker, which could deadlock the pool.
+    fn dispatch<V, R, F>(&self,
+                         operation: &'static str,
+                         mut request: AdapterRequest<HashMap<Id<Channel>, V>>,
+                         call: F)
+                         -> ResultMap<Id<Channel>, R, Error>
+        where V: Send + 'static,
+              R: Send + 'static,
+              F: Fn(&Arc<RawAdapter>, HashMap<Id<Channel>, V>) -> ResultMap<Id<Channel>, R, Error>,
+              F: Send + Sync + 'static
+    {
+        let deadline = Instant::now() + self.get_adapter_timeout();
+        let call = Arc::new(call);
+        let (tx, rx) = std_mpsc::channel();
+        let is_nested = WorkerPool::is_worker_thread();
+
+        // The channels of each adapter, in case it doesn't answer.
+        let mut pending = HashMap::new();
+        let mut results = HashMap::new();
+        for (adapter_id, (adapter, payload)) in request.drain() {
+            let channels = payload.keys().cloned().collect::<Vec<_>>();
+            let id = adapter_id.clone();
+            let key = adapter_id.to_string();
+            let call = call.clone();
+            let tx = tx.clone();
+            let job = move || {
+                // A call queued behind a slow adapter may only start once its caller
+                // has been told that it timed out. It must not happen at all then,
+                // e.g. a door must not unlock minutes after the failure was reported.
+                if Instant::now() >= deadline {
+                    let got = payload.keys()
+                        .map(|id| (id.clone(), Err(Error::Timeout(adapter_id.clone()))))
+                        .collect();
+                    let _ = tx.send((adapter_id, got));
+                    return;
+                }
+                let start = Instant::now();
+                let got = call(&adapter, payload);
+                let elapsed = start.elapsed();
+                metrics::global().observe("foxbox_adapter_request_duration_seconds",
+                                          "Time taken by adapters to fetch or send values",
+                                          vec![("adapter", adapter_id.to_string()),
+                                               ("operation", operation.to_owned())],
+                                          elapsed.as_secs() as f64 +
+                                          elapsed.subsec_nanos() as f64 / 1_000_000_000.);
+                // The manager may have stopped waiting already.
+                let _ = tx.send((adapter_id, got));
+            };
+            if is_nested {
+                pending.insert(id, channels);
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("[AdapterManager] Adapter {} panicked", key);
+                }
+            } else if self.pool.execute_for(&key, job).is_ok() {
+                pending.insert(id, channels);
+            } else {
+                warn!("[AdapterManager] Adapter {} is busy", key);
+                for id in channels {
+                    let error = api::InternalError::GenericError(format!("Adapter {} is busy",
+                                                                         key));
+                    results.insert(id, Err(Error::Internal(error)));
+                }
+            }
+        }
+        drop(tx);
+
+        let mut disconnected = false;
+        while !pending.is_empty() {
+            // Past the deadline, still collect the results that have arrived,
+            // e.g. those of the calls that ran inline.
+            let now = Instant::now();
+            let timeout = if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            };
+            match rx.recv_timeout(timeout) {
+                Ok((adapter_id, got)) => {
+                    pending.remove(&adapter_id);
+                    results.extend(got);
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    // All the remaining calls panicked.
+                    disconnected = true;
+                    break;
+                }
+            }
+        }
+
+        for (adapter_id, channels) in pending {
+            if disconnected {
+                error!("[AdapterManager] Adapter {} failed", adapter_id);
+            } else {
+                warn!("[AdapterManager] Adapter {} did not answer in time", adapter_id);
+            }
+            for id in channels {
+                let error = if disconnected {
+                    Error::Internal(api::InternalError::GenericError(format!("Adapter {} failed",
+                                                                             adapter_id)))
+                } else {
+                    Error::Timeout(adapter_id.clone())
+                };
+                results.insert(id, Err(error));
+            }
+        }
+        results
+    }
 }
 
 impl Default for AdapterManager {
@@ -176,10 +428,15 @@ impl API for AdapterManager {
     /// the resulting `usize` nevertheless.
     ///
     /// Note that this call is _not live_. In other words, if services
-    /// are added after the call, they will not be affected.
+    /// are added after the call, they will not be affected. See
+    /// `set_live_service_tag` for tags that follow the services.
     fn add_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().add_service_tags(selectors, tags)
-        // FIXME: This can cause watcher registrations
+        let (request, result) = {
+            // Acquire and release the write lock.
+            self.back_end.write().unwrap().add_service_tags(selectors, tags)
+        };
+        self.register_watches(request);
+        result
     }
 
     /// Remove a set of tags from a set of services.
@@ -196,7 +453,12 @@ impl API for AdapterManager {
     /// Note that this call is _not live_. In okther words, if services
     /// are added after the call, they will not be affected.
     fn remove_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().remove_service_tags(selectors, tags)
+        let (request, result) = {
+            // Acquire and release the write lock.
+            self.back_end.write().unwrap().remove_service_tags(selectors, tags)
+        };
+        self.register_watches(request);
+        result
     }
 
     /// Get a list of channels matching some conditions
@@ -243,7 +505,37 @@ impl API for AdapterManager {
     /// Note that this call is _not live_. In other words, if channels
     /// are added after the call, they will not be affected.
     fn remove_channel_tags(&self, selectors: Vec<ChannelSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().remove_channel_tags(selectors, tags)
+        let (request, result) = {
+            // Acquire and release the write lock.
+            self.back_end.write().unwrap().remove_channel_tags(selectors, tags)
+        };
+        self.register_watches(request);
+        result
+    }
+
+    /// Give a name to a set of services, or remove their name if `name` is `None`.
+    fn set_service_name(&self, selectors: Vec<ServiceSelector>, name: Option<String>) -> usize {
+        let (request, result) = {
+            // Acquire and release the write lock.
+            self.back_end.write().unwrap().set_service_name(selectors, name)
+        };
+        self.register_watches(request);
+        result
+    }
+
+    /// Place a set of services in a room, or remove their room if `room` is `None`.
+    fn set_service_room(&self, selectors: Vec<ServiceSelector>, room: Option<String>) -> usize {
+        let (request, result) = {
+            // Acquire and release the write lock.
+            self.back_end.write().unwrap().set_service_room(selectors, room)
+        };
+        self.register_watches(request);
+        result
+    }
+
+    /// Give a name to a set of channels, or remove their name if `name` is `None`.
+    fn set_channel_name(&self, selectors: Vec<ChannelSelector>, name: Option<String>) -> usize {
+        self.back_end.write().unwrap().set_channel_name(selectors, name)
     }
 
     /// Read the latest value from a set of channels
@@ -252,18 +544,20 @@ impl API for AdapterManager {
                     user: User)
                     -> OpResult<(Payload, Arc<Format>)> {
         // First, prepare the request.
-        let mut request;
+        let request;
         {
             // Make sure that the lock is released asap.
             request = self.back_end.read().unwrap().prepare_fetch_values(selectors);
         }
-        // Now fetch the values
-        let mut results = HashMap::new();
-        for (_, (adapter, mut channels)) in request.drain() {
-            let channels = channels.drain().collect();
-            let got = adapter.fetch_values(channels, user.clone());
-
-            results.extend(got);
+        // Now fetch the values, from all the adapters at once.
+        let cached_user = user.clone();
+        let results = self.dispatch("fetch_values", request, move |adapter, mut channels| {
+            adapter.fetch_values(channels.drain().collect(), user.clone())
+        });
+        for (id, result) in &results {
+            if let Ok(Some((ref payload, ref format))) = *result {
+                self.values.update_fetched(&cached_user, id, payload, format);
+            }
         }
         results
     }
@@ -274,19 +568,46 @@ impl API for AdapterManager {
                    user: User)
                    -> ResultMap<Id<Channel>, (), Error> {
         // First, prepare the request.
-        let mut prepared;
+        let prepared;
         {
             // Make sure that the lock is released asap.
             prepared = self.back_end.read().unwrap().prepare_send_values(keyvalues);
         }
 
-        // Dispatch to adapter
-        let mut results = HashMap::new();
-        for (_, (adapter, request)) in prepared.drain() {
-            let got = adapter.send_values(request, user.clone());
-            results.extend(got);
-        }
+        // Keep what we need to audit the request.
+        let sent: Vec<(Id<Channel>, Payload)> = prepared.values()
+            .flat_map(|&(_, ref request)| {
+                request.iter().map(|(id, &(ref payload, _))| (id.clone(), payload.clone()))
+            })
+            .collect();
+        let channels: HashMap<_, _> = self.get_channels(sent.iter()
+                .map(|&(ref id, _)| ChannelSelector::new().with_id(id))
+                .collect())
+            .into_iter()
+            .map(|channel| (channel.id.clone(), channel))
+            .collect();
+
+        // Dispatch to all the adapters at once.
+        let audited_user = user.clone();
+        let results = self.dispatch("send_values", prepared, move |adapter, request| {
+            adapter.send_values(request, user.clone())
+        });
 
+        for (id, payload) in sent {
+            let channel = channels.get(&id);
+            let mut entry = AuditEntry::new(&audited_user, "send_values")
+                .with_channel(&id)
+                .with_payload(channel.map(|channel| &channel.feature), payload.to_json());
+            if let Some(channel) = channel {
+                entry = entry.with_service(&channel.service);
+            }
+            match results.get(&id) {
+                Some(&Ok(())) => {}
+                Some(&Err(ref err)) => entry = entry.with_error(format!("{}", err)),
+                None => entry = entry.with_error("No result".to_owned()),
+            }
+            self.audit.record(entry);
+        }
         results
     }
 
@@ -341,7 +662,9 @@ impl AdapterManager {
     }
 
     /// Start the background thread .
-    fn handle_watches(state: Weak<MainLock<State>>) -> RawSender<WatchOp> {
+    fn handle_watches(state: Weak<MainLock<State>>,
+                      values: Arc<ValueCache>)
+                      -> RawSender<WatchOp> {
         let (tx, rx) = channel();
         let state = state.clone();
         thread::spawn(move || {
@@ -351,7 +674,7 @@ impl AdapterManager {
                     Some(backend) => {
                         match msg {
                             WatchOp::Start(request, tx) => {
-                                let add = State::start_watch(request);
+                                let add = State::start_watch(request, values.clone());
                                 backend.write().unwrap().register_ongoing_watch(add);
                                 let _ = tx.send(());
                             }
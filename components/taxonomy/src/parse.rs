@@ -6,7 +6,7 @@ use util::Id;
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::error::Error as StdError;
-use std::fmt::{Display, Debug, Error as FmtError, Formatter};
+use std::fmt::{Display, Error as FmtError, Formatter};
 use std::hash::Hash;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -113,7 +113,23 @@ pub enum ParseError {
 
 impl Display for ParseError {
     fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
-        (self as &Debug).fmt(formatter)
+        match *self {
+            ParseError::JSON(ref err) => write!(formatter, "Invalid JSON: {}", err.0),
+            ParseError::MissingField { ref name, .. } => {
+                write!(formatter, "Missing field `{}`", name)
+            }
+            ParseError::UnknownFields { ref names, .. } => {
+                write!(formatter, "Unknown fields `{}`", names.join("`, `"))
+            }
+            ParseError::TypeError { ref name, ref expected, .. } => {
+                write!(formatter, "Invalid `{}`, expected {}", name, expected)
+            }
+            ParseError::EmptyObject { .. } => write!(formatter, "Expected a non-empty object"),
+            ParseError::UnknownConstant { ref constant, .. } => {
+                write!(formatter, "Unknown constant `{}`", constant)
+            }
+            ParseError::InternalError(ref err) => write!(formatter, "{}", err),
+        }
     }
 }
 impl StdError for ParseError {
@@ -129,6 +145,32 @@ impl From<ParseError> for APIError {
 }
 
 impl ParseError {
+    /// A stable, machine-readable code for the kind of error.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ParseError::JSON(_) => "invalid_json",
+            ParseError::MissingField { .. } => "missing_field",
+            ParseError::UnknownFields { .. } => "unknown_fields",
+            ParseError::TypeError { .. } => "type_error",
+            ParseError::EmptyObject { .. } => "empty_object",
+            ParseError::UnknownConstant { .. } => "unknown_constant",
+            ParseError::InternalError(_) => "parse_error",
+        }
+    }
+
+    /// The path of the offending value in the JSON tree, if known.
+    pub fn path(&self) -> Option<&str> {
+        match *self {
+            ParseError::MissingField { ref at, .. } |
+            ParseError::UnknownFields { ref at, .. } |
+            ParseError::TypeError { ref at, .. } |
+            ParseError::EmptyObject { ref at } |
+            ParseError::UnknownConstant { ref at, .. } => Some(at),
+            ParseError::JSON(_) |
+            ParseError::InternalError(_) => None,
+        }
+    }
+
     pub fn missing_field(name: &str, at: &Path) -> Self {
         ParseError::MissingField {
             name: name.to_owned(),
@@ -200,6 +242,15 @@ impl Serialize for JSONError {
 /// typically leave an empty JSON object.
 pub trait Parser<T: Sized> {
     fn description() -> String;
+
+    /// A machine-readable description of the JSON accepted by `parse`, loosely
+    /// following JSON Schema.
+    ///
+    /// Used to document the REST API. By default, this accepts any JSON value.
+    fn schema() -> JSON {
+        vec![("description", Self::description())].to_json()
+    }
+
     fn from_str(source: &str) -> Result<T, ParseError> {
         Self::from_str_at(Path::new(), source)
     }
@@ -292,6 +343,9 @@ impl Parser<f64> for f64 {
     fn description() -> String {
         "Number".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "number")].to_json()
+    }
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
         match *source {
             JSON::I64(val) => Ok(val as f64),
@@ -306,6 +360,9 @@ impl Parser<bool> for bool {
     fn description() -> String {
         "bool".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "boolean")].to_json()
+    }
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
         match *source {
             JSON::Bool(ref b) => Ok(*b),
@@ -322,6 +379,10 @@ impl Parser<u8> for u8 {
     fn description() -> String {
         "byte".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "integer".to_json()), ("minimum", JSON::U64(0)), ("maximum", JSON::U64(255))]
+            .to_json()
+    }
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
         match source.as_u64() {
             None => Err(ParseError::type_error("as byte", &path, "positive integer")),
@@ -333,12 +394,52 @@ impl Parser<u8> for u8 {
     }
 }
 
+impl Parser<String> for String {
+    fn description() -> String {
+        "string".to_owned()
+    }
+    fn schema() -> JSON {
+        vec![("type", "string")].to_json()
+    }
+    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+        match *source {
+            JSON::String(ref string) => Ok(string.clone()),
+            _ => Err(ParseError::type_error("as string", &path, "string")),
+        }
+    }
+}
+
+impl<T> Parser<Option<T>> for Option<T>
+    where T: Parser<T>
+{
+    fn description() -> String {
+        format!("Option<{}>", T::description())
+    }
+    fn schema() -> JSON {
+        // `null` is also accepted.
+        T::schema()
+    }
+    fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
+        // Accept `null` as `None`.
+        if let JSON::Null = *source {
+            Ok(None)
+        } else {
+            T::parse(path, source).map(Some)
+        }
+    }
+}
+
 impl<T, P> Parser<Vec<T>> for Vec<P>
     where P: Parser<T>
 {
     fn description() -> String {
         format!("Array<{}>", P::description())
     }
+    fn schema() -> JSON {
+        // A single value is also accepted, as an array of one value.
+        let array = vec![("type", "array".to_json()), ("items", P::schema())].to_json();
+        vec![("anyOf", vec![array, P::schema()])].to_json()
+    }
     fn parse(path: Path, source: &JSON) -> Result<Vec<T>, ParseError> {
         // Otherwise, parse as an actual array.
         match *source {
@@ -390,6 +491,9 @@ impl<T> Parser<Arc<T>> for Arc<T>
     fn description() -> String {
         T::description()
     }
+    fn schema() -> JSON {
+        T::schema()
+    }
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
         Ok(Arc::new(try!(T::parse(path, source))))
     }
@@ -487,7 +591,7 @@ impl<K, T, V> ToJSON for HashMap<Id<K>, Result<T, V>>
                 let k = k.to_string();
                 let result = match *result {
                     Ok(ref ok) => ok.to_json(),
-                    Err(ref err) => vec![("Error", err)].to_json(),
+                    Err(ref err) => vec![("error", err)].to_json(),
                 };
                 (k, result)
             })
@@ -10,7 +10,7 @@ use services::Service;
 use util::*;
 
 use std::hash::Hash;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 fn merge<T>(mut a: HashSet<T>, b: Vec<T>) -> HashSet<T>
     where T: Hash + Eq
@@ -21,6 +21,41 @@ fn merge<T>(mut a: HashSet<T>, b: Vec<T>) -> HashSet<T>
     a
 }
 
+/// Combine two sets of properties, requiring the values of both.
+fn merge_properties(mut a: HashMap<String, Exactly<String>>,
+                    b: HashMap<String, Exactly<String>>)
+                    -> HashMap<String, Exactly<String>> {
+    for (key, value) in b {
+        let value = match a.remove(&key) {
+            Some(previous) => previous.and(value),
+            None => value,
+        };
+        a.insert(key, value);
+    }
+    a
+}
+
+/// Combine two `any_of` alternatives, i.e. turn `(a1 | a2) & (b1 | b2)` into
+/// `(a1 & b1) | (a1 & b2) | (a2 & b1) | (a2 & b2)`.
+fn merge_any_of<T, F>(a: Vec<T>, b: Vec<T>, and: F) -> Vec<T>
+    where T: Clone,
+          F: Fn(T, T) -> T
+{
+    if a.is_empty() {
+        return b;
+    }
+    if b.is_empty() {
+        return a;
+    }
+    let mut result = Vec::with_capacity(a.len() * b.len());
+    for x in &a {
+        for y in &b {
+            result.push(and(x.clone(), y.clone()));
+        }
+    }
+    result
+}
+
 pub trait SelectedBy<T> {
     fn matches(&self, &T) -> bool;
 }
@@ -41,6 +76,9 @@ impl SelectedBy<Option<Signature>> for Exactly<bool> {
 pub trait ServiceLike {
     fn id(&self) -> &Id<ServiceId>;
     fn adapter(&self) -> &Id<AdapterId>;
+    fn properties(&self) -> &HashMap<String, String>;
+    fn name(&self) -> Option<&str>;
+    fn room(&self) -> Option<&str>;
     fn with_tags<F>(&self, f: F) -> bool where F: Fn(&HashSet<Id<TagId>>) -> bool;
     fn has_channels<F>(&self, f: F) -> bool where F: Fn(&Channel) -> bool;
 }
@@ -52,6 +90,15 @@ impl ServiceLike for Service {
     fn adapter(&self) -> &Id<AdapterId> {
         &self.adapter
     }
+    fn properties(&self) -> &HashMap<String, String> {
+        &self.properties
+    }
+    fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| name.as_str())
+    }
+    fn room(&self) -> Option<&str> {
+        self.room.as_ref().map(|room| room.as_str())
+    }
     fn with_tags<F>(&self, f: F) -> bool
         where F: Fn(&HashSet<Id<TagId>>) -> bool
     {
@@ -88,9 +135,18 @@ impl ServiceLike for Service {
 /// A selector is an object with the following fields:
 ///
 /// - (optional) string `id`: accept only a service with a given id;
+/// - (optional) string `adapter`: accept only services of the adapter with a given id;
 /// - (optional) array of string `tags`:  accept only services with all the tags in the array;
+/// - (optional) object `properties`: accept only services whose properties (e.g. `model`,
+///    `manufacturer`) have all the string values of this object;
+/// - (optional) string `name`: accept only services with the name given by the user;
+/// - (optional) string `room`: accept only services placed by the user in a given room;
 /// - (optional) array of objects `channels` (see `ChannelSelector`): accept only services with
 ///    channels matching all the selectors in this array;
+/// - (optional) object or array of objects `not`: reject the services matched by any of these
+///    selectors;
+/// - (optional) object or array of objects `any_of`: accept only services matched by at least
+///    one of these selectors, e.g. services having one of several sets of tags.
 ///
 /// While each field is optional, at least one field must be provided.
 ///
@@ -100,10 +156,16 @@ impl ServiceLike for Service {
 /// // A selector with all fields defined.
 /// let json_selector = "{
 ///   \"id\": \"setter 1\",
+///   \"adapter\": \"adapter 1\",
 ///   \"tags\": [\"tag 1\", \"tag 2\"],
+///   \"properties\": {\"manufacturer\": \"Philips\"},
+///   \"name\": \"Reading lamp\",
+///   \"room\": \"Living room\",
 ///   \"channels\": [{
 ///     \"feature\": \"chronometer/is-ready\"
-///   }]
+///   }],
+///   \"not\": {\"tags\": [\"tag 3\"]},
+///   \"any_of\": [{\"tags\": [\"tag 4\"]}, {\"tags\": [\"tag 5\", \"tag 6\"]}]
 /// }";
 ///
 /// ServiceSelector::from_str(json_selector).unwrap();
@@ -120,12 +182,31 @@ pub struct ServiceSelector {
     /// If `Exactly(id)`, return only the service with the corresponding id.
     pub id: Exactly<Id<ServiceId>>,
 
+    /// If `Exactly(id)`, return only the services of adapter `id`.
+    pub adapter: Exactly<Id<AdapterId>>,
+
     ///  Restrict results to services that have all the tags in `tags`.
     pub tags: HashSet<Id<TagId>>,
 
+    /// Restrict results to services whose properties have all the values in `properties`.
+    pub properties: HashMap<String, Exactly<String>>,
+
+    /// If `Exactly(name)`, return only the services with the name given by the user.
+    pub name: Exactly<String>,
+
+    /// If `Exactly(room)`, return only the services placed by the user in `room`.
+    pub room: Exactly<String>,
+
     /// Restrict results to services that have all the channels in `channels`.
     pub channels: Vec<ChannelSelector>,
 
+    /// Reject the services accepted by any of the selectors in `not`.
+    pub not: Vec<ServiceSelector>,
+
+    /// If non-empty, restrict results to services accepted by at least one of the selectors
+    /// in `any_of`.
+    pub any_of: Vec<ServiceSelector>,
+
     /// Make sure that we can't instantiate from another crate.
     private: (),
 }
@@ -134,6 +215,20 @@ impl Parser<ServiceSelector> for ServiceSelector {
     fn description() -> String {
         "ServiceSelector".to_owned()
     }
+    fn schema() -> JSON {
+        let properties = vec![
+            ("id", string_schema()),
+            ("adapter", string_schema()),
+            ("tags", array_schema(string_schema())),
+            ("properties", properties_schema()),
+            ("name", string_schema()),
+            ("room", string_schema()),
+            ("channels", array_schema(schema_ref(&ChannelSelector::description()))),
+            ("not", one_or_array_schema(schema_ref(&Self::description()))),
+            ("any_of", one_or_array_schema(schema_ref(&Self::description()))),
+        ];
+        selector_schema(properties)
+    }
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
         let mut is_empty = true;
         let id = try!(match path.push("id", |path| Exactly::take_opt(path, source, "id")) {
@@ -143,6 +238,14 @@ impl Parser<ServiceSelector> for ServiceSelector {
                 result
             }
         });
+        let adapter =
+            try!(match path.push("adapter", |path| Exactly::take_opt(path, source, "adapter")) {
+                None => Ok(Exactly::Always),
+                Some(result) => {
+                    is_empty = false;
+                    result
+                }
+            });
         let tags: HashSet<_> =
             match path.push("tags", |path| Id::take_vec_opt(path, source, "tags")) {
                 None => HashSet::new(),
@@ -152,6 +255,29 @@ impl Parser<ServiceSelector> for ServiceSelector {
                 }
                 Some(Err(err)) => return Err(err),
             };
+        let properties =
+            match path.push("properties", |path| take_properties_opt(path, source, "properties")) {
+                None => HashMap::new(),
+                Some(Ok(properties)) => {
+                    is_empty = false;
+                    properties
+                }
+                Some(Err(err)) => return Err(err),
+            };
+        let name = try!(match path.push("name", |path| Exactly::take_opt(path, source, "name")) {
+            None => Ok(Exactly::Always),
+            Some(result) => {
+                is_empty = false;
+                result
+            }
+        });
+        let room = try!(match path.push("room", |path| Exactly::take_opt(path, source, "room")) {
+            None => Ok(Exactly::Always),
+            Some(result) => {
+                is_empty = false;
+                result
+            }
+        });
         let channels = match path.push("channels", |path| {
             ChannelSelector::take_vec_opt(path, source, "channels")
         }) {
@@ -162,14 +288,37 @@ impl Parser<ServiceSelector> for ServiceSelector {
             }
             Some(Err(err)) => return Err(err),
         };
+        let not = match path.push("not", |path| Vec::<Self>::take_opt(path, source, "not")) {
+            None => vec![],
+            Some(Ok(vec)) => {
+                is_empty = false;
+                vec
+            }
+            Some(Err(err)) => return Err(err),
+        };
+        let any_of =
+            match path.push("any_of", |path| Vec::<Self>::take_opt(path, source, "any_of")) {
+                None => vec![],
+                Some(Ok(vec)) => {
+                    is_empty = false;
+                    vec
+                }
+                Some(Err(err)) => return Err(err),
+            };
 
         if is_empty {
             Err(ParseError::empty_object(&path))
         } else {
             Ok(ServiceSelector {
                 id: id,
+                adapter: adapter,
                 tags: tags,
+                properties: properties,
+                name: name,
+                room: room,
                 channels: channels,
+                not: not,
+                any_of: any_of,
                 private: (),
             })
         }
@@ -187,11 +336,33 @@ impl ServiceSelector {
         ServiceSelector { id: self.id.and(Exactly::Exactly(id.clone())), ..self }
     }
 
+    /// Restrict results to services of a specific adapter.
+    pub fn with_adapter(self, id: &Id<AdapterId>) -> Self {
+        ServiceSelector { adapter: self.adapter.and(Exactly::Exactly(id.clone())), ..self }
+    }
+
+    /// Restrict results to services whose property `key` is `value`.
+    pub fn with_property(self, key: &str, value: &str) -> Self {
+        let mut property = HashMap::new();
+        property.insert(key.to_owned(), Exactly::Exactly(value.to_owned()));
+        ServiceSelector { properties: merge_properties(self.properties, property), ..self }
+    }
+
     ///  Restrict results to services that have all the tags in `tags`.
     pub fn with_tags(self, tags: Vec<Id<TagId>>) -> Self {
         ServiceSelector { tags: merge(self.tags, tags), ..self }
     }
 
+    /// Restrict results to services with the name given by the user.
+    pub fn with_name(self, name: &str) -> Self {
+        ServiceSelector { name: self.name.and(Exactly::Exactly(name.to_owned())), ..self }
+    }
+
+    /// Restrict results to services placed by the user in `room`.
+    pub fn with_room(self, room: &str) -> Self {
+        ServiceSelector { room: self.room.and(Exactly::Exactly(room.to_owned())), ..self }
+    }
+
     /// Restrict results to services that have all the channels in `channels`.
     pub fn with_channels(mut self, mut channels: Vec<ChannelSelector>) -> Self {
         ServiceSelector {
@@ -203,15 +374,35 @@ impl ServiceSelector {
         }
     }
 
+    /// Reject services that are accepted by `selector`.
+    pub fn without(mut self, selector: ServiceSelector) -> Self {
+        self.not.push(selector);
+        self
+    }
+
+    /// Restrict results to services that are accepted by at least one of `selectors`.
+    pub fn with_any_of(self, selectors: Vec<ServiceSelector>) -> Self {
+        ServiceSelector { any_of: merge_any_of(self.any_of, selectors, Self::and), ..self }
+    }
+
     /// Restrict results to services that are accepted by two selector.
     pub fn and(mut self, mut other: ServiceSelector) -> Self {
         ServiceSelector {
             id: self.id.and(other.id),
+            adapter: self.adapter.and(other.adapter),
             tags: self.tags.union(&other.tags).cloned().collect(),
+            properties: merge_properties(self.properties, other.properties),
+            name: self.name.and(other.name),
+            room: self.room.and(other.room),
             channels: {
                 self.channels.append(&mut other.channels);
                 self.channels
             },
+            not: {
+                self.not.append(&mut other.not);
+                self.not
+            },
+            any_of: merge_any_of(self.any_of, other.any_of, Self::and),
             private: (),
         }
     }
@@ -222,17 +413,35 @@ impl ServiceSelector {
         if !self.id.matches(service.id()) {
             return false;
         }
+        if !self.adapter.matches(service.adapter()) {
+            return false;
+        }
         if !service.with_tags(|tags| has_selected_tags(&self.tags, tags)) {
             return false;
         }
+        if !has_selected_properties(&self.properties, service.properties()) {
+            return false;
+        }
+        if !matches_opt(&self.name, service.name()) || !matches_opt(&self.room, service.room()) {
+            return false;
+        }
         // If any of the getter selectors doesn't find a getter,
         // we don't match.
-        let channels_fail = self.channels
-            .iter()
-            .any(|selector| !service.has_channels(|channel| selector.matches(&self.tags, channel)));
+        let channels_fail = self.channels.iter().any(|selector| {
+            !service.has_channels(|channel| {
+                selector.matches(&self.tags, service.properties(), channel)
+            })
+        });
         if channels_fail {
             return false;
         }
+        let matches = |selector: &ServiceSelector| selector.matches(service);
+        if self.not.iter().any(&matches) {
+            return false;
+        }
+        if !self.any_of.is_empty() && !self.any_of.iter().any(&matches) {
+            return false;
+        }
 
         true
     }
@@ -265,10 +474,18 @@ impl SelectedBy<ServiceSelector> for Service {
 ///
 /// - (optional) string `id`: accept only a channel with a given id;
 /// - (optional) string `service`: accept only channels of a service with a given id;
+/// - (optional) string `adapter`: accept only channels of the adapter with a given id;
 /// - (optional) array of string `tags`:  accept only channels with all the tags in the array;
 /// - (optional) array of string `service_tags`:  accept only channels of a service with all the
 ///        tags in the array;
-/// - (optional) string|object `kind` (see `ChannelKind`): accept only channels of a given kind.
+/// - (optional) object `service_properties`: accept only channels of a service whose properties
+///        have all the string values of this object;
+/// - (optional) string `feature`: accept only channels with a given feature. A feature ending
+///        with `*`, e.g. `light/*`, accepts all the features starting with the same prefix;
+/// - (optional) object or array of objects `not`: reject the channels matched by any of these
+///        selectors;
+/// - (optional) object or array of objects `any_of`: accept only channels matched by at least
+///        one of these selectors.
 ///
 /// While each field is optional, at least one field must be provided.
 ///
@@ -276,12 +493,16 @@ impl SelectedBy<ServiceSelector> for Service {
 /// use foxbox_taxonomy::selector::*;
 ///
 /// // A selector with all fields defined.
-/// let json_selector = "{                         \
-///   \"id\": \"setter 1\",                        \
-///   \"service\": \"service 1\",                  \
-///   \"tags\": [\"tag 1\", \"tag 2\"],            \
-///   \"service_tags\": [\"tag 3\", \"tag 4\"],    \
-///   \"kind\": \"Ready\"                          \
+/// let json_selector = "{                                      \
+///   \"id\": \"setter 1\",                                     \
+///   \"service\": \"service 1\",                               \
+///   \"adapter\": \"adapter 1\",                               \
+///   \"tags\": [\"tag 1\", \"tag 2\"],                         \
+///   \"service_tags\": [\"tag 3\", \"tag 4\"],                 \
+///   \"service_properties\": {\"model\": \"Hue Bulb\"},         \
+///   \"feature\": \"light/*\",                                 \
+///   \"not\": {\"tags\": [\"tag 5\"]},                          \
+///   \"any_of\": [{\"tags\": [\"tag 6\"]}, {\"tags\": [\"tag 7\"]}] \
 /// }";
 ///
 /// ChannelSelector::from_str(json_selector).unwrap();
@@ -302,19 +523,36 @@ pub struct ChannelSelector {
     /// service `id`.
     pub parent: Exactly<Id<ServiceId>>,
 
+    /// If `Exactly(id)`, return only channels of adapter `id`.
+    pub adapter: Exactly<Id<AdapterId>>,
+
     ///  Restrict results to channels that have all the tags in `tags`.
     pub tags: HashSet<Id<TagId>>,
 
     ///  Restrict results to channels offered by a service that has all the tags in `tags`.
     pub service_tags: HashSet<Id<TagId>>,
 
+    /// Restrict results to channels offered by a service whose properties have all the values
+    /// in `service_properties`.
+    pub service_properties: HashMap<String, Exactly<String>>,
+
     /// If `Exactly(k)`, restrict results to channels that provide feature `k`
     pub feature: Exactly<Id<FeatureId>>,
 
+    /// If `Exactly(prefix)`, restrict results to channels whose feature starts with `prefix`.
+    pub feature_prefix: Exactly<String>,
+
     pub supports_send: Exactly<bool>,
     pub supports_fetch: Exactly<bool>,
     pub supports_watch: Exactly<bool>,
 
+    /// Reject the channels accepted by any of the selectors in `not`.
+    pub not: Vec<ChannelSelector>,
+
+    /// If non-empty, restrict results to channels accepted by at least one of the selectors
+    /// in `any_of`.
+    pub any_of: Vec<ChannelSelector>,
+
     /// Make sure that we can't instantiate from another crate.
     private: (),
 }
@@ -323,6 +561,25 @@ impl Parser<ChannelSelector> for ChannelSelector {
     fn description() -> String {
         "ChannelSelector".to_owned()
     }
+    fn schema() -> JSON {
+        let feature = vec![("type", "string"),
+                           ("description", "A feature, or a prefix of features ending with `*`")];
+        let properties = vec![
+            ("id", string_schema()),
+            ("service", string_schema()),
+            ("adapter", string_schema()),
+            ("tags", array_schema(string_schema())),
+            ("service_tags", array_schema(string_schema())),
+            ("service_properties", properties_schema()),
+            ("feature", feature.to_json()),
+            ("supports_send", bool::schema()),
+            ("supports_fetch", bool::schema()),
+            ("supports_watch", bool::schema()),
+            ("not", one_or_array_schema(schema_ref(&Self::description()))),
+            ("any_of", one_or_array_schema(schema_ref(&Self::description()))),
+        ];
+        selector_schema(properties)
+    }
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
         let mut is_empty = true;
         let id = try!(match path.push("id", |path| Exactly::take_opt(path, source, "id")) {
@@ -340,6 +597,14 @@ impl Parser<ChannelSelector> for ChannelSelector {
                     result
                 }
             });
+        let adapter =
+            try!(match path.push("adapter", |path| Exactly::take_opt(path, source, "adapter")) {
+                None => Ok(Exactly::Always),
+                Some(result) => {
+                    is_empty = false;
+                    result
+                }
+            });
         let tags: HashSet<_> =
             match path.push("tags", |path| Id::take_vec_opt(path, source, "tags")) {
                 None => HashSet::new(),
@@ -359,9 +624,19 @@ impl Parser<ChannelSelector> for ChannelSelector {
             }
             Some(Err(err)) => return Err(err),
         };
-        let feature =
-            try!(match path.push("feature", |path| Exactly::take_opt(path, source, "feature")) {
-                None => Ok(Exactly::Always),
+        let service_properties = match path.push("service_properties", |path| {
+            take_properties_opt(path, source, "service_properties")
+        }) {
+            None => HashMap::new(),
+            Some(Ok(properties)) => {
+                is_empty = false;
+                properties
+            }
+            Some(Err(err)) => return Err(err),
+        };
+        let (feature, feature_prefix) =
+            try!(match path.push("feature", |path| take_feature_opt(path, source)) {
+                None => Ok((Exactly::Always, Exactly::Always)),
                 Some(result) => {
                     is_empty = false;
                     result
@@ -385,18 +660,40 @@ impl Parser<ChannelSelector> for ChannelSelector {
             None => Ok(Exactly::Always),
             Some(result) => result,
         });
+        let not = match path.push("not", |path| Vec::<Self>::take_opt(path, source, "not")) {
+            None => vec![],
+            Some(Ok(vec)) => {
+                is_empty = false;
+                vec
+            }
+            Some(Err(err)) => return Err(err),
+        };
+        let any_of =
+            match path.push("any_of", |path| Vec::<Self>::take_opt(path, source, "any_of")) {
+                None => vec![],
+                Some(Ok(vec)) => {
+                    is_empty = false;
+                    vec
+                }
+                Some(Err(err)) => return Err(err),
+            };
         if is_empty {
             Err(ParseError::empty_object(&path))
         } else {
             Ok(ChannelSelector {
                 id: id,
                 parent: service_id,
+                adapter: adapter,
                 tags: tags,
                 service_tags: service_tags,
+                service_properties: service_properties,
                 feature: feature,
+                feature_prefix: feature_prefix,
                 supports_send: supports_send,
                 supports_fetch: supports_fetch,
                 supports_watch: supports_watch,
+                not: not,
+                any_of: any_of,
                 private: (),
             })
         }
@@ -419,11 +716,24 @@ impl ChannelSelector {
         ChannelSelector { parent: self.parent.and(Exactly::Exactly(id.clone())), ..self }
     }
 
+    /// Restrict to channels of a specific adapter.
+    pub fn with_adapter(self, id: &Id<AdapterId>) -> Self {
+        ChannelSelector { adapter: self.adapter.and(Exactly::Exactly(id.clone())), ..self }
+    }
+
     /// Restrict to a channel with a specific kind.
     pub fn with_feature(self, feature: &Id<FeatureId>) -> Self {
         ChannelSelector { feature: self.feature.and(Exactly::Exactly(feature.clone())), ..self }
     }
 
+    /// Restrict to channels whose feature starts with `prefix`, e.g. "light/".
+    pub fn with_feature_prefix(self, prefix: &str) -> Self {
+        ChannelSelector {
+            feature_prefix: self.feature_prefix.and(Exactly::Exactly(prefix.to_owned())),
+            ..self
+        }
+    }
+
     ///  Restrict to channels that have all the tags in `tags`.
     pub fn with_tags(self, tags: Vec<Id<TagId>>) -> Self {
         ChannelSelector { tags: merge(self.tags, tags), ..self }
@@ -446,32 +756,72 @@ impl ChannelSelector {
         ChannelSelector { service_tags: merge(self.service_tags, tags), ..self }
     }
 
+    /// Restrict to channels offered by a service whose property `key` is `value`.
+    pub fn with_service_property(self, key: &str, value: &str) -> Self {
+        let mut property = HashMap::new();
+        property.insert(key.to_owned(), Exactly::Exactly(value.to_owned()));
+        ChannelSelector {
+            service_properties: merge_properties(self.service_properties, property),
+            ..self
+        }
+    }
+
+    /// Reject channels that are accepted by `selector`.
+    pub fn without(mut self, selector: ChannelSelector) -> Self {
+        self.not.push(selector);
+        self
+    }
+
+    /// Restrict to channels that are accepted by at least one of `selectors`.
+    pub fn with_any_of(self, selectors: Vec<ChannelSelector>) -> Self {
+        ChannelSelector { any_of: merge_any_of(self.any_of, selectors, Self::and), ..self }
+    }
+
     /// Restrict to channels that are accepted by two selector.
-    pub fn and(self, other: Self) -> Self {
+    pub fn and(mut self, mut other: Self) -> Self {
         ChannelSelector {
             id: self.id.and(other.id),
             parent: self.parent.and(other.parent),
+            adapter: self.adapter.and(other.adapter),
             tags: self.tags.union(&other.tags).cloned().collect(),
             service_tags: self.service_tags.union(&other.service_tags).cloned().collect(),
+            service_properties: merge_properties(self.service_properties,
+                                                 other.service_properties),
             feature: self.feature.and(other.feature),
+            feature_prefix: self.feature_prefix.and(other.feature_prefix),
             supports_send: self.supports_send.and(other.supports_send),
             supports_fetch: self.supports_fetch.and(other.supports_fetch),
             supports_watch: self.supports_watch.and(other.supports_watch),
+            not: {
+                self.not.append(&mut other.not);
+                self.not
+            },
+            any_of: merge_any_of(self.any_of, other.any_of, Self::and),
             private: (),
         }
     }
 
     /// Determine if a channel is matched by this selector.
-    pub fn matches(&self, service_tags: &HashSet<Id<TagId>>, channel: &Channel) -> bool {
+    pub fn matches(&self,
+                   service_tags: &HashSet<Id<TagId>>,
+                   service_properties: &HashMap<String, String>,
+                   channel: &Channel)
+                   -> bool {
         if !self.id.matches(&channel.id) {
             return false;
         }
         if !self.parent.matches(&channel.service) {
             return false;
         }
+        if !self.adapter.matches(&channel.adapter) {
+            return false;
+        }
         if !self.feature.matches(&channel.feature) {
             return false;
         }
+        if !has_selected_prefix(&self.feature_prefix, &channel.feature) {
+            return false;
+        }
         if !(&self.supports_send as &SelectedBy<_>).matches(&channel.supports_send) {
             return false;
         }
@@ -487,6 +837,18 @@ impl ChannelSelector {
         if !has_selected_tags(&self.service_tags, service_tags) {
             return false;
         }
+        if !has_selected_properties(&self.service_properties, service_properties) {
+            return false;
+        }
+        let matches = |selector: &ChannelSelector| {
+            selector.matches(service_tags, service_properties, channel)
+        };
+        if self.not.iter().any(&matches) {
+            return false;
+        }
+        if !self.any_of.is_empty() && !self.any_of.iter().any(&matches) {
+            return false;
+        }
         true
     }
 }
@@ -499,6 +861,10 @@ impl Parser<ChannelSelector> for ChannelSelectorWithFeature {
     fn description() -> String {
         "ChannelSelector (feature must be specified)".to_owned()
     }
+    fn schema() -> JSON {
+        let required = vec![("required", vec!["feature"])].to_json();
+        vec![("allOf", vec![schema_ref(&ChannelSelector::description()), required])].to_json()
+    }
     fn parse(path: Path, source: &JSON) -> Result<ChannelSelector, ParseError> {
         let selector = try!(ChannelSelector::parse(path.clone(), source));
         if let Exactly::Exactly(_) = selector.feature {
@@ -517,3 +883,109 @@ fn has_selected_tags(actual: &HashSet<Id<TagId>>, requested: &HashSet<Id<TagId>>
     }
     true
 }
+
+fn has_selected_properties(selected: &HashMap<String, Exactly<String>>,
+                           actual: &HashMap<String, String>)
+                           -> bool {
+    selected.iter().all(|(key, value)| match actual.get(key) {
+        Some(actual) => value.matches(actual),
+        None => false,
+    })
+}
+
+/// Whether an optional value, e.g. the room of a service, is the `selected` one.
+fn matches_opt(selected: &Exactly<String>, actual: Option<&str>) -> bool {
+    match *selected {
+        Exactly::Always => true,
+        Exactly::Exactly(ref selected) => actual == Some(selected.as_str()),
+        Exactly::Never => false,
+    }
+}
+
+fn has_selected_prefix(prefix: &Exactly<String>, feature: &Id<FeatureId>) -> bool {
+    match *prefix {
+        Exactly::Always => true,
+        Exactly::Exactly(ref prefix) => {
+            let feature: &str = feature.as_atom().as_ref();
+            feature.starts_with(prefix.as_str())
+        }
+        Exactly::Never => false,
+    }
+}
+
+/// Parse field `feature`, which is either a feature id or a prefix followed by `*`, e.g.
+/// `light/*`.
+fn take_feature_opt(path: Path,
+                    source: &JSON)
+                    -> Option<Result<(Exactly<Id<FeatureId>>, Exactly<String>), ParseError>> {
+    if let JSON::Object(ref obj) = *source {
+        if let Some(&JSON::String(ref feature)) = obj.get("feature") {
+            if feature.ends_with('*') {
+                let prefix = feature[..feature.len() - 1].to_owned();
+                return Some(Ok((Exactly::Always, Exactly::Exactly(prefix))));
+            }
+        }
+    }
+    Exactly::take_opt(path, source, "feature")
+        .map(|result| result.map(|feature| (feature, Exactly::Always)))
+}
+
+/// A reference to the schema of a selector, which cannot be inlined since selectors
+/// are recursive. Documents embedding selector schemas must provide the schemas of
+/// `ServiceSelector` and `ChannelSelector` as components, named by their descriptions.
+fn schema_ref(name: &str) -> JSON {
+    vec![("$ref", format!("#/components/schemas/{}", name))].to_json()
+}
+
+fn string_schema() -> JSON {
+    String::schema()
+}
+
+fn array_schema(items: JSON) -> JSON {
+    vec![("type", "array".to_json()), ("items", items)].to_json()
+}
+
+/// The schema of a field accepting either a value or an array of values.
+fn one_or_array_schema(item: JSON) -> JSON {
+    vec![("anyOf", vec![item.clone(), array_schema(item)])].to_json()
+}
+
+fn properties_schema() -> JSON {
+    vec![("type", "object".to_json()), ("additionalProperties", string_schema())].to_json()
+}
+
+fn selector_schema(properties: Vec<(&str, JSON)>) -> JSON {
+    vec![("type", "object".to_json()),
+         ("minProperties", JSON::U64(1)),
+         ("properties", properties.to_json())]
+        .to_json()
+}
+
+/// Parse a field containing an object whose values are strings, e.g. service properties.
+fn take_properties_opt(path: Path,
+                       source: &JSON,
+                       field_name: &str)
+                       -> Option<Result<HashMap<String, Exactly<String>>, ParseError>> {
+    let properties = match *source {
+        JSON::Object(ref obj) => {
+            match obj.get(field_name) {
+                Some(properties) => properties,
+                None => return None,
+            }
+        }
+        _ => return Some(Err(ParseError::type_error(field_name, &path, "object"))),
+    };
+    if let JSON::Object(ref obj) = *properties {
+        let mut result = HashMap::new();
+        for (key, value) in obj {
+            if let JSON::String(ref value) = *value {
+                result.insert(key.clone(), Exactly::Exactly(value.clone()));
+            } else {
+                return Some(Err(ParseError::type_error(key, &path, "string")));
+            }
+        }
+        Some(Ok(result))
+    } else {
+        Some(Err(ParseError::type_error(field_name, &path, "object")))
+    }
+}
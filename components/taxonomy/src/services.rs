@@ -42,7 +42,9 @@ macro_rules! tag_id {
 /// - id: string - an id unique to this service;
 /// - adapter: string;
 /// - tags: array of strings;
-/// - properties: object;
+/// - name: string or null - the name given by the user;
+/// - room: string or null - the room in which the user placed the service;
+/// - properties: object (see `properties` for the well-known keys);
 /// - getters: object (keys are string identifiers, for more details on values see Channel<Getter>);
 /// - setters: object (keys are string identifiers, for more details on values see Channel<Setter>);
 ///
@@ -66,8 +68,21 @@ pub struct Service {
 
     /// Service properties that are set at creation time.
     /// For instance, these can be device manufacturer, model, etc.
+    /// See module `properties` for the well-known keys.
     pub properties: HashMap<String, String>,
 
+    /// A human-readable name given by the user, e.g. "Reading lamp".
+    ///
+    /// Like tags, names are stored in the tags database, so adapters
+    /// should leave this to `None`.
+    pub name: Option<String>,
+
+    /// The room in which the user placed the service, e.g. "Living room".
+    ///
+    /// Like tags, rooms are stored in the tags database, so adapters
+    /// should leave this to `None`.
+    pub room: Option<String>,
+
     /// Channels connected directly to this service.
     pub channels: HashMap<Id<Channel>, Channel>,
 
@@ -82,6 +97,8 @@ impl Service {
             tags: HashSet::new(),
             channels: HashMap::new(),
             properties: HashMap::new(),
+            name: None,
+            room: None,
             id: id.clone(),
             adapter: adapter.clone(),
         }
@@ -94,9 +111,26 @@ impl ToJSON for Service {
             ("id", self.id.to_json()),
             ("adapter", self.adapter.to_json()),
             ("tags", self.tags.to_json()),
+            ("name", self.name.to_json()),
+            ("room", self.room.to_json()),
             ("properties", self.properties.to_json()),
             ("channels", self.channels.to_json()),
         ]
             .to_json()
     }
 }
+
+/// Well-known keys of `Service::properties`, filled by the adapters that know them.
+pub mod properties {
+    /// The manufacturer of the device, e.g. "Philips".
+    pub const MANUFACTURER: &'static str = "manufacturer";
+
+    /// The model of the device, e.g. "LCT007".
+    pub const MODEL: &'static str = "model";
+
+    /// The version of the firmware of the device.
+    pub const FIRMWARE: &'static str = "firmware";
+
+    /// A hint for the icon to display for the device, e.g. "light", "door" or "sensor".
+    pub const ICON: &'static str = "icon";
+}
@@ -5,11 +5,46 @@
 /// ! This is the database that holds tags associated to various objects.
 /// ! It provides an api to manage Id <-> tags relationships.
 /// ! All users share the same tags for objects.
+/// ! It also holds the metadata set by users on objects, such as their names.
 
+use foxbox_core::migrations::{self, Migration};
 use rusqlite::{Connection, Result};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use util::{Id, TagId};
 
+/// The metadata key for the name given by the user to a service or a channel.
+pub const METADATA_NAME: &'static str = "name";
+
+/// The metadata key for the room in which the user placed a service.
+pub const METADATA_ROOM: &'static str = "room";
+
+/// The schema version of the tags database written by this build.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// The schema history of the tags database.
+static MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    Migration {
+        version: 1,
+        description: "Create the tags table",
+        sql: "CREATE TABLE IF NOT EXISTS tags (
+                  key    TEXT NOT NULL PRIMARY KEY,
+                  id     TEXT NOT NULL,
+                  tag    TEXT NOT NULL
+              )",
+    },
+    Migration {
+        version: 2,
+        description: "Create the metadata table",
+        sql: "CREATE TABLE IF NOT EXISTS metadata (
+                  id     TEXT NOT NULL,
+                  key    TEXT NOT NULL,
+                  value  TEXT NOT NULL,
+                  PRIMARY KEY (id, key)
+              )",
+    },
+];
+
 fn escape<T>(string: &Id<T>) -> String {
     // http://www.sqlite.org/faq.html#q14
     format!("{}", string).replace("'", "''")
@@ -50,21 +85,13 @@ impl TagStorage {
         }
 
         debug!("Opening taxonomy tags database at {}", self.path.display());
-        let db = Connection::open(self.path.clone()).unwrap_or_else(|err| {
+        let mut db = Connection::open(self.path.clone()).unwrap_or_else(|err| {
             panic!("Unable to open taxonomy tags database: {}", err);
         });
 
-        db.execute("CREATE TABLE IF NOT EXISTS tags (
-                    key    TEXT NOT NULL \
-                      PRIMARY KEY,
-                    id     TEXT NOT NULL,
-                    \
-                      tag    TEXT NOT NULL
-            )",
-                     &[])
-            .unwrap_or_else(|err| {
-                panic!("Unable to create taxonomy tags database: {}", err);
-            });
+        migrations::migrate(&mut db, "taxonomy tags", &MIGRATIONS).unwrap_or_else(|err| {
+            panic!("Unable to migrate taxonomy tags database: {}", err);
+        });
 
         self.db = Some(db);
     }
@@ -139,6 +166,40 @@ impl TagStorage {
         }
         Ok(subs)
     }
+
+    /// Sets the value of metadata `key` (e.g. "name") for `id`, or removes it if `value`
+    /// is `None`.
+    pub fn set_metadata<T>(&mut self, id: &Id<T>, key: &str, value: Option<&str>) -> Result<()> {
+        self.ensure_db();
+        let db = self.db.as_ref().unwrap();
+        match value {
+            Some(value) => {
+                try!(db.execute("INSERT OR REPLACE INTO metadata VALUES ($1, $2, $3)",
+                                &[&escape(id), &key, &value]));
+            }
+            None => {
+                try!(db.execute("DELETE FROM metadata WHERE id=$1 AND key=$2",
+                                &[&escape(id), &key]));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_metadata_for<T>(&mut self, id: &Id<T>) -> Result<HashMap<String, String>> {
+        self.ensure_db();
+        let mut metadata = HashMap::new();
+        let mut stmt = try!(self.db
+            .as_ref()
+            .unwrap()
+            .prepare("SELECT key, value FROM metadata WHERE id=$1"));
+        let mut rows = try!(stmt.query(&[&escape(id)]));
+
+        while let Some(result_row) = rows.next() {
+            let row = try!(result_row);
+            metadata.insert(row.get(0), row.get(1));
+        }
+        Ok(metadata)
+    }
 }
 
 #[cfg(test)]
@@ -257,3 +318,83 @@ fn storage_test() {
     tags = store.get_tags_for(&id1).unwrap();
     assert_eq!(tags.len(), 0);
 }
+
+#[test]
+#[allow(unused_variables)]
+fn metadata_test() {
+    use util::ServiceId;
+
+    struct AutoDeleteDb { };
+    impl Drop for AutoDeleteDb {
+        fn drop(&mut self) {
+            remove_test_db();
+        }
+    }
+    let auto_db = AutoDeleteDb {};
+
+    let mut store = TagStorage::new(&get_db_environment());
+
+    let id1 = Id::<ServiceId>::new("first id");
+    let id2 = Id::<ServiceId>::new("second id");
+
+    assert!(store.get_metadata_for(&id1).unwrap().is_empty());
+
+    store.set_metadata(&id1, "name", Some("Reading lamp")).unwrap();
+    store.set_metadata(&id1, "room", Some("Living room")).unwrap();
+    store.set_metadata(&id2, "name", Some("Door")).unwrap();
+    let metadata = store.get_metadata_for(&id1).unwrap();
+    assert_eq!(metadata.len(), 2);
+    assert_eq!(metadata.get("name"), Some(&"Reading lamp".to_owned()));
+    assert_eq!(metadata.get("room"), Some(&"Living room".to_owned()));
+
+    // Setting a value again replaces it.
+    store.set_metadata(&id1, "room", Some("Bedroom")).unwrap();
+    assert_eq!(store.get_metadata_for(&id1).unwrap().get("room"),
+               Some(&"Bedroom".to_owned()));
+
+    // Setting `None` removes the value.
+    store.set_metadata(&id1, "name", None).unwrap();
+    let metadata = store.get_metadata_for(&id1).unwrap();
+    assert_eq!(metadata.len(), 1);
+    assert_eq!(metadata.get("name"), None);
+
+    // Other ids are unchanged.
+    assert_eq!(store.get_metadata_for(&id2).unwrap().get("name"),
+               Some(&"Door".to_owned()));
+}
+
+#[test]
+#[allow(unused_variables)]
+fn storage_upgrade_test() {
+    use foxbox_core::migrations::schema_version;
+    use util::ServiceId;
+
+    struct AutoDeleteDb { };
+    impl Drop for AutoDeleteDb {
+        fn drop(&mut self) {
+            remove_test_db();
+        }
+    }
+    let auto_db = AutoDeleteDb {};
+
+    let id = Id::<ServiceId>::new("legacy id");
+    let tag = Id::<TagId>::new("legacy tag");
+
+    // A database created before schema versioning.
+    {
+        let legacy = Connection::open(get_db_environment()).unwrap();
+        legacy.execute("CREATE TABLE tags (key TEXT NOT NULL PRIMARY KEY, id TEXT NOT NULL, \
+                        tag TEXT NOT NULL)",
+                     &[])
+            .unwrap();
+        legacy.execute("INSERT INTO tags VALUES ($1, $2, $3)",
+                     &[&create_key(&id, &tag), &escape(&id), &escape(&tag)])
+            .unwrap();
+        assert_eq!(schema_version(&legacy).unwrap(), 0);
+    }
+
+    let mut store = TagStorage::new(&get_db_environment());
+    assert_eq!(store.get_tags_for(&id).unwrap(), [tag]);
+    assert_eq!(schema_version(store.db.as_ref().unwrap()).unwrap(),
+               MIGRATIONS.len() as u32);
+}
@@ -33,6 +33,10 @@ impl<T> Parser<Exactly<T>> for Exactly<T>
     fn description() -> String {
         T::description()
     }
+    fn schema() -> JSON {
+        // `null` is also accepted, to accept any value.
+        T::schema()
+    }
     /// Parse a single value from JSON, consuming as much as necessary from JSON.
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
         if let JSON::Null = *source {
@@ -256,6 +260,9 @@ impl<T> Parser<Id<T>> for Id<T> {
     fn description() -> String {
         "Id".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "string")].to_json()
+    }
     /// Parse a single value from JSON, consuming as much as necessary from JSON.
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
         if let JSON::String(ref string) = *source {
@@ -0,0 +1,142 @@
+//! The last known value of each channel.
+//!
+//! The `AdapterManager` records every value it sees, whether it comes from
+//! a fetch or from a watch, so that callers can accept a recent value
+//! instead of querying the device again. This is also the only way to read
+//! devices that can't be queried on demand, e.g. battery-powered sensors
+//! that only report changes.
+//!
+//! Adapters may answer a fetch differently depending on the user (e.g. the
+//! webpush subscriptions of that user), so fetched values are only served
+//! back to the user who fetched them. Watches are not made on behalf of a
+//! user, so watched values are shared by everybody.
+
+use api::User;
+use channel::Channel;
+use io::{Format, Payload};
+use parse::ToJSON;
+use util::Id;
+
+use chrono::{DateTime, UTC};
+use serde_json::value::Value as JSON;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How a cached value was obtained.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueSource {
+    Fetch,
+    Watch,
+}
+
+impl ToJSON for ValueSource {
+    fn to_json(&self) -> JSON {
+        match *self {
+                ValueSource::Fetch => "fetch",
+                ValueSource::Watch => "watch",
+            }
+            .to_json()
+    }
+}
+
+#[derive(Clone)]
+pub struct CachedValue {
+    pub payload: Payload,
+    pub format: Arc<Format>,
+    pub last_updated: DateTime<UTC>,
+    pub source: ValueSource,
+}
+
+impl CachedValue {
+    pub fn new(payload: Payload, format: Arc<Format>, source: ValueSource) -> Self {
+        CachedValue {
+            payload: payload,
+            format: format,
+            last_updated: UTC::now(),
+            source: source,
+        }
+    }
+
+    /// Returns true if this value is at most `max_age` old.
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        let age = (UTC::now() - self.last_updated).num_milliseconds();
+        let max_age = max_age.as_secs() as i64 * 1000 + (max_age.subsec_nanos() / 1_000_000) as i64;
+        age <= max_age
+    }
+}
+
+impl ToJSON for CachedValue {
+    fn to_json(&self) -> JSON {
+        vec![("value", self.payload.to_json()),
+             ("last_updated", self.last_updated.to_rfc3339().to_json()),
+             ("source", self.source.to_json())]
+            .to_json()
+    }
+}
+
+#[derive(Default)]
+struct Values {
+    /// The values fetched by each user.
+    fetched: HashMap<(User, Id<Channel>), CachedValue>,
+
+    /// The values reported by watches, for all users.
+    watched: HashMap<Id<Channel>, CachedValue>,
+}
+
+/// A thread-safe map from channels to their last known value.
+#[derive(Default)]
+pub struct ValueCache {
+    values: Mutex<Values>,
+}
+
+impl ValueCache {
+    pub fn new() -> Self {
+        ValueCache::default()
+    }
+
+    /// Records a value fetched by `user`.
+    pub fn update_fetched(&self,
+                          user: &User,
+                          id: &Id<Channel>,
+                          payload: &Payload,
+                          format: &Arc<Format>) {
+        let value = CachedValue::new(payload.clone(), format.clone(), ValueSource::Fetch);
+        self.values.lock().unwrap().fetched.insert((user.clone(), id.clone()), value);
+    }
+
+    /// Records a value reported by a watch.
+    pub fn update_watched(&self, id: &Id<Channel>, payload: &Payload, format: &Arc<Format>) {
+        let value = CachedValue::new(payload.clone(), format.clone(), ValueSource::Watch);
+        self.values.lock().unwrap().watched.insert(id.clone(), value);
+    }
+
+    /// Returns the most recent value of a channel that `user` may see.
+    pub fn get(&self, user: &User, id: &Id<Channel>) -> Option<CachedValue> {
+        let values = self.values.lock().unwrap();
+        let fetched = values.fetched.get(&(user.clone(), id.clone()));
+        match (fetched, values.watched.get(id)) {
+            (Some(fetched), Some(watched)) => {
+                if fetched.last_updated >= watched.last_updated {
+                    Some(fetched.clone())
+                } else {
+                    Some(watched.clone())
+                }
+            }
+            (fetched, watched) => fetched.or(watched).cloned(),
+        }
+    }
+
+    pub fn remove(&self, id: &Id<Channel>) {
+        let mut values = self.values.lock().unwrap();
+        values.fetched.retain(|&(_, ref key), _| key != id);
+        values.watched.remove(id);
+    }
+
+    pub fn clear(&self) {
+        let mut values = self.values.lock().unwrap();
+        values.fetched.clear();
+        values.watched.clear();
+    }
+}
@@ -147,6 +147,18 @@ pub trait Data: Debug + Send + Sync + mopa::Any {
     /// Used mainly in `TypeError` error messages.
     fn description() -> String where Self: Sized;
 
+    /// A machine-readable description of the _type_ of the value, loosely following
+    /// JSON Schema (`type`, `enum`, `minimum`/`maximum`, `properties`, ...), extended
+    /// with a `unit` where applicable.
+    ///
+    /// Used by clients to generate controls for channels without hardcoding features.
+    /// By default, this accepts any JSON value.
+    fn schema() -> JSON
+        where Self: Sized
+    {
+        vec![("description", Self::description())].to_json()
+    }
+
     /// Attempt to build a `Value` from a json `source` and `binary` components.
     fn parse(path: Path, source: &JSON, binary: &BinarySource) -> Result<Self, Error>
         where Self: Sized;
@@ -225,6 +237,9 @@ impl<T> Parser<T> for T
     fn description() -> String {
         T::description()
     }
+    fn schema() -> JSON {
+        <T as Data>::schema()
+    }
     fn parse(path: Path, source: &JSON) -> Result<Self, ParseError> {
         match T::parse(path, source, &BinarySource) {
             Ok(ok) => Ok(ok),
@@ -238,6 +253,9 @@ impl Data for String {
     fn description() -> String {
         "String".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "string")].to_json()
+    }
     fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<String, Error> {
         match source.as_str() {
             None => Err(Error::Parsing(ParseError::type_error("String", &path, "string"))),
@@ -254,6 +272,9 @@ impl Data for () {
     fn description() -> String {
         "Nothing".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "null")].to_json()
+    }
     /// Attempt to build a `Value` from a json `source` and `binary` components.
     fn parse(_: Path, _: &JSON, _: &BinarySource) -> Result<Self, Error> {
         Ok(())
@@ -334,6 +355,9 @@ impl Data for OnOff {
     fn description() -> String {
         "On/Off".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "string".to_json()), ("enum", vec!["On", "Off"].to_json())].to_json()
+    }
     fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
         let result = match source.as_str() {
             Some("On") => OnOff::On,
@@ -422,6 +446,9 @@ impl Data for OpenClosed {
     fn description() -> String {
         "Open/Closed".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "string".to_json()), ("enum", vec!["Open", "Closed"].to_json())].to_json()
+    }
     fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
         let result = match source.as_str() {
             Some("Open") => OpenClosed::Open,
@@ -495,6 +522,9 @@ impl Data for IsLocked {
     fn description() -> String {
         "IsLocked".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "string".to_json()), ("enum", vec!["Locked", "Unlocked"].to_json())].to_json()
+    }
     fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
         match source.as_str() {
             Some("Locked") => Ok(IsLocked::Locked),
@@ -582,6 +612,9 @@ impl Data for IsSecure {
     fn description() -> String {
         "Secure/Insecure".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "string".to_json()), ("enum", vec!["Insecure", "Secure"].to_json())].to_json()
+    }
     fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
         let result = match source.as_str() {
             Some("Secure") => IsSecure::Secure,
@@ -819,6 +852,18 @@ impl Data for Color {
     fn description() -> String {
         "Color {h, s, v}".to_owned()
     }
+    fn schema() -> JSON {
+        let fraction = vec![("type", "number".to_json()),
+                            ("minimum", 0.0.to_json()),
+                            ("maximum", 1.0.to_json())];
+        let properties = vec![("h", vec![("type", "number"), ("unit", "degrees")].to_json()),
+                              ("s", fraction.to_json()),
+                              ("v", fraction.to_json())];
+        vec![("type", "object".to_json()),
+             ("properties", properties.to_json()),
+             ("required", vec!["h", "s", "v"].to_json())]
+            .to_json()
+    }
     fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
         let h = try!(path.push("h", |path| f64::take(path, source, "h")));
         let s = try!(path.push("s", |path| f64::take(path, source, "s")));
@@ -889,6 +934,18 @@ impl Data for Binary {
     fn description() -> String {
         "Binary".to_owned()
     }
+    fn schema() -> JSON {
+        let byte = vec![("type", "integer".to_json()),
+                        ("minimum", JSON::U64(0)),
+                        ("maximum", JSON::U64(255))];
+        let data = vec![("type", "array".to_json()), ("items", byte.to_json())];
+        let properties = vec![("data", data.to_json()),
+                              ("mimetype", vec![("type", "string")].to_json())];
+        vec![("type", "object".to_json()),
+             ("properties", properties.to_json()),
+             ("required", vec!["data", "mimetype"].to_json())]
+            .to_json()
+    }
     fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
         let data = try!(path.push("data", |path| {
             Vec::<u8>::take(path, source, "data").map_err(Error::Parsing)
@@ -971,6 +1028,9 @@ impl Data for TimeStamp {
     fn description() -> String {
         "TimeStamp (RFC 3339)".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "string"), ("format", "date-time")].to_json()
+    }
     fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
         use chrono::{DateTime, UTC};
         use std::str::FromStr;
@@ -1096,6 +1156,27 @@ impl<T> Data for Range<T>
     fn description() -> String {
         format!("Range of {}", T::description())
     }
+    fn schema() -> JSON {
+        // Exactly one of `Leq`, `Geq`, `Eq`, `BetweenEq`, `OutOfStrict`.
+        let bounds = vec![("type", "array".to_json()),
+                          ("items", T::schema()),
+                          ("minItems", JSON::U64(2)),
+                          ("maxItems", JSON::U64(2))];
+        let variants = vec![("Leq", T::schema()),
+                            ("Geq", T::schema()),
+                            ("Eq", T::schema()),
+                            ("BetweenEq", bounds.to_json()),
+                            ("OutOfStrict", bounds.to_json())];
+        let one_of: Vec<_> = variants.into_iter()
+            .map(|(key, schema)| {
+                vec![("type", "object".to_json()),
+                     ("properties", vec![(key, schema)].to_json()),
+                     ("required", vec![key].to_json())]
+                    .to_json()
+            })
+            .collect();
+        vec![("oneOf", one_of)].to_json()
+    }
     fn parse(path: Path, source: &JSON, binary: &BinarySource) -> Result<Self, Error> {
         use self::Range::*;
         match *source {
@@ -1208,6 +1289,9 @@ impl Data for Duration {
     fn description() -> String {
         "Duration (s)".to_owned()
     }
+    fn schema() -> JSON {
+        vec![("type", "number"), ("unit", "s")].to_json()
+    }
     fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
         let val = try!(f64::parse(path, source).map_err(Error::Parsing));
         Ok(Duration(ChronoDuration::milliseconds((val * 1000.) as i64)))
@@ -1236,6 +1320,59 @@ impl Into<ChronoDuration> for Duration {
     }
 }
 
+/// A number without a unit, e.g. a counter or a user-defined variable.
+///
+/// # JSON
+///
+/// Represented by a (floating-point) number.
+///
+/// ```
+/// extern crate foxbox_taxonomy;
+///
+/// use foxbox_taxonomy::values::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// # fn main() {
+///
+/// let parsed = Number::from_str("21.5").unwrap();
+/// assert_eq!(parsed.as_f64(), 21.5);
+///
+/// let serialized: JSON = parsed.to_json();
+/// assert_eq!(serialized.as_f64().unwrap(), 21.5);
+///
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+pub struct Number(pub f64);
+
+impl Number {
+    pub fn as_f64(&self) -> f64 {
+        self.0
+    }
+}
+
+impl Data for Number {
+    fn description() -> String {
+        "Number".to_owned()
+    }
+    fn schema() -> JSON {
+        vec![("type", "number")].to_json()
+    }
+    fn parse(path: Path, source: &JSON, _binary: &BinarySource) -> Result<Self, Error> {
+        let val = try!(f64::parse(path, source).map_err(Error::Parsing));
+        Ok(Number(val))
+    }
+    fn serialize(source: &Self, _binary: &BinaryTarget) -> Result<JSON, Error> {
+        Ok(source.to_json())
+    }
+}
+
+impl ToJSON for Number {
+    fn to_json(&self) -> JSON {
+        JSON::F64(self.0)
+    }
+}
+
 
 /// A library of standardized instances of `Format` for most common cases.
 pub mod format {
@@ -1255,5 +1392,24 @@ pub mod format {
         pub static ref BINARY : Arc<Format> = Arc::new(Format::new::<Binary>());
         pub static ref TIMESTAMP : Arc<Format> = Arc::new(Format::new::<TimeStamp>());
         pub static ref DURATION : Arc<Format> = Arc::new(Format::new::<Duration>());
+        pub static ref NUMBER : Arc<Format> = Arc::new(Format::new::<Number>());
+    }
+
+    /// All the standardized formats, along with the name of the type they represent.
+    ///
+    /// Adapters may define additional formats, which are not listed here.
+    pub fn all() -> Vec<(&'static str, Arc<Format>)> {
+        vec![("OnOff", ON_OFF.clone()),
+             ("OpenClosed", OPEN_CLOSED.clone()),
+             ("IsSecure", IS_SECURE.clone()),
+             ("IsLocked", IS_LOCKED.clone()),
+             ("Color", COLOR.clone()),
+             ("Json", JSON.clone()),
+             ("String", STRING.clone()),
+             ("Unit", UNIT.clone()),
+             ("Binary", BINARY.clone()),
+             ("TimeStamp", TIMESTAMP.clone()),
+             ("Duration", DURATION.clone()),
+             ("Number", NUMBER.clone())]
     }
 }
@@ -0,0 +1,237 @@
+//! A fixed-size pool of threads, used to call adapters concurrently.
+//!
+//! Adapter calls may block for a long time (e.g. waiting for an unreachable
+//! device over HTTP). Running them on a bounded set of threads lets the
+//! `AdapterManager` wait for several adapters at once without spawning one
+//! thread per request.
+//!
+//! Jobs are grouped by key, e.g. the adapter they call, and only a few jobs of
+//! the same key run at once, the others waiting in a queue of their own. This
+//! way, a hung adapter holds on to a couple of threads at most, instead of
+//! taking them all as the calls of its callers time out and get retried.
+
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+/// The number of threads used by default.
+pub const DEFAULT_POOL_SIZE: usize = 8;
+
+/// The number of jobs of the same key that may run at once.
+pub const MAX_RUNNING_PER_KEY: usize = 2;
+
+/// The number of jobs of the same key that may wait for their turn.
+pub const MAX_QUEUED_PER_KEY: usize = 32;
+
+thread_local!(static IS_WORKER: Cell<bool> = Cell::new(false));
+
+trait FnBox {
+    fn call_box(self: Box<Self>);
+}
+
+impl<F: FnOnce()> FnBox for F {
+    fn call_box(self: Box<F>) {
+        (*self)()
+    }
+}
+
+type Job = Box<FnBox + Send + 'static>;
+
+#[derive(Default)]
+struct KeyState {
+    running: usize,
+    queued: VecDeque<Job>,
+}
+
+type Keys = Arc<Mutex<HashMap<String, KeyState>>>;
+
+/// Returned when too many jobs of the same key are waiting already.
+#[derive(Debug, PartialEq)]
+pub struct Busy;
+
+pub struct WorkerPool {
+    tx: Mutex<Sender<Job>>,
+    keys: Keys,
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0);
+        let (tx, rx) = channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for index in 0..size {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("AdapterWorker-{}", index))
+                .spawn(move || Self::run(rx))
+                .unwrap();
+        }
+        WorkerPool {
+            tx: Mutex::new(tx),
+            keys: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns true if the current thread is a worker of a pool. Jobs that
+    /// wait for other jobs of the same pool could otherwise deadlock it.
+    pub fn is_worker_thread() -> bool {
+        IS_WORKER.with(|is_worker| is_worker.get())
+    }
+
+    fn run(rx: Arc<Mutex<Receiver<Job>>>) {
+        IS_WORKER.with(|is_worker| is_worker.set(true));
+        loop {
+            // Release the lock before running the job, so that other workers can proceed.
+            let job = match rx.lock().unwrap().recv() {
+                Ok(job) => job,
+                // The pool has been dropped.
+                Err(_) => return,
+            };
+            // A panicking adapter must not take the worker down with it.
+            if panic::catch_unwind(AssertUnwindSafe(|| job.call_box())).is_err() {
+                error!("[WorkerPool] A job panicked");
+            }
+        }
+    }
+
+    /// Runs `job` on the first available thread. If all the threads are busy,
+    /// the job is queued.
+    pub fn execute<F>(&self, job: F)
+        where F: FnOnce() + Send + 'static
+    {
+        // The workers only stop once the pool is dropped, so this can't fail.
+        let _ = self.tx.lock().unwrap().send(Box::new(job));
+    }
+
+    /// Runs `job` once fewer than `MAX_RUNNING_PER_KEY` jobs of `key` are
+    /// running. Fails if `MAX_QUEUED_PER_KEY` jobs of `key` are waiting already.
+    pub fn execute_for<F>(&self, key: &str, job: F) -> Result<(), Busy>
+        where F: FnOnce() + Send + 'static
+    {
+        let job: Job = Box::new(job);
+        {
+            let mut keys = self.keys.lock().unwrap();
+            let state = keys.entry(key.to_owned()).or_insert_with(KeyState::default);
+            if state.running >= MAX_RUNNING_PER_KEY {
+                if state.queued.len() >= MAX_QUEUED_PER_KEY {
+                    return Err(Busy);
+                }
+                state.queued.push_back(job);
+                return Ok(());
+            }
+            state.running += 1;
+        }
+        let tx = self.tx.lock().unwrap().clone();
+        let _ = tx.send(Self::keyed(self.keys.clone(), tx.clone(), key.to_owned(), job));
+        Ok(())
+    }
+
+    /// Wraps a job of `key` to start the next job of `key` once it's done.
+    fn keyed(keys: Keys, tx: Sender<Job>, key: String, job: Job) -> Job {
+        Box::new(move || {
+            if panic::catch_unwind(AssertUnwindSafe(|| job.call_box())).is_err() {
+                error!("[WorkerPool] A job of {} panicked", key);
+            }
+            let next = {
+                let mut keys = keys.lock().unwrap();
+                let (next, is_idle) = match keys.get_mut(&key) {
+                    Some(state) => {
+                        let next = state.queued.pop_front();
+                        if next.is_none() {
+                            state.running -= 1;
+                        }
+                        (next, state.running == 0)
+                    }
+                    None => (None, false),
+                };
+                if is_idle {
+                    keys.remove(&key);
+                }
+                next
+            };
+            if let Some(next) = next {
+                let _ = tx.send(Self::keyed(keys, tx.clone(), key, next));
+            }
+        })
+    }
+}
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_POOL_SIZE)
+    }
+}
+
+#[test]
+fn test_worker_pool() {
+    use std::time::Duration;
+
+    let pool = WorkerPool::new(2);
+    let (tx, rx) = channel();
+
+    // Block both workers, the third job should wait for one of them.
+    for index in 0..3 {
+        let tx = tx.clone();
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(100));
+            tx.send(index).unwrap();
+        });
+    }
+    pool.execute(|| panic!("This panic is expected"));
+
+    let mut results: Vec<usize> = rx.iter().take(3).collect();
+    results.sort();
+    assert_eq!(results, vec![0, 1, 2]);
+}
+
+#[test]
+fn test_worker_pool_keys() {
+    use std::time::Duration;
+
+    let pool = WorkerPool::new(4);
+    let (tx, rx) = channel();
+
+    // The jobs of a hung key only take `MAX_RUNNING_PER_KEY` workers.
+    let (tx_hang, rx_hang) = channel::<()>();
+    let rx_hang = Arc::new(Mutex::new(rx_hang));
+    for _ in 0..MAX_RUNNING_PER_KEY + MAX_QUEUED_PER_KEY {
+        let rx_hang = rx_hang.clone();
+        pool.execute_for("hung", move || {
+                let _ = rx_hang.lock().unwrap().recv();
+            })
+            .unwrap();
+    }
+    assert_eq!(pool.execute_for("hung", || {}), Err(Busy));
+
+    for index in 0..4 {
+        let tx = tx.clone();
+        pool.execute_for("other", move || {
+                assert!(WorkerPool::is_worker_thread());
+                tx.send(index).unwrap();
+            })
+            .unwrap();
+    }
+    let mut results: Vec<usize> = (0..4)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    results.sort();
+    assert_eq!(results, vec![0, 1, 2, 3]);
+    assert!(!WorkerPool::is_worker_thread());
+
+    // Once unblocked, the queued jobs run.
+    drop(tx_hang);
+    let mut done = false;
+    for _ in 0..50 {
+        let tx = tx.clone();
+        if pool.execute_for("hung", move || tx.send(42).unwrap()).is_ok() {
+            done = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(done);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 42);
+}
@@ -9,9 +9,12 @@ use foxbox_taxonomy::io::*;
 use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::fake_adapter::*;
 use foxbox_taxonomy::api::{ API, Error, InternalError, TargetMap, Targetted, User, WatchEvent as Event };
+use foxbox_taxonomy::audit::{ AuditFilter, REDACTED };
+use foxbox_taxonomy::parse::ToJSON;
 use foxbox_taxonomy::selector::*;
 use foxbox_taxonomy::services::*;
 use foxbox_taxonomy::values::*;
+use foxbox_taxonomy::value_cache::CachedValue;
 
 use transformable_channels::mpsc::*;
 
@@ -329,6 +332,108 @@ fn test_tags_in_db() {
     }
 }
 
+#[test]
+#[allow(unused_variables)]
+fn test_metadata_in_db() {
+    // Simple RAII style struct to delete the test db.
+    struct AutoDeleteDb { };
+    impl Drop for AutoDeleteDb {
+        fn drop(&mut self) {
+            remove_test_db();
+        }
+    }
+    let auto_db = AutoDeleteDb { };
+
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_1 = Id::<Channel>::new("getter id 1");
+
+    let mut service_1 = Service::empty(&service_id_1, &id_1);
+    service_1.properties.insert(properties::MANUFACTURER.to_owned(), "Philips".to_owned());
+    service_1.properties.insert(properties::MODEL.to_owned(), "LCT007".to_owned());
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        feature: Id::new("light/is-on"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    };
+
+    println!("* Start a session, name the service and the channel.");
+    {
+        let manager = AdapterManager::new(Some(get_db_environment()));
+        manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+        manager.add_service(service_1.clone()).unwrap();
+        manager.add_channel(getter_1.clone()).unwrap();
+
+        let services = manager.get_services(vec![]);
+        assert_eq!(services[0].name, None);
+        assert_eq!(services[0].room, None);
+
+        assert_eq!(manager.set_service_name(vec![ServiceSelector::new().with_id(&service_id_1)],
+                                            Some("Reading lamp".to_owned())), 1);
+        assert_eq!(manager.set_service_room(vec![ServiceSelector::new().with_id(&service_id_1)],
+                                            Some("Living room".to_owned())), 1);
+        assert_eq!(manager.set_channel_name(vec![ChannelSelector::new().with_id(&getter_id_1)],
+                                            Some("Lamp is on".to_owned())), 1);
+        assert_eq!(manager.set_service_name(vec![ServiceSelector::new().with_tags(vec![Id::new("nope")])],
+                                            Some("Nothing".to_owned())), 0);
+
+        let services = manager.get_services(vec![]);
+        assert_eq!(services[0].name, Some("Reading lamp".to_owned()));
+        assert_eq!(services[0].room, Some("Living room".to_owned()));
+        assert_eq!(services[0].channels[&getter_id_1].name, Some("Lamp is on".to_owned()));
+        let channels = manager.get_channels(vec![ChannelSelector::new()]);
+        assert_eq!(channels[0].name, Some("Lamp is on".to_owned()));
+
+        println!("* Metadata is part of the JSON.");
+        let json = services[0].to_json();
+        assert_eq!(json.find("name").unwrap().as_str(), Some("Reading lamp"));
+        assert_eq!(json.find("room").unwrap().as_str(), Some("Living room"));
+        assert_eq!(json.find_path(&["properties", "manufacturer"]).unwrap().as_str(), Some("Philips"));
+        assert_eq!(json.find_path(&["properties", "model"]).unwrap().as_str(), Some("LCT007"));
+        assert_eq!(channels[0].to_json().find("name").unwrap().as_str(), Some("Lamp is on"));
+
+        manager.remove_adapter(&id_1).unwrap();
+        manager.stop();
+    }
+
+    println!("* Start a new session, the metadata must still be present.");
+    {
+        let manager = AdapterManager::new(Some(get_db_environment()));
+        manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+        manager.add_service(service_1.clone()).unwrap();
+        manager.add_channel(getter_1.clone()).unwrap();
+
+        let services = manager.get_services(vec![]);
+        assert_eq!(services[0].name, Some("Reading lamp".to_owned()));
+        assert_eq!(services[0].room, Some("Living room".to_owned()));
+        let channels = manager.get_channels(vec![ChannelSelector::new()]);
+        assert_eq!(channels[0].name, Some("Lamp is on".to_owned()));
+
+        println!("* Remove the name, keep the room.");
+        manager.set_service_name(vec![ServiceSelector::new().with_id(&service_id_1)], None);
+
+        manager.remove_adapter(&id_1).unwrap();
+        manager.stop();
+    }
+
+    println!("* Start a third session, only the room must still be present.");
+    {
+        let manager = AdapterManager::new(Some(get_db_environment()));
+        manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+        manager.add_service(service_1.clone()).unwrap();
+
+        let services = manager.get_services(vec![]);
+        assert_eq!(services[0].name, None);
+        assert_eq!(services[0].room, Some("Living room".to_owned()));
+
+        manager.remove_adapter(&id_1).unwrap();
+        manager.stop();
+    }
+}
+
 #[test]
 fn test_add_remove_adapter() {
     for clear in vec![false, true] {
@@ -973,6 +1078,283 @@ fn test_add_remove_tags() {
     println!("");
 }
 
+#[test]
+fn test_live_tags() {
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    let id_adapter = Id::<AdapterId>::new("adapter id");
+    let id_living = Id::<ServiceId>::new("living room lamp");
+    let id_kitchen = Id::<ServiceId>::new("kitchen lamp");
+    let id_living_light = Id::<Channel>::new("living room light");
+    let id_kitchen_light = Id::<Channel>::new("kitchen light");
+    let tag_room = Id::<TagId>::new("room:living");
+    let tag_group = Id::<TagId>::new("group:living-room-lights");
+
+    let light = Channel {
+        adapter: id_adapter.clone(),
+        feature: Id::new("light/is-on"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Required(format::ON_OFF.clone()),
+            returns: Maybe::Required(format::ON_OFF.clone())
+        }),
+        .. Channel::default()
+    };
+    let in_group = |manager: &AdapterManager| {
+        let mut ids: Vec<_> = manager.get_channels(vec![
+            ChannelSelector::new().with_service_tags(vec![tag_group.clone()])
+        ]).drain(..).map(|channel| channel.id.to_string()).collect();
+        ids.sort();
+        ids
+    };
+
+    let adapter = FakeAdapter::new(&id_adapter);
+    let tweak = adapter.get_tweak();
+    let _rx = adapter.take_rx();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    let mut living = Service::empty(&id_living, &id_adapter);
+    living.tags.insert(tag_room.clone());
+    manager.add_service(living).unwrap();
+
+    println!("* Services matching the selectors get the tag.");
+    let selector = ServiceSelector::new()
+        .with_tags(vec![tag_room.clone()])
+        .with_channels(vec![ChannelSelector::new().with_feature(&Id::new("light/is-on"))]);
+    assert_eq!(manager.set_live_service_tag(&tag_group, vec![selector]), 0);
+    manager.add_channel(Channel {
+        id: id_living_light.clone(),
+        service: id_living.clone(),
+        .. light.clone()
+    }).unwrap();
+    assert_eq!(in_group(&manager), vec![id_living_light.to_string()]);
+
+    println!("* Services that don't match don't get the tag.");
+    manager.add_service(Service::empty(&id_kitchen, &id_adapter)).unwrap();
+    manager.add_channel(Channel {
+        id: id_kitchen_light.clone(),
+        service: id_kitchen.clone(),
+        .. light.clone()
+    }).unwrap();
+    assert_eq!(in_group(&manager), vec![id_living_light.to_string()]);
+
+    println!("* Watchers of the tag see new members.");
+    let (tx_watch, rx_watch) = channel();
+    let _guard = manager.watch_values(target_map(vec![(
+        vec![ChannelSelector::new().with_service_tags(vec![tag_group.clone()])],
+        Exactly::Always
+    )]), Box::new(tx_watch));
+    assert_eq!(manager.set_live_service_tag(&tag_group, vec![
+        ServiceSelector::new().with_channels(vec![
+            ChannelSelector::new().with_feature(&Id::new("light/is-on"))
+        ])
+    ]), 2);
+    match rx_watch.recv().unwrap() {
+        Event::ChannelAdded(ref id) if *id == id_kitchen_light => {}
+        other => panic!("Unexpected event {:?}", other)
+    }
+    tweak(Tweak::InjectGetterValue(id_kitchen_light.clone(), Ok(Some(Value::new(OnOff::On)))));
+    assert_matches!(rx_watch.recv().unwrap(), Event::EnterRange { .. });
+    assert_eq!(in_group(&manager),
+               vec![id_kitchen_light.to_string(), id_living_light.to_string()]);
+
+    println!("* Services that stop matching lose the tag.");
+    manager.remove_channel(&id_kitchen_light).unwrap();
+    match rx_watch.recv().unwrap() {
+        Event::ChannelRemoved(ref id) if *id == id_kitchen_light => {}
+        other => panic!("Unexpected event {:?}", other)
+    }
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_tags(vec![tag_group.clone()])]).len(), 1);
+
+    println!("* Services placed in a room get the tags selecting that room.");
+    let tag_upstairs = Id::<TagId>::new("group:upstairs");
+    assert_eq!(manager.set_live_service_tag(&tag_upstairs,
+                                            vec![ServiceSelector::new().with_room("Upstairs")]),
+               0);
+    assert_eq!(manager.set_service_room(vec![ServiceSelector::new().with_id(&id_living)],
+                                        Some("Upstairs".to_owned())), 1);
+    assert_eq!(manager.get_channels(vec![
+        ChannelSelector::new().with_service_tags(vec![tag_upstairs.clone()])
+    ]).len(), 1);
+    manager.set_service_room(vec![ServiceSelector::new().with_id(&id_living)], None);
+    assert!(manager.get_services(vec![ServiceSelector::new().with_tags(vec![tag_upstairs])])
+        .is_empty());
+
+    println!("* Removing the live tag removes it from all services.");
+    assert!(manager.remove_live_service_tag(&tag_group));
+    assert!(!manager.remove_live_service_tag(&tag_group));
+    assert!(in_group(&manager).is_empty());
+    manager.add_channel(Channel {
+        id: id_kitchen_light.clone(),
+        service: id_kitchen.clone(),
+        .. light.clone()
+    }).unwrap();
+    assert!(in_group(&manager).is_empty());
+
+    println!("");
+}
+
+#[test]
+fn test_selectors() {
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    let id_hue = Id::<AdapterId>::new("hue@link.mozilla.org");
+    let id_zwave = Id::<AdapterId>::new("zwave@link.mozilla.org");
+
+    let add_adapter = |id: &Id<AdapterId>| {
+        let adapter = FakeAdapter::new(id);
+        let rx = adapter.take_rx();
+        manager.add_adapter(Arc::new(adapter)).unwrap();
+        rx
+    };
+    let _rx_hue = add_adapter(&id_hue);
+    let _rx_zwave = add_adapter(&id_zwave);
+
+    let add_service = |id: &str, adapter: &Id<AdapterId>, tag: &str, properties: Vec<(&str, &str)>| {
+        let mut service = Service::empty(&Id::new(id), adapter);
+        service.tags.insert(Id::new(tag));
+        for (key, value) in properties {
+            service.properties.insert(key.to_owned(), value.to_owned());
+        }
+        manager.add_service(service).unwrap();
+    };
+    let add_channel = |id: &str, service: &str, adapter: &Id<AdapterId>, feature: &str| {
+        manager.add_channel(Channel {
+            id: Id::new(id),
+            service: Id::new(service),
+            adapter: adapter.clone(),
+            feature: Id::new(feature),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+            .. Channel::default()
+        }).unwrap();
+    };
+    add_service("living", &id_hue, "room:living", vec![("manufacturer", "Philips"), ("model", "Hue Bulb")]);
+    add_channel("living-on", "living", &id_hue, "light/is-on");
+    add_channel("living-color", "living", &id_hue, "light/color");
+    add_service("kitchen", &id_hue, "room:kitchen", vec![("manufacturer", "Philips"), ("model", "Hue Go")]);
+    add_channel("kitchen-on", "kitchen", &id_hue, "light/is-on");
+    add_service("door", &id_zwave, "room:entrance", vec![("manufacturer", "Aeotec")]);
+    add_channel("door-open", "door", &id_zwave, "door/is-open");
+
+    let services = |selectors: Vec<ServiceSelector>| {
+        let mut ids: Vec<_> = manager.get_services(selectors).drain(..)
+            .map(|service| service.id.to_string()).collect();
+        ids.sort();
+        ids
+    };
+    let channels = |selectors: Vec<ChannelSelector>| {
+        let mut ids: Vec<_> = manager.get_channels(selectors).drain(..)
+            .map(|channel| channel.id.to_string()).collect();
+        ids.sort();
+        ids
+    };
+    let services_json = |source: &str| services(vec![ServiceSelector::from_str(source).unwrap()]);
+    let channels_json = |source: &str| channels(vec![ChannelSelector::from_str(source).unwrap()]);
+
+    println!("* We can select by adapter.");
+    assert_eq!(services(vec![ServiceSelector::new().with_adapter(&id_zwave)]), vec!["door"]);
+    assert_eq!(services_json(r#"{"adapter": "zwave@link.mozilla.org"}"#), vec!["door"]);
+    assert_eq!(channels(vec![ChannelSelector::new().with_adapter(&id_zwave)]), vec!["door-open"]);
+    assert_eq!(channels_json(r#"{"adapter": "hue@link.mozilla.org"}"#),
+               vec!["kitchen-on", "living-color", "living-on"]);
+
+    println!("* We can select by service properties.");
+    assert_eq!(services(vec![ServiceSelector::new().with_property("manufacturer", "Philips")]),
+               vec!["kitchen", "living"]);
+    assert_eq!(services_json(r#"{"properties": {"manufacturer": "Philips", "model": "Hue Go"}}"#),
+               vec!["kitchen"]);
+    assert!(services_json(r#"{"properties": {"color": "red"}}"#).is_empty());
+    assert!(services(vec![
+        ServiceSelector::new().with_property("model", "Hue Go").with_property("model", "Hue Bulb")
+    ]).is_empty());
+    assert_eq!(channels(vec![ChannelSelector::new().with_service_property("manufacturer", "Aeotec")]),
+               vec!["door-open"]);
+    assert_eq!(channels_json(r#"{"service_properties": {"model": "Hue Bulb"}}"#),
+               vec!["living-color", "living-on"]);
+    assert_matches!(ServiceSelector::from_str(r#"{"properties": {"model": 3}}"#),
+                    Err(ParseError::TypeError { .. }));
+    assert_matches!(ChannelSelector::from_str(r#"{"service_properties": "Hue"}"#),
+                    Err(ParseError::TypeError { .. }));
+
+    println!("* We can select features by prefix.");
+    assert_eq!(channels_json(r#"{"feature": "light/*"}"#),
+               vec!["kitchen-on", "living-color", "living-on"]);
+    assert_eq!(channels(vec![ChannelSelector::new().with_feature_prefix("door/")]),
+               vec!["door-open"]);
+    assert_eq!(channels_json(r#"{"feature": "*"}"#).len(), 4);
+    assert_eq!(channels_json(r#"{"feature": "light/color"}"#), vec!["living-color"]);
+    assert_eq!(channels_json(r#"{"feature": "light/is-*"}"#), vec!["kitchen-on", "living-on"]);
+    assert_eq!(services_json(r#"{"channels": [{"feature": "light/*"}]}"#),
+               vec!["kitchen", "living"]);
+    assert_matches!(ChannelSelectorWithFeature::from_str(r#"{"feature": "light/*"}"#),
+                    Err(ParseError::MissingField { .. }));
+
+    println!("* We can negate selectors.");
+    assert_eq!(services_json(r#"{"not": {"tags": ["room:kitchen"]}}"#), vec!["door", "living"]);
+    assert_eq!(services(vec![
+        ServiceSelector::new().without(ServiceSelector::new().with_adapter(&id_hue))
+    ]), vec!["door"]);
+    assert_eq!(channels_json(r#"{"feature": "light/*",
+                                 "not": [{"feature": "light/color"}, {"service": "kitchen"}]}"#),
+               vec!["living-on"]);
+    assert_eq!(channels(vec![
+        ChannelSelector::new()
+            .without(ChannelSelector::new().with_service_tags(vec![Id::new("room:living")]))
+    ]), vec!["door-open", "kitchen-on"]);
+
+    println!("* We can select any of several alternatives.");
+    assert_eq!(services_json(r#"{"any_of": [{"tags": ["room:kitchen"]}, {"tags": ["room:entrance"]}]}"#),
+               vec!["door", "kitchen"]);
+    assert!(services_json(r#"{"any_of": [{"tags": ["room:living", "room:kitchen"]}]}"#).is_empty());
+    assert_eq!(channels_json(r#"{"any_of": [{"feature": "door/is-open"}, {"feature": "light/color"}]}"#),
+               vec!["door-open", "living-color"]);
+    assert_eq!(channels(vec![
+        ChannelSelector::new().with_any_of(vec![
+            ChannelSelector::new().with_feature(&Id::new("door/is-open")),
+            ChannelSelector::new().with_service_property("model", "Hue Go"),
+        ])
+    ]), vec!["door-open", "kitchen-on"]);
+
+    println!("* Alternatives are combined by `and`.");
+    let rooms = ServiceSelector::new().with_any_of(vec![
+        ServiceSelector::new().with_tags(vec![Id::new("room:kitchen")]),
+        ServiceSelector::new().with_tags(vec![Id::new("room:living")]),
+    ]);
+    let devices = ServiceSelector::new().with_any_of(vec![
+        ServiceSelector::new().with_property("model", "Hue Go"),
+        ServiceSelector::new().with_adapter(&id_zwave),
+    ]);
+    assert_eq!(services(vec![rooms.clone()]), vec!["kitchen", "living"]);
+    assert_eq!(services(vec![devices.clone()]), vec!["door", "kitchen"]);
+    assert_eq!(services(vec![rooms.and(devices)]), vec!["kitchen"]);
+
+    println!("* We can select by name and room.");
+    assert_eq!(manager.set_service_name(vec![ServiceSelector::new().with_id(&Id::new("kitchen"))],
+                                        Some("Counter light".to_owned())), 1);
+    assert_eq!(manager.set_service_room(vec![ServiceSelector::new().with_adapter(&id_hue)],
+                                        Some("Ground floor".to_owned())), 2);
+    assert_eq!(services(vec![ServiceSelector::new().with_room("Ground floor")]),
+               vec!["kitchen", "living"]);
+    assert_eq!(services_json(r#"{"room": "Ground floor", "name": "Counter light"}"#),
+               vec!["kitchen"]);
+    assert!(services(vec![
+        ServiceSelector::new().with_name("Counter light").with_name("Reading lamp")
+    ]).is_empty());
+    assert!(services_json(r#"{"room": "Attic"}"#).is_empty());
+    assert_eq!(services_json(r#"{"not": {"room": "Ground floor"}}"#), vec!["door"]);
+    assert_matches!(ServiceSelector::from_str(r#"{"room": 3}"#),
+                    Err(ParseError::TypeError { .. }));
+
+    println!("* Invalid alternatives are rejected.");
+    assert_matches!(ServiceSelector::from_str(r#"{"any_of": 3}"#),
+                    Err(ParseError::TypeError { .. }));
+    assert_matches!(ChannelSelector::from_str(r#"{"not": {}}"#),
+                    Err(ParseError::EmptyObject { .. }));
+
+    println!("");
+}
+
 #[test]
 fn test_fetch() {
     println!("");
@@ -1275,6 +1657,340 @@ fn test_send() {
 }
 
 
+#[test]
+fn test_audit_send() {
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    let adapter_id = Id::<AdapterId>::new("adapter id");
+    let service_id = Id::<ServiceId>::new("service id");
+    let light_id = Id::<Channel>::new("setter light");
+    let password_id = Id::<Channel>::new("setter password");
+
+    let adapter = FakeAdapter::new(&adapter_id);
+    let tweak = adapter.get_tweak();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+    manager.add_channel(Channel {
+        id: light_id.clone(),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        feature: Id::new("light/is-on"),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    }).unwrap();
+    manager.add_channel(Channel {
+        id: password_id.clone(),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        .. PASSWORD.clone()
+    }).unwrap();
+
+    let user = User::Id("1".to_owned());
+    let data_on = Payload::from_value(&Value::new(OnOff::On), &format::ON_OFF).unwrap();
+    let password = Payload::from_value(&Value::new("hunter2".to_owned()), &format::STRING).unwrap();
+
+    println!("* Sending values records them in the audit log, with the user and the result.");
+    tweak(Tweak::InjectSetterError(light_id.clone(), Some(Error::Internal(InternalError::InvalidInitialService))));
+    manager.send_values(target_map(vec![(vec![ChannelSelector::new().with_id(&light_id)], data_on.clone())]), user.clone());
+    let entries = manager.get_audit_log().query(&AuditFilter::default()).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].action, "send_values");
+    assert_eq!(entries[0].user, user);
+    assert_eq!(entries[0].service, Some(service_id.clone()));
+    assert_eq!(entries[0].channel, Some(light_id.clone()));
+    assert_eq!(entries[0].payload, data_on.to_json());
+    assert!(entries[0].result.is_err());
+
+    println!("* Secret payloads are redacted.");
+    manager.send_values(target_map(vec![(vec![ChannelSelector::new().with_id(&password_id)], password)]), user.clone());
+    let filter = AuditFilter { target: Some(password_id.to_string()), .. AuditFilter::default() };
+    let entries = manager.get_audit_log().query(&filter).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].payload.as_str(), Some(REDACTED));
+    assert_eq!(entries[0].result, Ok(()));
+
+    println!("");
+}
+
+#[test]
+fn test_slow_adapter() {
+    use std::time::{ Duration, Instant };
+
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    manager.set_adapter_timeout(Duration::from_millis(500));
+
+    let id_slow = Id::<AdapterId>::new("slow adapter");
+    let id_fast = Id::<AdapterId>::new("fast adapter");
+    let service_id_slow = Id::<ServiceId>::new("slow service");
+    let service_id_fast = Id::<ServiceId>::new("fast service");
+    let channel_id_slow = Id::<Channel>::new("slow channel");
+    let channel_id_fast = Id::<Channel>::new("fast channel");
+
+    let light_on = Channel {
+        feature: Id::new("light/is-on"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    };
+
+    let adapter_slow = FakeAdapter::new(&id_slow);
+    let adapter_fast = FakeAdapter::new(&id_fast);
+    let tweak_slow = adapter_slow.get_tweak();
+    let tweak_fast = adapter_fast.get_tweak();
+    let _rx_slow = adapter_slow.take_rx();
+    let _rx_fast = adapter_fast.take_rx();
+
+    manager.add_adapter(Arc::new(adapter_slow)).unwrap();
+    manager.add_adapter(Arc::new(adapter_fast)).unwrap();
+    manager.add_service(Service::empty(&service_id_slow, &id_slow)).unwrap();
+    manager.add_service(Service::empty(&service_id_fast, &id_fast)).unwrap();
+    manager.add_channel(Channel {
+        id: channel_id_slow.clone(),
+        service: service_id_slow.clone(),
+        adapter: id_slow.clone(),
+        .. light_on.clone()
+    }).unwrap();
+    manager.add_channel(Channel {
+        id: channel_id_fast.clone(),
+        service: service_id_fast.clone(),
+        adapter: id_fast.clone(),
+        .. light_on.clone()
+    }).unwrap();
+    tweak_fast(Tweak::InjectGetterValue(channel_id_fast.clone(), Ok(Some(Value::new(OnOff::On)))));
+
+    println!("* Adapters that answer within the deadline are all called concurrently.");
+    tweak_slow(Tweak::InjectDelay(Some(Duration::from_millis(300))));
+    tweak_fast(Tweak::InjectDelay(Some(Duration::from_millis(300))));
+    let start = Instant::now();
+    let data = manager.fetch_values(vec![ChannelSelector::new()], User::None);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    match data.get(&channel_id_slow).as_cast::<OnOff>() {
+        Some(Ok(None)) => {},
+        other => panic!("Unexpected result, {:?}", other)
+    }
+    match data.get(&channel_id_fast).as_cast() {
+        Some(Ok(Some(OnOff::On))) => {},
+        other => panic!("Unexpected result, {:?}", other)
+    }
+
+    println!("* A slow adapter gets a timeout when fetching, without holding up the others.");
+    tweak_slow(Tweak::InjectDelay(Some(Duration::from_secs(3))));
+    tweak_fast(Tweak::InjectDelay(None));
+    let start = Instant::now();
+    let data = manager.fetch_values(vec![ChannelSelector::new()], User::None);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(data.len(), 2);
+    match data.get(&channel_id_slow).as_cast::<OnOff>() {
+        Some(Err(Error::Timeout(ref id))) if *id == id_slow => {},
+        other => panic!("Unexpected result, {:?}", other)
+    }
+    match data.get(&channel_id_fast).as_cast() {
+        Some(Ok(Some(OnOff::On))) => {},
+        other => panic!("Unexpected result, {:?}", other)
+    }
+
+    println!("* A slow adapter gets a timeout when sending, without holding up the others.");
+    let data_on = Payload::from_value(&Value::new(OnOff::On), &format::ON_OFF).unwrap();
+    let start = Instant::now();
+    let data = manager.send_values(target_map(vec![(vec![ChannelSelector::new()], data_on)]), User::None);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(data.len(), 2);
+    assert_matches!(data.get(&channel_id_slow), Some(&Err(Error::Timeout(_))));
+    assert_matches!(data.get(&channel_id_fast), Some(&Ok(())));
+
+    println!("* A slow adapter doesn't use up all the workers.");
+    manager.set_adapter_timeout(Duration::from_millis(50));
+    for _ in 0..10 {
+        let data = manager.fetch_values(vec![ChannelSelector::new().with_id(&channel_id_slow)],
+                                        User::None);
+        assert_matches!(data.get(&channel_id_slow), Some(&Err(_)));
+    }
+    manager.set_adapter_timeout(Duration::from_millis(500));
+    let data = manager.fetch_values(vec![ChannelSelector::new().with_id(&channel_id_fast)],
+                                    User::None);
+    match data.get(&channel_id_fast).as_cast() {
+        Some(Ok(Some(OnOff::On))) => {},
+        other => panic!("Unexpected result, {:?}", other)
+    }
+
+    println!("");
+}
+
+#[test]
+fn test_timed_out_sends() {
+    use std::time::Duration;
+
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    manager.set_adapter_timeout(Duration::from_millis(100));
+
+    let id_adapter = Id::<AdapterId>::new("slow adapter");
+    let id_service = Id::<ServiceId>::new("slow service");
+    let id_door = Id::<Channel>::new("door");
+
+    let adapter = FakeAdapter::new(&id_adapter);
+    let tweak = adapter.get_tweak();
+    let rx = adapter.take_rx();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    manager.add_service(Service::empty(&id_service, &id_adapter)).unwrap();
+    manager.add_channel(Channel {
+        id: id_door.clone(),
+        service: id_service.clone(),
+        adapter: id_adapter.clone(),
+        feature: Id::new("door/is-locked"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    }).unwrap();
+
+    println!("* A send queued behind a slow adapter never reaches it once timed out.");
+    tweak(Tweak::InjectDelay(Some(Duration::from_millis(400))));
+    // Keep all the running slots of the adapter busy.
+    for _ in 0..2 {
+        let data = manager.fetch_values(vec![ChannelSelector::new().with_id(&id_door)],
+                                        User::None);
+        assert_matches!(data.get(&id_door), Some(&Err(Error::Timeout(_))));
+    }
+    let unlock = Payload::from_value(&Value::new(OnOff::Off), &format::ON_OFF).unwrap();
+    let data = manager.send_values(target_map(vec![(vec![ChannelSelector::new().with_id(&id_door)],
+                                                    unlock.clone())]),
+                                   User::None);
+    assert_matches!(data.get(&id_door), Some(&Err(Error::Timeout(_))));
+    thread::sleep(Duration::from_secs(1));
+    assert!(rx.try_recv().is_err());
+
+    println!("* Once the adapter is responsive again, sends go through.");
+    tweak(Tweak::InjectDelay(None));
+    let data = manager.send_values(target_map(vec![(vec![ChannelSelector::new().with_id(&id_door)],
+                                                    unlock)]),
+                                   User::None);
+    assert_matches!(data.get(&id_door), Some(&Ok(())));
+    let Effect::ValueSent(id, _) = rx.try_recv().unwrap();
+    assert_eq!(id, id_door);
+
+    println!("");
+}
+
+#[test]
+fn test_value_cache() {
+    use foxbox_taxonomy::value_cache::ValueSource;
+    use std::time::Duration;
+
+    println!("");
+
+    let manager = AdapterManager::new(None);
+    let id_adapter = Id::<AdapterId>::new("adapter id");
+    let id_service = Id::<ServiceId>::new("service id");
+    let id_light = Id::<Channel>::new("light");
+    let id_sensor = Id::<Channel>::new("sensor");
+
+    let adapter = FakeAdapter::new(&id_adapter);
+    let tweak = adapter.get_tweak();
+    let _rx = adapter.take_rx();
+    manager.add_adapter(Arc::new(adapter)).unwrap();
+    manager.add_service(Service::empty(&id_service, &id_adapter)).unwrap();
+    manager.add_channel(Channel {
+        id: id_light.clone(),
+        service: id_service.clone(),
+        adapter: id_adapter.clone(),
+        feature: Id::new("light/is-on"),
+        supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+        .. Channel::default()
+    }).unwrap();
+    // A battery-powered sensor, that can only report changes.
+    manager.add_channel(Channel {
+        id: id_sensor.clone(),
+        service: id_service.clone(),
+        adapter: id_adapter.clone(),
+        feature: Id::new("door/is-open"),
+        supports_watch: Some(Signature {
+            accepts: Maybe::Nothing,
+            returns: Maybe::Required(format::ON_OFF.clone())
+        }),
+        .. Channel::default()
+    }).unwrap();
+
+    let as_on_off = |value: &CachedValue| {
+        (value.payload.clone(), value.format.clone()).as_value().cast::<OnOff>().unwrap().clone()
+    };
+    let max_age = Some(Duration::from_secs(60));
+
+    println!("* Channels that haven't reported anything have no cached value.");
+    assert!(manager.get_cached_value(&id_light, &User::None).is_none());
+    assert!(manager.get_cached_value(&id_sensor, &User::None).is_none());
+    tweak(Tweak::InjectGetterValue(id_light.clone(), Ok(Some(Value::new(OnOff::On)))));
+    let data = manager.fetch_values_cached(vec![ChannelSelector::new()], User::None, max_age);
+    assert_eq!(data.len(), 2);
+    assert_matches!(data.get(&id_sensor), Some(&Ok(None)));
+
+    println!("* Fetched values are cached.");
+    match data.get(&id_light) {
+        Some(&Ok(Some(ref value))) => {
+            assert_eq!(as_on_off(value), OnOff::On);
+            assert_eq!(value.source, ValueSource::Fetch);
+        }
+        other => panic!("Unexpected result {:?}", other.map(|_| ()))
+    }
+    assert_eq!(as_on_off(&manager.get_cached_value(&id_light, &User::None).unwrap()), OnOff::On);
+
+    println!("* Fetched values are not served to other users.");
+    let other_user = User::Id(String::from("other"));
+    assert!(manager.get_cached_value(&id_light, &other_user).is_none());
+
+    println!("* Recent values are served from the cache.");
+    tweak(Tweak::InjectGetterValue(id_light.clone(), Ok(Some(Value::new(OnOff::Off)))));
+    let data = manager.fetch_values_cached(vec![ChannelSelector::new().with_id(&id_light)],
+                                           User::None, max_age);
+    match data.get(&id_light) {
+        Some(&Ok(Some(ref value))) => assert_eq!(as_on_off(value), OnOff::On),
+        other => panic!("Unexpected result {:?}", other.map(|_| ()))
+    }
+
+    println!("* Older values are fetched again.");
+    thread::sleep(Duration::from_millis(50));
+    let data = manager.fetch_values_cached(vec![ChannelSelector::new().with_id(&id_light)],
+                                           User::None, Some(Duration::from_millis(10)));
+    match data.get(&id_light) {
+        Some(&Ok(Some(ref value))) => assert_eq!(as_on_off(value), OnOff::Off),
+        other => panic!("Unexpected result {:?}", other.map(|_| ()))
+    }
+    let data = manager.fetch_values_cached(vec![ChannelSelector::new().with_id(&id_light)],
+                                           User::None, None);
+    assert_eq!(data.len(), 1);
+
+    println!("* Watched values are cached.");
+    let (tx_watch, rx_watch) = channel();
+    let _guard = manager.watch_values(target_map(vec![(
+        vec![ChannelSelector::new().with_id(&id_sensor)],
+        Exactly::Always
+    )]), Box::new(tx_watch));
+    tweak(Tweak::InjectGetterValue(id_sensor.clone(), Ok(Some(Value::new(OnOff::On)))));
+    assert_matches!(rx_watch.recv().unwrap(), Event::EnterRange { .. });
+    let value = manager.get_cached_value(&id_sensor, &User::None).unwrap();
+    assert_eq!(as_on_off(&value), OnOff::On);
+    assert_eq!(value.source, ValueSource::Watch);
+    assert!(manager.get_cached_value(&id_sensor, &other_user).is_some());
+
+    println!("* Channels that can't be fetched return their last value, however old.");
+    thread::sleep(Duration::from_millis(50));
+    let data = manager.fetch_values_cached(vec![ChannelSelector::new().with_id(&id_sensor)],
+                                           User::None, Some(Duration::from_millis(10)));
+    match data.get(&id_sensor) {
+        Some(&Ok(Some(ref value))) => assert_eq!(as_on_off(value), OnOff::On),
+        other => panic!("Unexpected result {:?}", other.map(|_| ()))
+    }
+
+    println!("* Removing a channel forgets its value.");
+    manager.remove_channel(&id_sensor).unwrap();
+    assert!(manager.get_cached_value(&id_sensor, &User::None).is_none());
+
+    println!("");
+}
+
 #[test]
 fn test_watch() {
     println!("");
@@ -9,6 +9,7 @@
 //!
 //! See module `ast` for more details on the grammar of scripts.
 
+extern crate foxbox_core;
 extern crate foxbox_taxonomy;
 
 extern crate transformable_channels;
@@ -6,6 +6,7 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::path::{Path as FilePath, PathBuf as FilePathBuf};
 
+use foxbox_core::migrations::{self, Migration, MigrationError};
 use foxbox_taxonomy::api::{ResultMap, User};
 use foxbox_taxonomy::parse::*;
 use foxbox_taxonomy::util::Id;
@@ -29,6 +30,23 @@ pub enum Error {
     ParseError(String),
 }
 
+/// The schema version of the scripts database written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The schema history of the scripts database.
+static MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    Migration {
+        version: 1,
+        description: "Create the scripts table",
+        sql: "CREATE TABLE IF NOT EXISTS scripts (
+                  id          TEXT NOT NULL PRIMARY KEY,
+                  source      TEXT NOT NULL,
+                  is_enabled  BOOL NOT NULL DEFAULT 1,
+                  owner       TEXT
+              )",
+    },
+];
+
 /// A type for ensuring type-safety (Id<ScriptId>).
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
 pub struct ScriptId;
@@ -73,13 +91,8 @@ impl<Env, T> ScriptManager<Env, T>
     /// to ensure validity.
     pub fn new(env: Env, path: &FilePath, tx: Box<T>) -> Result<Self, Error> {
 
-        let connection = try!(rusqlite::Connection::open(&path));
-        try!(connection.execute("CREATE TABLE IF NOT EXISTS scripts (
-            id          TEXT NOT NULL PRIMARY KEY,
-            source      TEXT NOT NULL,
-            is_enabled  BOOL NOT NULL DEFAULT 1,
-            owner       TEXT
-        )", &[]));
+        let mut connection = try!(rusqlite::Connection::open(&path));
+        try!(migrations::migrate(&mut connection, "thinkerbell scripts", &MIGRATIONS));
 
         Ok(ScriptManager {
             path: path.to_owned(),
@@ -263,6 +276,12 @@ impl From<rusqlite::Error> for Error {
     }
 }
 
+impl From<MigrationError> for Error {
+    fn from(err: MigrationError) -> Error {
+        Error::SQLError(format!("{}", err))
+    }
+}
+
 impl From<RunError> for Error {
     fn from(err: RunError) -> Error {
         Error::RunError(err)
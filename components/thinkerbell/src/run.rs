@@ -5,6 +5,8 @@ use compile::{Compiler, CompiledCtx, ExecutableDevEnv};
 pub use compile::{Error as CompileError, SourceError, TypeError};
 use compile;
 
+use foxbox_core::metrics;
+
 use foxbox_taxonomy::api;
 use foxbox_taxonomy::api::{API, Error as APIError, Targetted, User, WatchEvent};
 use foxbox_taxonomy::channel::Channel;
@@ -530,6 +532,9 @@ impl<Env> ExecutionTask<Env>
 
         if !condition_was_met && condition_is_met {
             // Ahah, we have just triggered the statements!
+            metrics::global().inc("foxbox_thinkerbell_rule_firings_total",
+                                  "Number of times a Thinkerbell rule was triggered.",
+                                  vec![]);
             debug!("[Thinkerbell update_condition {}] Triggering {} statements.",
                    name,
                    self.script.rules[rule_index].execute.len());
@@ -1,12 +1,13 @@
 #![feature(custom_derive, plugin)]
 extern crate foxbox_taxonomy;
 extern crate foxbox_thinkerbell;
+extern crate rusqlite;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate transformable_channels;
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
 use std::path::Path;
 use transformable_channels::mpsc::*;
@@ -83,3 +84,48 @@ fn test_database_add_remove_script() {
         .unwrap();
     assert_eq!(db.get_running_count(), 1);
 }
+
+#[test]
+fn test_database_upgrade() {
+    let path = Path::new("./test_script_database_legacy.sqlite");
+    let _ = fs::remove_file(path);
+
+    println!("* Creating a database without schema version.");
+    {
+        let legacy = rusqlite::Connection::open(path).unwrap();
+        legacy.execute("CREATE TABLE scripts (id TEXT NOT NULL PRIMARY KEY, source TEXT NOT NULL, \
+                        is_enabled BOOL NOT NULL DEFAULT 1, owner TEXT)",
+                     &[])
+            .unwrap();
+        legacy.execute("INSERT INTO scripts (id, source, is_enabled, owner) \
+                        VALUES ($1, $2, 1, $3)",
+                     &[&"Legacy Ruleset", &load_json("./examples/ruleset.json"), &"1"])
+            .unwrap();
+    }
+
+    println!("* The existing recipe should be loaded from the upgraded database.");
+    {
+        let (tx_env, _) = channel();
+        let env = FakeEnv::new(Box::new(tx_env));
+        let (tx, _) = channel();
+        let mut db = ScriptManager::new(env, path, Box::new(tx)).unwrap();
+        db.load().unwrap();
+        assert_eq!(db.get_running_count(), 1);
+    }
+
+    println!("* A database from a newer version should be refused.");
+    {
+        let newer = rusqlite::Connection::open(path).unwrap();
+        newer.execute_batch("PRAGMA user_version = 1000").unwrap();
+    }
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+    let (tx, _) = channel();
+    match ScriptManager::new(env, path, Box::new(tx)) {
+        Err(Error::SQLError(_)) => {}
+        Err(err) => panic!("Unexpected error {:?}", err),
+        Ok(_) => panic!("A newer database should not be opened"),
+    }
+
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use chrono::{DateTime, Duration, TimeZone, UTC};
+
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::process::Command;
+
+use certificate_record::CertificateRecord;
+
+// The format used by `openssl x509` for the notBefore/notAfter dates,
+// once consecutive spaces have been collapsed, e.g. "Mar 9 14:01:06 2016 GMT".
+const OPENSSL_DATE_FORMAT: &'static str = "%b %d %H:%M:%S %Y GMT";
+
+/// Human readable details about an installed certificate.
+///
+/// The rust-openssl version we depend on can't read the validity period or
+/// the issuer of a certificate, so these details are extracted from the output
+/// of the `openssl x509` command line tool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertificateInfo {
+    pub hostname: String,
+    pub subject: String,
+    pub subject_alt_names: Vec<String>,
+    pub issuer: String,
+    pub not_before: DateTime<UTC>,
+    pub not_after: DateTime<UTC>,
+    pub fingerprint: String,
+}
+
+impl CertificateInfo {
+    pub fn from_record(record: &CertificateRecord) -> io::Result<Self> {
+        let text = try!(x509_text(&record.cert_file));
+        parse_x509_text(&record.hostname,
+                        &text,
+                        &record.get_certificate_fingerprint())
+    }
+
+    /// Returns true if this certificate stops being valid before `now + window`.
+    pub fn expires_within(&self, now: &DateTime<UTC>, window: Duration) -> bool {
+        self.not_after <= *now + window
+    }
+
+    /// Returns true if this certificate is valid at `now`.
+    pub fn is_valid_at(&self, now: &DateTime<UTC>) -> bool {
+        self.not_before <= *now && *now < self.not_after
+    }
+
+    /// Returns true if this certificate can be used for `hostname`, either
+    /// through its common name or one of its subject alternative names.
+    pub fn matches_hostname(&self, hostname: &str) -> bool {
+        let common_name = format!("CN={}", hostname);
+        let common_name_spaced = format!("CN = {}", hostname);
+        let subject_matches = self.subject
+            .split(|c| c == ',' || c == '/')
+            .any(|part| {
+                let part = part.trim();
+                part == common_name || part == common_name_spaced
+            });
+
+        subject_matches || self.subject_alt_names.iter().any(|name| name == hostname)
+    }
+}
+
+/// Runs `openssl x509` on a PEM file and returns its output.
+pub fn x509_text<P: AsRef<Path>>(pem_file: P) -> io::Result<String> {
+    let output = try!(Command::new("openssl")
+        .arg("x509")
+        .arg("-noout")
+        .arg("-subject")
+        .arg("-issuer")
+        .arg("-startdate")
+        .arg("-enddate")
+        .arg("-text")
+        .arg("-in")
+        .arg(pem_file.as_ref())
+        .output());
+
+    if !output.status.success() {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              format!("Could not read the certificate {:?}: {}",
+                                      pem_file.as_ref(),
+                                      String::from_utf8_lossy(&output.stderr))));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, format!("{}", err)))
+}
+
+fn parse_openssl_date(value: &str) -> io::Result<DateTime<UTC>> {
+    let normalized = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    UTC.datetime_from_str(&normalized, OPENSSL_DATE_FORMAT).map_err(|err| {
+        Error::new(ErrorKind::InvalidData,
+                   format!("Invalid certificate date '{}': {}", value, err))
+    })
+}
+
+/// Builds a `CertificateInfo` from the output of `x509_text`.
+pub fn parse_x509_text(hostname: &str,
+                       text: &str,
+                       fingerprint: &str)
+                       -> io::Result<CertificateInfo> {
+    let mut subject = None;
+    let mut issuer = None;
+    let mut not_before = None;
+    let mut not_after = None;
+    let mut subject_alt_names = vec![];
+
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if line.starts_with("subject=") && subject.is_none() {
+            subject = Some(line["subject=".len()..].trim().to_owned());
+        } else if line.starts_with("issuer=") && issuer.is_none() {
+            issuer = Some(line["issuer=".len()..].trim().to_owned());
+        } else if line.starts_with("notBefore=") {
+            not_before = Some(try!(parse_openssl_date(&line["notBefore=".len()..])));
+        } else if line.starts_with("notAfter=") {
+            not_after = Some(try!(parse_openssl_date(&line["notAfter=".len()..])));
+        } else if line.starts_with("X509v3 Subject Alternative Name") {
+            if let Some(names) = lines.next() {
+                subject_alt_names = names.split(',')
+                    .filter_map(|name| {
+                        let name = name.trim();
+                        if name.starts_with("DNS:") {
+                            Some(name["DNS:".len()..].to_owned())
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+            }
+        }
+    }
+
+    match (subject, issuer, not_before, not_after) {
+        (Some(subject), Some(issuer), Some(not_before), Some(not_after)) => {
+            Ok(CertificateInfo {
+                hostname: hostname.to_owned(),
+                subject: subject,
+                subject_alt_names: subject_alt_names,
+                issuer: issuer,
+                not_before: not_before,
+                not_after: not_after,
+                fingerprint: fingerprint.to_owned(),
+            })
+        }
+        _ => {
+            Err(Error::new(ErrorKind::InvalidData,
+                           format!("Incomplete certificate description for {}", hostname)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod certificate_info_test {
+    use chrono::{Duration, TimeZone, UTC};
+    use std::path::PathBuf;
+    use super::*;
+
+    const OPENSSL_1_0_OUTPUT: &'static str = "subject= /CN=foxbox.local
+issuer= /CN=foxbox.local
+notBefore=Jun  1 12:00:00 2016 GMT
+notAfter=Jun  1 12:00:00 2018 GMT
+Certificate:
+    Data:
+        X509v3 extensions:
+            X509v3 Subject Alternative Name:
+                DNS:local.abcd.knilxof.org, DNS:remote.abcd.knilxof.org
+";
+
+    #[test]
+    fn should_parse_openssl_output() {
+        let info = parse_x509_text("foxbox.local", OPENSSL_1_0_OUTPUT, "abcd").unwrap();
+
+        assert_eq!(info.subject, "/CN=foxbox.local");
+        assert_eq!(info.issuer, "/CN=foxbox.local");
+        assert_eq!(info.not_before, UTC.ymd(2016, 6, 1).and_hms(12, 0, 0));
+        assert_eq!(info.not_after, UTC.ymd(2018, 6, 1).and_hms(12, 0, 0));
+        assert_eq!(info.subject_alt_names,
+                   vec!["local.abcd.knilxof.org".to_owned(),
+                        "remote.abcd.knilxof.org".to_owned()]);
+        assert_eq!(info.fingerprint, "abcd");
+    }
+
+    #[test]
+    fn should_reject_incomplete_output() {
+        assert!(parse_x509_text("foxbox.local", "subject= /CN=foxbox.local", "abcd").is_err());
+    }
+
+    #[test]
+    fn should_detect_expiring_certificates() {
+        let info = parse_x509_text("foxbox.local", OPENSSL_1_0_OUTPUT, "abcd").unwrap();
+        let now = UTC.ymd(2018, 5, 25).and_hms(0, 0, 0);
+
+        assert!(info.is_valid_at(&now));
+        assert!(!info.expires_within(&now, Duration::days(1)));
+        assert!(info.expires_within(&now, Duration::days(14)));
+    }
+
+    #[test]
+    fn should_match_hostnames() {
+        let info = parse_x509_text("foxbox.local", OPENSSL_1_0_OUTPUT, "abcd").unwrap();
+
+        assert!(info.matches_hostname("foxbox.local"));
+        assert!(info.matches_hostname("remote.abcd.knilxof.org"));
+        assert!(!info.matches_hostname("example.com"));
+    }
+
+    #[test]
+    fn should_read_certificate_file() {
+        let mut cert_file = PathBuf::from(current_dir!());
+        cert_file.push("test_fixtures");
+        cert_file.push("cert.pem");
+
+        let text = x509_text(cert_file).unwrap();
+        let info = parse_x509_text("example.com", &text, "1fa576").unwrap();
+        assert!(info.subject.contains("ACME Tech Inc"));
+        assert!(info.issuer.contains("ACME Signing Authority Inc"));
+        assert_eq!(info.not_before, UTC.ymd(2016, 3, 9).and_hms(14, 1, 6));
+    }
+}
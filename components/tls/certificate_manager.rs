@@ -2,14 +2,20 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use chrono::UTC;
+use mktemp::Temp;
+
 use std::collections::HashMap;
+use std::fs;
 use std::io;
+use std::io::{ErrorKind, Write};
 use std::io::Error as IoError;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
+use certificate_info::CertificateInfo;
 use certificate_record::CertificateRecord;
-use ssl_context::SslContextProvider;
+use ssl_context::{create_ssl_context, SslContextProvider};
 use utils::*;
 
 const DEFAULT_BOX_NAME: &'static str = "foxbox.local";
@@ -132,7 +138,6 @@ impl CertificateManager {
         self.get_certificate(&self.get_remote_dns_name())
     }
 
-    #[allow(dead_code)]
     pub fn remove_certificate(&self, hostname: &str) {
         {
             checklock!(self.ssl_hosts.write()).remove(hostname);
@@ -141,6 +146,109 @@ impl CertificateManager {
         self.notify_provider();
     }
 
+    /// Returns all the certificates currently served, sorted by hostname.
+    pub fn list_certificates(&self) -> Vec<CertificateRecord> {
+        let mut records: Vec<CertificateRecord> =
+            checklock!(self.ssl_hosts.read()).values().cloned().collect();
+        records.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        records
+    }
+
+    /// Returns the details of all the certificates currently served.
+    /// Certificates that can't be inspected are logged and skipped.
+    pub fn list_certificate_infos(&self) -> Vec<CertificateInfo> {
+        self.list_certificates()
+            .iter()
+            .filter_map(|record| {
+                match CertificateInfo::from_record(record) {
+                    Ok(info) => Some(info),
+                    Err(err) => {
+                        warn!("Unable to inspect the certificate for {}: {}",
+                              record.hostname,
+                              err);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Validates and installs a user provided certificate for `hostname`,
+    /// replacing any existing one except the self signed box certificate.
+    ///
+    /// The certificate must match the private key, be currently valid and
+    /// be issued for `hostname`.
+    pub fn install_certificate(&self,
+                               hostname: &str,
+                               cert_pem: &str,
+                               key_pem: &str,
+                               chain_pem: Option<&str>)
+                               -> io::Result<CertificateInfo> {
+        if hostname.is_empty() || hostname.contains('/') || hostname.starts_with('.') {
+            return Err(IoError::new(ErrorKind::InvalidInput,
+                                    format!("Invalid hostname: {}", hostname)));
+        }
+        if hostname == DEFAULT_BOX_NAME {
+            return Err(IoError::new(ErrorKind::PermissionDenied,
+                                    "The box certificate can not be replaced"));
+        }
+
+        // Validate everything in a scratch directory first so that a bad
+        // upload never replaces a working certificate.
+        let temp_dir = try!(Temp::new_dir());
+        let staged = try!(write_certificate_files(&temp_dir.to_path_buf(),
+                                                  hostname,
+                                                  cert_pem,
+                                                  key_pem,
+                                                  chain_pem));
+        try!(validate_certificate_record(&staged));
+
+        let info = try!(CertificateInfo::from_record(&staged));
+        if !info.matches_hostname(hostname) {
+            return Err(IoError::new(ErrorKind::InvalidInput,
+                                    format!("The certificate is not valid for {}", hostname)));
+        }
+        if !info.is_valid_at(&UTC::now()) {
+            return Err(IoError::new(ErrorKind::InvalidInput,
+                                    "The certificate is expired or not yet valid"));
+        }
+
+        let record = try!(write_certificate_files(&self.directory,
+                                                  hostname,
+                                                  cert_pem,
+                                                  key_pem,
+                                                  chain_pem));
+        self.add_certificate(record.clone());
+
+        info!("Installed a new certificate for {}", hostname);
+        CertificateInfo::from_record(&record)
+    }
+
+    /// Removes the certificate for `hostname` from memory and from disk.
+    /// The self signed box certificate can't be deleted since the box
+    /// fingerprint is derived from it.
+    pub fn delete_certificate(&self, hostname: &str) -> io::Result<()> {
+        if hostname == DEFAULT_BOX_NAME {
+            return Err(IoError::new(ErrorKind::PermissionDenied,
+                                    "The box certificate can not be deleted"));
+        }
+
+        if self.get_certificate(hostname).is_none() {
+            return Err(IoError::new(ErrorKind::NotFound,
+                                    format!("No certificate for {}", hostname)));
+        }
+
+        let mut host_directory = self.directory.clone();
+        host_directory.push(hostname);
+        if host_directory.exists() {
+            try!(fs::remove_dir_all(&host_directory));
+        }
+
+        self.remove_certificate(hostname);
+        info!("Deleted the certificate for {}", hostname);
+        Ok(())
+    }
+
     pub fn get_context_provider(&self) -> Arc<Box<SslContextProvider>> {
         self.context_provider.clone()
     }
@@ -170,6 +278,51 @@ impl CertificateManager {
     }
 }
 
+fn write_certificate_files(directory: &Path,
+                           hostname: &str,
+                           cert_pem: &str,
+                           key_pem: &str,
+                           chain_pem: Option<&str>)
+                           -> io::Result<CertificateRecord> {
+    let mut host_directory = directory.to_path_buf();
+    host_directory.push(hostname);
+    try!(fs::create_dir_all(&host_directory));
+
+    let write_file = |name: &str, content: &str| -> io::Result<PathBuf> {
+        let mut path = host_directory.clone();
+        path.push(name);
+        let mut file = try!(fs::File::create(&path));
+        try!(file.write_all(content.as_bytes()));
+        Ok(path)
+    };
+
+    let cert_file = try!(write_file("cert.pem", cert_pem));
+    let key_file = try!(write_file("privkey.pem", key_pem));
+    let chain_file = match chain_pem {
+        Some(chain) => Some(try!(write_file("fullchain.pem", chain))),
+        None => {
+            // Don't keep a stale chain from a previous certificate around.
+            let mut path = host_directory.clone();
+            path.push("fullchain.pem");
+            if path.exists() {
+                try!(fs::remove_file(&path));
+            }
+            None
+        }
+    };
+
+    CertificateRecord::new(hostname.to_owned(), cert_file, key_file, chain_file)
+}
+
+fn validate_certificate_record(record: &CertificateRecord) -> io::Result<()> {
+    let invalid = |err| IoError::new(ErrorKind::InvalidInput, format!("{}", err));
+    let context = try!(create_ssl_context(&record.cert_file,
+                                          &record.private_key_file,
+                                          &record.full_chain)
+        .map_err(&invalid));
+    context.check_private_key().map_err(&invalid)
+}
+
 #[cfg(test)]
 mod certificate_manager_test {
     use openssl::ssl::{SslContext, SslMethod};
@@ -286,4 +439,80 @@ mod certificate_manager_test {
         assert!(rx_update_called.recv().unwrap(),
                 "Did not receive notification from handler after remove");
     }
+
+    #[test]
+    fn should_reject_mismatched_certificate_uploads() {
+        use mktemp::Temp;
+        use std::fs::File;
+        use std::io::Read;
+        use utils::generate_self_signed_certificate;
+
+        let temp_dir = Temp::new_dir().unwrap();
+        let first = generate_self_signed_certificate("first.example.com", temp_dir.to_path_buf())
+            .unwrap();
+        let second = generate_self_signed_certificate("second.example.com", temp_dir.to_path_buf())
+            .unwrap();
+        let read = |path: &PathBuf| {
+            let mut content = String::new();
+            File::open(path).unwrap().read_to_string(&mut content).unwrap();
+            content
+        };
+
+        let certs_dir = Temp::new_dir().unwrap();
+        let (tx_update_called, _) = channel();
+        let cert_manager =
+            CertificateManager::new(certs_dir.to_path_buf(),
+                                    "knilxof.org",
+                                    Box::new(TestSslContextProvider::new(tx_update_called)));
+
+        // Key from another certificate.
+        assert!(cert_manager.install_certificate("first.example.com",
+                                 &read(&first.cert_file),
+                                 &read(&second.private_key_file),
+                                 None)
+            .is_err());
+
+        // Certificate issued for another host.
+        assert!(cert_manager.install_certificate("second.example.com",
+                                 &read(&first.cert_file),
+                                 &read(&first.private_key_file),
+                                 None)
+            .is_err());
+        assert!(cert_manager.list_certificates().is_empty());
+
+        let info = cert_manager.install_certificate("first.example.com",
+                                 &read(&first.cert_file),
+                                 &read(&first.private_key_file),
+                                 None)
+            .unwrap();
+        assert_eq!(info.hostname, "first.example.com");
+        assert_eq!(info.fingerprint, first.get_certificate_fingerprint());
+        assert_eq!(cert_manager.list_certificate_infos(), vec![info]);
+
+        cert_manager.delete_certificate("first.example.com").unwrap();
+        assert!(cert_manager.list_certificates().is_empty());
+        assert!(cert_manager.delete_certificate("first.example.com").is_err());
+    }
+
+    #[test]
+    fn should_not_replace_the_box_certificate() {
+        let (tx_update_called, _) = channel();
+        let cert_manager =
+            CertificateManager::new(PathBuf::from(current_dir!()),
+                                    "knilxof.org",
+                                    Box::new(TestSslContextProvider::new(tx_update_called)));
+        let err = cert_manager.install_certificate("foxbox.local", "", "", None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn should_not_delete_the_box_certificate() {
+        let (tx_update_called, _) = channel();
+        let cert_manager =
+            CertificateManager::new(PathBuf::from(current_dir!()),
+                                    "knilxof.org",
+                                    Box::new(TestSslContextProvider::new(tx_update_called)));
+
+        assert!(cert_manager.delete_certificate("foxbox.local").is_err());
+    }
 }
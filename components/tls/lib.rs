@@ -9,6 +9,7 @@
 #![deny(clippy)]
 
 
+extern crate chrono;
 #[macro_use]
 extern crate hyper;
 extern crate iron;
@@ -40,6 +41,7 @@ macro_rules! current_dir {
     };
 }
 
+mod certificate_info;
 mod certificate_manager;
 mod certificate_record;
 mod dns_client;
@@ -47,14 +49,20 @@ mod letsencrypt;
 mod ssl_context;
 mod utils;
 
+pub use certificate_info::*;
 pub use certificate_manager::*;
 pub use certificate_record::*;
 pub use dns_client::*;
 pub use letsencrypt::*;
 pub use ssl_context::*;
 
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum TlsOption {
+    /// Serve the LetsEncrypt certificate of the box's public names.
     Enabled,
+    /// Plain HTTP.
     Disabled,
+    /// Serve the self-signed box certificate, whose fingerprint clients pin.
+    /// Used when the box can't reach the registration and DNS servers.
+    SelfSigned,
 }
@@ -0,0 +1,361 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter exposing the state of the TLS certificates of the box.
+//!
+//! The "certificates/expires-soon" channel is `On` as long as one of the
+//! certificates expires in less than `tls`/`expiry_warning_days` days, so
+//! that a Thinkerbell rule can warn the owner before the remote name stops
+//! working.
+
+use foxbox_core::config_schema::ConfigKey;
+use foxbox_core::config_store::ConfigService;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{format, Json, OnOff, Value};
+
+use transformable_channels::mpsc::*;
+
+use chrono;
+use chrono::UTC;
+use serde_json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use timer;
+use tls::{CertificateInfo, CertificateManager};
+
+static ADAPTER_NAME: &'static str = "Certificates adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+/// How many days before expiry a certificate is reported as expiring soon.
+pub fn expiry_warning_days_config() -> ConfigKey {
+    ConfigKey::integer("tls", "expiry_warning_days", 14, Some(1), Some(365))
+        .description("Days before expiry at which a certificate is reported as expiring soon")
+}
+
+/// How often the certificates are checked for expiry, in seconds.
+const CHECK_INTERVAL_S: i64 = 3600;
+
+/// The details of a certificate, as returned by the list channel and the REST API.
+#[derive(Debug, Serialize)]
+pub struct CertificateDescription {
+    hostname: String,
+    subject: String,
+    subject_alt_names: Vec<String>,
+    issuer: String,
+    not_before: String,
+    not_after: String,
+    fingerprint: String,
+    expires_in_days: i64,
+}
+
+impl<'a> From<&'a CertificateInfo> for CertificateDescription {
+    fn from(info: &'a CertificateInfo) -> Self {
+        CertificateDescription {
+            hostname: info.hostname.clone(),
+            subject: info.subject.clone(),
+            subject_alt_names: info.subject_alt_names.clone(),
+            issuer: info.issuer.clone(),
+            not_before: info.not_before.to_rfc3339(),
+            not_after: info.not_after.to_rfc3339(),
+            fingerprint: info.fingerprint.clone(),
+            expires_in_days: (info.not_after - UTC::now()).num_days(),
+        }
+    }
+}
+
+type Watcher = (Option<Value>, Box<ExtSender<WatchEvent<Value>>>);
+
+/// The watchers of the expires-soon channel, and the last known value.
+struct ExpiryWatchers {
+    current_index: usize,
+    map: HashMap<usize, Watcher>,
+    last_value: Option<Value>,
+}
+
+/// A guard used to stop watching the expires-soon channel.
+struct ExpiryGuard {
+    key: usize,
+    watchers: Arc<Mutex<ExpiryWatchers>>,
+}
+
+impl Drop for ExpiryGuard {
+    fn drop(&mut self) {
+        self.watchers.lock().unwrap().map.remove(&self.key);
+    }
+}
+
+impl AdapterWatchGuard for ExpiryGuard {}
+
+/// Checks the certificates and notifies the watchers when the
+/// expires-soon state changes.
+#[derive(Clone)]
+struct ExpiryChecker {
+    certificate_manager: CertificateManager,
+    config: Arc<ConfigService>,
+    watchers: Arc<Mutex<ExpiryWatchers>>,
+    channel_id: Id<Channel>,
+}
+
+impl ExpiryChecker {
+    fn expires_soon(&self) -> Value {
+        let now = UTC::now();
+        let warning_window =
+            chrono::Duration::days(self.config.get_integer(&expiry_warning_days_config()));
+        let expiring = self.certificate_manager
+            .list_certificate_infos()
+            .iter()
+            .any(|info| info.expires_within(&now, warning_window));
+        if expiring {
+            Value::new(OnOff::On)
+        } else {
+            Value::new(OnOff::Off)
+        }
+    }
+
+    fn check(&self) {
+        let value = self.expires_soon();
+        let mut watchers = self.watchers.lock().unwrap();
+        let previous = watchers.last_value.clone();
+        if previous.as_ref() == Some(&value) {
+            return;
+        }
+        watchers.last_value = Some(value.clone());
+
+        for &(ref range, ref tx) in watchers.map.values() {
+            let event = match *range {
+                None => {
+                    Some(WatchEvent::Enter {
+                        id: self.channel_id.clone(),
+                        value: value.clone(),
+                    })
+                }
+                Some(ref range) => {
+                    let was_in_range = previous.as_ref() == Some(range);
+                    if *range == value && !was_in_range {
+                        Some(WatchEvent::Enter {
+                            id: self.channel_id.clone(),
+                            value: value.clone(),
+                        })
+                    } else if *range != value && was_in_range {
+                        Some(WatchEvent::Exit {
+                            id: self.channel_id.clone(),
+                            value: value.clone(),
+                        })
+                    } else {
+                        None
+                    }
+                }
+            };
+            if let Some(event) = event {
+                let _ = tx.send(event);
+            }
+        }
+    }
+}
+
+pub struct Certificates {
+    checker: ExpiryChecker,
+
+    /// Timer used to periodically check for expiring certificates.
+    timer: Mutex<timer::Timer>,
+    timer_guard: Mutex<Option<timer::Guard>>,
+
+    getter_list_id: Id<Channel>,
+    getter_expires_soon_id: Id<Channel>,
+}
+
+impl Certificates {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("certificates@link.mozilla.org")
+    }
+    pub fn service_certificates_id() -> Id<ServiceId> {
+        Id::new("service:certificates@link.mozilla.org")
+    }
+    pub fn getter_list_id() -> Id<Channel> {
+        Id::new("getter:list.certificates@link.mozilla.org")
+    }
+    pub fn getter_expires_soon_id() -> Id<Channel> {
+        Id::new("getter:expires-soon.certificates@link.mozilla.org")
+    }
+}
+
+impl Adapter for Certificates {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        set.drain(..)
+            .map(|id| {
+                if id == self.getter_list_id {
+                    let infos = self.checker.certificate_manager.list_certificate_infos();
+                    let descriptions: Vec<CertificateDescription> =
+                        infos.iter().map(CertificateDescription::from).collect();
+                    (id, Ok(Some(Value::new(Json(serde_json::to_value(&descriptions))))))
+                } else if id == self.getter_expires_soon_id {
+                    (id, Ok(Some(self.checker.expires_soon())))
+                } else {
+                    (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+                }
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, _)| (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id)))))
+            .collect()
+    }
+
+    fn register_watch(&self, mut watch: Vec<WatchTarget>) -> WatchResult {
+        watch.drain(..)
+            .map(|(id, range, tx)| {
+                if id != self.getter_expires_soon_id {
+                    return (id.clone(), Err(Error::OperationNotSupported(Operation::Watch, id)));
+                }
+
+                let mut watchers = self.checker.watchers.lock().unwrap();
+                let key = watchers.current_index;
+                watchers.current_index += 1;
+                watchers.map.insert(key, (range, tx));
+
+                let guard = ExpiryGuard {
+                    key: key,
+                    watchers: self.checker.watchers.clone(),
+                };
+                (id, Ok(Box::new(guard) as Box<AdapterWatchGuard>))
+            })
+            .collect()
+    }
+
+    fn stop(&self) {
+        self.timer_guard.lock().unwrap().take();
+    }
+}
+
+impl Certificates {
+    pub fn init<C: Controller>(controller: C, adapt: &Arc<AdapterManager>) -> Result<(), Error> {
+        let getter_list_id = Certificates::getter_list_id();
+        let getter_expires_soon_id = Certificates::getter_expires_soon_id();
+        let service_id = Certificates::service_certificates_id();
+        let adapter_id = Certificates::id();
+
+        let config = controller.get_config();
+        config.register(expiry_warning_days_config());
+
+        let checker = ExpiryChecker {
+            certificate_manager: controller.get_certificate_manager(),
+            config: config.clone(),
+            watchers: Arc::new(Mutex::new(ExpiryWatchers {
+                current_index: 0,
+                map: HashMap::new(),
+                last_value: None,
+            })),
+            channel_id: getter_expires_soon_id.clone(),
+        };
+
+        let certificates = Arc::new(Certificates {
+            checker: checker.clone(),
+            timer: Mutex::new(timer::Timer::new()),
+            timer_guard: Mutex::new(None),
+            getter_list_id: getter_list_id.clone(),
+            getter_expires_soon_id: getter_expires_soon_id.clone(),
+        });
+
+        {
+            let checker = checker.clone();
+            config.add_listener(move |change| {
+                if change.namespace == "tls" && change.property == "expiry_warning_days" {
+                    checker.check();
+                }
+            });
+        }
+
+        // Evaluate once at startup rather than waiting for the first tick.
+        checker.check();
+        {
+            let guard = certificates.timer
+                .lock()
+                .unwrap()
+                .schedule_repeating(chrono::Duration::seconds(CHECK_INTERVAL_S),
+                                    move || checker.check());
+            *certificates.timer_guard.lock().unwrap() = Some(guard);
+        }
+
+        try!(adapt.add_adapter(certificates));
+        let mut service = Service::empty(&service_id, &adapter_id);
+        service.properties.insert("model".to_owned(), "Mozilla certificates v1".to_owned());
+        try!(adapt.add_service(service));
+
+        let template = Channel {
+            service: service_id.clone(),
+            adapter: adapter_id.clone(),
+            ..Channel::default()
+        };
+
+        try!(adapt.add_channel(Channel {
+            feature: Id::new("certificates/list"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            id: getter_list_id,
+            ..template.clone()
+        }));
+        try!(adapt.add_channel(Channel {
+            feature: Id::new("certificates/expires-soon"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+            supports_watch: Some(Signature {
+                accepts: Maybe::Required(format::ON_OFF.clone()),
+                returns: Maybe::Required(format::ON_OFF.clone()),
+            }),
+            id: getter_expires_soon_id,
+            ..template.clone()
+        }));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+describe! certificates_adapter {
+    before_each {
+        use foxbox_taxonomy::manager::AdapterManager;
+        use std::sync::Arc;
+        use stubs::controller::ControllerStub;
+
+        let taxo_manager = Arc::new(AdapterManager::new(None));
+        Certificates::init(ControllerStub::new(), &taxo_manager).unwrap();
+    }
+
+    it "should report no expiring certificate when none is installed" {
+        use foxbox_taxonomy::api::{API, User};
+        use foxbox_taxonomy::selector::ChannelSelector;
+
+        let selector = vec![ChannelSelector::new().with_id(&Certificates::getter_expires_soon_id())];
+        let mut results = taxo_manager.fetch_values(selector, User::None);
+        let (_, result) = results.drain().next().unwrap();
+        let (payload, format) = result.unwrap().unwrap();
+        assert_eq!(payload.to_value(&format).unwrap(), Value::new(OnOff::Off));
+    }
+}
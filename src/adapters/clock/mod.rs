@@ -1,11 +1,21 @@
 //! An adapter providing time-related services, such as the current
 //! timestamp or the current time of day.
+//!
+//! Once `clock`/`latitude` and `clock`/`longitude` are configured, it also
+//! reports sunrise, sunset and twilights, and whether it is daylight.
 
+mod sun;
+
+pub use self::sun::{Location, SolarEvent};
+
+use foxbox_core::config_schema::ConfigKey;
+use foxbox_core::config_store::ConfigService;
+use foxbox_core::traits::Controller;
 use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::services::*;
-use foxbox_taxonomy::values::{format, Duration as ValDuration, Range, TimeStamp, Value};
+use foxbox_taxonomy::values::{format, Duration as ValDuration, OnOff, Range, TimeStamp, Value};
 
 use transformable_channels::mpsc::*;
 
@@ -13,13 +23,53 @@ use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
 use chrono;
-use chrono::{DateTime, Duration, Local, NaiveTime, Timelike};
+use chrono::{DateTime, Duration, Local, NaiveTime, Timelike, UTC};
 use timer;
 
 static ADAPTER_NAME: &'static str = "Clock adapter (built-in)";
 static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
 static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
 
+/// How often the position of the sun is checked for watches, in seconds.
+const SUN_CHECK_PERIOD_S: i64 = 30;
+
+pub fn latitude_config() -> ConfigKey {
+    ConfigKey::float("clock", "latitude", "", Some(-90.), Some(90.))
+        .description("Latitude of the box in degrees, used for sunrise and sunset")
+}
+
+pub fn longitude_config() -> ConfigKey {
+    ConfigKey::float("clock", "longitude", "", Some(-180.), Some(180.))
+        .description("Longitude of the box in degrees, east positive, used for sunrise and sunset")
+}
+
+/// Returns the configured location, if any.
+fn location(config: &ConfigService) -> Option<Location> {
+    match (config.get_float(&latitude_config()), config.get_float(&longitude_config())) {
+        (Some(latitude), Some(longitude)) => {
+            Some(Location {
+                latitude: latitude,
+                longitude: longitude,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn location_error() -> Error {
+    Error::Internal(InternalError::GenericError("The clock latitude and longitude are not \
+                                                 configured"
+        .to_owned()))
+}
+
+fn daylight_value(location: &Location, date: &DateTime<UTC>) -> Value {
+    if location.is_daylight(date) {
+        Value::new(OnOff::On)
+    } else {
+        Value::new(OnOff::Off)
+    }
+}
+
 #[derive(Clone)]
 enum Op {
     Enter(Id<Channel>, Value),
@@ -35,9 +85,13 @@ pub struct Clock {
     /// Timer used to dispatch `register_watch` requests.
     timer: Mutex<timer::Timer>,
 
+    config: Arc<ConfigService>,
+
     getter_timestamp_id: Id<Channel>,
     getter_time_of_day_id: Id<Channel>,
     getter_interval_id: Id<Channel>,
+    getter_is_daylight_id: Id<Channel>,
+    getter_solar_event_ids: Vec<(Id<Channel>, SolarEvent)>,
 }
 
 /// A guard used to cancel watching for values.
@@ -60,6 +114,19 @@ impl Clock {
     pub fn getter_interval_id() -> Id<Channel> {
         Id::new("getter:interval.clock@link.mozilla.org")
     }
+    pub fn getter_is_daylight_id() -> Id<Channel> {
+        Id::new("getter:is-daylight.clock@link.mozilla.org")
+    }
+    pub fn getter_solar_event_id(event: SolarEvent) -> Id<Channel> {
+        Id::new(&format!("getter:{}.clock@link.mozilla.org", event.name()))
+    }
+
+    fn solar_event(&self, id: &Id<Channel>) -> Option<SolarEvent> {
+        self.getter_solar_event_ids
+            .iter()
+            .find(|&&(ref event_id, _)| event_id == id)
+            .map(|&(_, event)| event)
+    }
 }
 impl Adapter for Clock {
     fn id(&self) -> Id<AdapterId> {
@@ -93,6 +160,20 @@ impl Adapter for Clock {
                     let duration =
                         chrono::Duration::seconds(date.num_seconds_from_midnight() as i64);
                     (id, Ok(Some(Value::new(ValDuration::from(duration)))))
+                } else if id == self.getter_is_daylight_id {
+                    match location(&self.config) {
+                        Some(location) => (id, Ok(Some(daylight_value(&location, &UTC::now())))),
+                        None => (id, Err(location_error())),
+                    }
+                } else if let Some(event) = self.solar_event(&id) {
+                    match location(&self.config) {
+                        Some(location) => {
+                            let next = location.next_event(event, &UTC::now())
+                                .map(|date| Value::new(TimeStamp::from_datetime(date)));
+                            (id, Ok(next))
+                        }
+                        None => (id, Err(location_error())),
+                    }
                 } else {
                     (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
                 }
@@ -128,11 +209,7 @@ impl Adapter for Clock {
                         }
                     }
                 });
-                (id.clone(),
-                 match filter {
-                    Some(range) => self.aux_register_watch(&id, &range, Box::new(tx.clone())),
-                    _ => Err(Error::GetterRequiresThresholdForWatching(id)),
-                })
+                (id.clone(), self.aux_register_watch(&id, filter, Box::new(tx.clone())))
             })
             .collect()
     }
@@ -141,21 +218,117 @@ impl Adapter for Clock {
 impl Clock {
     fn aux_register_watch(&self,
                           id: &Id<Channel>,
-                          range: &Value,
+                          range: Option<Value>,
                           tx: Box<ExtSender<Op>>)
                           -> Result<Box<AdapterWatchGuard>, Error> {
+        // These ones don't need a threshold.
+        if let Some(event) = self.solar_event(id) {
+            return self.aux_register_watch_solar_event(id, event, range, tx);
+        }
+        if *id == self.getter_is_daylight_id {
+            return self.aux_register_watch_is_daylight(id, range, tx);
+        }
+
+        let range = match range {
+            Some(range) => range,
+            None => return Err(Error::GetterRequiresThresholdForWatching(id.clone())),
+        };
         match () {
             _ if *id == self.getter_time_of_day_id => {
-                self.aux_register_watch_timeofday(id, range, tx)
+                self.aux_register_watch_timeofday(id, &range, tx)
             }
             _ if *id == self.getter_timestamp_id => {
-                self.aux_register_watch_timestamp(id, range, tx)
+                self.aux_register_watch_timestamp(id, &range, tx)
+            }
+            _ if *id == self.getter_interval_id => {
+                self.aux_register_watch_interval(id, &range, tx)
             }
-            _ if *id == self.getter_interval_id => self.aux_register_watch_interval(id, range, tx),
             _ => Err(Error::OperationNotSupported(Operation::Watch, id.clone())),
         }
     }
 
+    /// Watches a solar event. The optional value is an offset, e.g. -1800 to
+    /// trigger half an hour before sunset. Each occurrence sends an Enter
+    /// immediately followed by an Exit, with the time of the event.
+    fn aux_register_watch_solar_event(&self,
+                                      id: &Id<Channel>,
+                                      event: SolarEvent,
+                                      offset: Option<Value>,
+                                      tx: Box<ExtSender<Op>>)
+                                      -> Result<Box<AdapterWatchGuard>, Error> {
+        let offset: Duration = match offset {
+            Some(value) => try!(value.cast::<ValDuration>()).as_duration(),
+            None => Duration::zero(),
+        };
+
+        let id = id.clone();
+        let config = self.config.clone();
+        let mut last_check = UTC::now();
+        let guard = self.timer
+            .lock()
+            .unwrap()
+            .schedule_repeating(Duration::seconds(SUN_CHECK_PERIOD_S), move || {
+                let now = UTC::now();
+                if let Some(location) = location(&config) {
+                    // Fire if the event, shifted by the offset, happened since the last check.
+                    if let Some(date) = location.next_event(event, &(last_check - offset)) {
+                        if date + offset <= now {
+                            let value = Value::new(TimeStamp::from_datetime(date));
+                            let _ = tx.send(Op::Enter(id.clone(), value.clone()));
+                            let _ = tx.send(Op::Exit(id.clone(), value));
+                        }
+                    }
+                }
+                last_check = now;
+            });
+        Ok(Box::new(Guard(vec![guard])))
+    }
+
+    fn aux_register_watch_is_daylight(&self,
+                                      id: &Id<Channel>,
+                                      range: Option<Value>,
+                                      tx: Box<ExtSender<Op>>)
+                                      -> Result<Box<AdapterWatchGuard>, Error> {
+        if let Some(ref range) = range {
+            try!(range.cast::<OnOff>());
+        }
+
+        let id = id.clone();
+        let config = self.config.clone();
+        let mut previous: Option<Value> = None;
+        let guard = self.timer
+            .lock()
+            .unwrap()
+            .schedule_repeating(Duration::seconds(SUN_CHECK_PERIOD_S), move || {
+                let value = match location(&config) {
+                    Some(location) => daylight_value(&location, &UTC::now()),
+                    None => return,
+                };
+                if previous.as_ref() == Some(&value) {
+                    return;
+                }
+
+                let event = match range {
+                    None => Some(Op::Enter(id.clone(), value.clone())),
+                    Some(ref range) => {
+                        let was_in_range = previous.as_ref() == Some(range);
+                        if *range == value && !was_in_range {
+                            Some(Op::Enter(id.clone(), value.clone()))
+                        } else if *range != value && was_in_range {
+                            Some(Op::Exit(id.clone(), value.clone()))
+                        } else {
+                            None
+                        }
+                    }
+                };
+                if let Some(event) = event {
+                    let _ = tx.send(event);
+                }
+                previous = Some(value);
+            });
+        Ok(Box::new(Guard(vec![guard])))
+    }
+
     fn aux_register_watch_interval(&self,
                                    id: &Id<Channel>,
                                    value: &Value,
@@ -330,17 +503,30 @@ impl Clock {
 }
 
 impl Clock {
-    pub fn init(adapt: &Arc<AdapterManager>) -> Result<(), Error> {
+    pub fn init<C: Controller>(controller: C, adapt: &Arc<AdapterManager>) -> Result<(), Error> {
         let getter_timestamp_id = Clock::getter_timestamp_id();
         let getter_time_of_day_id = Clock::getter_time_of_day_id();
         let getter_interval_id = Clock::getter_interval_id();
+        let getter_is_daylight_id = Clock::getter_is_daylight_id();
+        let getter_solar_event_ids: Vec<(Id<Channel>, SolarEvent)> = SolarEvent::all()
+            .iter()
+            .map(|&event| (Clock::getter_solar_event_id(event), event))
+            .collect();
         let service_clock_id = Clock::service_clock_id();
         let adapter_id = Clock::id();
+
+        let config = controller.get_config();
+        config.register(latitude_config());
+        config.register(longitude_config());
+
         let clock = Arc::new(Clock {
             timer: Mutex::new(timer::Timer::new()),
+            config: config,
             getter_timestamp_id: getter_timestamp_id.clone(),
             getter_time_of_day_id: getter_time_of_day_id.clone(),
             getter_interval_id: getter_interval_id.clone(),
+            getter_is_daylight_id: getter_is_daylight_id.clone(),
+            getter_solar_event_ids: getter_solar_event_ids.clone(),
         });
         try!(adapt.add_adapter(clock));
         let mut service = Service::empty(&service_clock_id, &adapter_id);
@@ -381,6 +567,71 @@ impl Clock {
             adapter: adapter_id.clone(),
             ..Channel::default()
         }));
+        try!(adapt.add_channel(Channel {
+            feature: Id::new("clock/is-daylight"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+            supports_watch: Some(Signature {
+                accepts: Maybe::Optional(format::ON_OFF.clone()),
+                returns: Maybe::Required(format::ON_OFF.clone())
+            }),
+            id: getter_is_daylight_id,
+            service: service_clock_id.clone(),
+            adapter: adapter_id.clone(),
+            ..Channel::default()
+        }));
+        for (id, event) in getter_solar_event_ids {
+            try!(adapt.add_channel(Channel {
+                feature: Id::new(&format!("clock/{}", event.name())),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::TIMESTAMP.clone()))),
+                supports_watch: Some(Signature {
+                    accepts: Maybe::Optional(format::DURATION.clone()),
+                    returns: Maybe::Required(format::TIMESTAMP.clone())
+                }),
+                id: id,
+                service: service_clock_id.clone(),
+                adapter: adapter_id.clone(),
+                ..Channel::default()
+            }));
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+describe! clock_adapter {
+    before_each {
+        use foxbox_core::traits::Controller;
+        use foxbox_taxonomy::api::{API, User};
+        use foxbox_taxonomy::manager::AdapterManager;
+        use foxbox_taxonomy::selector::ChannelSelector;
+        use std::sync::Arc;
+        use stubs::controller::ControllerStub;
+
+        let controller = ControllerStub::new();
+        let taxo_manager = Arc::new(AdapterManager::new(None));
+        Clock::init(controller.clone(), &taxo_manager).unwrap();
+
+        let sunset = vec![ChannelSelector::new()
+            .with_id(&Clock::getter_solar_event_id(SolarEvent::Sunset))];
+    }
+
+    it "should require a location for solar events" {
+        let mut results = taxo_manager.fetch_values(sunset, User::None);
+        let (_, result) = results.drain().next().unwrap();
+        assert!(result.is_err());
+    }
+
+    it "should return the next sunset" {
+        let config = controller.get_config();
+        config.set("clock", "latitude", "48.8566");
+        config.set("clock", "longitude", "2.3522");
+
+        let mut results = taxo_manager.fetch_values(sunset, User::None);
+        let (_, result) = results.drain().next().unwrap();
+        let (payload, format) = result.unwrap().unwrap();
+        let value = payload.to_value(&format).unwrap();
+        let date = value.cast::<TimeStamp>().unwrap().as_datetime().clone();
+        assert!(date > UTC::now());
+        assert!(date < UTC::now() + Duration::days(1));
+    }
+}
@@ -0,0 +1,226 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Sunrise, sunset and twilight times, computed locally with the sunrise
+//! equation (see https://en.wikipedia.org/wiki/Sunrise_equation).
+//!
+//! The results are within a minute or two of the published ephemerides,
+//! which is plenty for home automation rules.
+
+use chrono::{DateTime, NaiveDateTime, UTC};
+
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2440587.5;
+const J2000_JULIAN_DAY: f64 = 2451545.0;
+const SECONDS_PER_DAY: f64 = 86400.0;
+const EARTH_OBLIQUITY: f64 = 23.4397;
+
+/// How many days to look ahead for the next event. Near the poles, the sun
+/// may not rise or set for months.
+const MAX_DAYS_AHEAD: i64 = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SolarEvent {
+    NauticalDawn,
+    CivilDawn,
+    Sunrise,
+    Sunset,
+    CivilDusk,
+    NauticalDusk,
+}
+
+impl SolarEvent {
+    pub fn all() -> &'static [SolarEvent] {
+        static ALL: [SolarEvent; 6] = [SolarEvent::NauticalDawn,
+                                       SolarEvent::CivilDawn,
+                                       SolarEvent::Sunrise,
+                                       SolarEvent::Sunset,
+                                       SolarEvent::CivilDusk,
+                                       SolarEvent::NauticalDusk];
+        &ALL
+    }
+
+    /// The name used in the channel ids and features.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SolarEvent::NauticalDawn => "nautical-dawn",
+            SolarEvent::CivilDawn => "civil-dawn",
+            SolarEvent::Sunrise => "sunrise",
+            SolarEvent::Sunset => "sunset",
+            SolarEvent::CivilDusk => "civil-dusk",
+            SolarEvent::NauticalDusk => "nautical-dusk",
+        }
+    }
+
+    /// The elevation of the center of the sun at which the event happens, in degrees.
+    /// Sunrise and sunset account for the refraction and the apparent radius of the sun.
+    fn elevation(&self) -> f64 {
+        match *self {
+            SolarEvent::Sunrise | SolarEvent::Sunset => -0.833,
+            SolarEvent::CivilDawn | SolarEvent::CivilDusk => -6.0,
+            SolarEvent::NauticalDawn | SolarEvent::NauticalDusk => -12.0,
+        }
+    }
+
+    fn is_morning(&self) -> bool {
+        match *self {
+            SolarEvent::NauticalDawn | SolarEvent::CivilDawn | SolarEvent::Sunrise => true,
+            SolarEvent::Sunset | SolarEvent::CivilDusk | SolarEvent::NauticalDusk => false,
+        }
+    }
+}
+
+/// The position of the sun around a given solar noon.
+struct SolarDay {
+    /// The solar noon, as a julian day.
+    transit: f64,
+    /// The declination of the sun, in radians.
+    declination: f64,
+}
+
+impl SolarDay {
+    /// `day` is the number of days since J2000, `longitude` is in degrees, east positive.
+    fn new(day: i64, longitude: f64) -> Self {
+        let mean_solar_noon = day as f64 + 0.0008 - longitude / 360.0;
+        let mean_anomaly = ((357.5291 + 0.98560028 * mean_solar_noon) % 360.0).to_radians();
+        let center = 1.9148 * mean_anomaly.sin() + 0.0200 * (2.0 * mean_anomaly).sin() +
+                     0.0003 * (3.0 * mean_anomaly).sin();
+        let ecliptic_longitude = ((mean_anomaly.to_degrees() + center + 180.0 + 102.9372) %
+                                  360.0)
+            .to_radians();
+        SolarDay {
+            transit: J2000_JULIAN_DAY + mean_solar_noon + 0.0053 * mean_anomaly.sin() -
+                     0.0069 * (2.0 * ecliptic_longitude).sin(),
+            declination: (ecliptic_longitude.sin() * EARTH_OBLIQUITY.to_radians().sin()).asin(),
+        }
+    }
+}
+
+fn to_julian_day(date: &DateTime<UTC>) -> f64 {
+    date.timestamp() as f64 / SECONDS_PER_DAY + UNIX_EPOCH_JULIAN_DAY
+}
+
+fn from_julian_day(julian_day: f64) -> DateTime<UTC> {
+    let seconds = ((julian_day - UNIX_EPOCH_JULIAN_DAY) * SECONDS_PER_DAY).round() as i64;
+    DateTime::from_utc(NaiveDateTime::from_timestamp(seconds, 0), UTC)
+}
+
+/// A place on Earth, in degrees. The longitude is positive east of Greenwich.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    /// The time of `event` around the solar noon of `day`, as a julian day.
+    /// Returns None if the sun doesn't reach the elevation of the event that day.
+    fn event_on(&self, day: i64, event: SolarEvent) -> Option<f64> {
+        let solar_day = SolarDay::new(day, self.longitude);
+        let latitude = self.latitude.to_radians();
+        let cos_hour_angle = (event.elevation().to_radians().sin() -
+                              latitude.sin() * solar_day.declination.sin()) /
+                             (latitude.cos() * solar_day.declination.cos());
+        if cos_hour_angle < -1.0 || cos_hour_angle > 1.0 {
+            return None;
+        }
+        let half_arc = cos_hour_angle.acos().to_degrees() / 360.0;
+        if event.is_morning() {
+            Some(solar_day.transit - half_arc)
+        } else {
+            Some(solar_day.transit + half_arc)
+        }
+    }
+
+    /// Returns the first occurrence of `event` strictly after `after`.
+    pub fn next_event(&self, event: SolarEvent, after: &DateTime<UTC>) -> Option<DateTime<UTC>> {
+        let after = to_julian_day(after);
+        let today = (after - J2000_JULIAN_DAY).floor() as i64;
+        for day in (today - 1)..(today + MAX_DAYS_AHEAD) {
+            if let Some(time) = self.event_on(day, event) {
+                if time > after {
+                    return Some(from_julian_day(time));
+                }
+            }
+        }
+        None
+    }
+
+    /// The elevation of the center of the sun above the horizon at `date`, in degrees.
+    pub fn sun_elevation(&self, date: &DateTime<UTC>) -> f64 {
+        let now = to_julian_day(date);
+        let today = (now - J2000_JULIAN_DAY).round() as i64;
+
+        // Use the closest solar noon.
+        let mut solar_day = SolarDay::new(today, self.longitude);
+        for day in &[today - 1, today + 1] {
+            let candidate = SolarDay::new(*day, self.longitude);
+            if (candidate.transit - now).abs() < (solar_day.transit - now).abs() {
+                solar_day = candidate;
+            }
+        }
+
+        let hour_angle = ((now - solar_day.transit) * 360.0).to_radians();
+        let latitude = self.latitude.to_radians();
+        (latitude.sin() * solar_day.declination.sin() +
+         latitude.cos() * solar_day.declination.cos() * hour_angle.cos())
+            .asin()
+            .to_degrees()
+    }
+
+    /// Returns true between sunrise and sunset.
+    pub fn is_daylight(&self, date: &DateTime<UTC>) -> bool {
+        self.sun_elevation(date) > SolarEvent::Sunrise.elevation()
+    }
+}
+
+#[cfg(test)]
+describe! sun {
+    before_each {
+        use chrono::{DateTime, Duration, UTC};
+
+        let parse = |date: &str| DateTime::parse_from_rfc3339(date).unwrap().with_timezone(&UTC);
+        let paris = Location { latitude: 48.8566, longitude: 2.3522 };
+        let tromso = Location { latitude: 69.6492, longitude: 18.9553 };
+        let summer_solstice = parse("2016-06-21T00:00:00Z");
+
+        let assert_close = |actual: DateTime<UTC>, expected: &str| {
+            let expected = parse(expected);
+            let delta = (actual - expected).num_seconds().abs();
+            assert!(delta < 180, "{} is too far from {}", actual, expected);
+        };
+    }
+
+    it "should compute sunrise and sunset" {
+        assert_close(paris.next_event(SolarEvent::Sunrise, &summer_solstice).unwrap(),
+                     "2016-06-21T03:47:00Z");
+        assert_close(paris.next_event(SolarEvent::Sunset, &summer_solstice).unwrap(),
+                     "2016-06-21T19:58:00Z");
+    }
+
+    it "should order twilights around sunrise and sunset" {
+        let at = |event| paris.next_event(event, &summer_solstice).unwrap();
+        assert!(at(SolarEvent::NauticalDawn) < at(SolarEvent::CivilDawn));
+        assert!(at(SolarEvent::CivilDawn) < at(SolarEvent::Sunrise));
+        assert!(at(SolarEvent::Sunset) < at(SolarEvent::CivilDusk));
+        assert!(at(SolarEvent::CivilDusk) < at(SolarEvent::NauticalDusk));
+    }
+
+    it "should return the next occurrence" {
+        let sunrise = paris.next_event(SolarEvent::Sunrise, &summer_solstice).unwrap();
+        let next = paris.next_event(SolarEvent::Sunrise, &sunrise).unwrap();
+        assert!(next - sunrise > Duration::hours(23));
+        assert!(next - sunrise < Duration::hours(25));
+    }
+
+    it "should skip days without sunset" {
+        let sunset = tromso.next_event(SolarEvent::Sunset, &summer_solstice).unwrap();
+        assert!(sunset > parse("2016-07-15T00:00:00Z"));
+        assert!(tromso.is_daylight(&summer_solstice));
+    }
+
+    it "should know when it is daylight" {
+        assert!(paris.is_daylight(&parse("2016-06-21T12:00:00Z")));
+        assert!(!paris.is_daylight(&summer_solstice));
+    }
+}
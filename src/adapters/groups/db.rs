@@ -0,0 +1,161 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Stores the groups.
+//!
+//! # The groups database
+//!
+//! The "groups" table has one row per group. The selectors of a group are
+//! stored as JSON, in the format of the REST API.
+
+use foxbox_core::migrations::{self, Migration};
+use rusqlite::{self, Connection};
+use serde_json;
+use super::{Aggregate, Group};
+
+/// The schema version of the groups database written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The schema history of the groups database.
+static MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    Migration {
+        version: 1,
+        description: "Create the groups table",
+        sql: "CREATE TABLE IF NOT EXISTS groups (
+                  id         TEXT NOT NULL PRIMARY KEY,
+                  name       TEXT NOT NULL,
+                  selectors  TEXT NOT NULL,
+                  feature    TEXT NOT NULL,
+                  aggregate  TEXT NOT NULL
+              );",
+    },
+];
+
+pub struct GroupDb {
+    db: Connection,
+}
+
+fn group_from_row(row: rusqlite::Row) -> Option<Group> {
+    let id: String = row.get(0);
+    let aggregate: String = row.get(4);
+    let aggregate = match Aggregate::from_name(&aggregate) {
+        Some(aggregate) => aggregate,
+        None => {
+            warn!("[groups] Group {} has an unknown aggregate {}", id, aggregate);
+            return None;
+        }
+    };
+    let selectors: String = row.get(2);
+    let selectors = match serde_json::from_str(&selectors) {
+        Ok(selectors) => selectors,
+        Err(err) => {
+            warn!("[groups] The selectors of group {} cannot be parsed: {}", id, err);
+            return None;
+        }
+    };
+    Some(Group {
+        id: id,
+        name: row.get(1),
+        selectors: selectors,
+        feature: row.get(3),
+        aggregate: aggregate,
+    })
+}
+
+impl GroupDb {
+    /// Opens the database at `path` and creates it if not available yet.
+    /// Panics if the database can't be opened or migrated to the current schema.
+    pub fn new(path: &str) -> Self {
+        let mut db = Connection::open(path).unwrap();
+        migrations::migrate(&mut db, "groups", &MIGRATIONS).unwrap_or_else(|err| {
+            panic!("Unable to migrate the groups database: {}", err);
+        });
+
+        GroupDb { db: db }
+    }
+
+    /// Gets all the groups, sorted by id.
+    pub fn list(&self) -> rusqlite::Result<Vec<Group>> {
+        let mut groups = Vec::new();
+        let mut stmt = try!(self.db
+            .prepare("SELECT id, name, selectors, feature, aggregate FROM groups ORDER BY id"));
+        let mut rows = try!(stmt.query(&[]));
+        while let Some(result_row) = rows.next() {
+            if let Some(group) = group_from_row(try!(result_row)) {
+                groups.push(group);
+            }
+        }
+        Ok(groups)
+    }
+
+    pub fn get(&self, id: &str) -> rusqlite::Result<Option<Group>> {
+        let mut stmt = try!(self.db
+            .prepare("SELECT id, name, selectors, feature, aggregate FROM groups WHERE id=$1"));
+        let mut rows = try!(stmt.query(&[&id]));
+        match rows.next() {
+            Some(result_row) => Ok(group_from_row(try!(result_row))),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds a group, or replaces the group with the same id.
+    pub fn put(&self, group: &Group) -> rusqlite::Result<()> {
+        let selectors = serde_json::to_string(&group.selectors).unwrap();
+        try!(self.db.execute("INSERT OR REPLACE INTO groups VALUES ($1, $2, $3, $4, $5)",
+                             &[&group.id,
+                               &group.name,
+                               &selectors,
+                               &group.feature,
+                               &group.aggregate.name()]));
+        Ok(())
+    }
+
+    /// Removes a group. Returns false if there was no such group.
+    pub fn remove(&self, id: &str) -> rusqlite::Result<bool> {
+        let count = try!(self.db.execute("DELETE FROM groups WHERE id=$1", &[&id]));
+        Ok(count > 0)
+    }
+}
+
+#[cfg(test)]
+describe! group_db {
+    before_each {
+        use serde_json;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("groups").unwrap();
+        let path = dir.path().join("groups.sqlite");
+        let db = GroupDb::new(path.to_str().unwrap());
+
+        let group = Group {
+            id: "living-room-lights".to_owned(),
+            name: "Living room lights".to_owned(),
+            selectors: serde_json::from_str(r#"[{"tags": ["room:living-room"]}]"#).unwrap(),
+            feature: "light/is-on".to_owned(),
+            aggregate: Aggregate::AnyOn,
+        };
+    }
+
+    it "should store groups" {
+        assert_eq!(db.list().unwrap(), vec![]);
+        db.put(&group).unwrap();
+        assert_eq!(db.get("living-room-lights").unwrap(), Some(group.clone()));
+        assert_eq!(db.list().unwrap(), vec![group.clone()]);
+        assert_eq!(db.get("kitchen").unwrap(), None);
+    }
+
+    it "should remove groups" {
+        db.put(&group).unwrap();
+        assert!(db.remove("living-room-lights").unwrap());
+        assert!(!db.remove("living-room-lights").unwrap());
+        assert_eq!(db.get("living-room-lights").unwrap(), None);
+    }
+
+    it "should keep groups across reopening" {
+        db.put(&group).unwrap();
+        drop(db);
+        let db = GroupDb::new(path.to_str().unwrap());
+        assert_eq!(db.list().unwrap(), vec![group]);
+    }
+}
@@ -0,0 +1,663 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Groups: named sets of devices defined by `ServiceSelector`s, e.g. "all
+//! the lights in the living room".
+//!
+//! The members of a group are labelled with the live tag `group:<id>`, which
+//! the taxonomy keeps up to date as devices come and go, so any selector can
+//! target a group. Each group is also exposed as a service with a single
+//! channel: sending to it sends to all the members, and fetching from it
+//! returns an aggregate of the values of the members, e.g. whether any light
+//! is on, or the average temperature. Groups are edited with the REST API,
+//! see `groups_router`.
+
+pub mod db;
+
+use foxbox_core::traits::Controller;
+use foxbox_core::utils;
+use foxbox_taxonomy::adapter::*;
+use foxbox_taxonomy::api::{API, Error, InternalError, Targetted, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::{Format, Payload};
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::parse::{Parser, Path};
+use foxbox_taxonomy::selector::{ChannelSelector, ServiceSelector};
+use foxbox_taxonomy::services::{AdapterId, Id, Service, ServiceId, TagId};
+use foxbox_taxonomy::util::Maybe;
+use foxbox_taxonomy::values::{format, Number, OnOff, Value};
+use rusqlite;
+use serde_json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use self::db::GroupDb;
+
+static ADAPTER_NAME: &'static str = "Groups adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+/// How the values of the members are combined when fetching from a group.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Aggregate {
+    /// `On` if any member is `On`.
+    #[serde(rename = "any-on")]
+    AnyOn,
+    /// `On` if all the members are `On`.
+    #[serde(rename = "all-on")]
+    AllOn,
+    /// The average of numeric values.
+    #[serde(rename = "average")]
+    Average,
+}
+
+impl Aggregate {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Aggregate::AnyOn => "any-on",
+            Aggregate::AllOn => "all-on",
+            Aggregate::Average => "average",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Aggregate> {
+        match name {
+            "any-on" => Some(Aggregate::AnyOn),
+            "all-on" => Some(Aggregate::AllOn),
+            "average" => Some(Aggregate::Average),
+            _ => None,
+        }
+    }
+
+    fn format(&self) -> Arc<Format> {
+        match *self {
+            Aggregate::AnyOn | Aggregate::AllOn => format::ON_OFF.clone(),
+            Aggregate::Average => format::NUMBER.clone(),
+        }
+    }
+
+    /// Combines the values of the members. Values of another type are ignored.
+    fn combine(&self, values: &[Value]) -> Option<Value> {
+        match *self {
+            Aggregate::AnyOn | Aggregate::AllOn => {
+                let states: Vec<_> = values.iter()
+                    .filter_map(|value| value.downcast::<OnOff>())
+                    .collect();
+                if states.is_empty() {
+                    return None;
+                }
+                let is_on = if *self == Aggregate::AnyOn {
+                    states.iter().any(|state| **state == OnOff::On)
+                } else {
+                    states.iter().all(|state| **state == OnOff::On)
+                };
+                Some(Value::new(if is_on { OnOff::On } else { OnOff::Off }))
+            }
+            Aggregate::Average => {
+                let numbers: Vec<_> = values.iter()
+                    .filter_map(|value| value.downcast::<Number>())
+                    .map(|number| number.as_f64())
+                    .collect();
+                if numbers.is_empty() {
+                    return None;
+                }
+                let sum: f64 = numbers.iter().sum();
+                Some(Value::new(Number(sum / numbers.len() as f64)))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    /// The `ServiceSelector`s of the members, as JSON.
+    pub selectors: serde_json::Value,
+    /// The feature of the channels of the members, e.g. "light/is-on".
+    pub feature: String,
+    pub aggregate: Aggregate,
+}
+
+#[derive(Debug)]
+pub enum GroupError {
+    NoSuchGroup(String),
+    InvalidGroup(String),
+    Database(rusqlite::Error),
+    Api(Error),
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GroupError::NoSuchGroup(ref id) => write!(f, "No such group: {}", id),
+            GroupError::InvalidGroup(ref msg) => write!(f, "Invalid group: {}", msg),
+            GroupError::Database(ref err) => write!(f, "Database error: {}", err),
+            GroupError::Api(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for GroupError {
+    fn from(err: rusqlite::Error) -> Self {
+        GroupError::Database(err)
+    }
+}
+
+impl From<Error> for GroupError {
+    fn from(err: Error) -> Self {
+        GroupError::Api(err)
+    }
+}
+
+fn parse_selectors(group: &Group) -> Result<Vec<ServiceSelector>, GroupError> {
+    Vec::<ServiceSelector>::parse(Path::new(), &group.selectors)
+        .map_err(|err| GroupError::InvalidGroup(format!("Invalid selectors: {:?}", err)))
+}
+
+/// Access to the stored groups and to their services. The REST API and the
+/// adapter each have their own instance, the database being shared.
+#[derive(Clone)]
+pub struct Groups {
+    manager: Arc<AdapterManager>,
+    db_path: String,
+}
+
+impl Groups {
+    pub fn new(manager: &Arc<AdapterManager>, db_path: &str) -> Self {
+        Groups {
+            manager: manager.clone(),
+            db_path: db_path.to_owned(),
+        }
+    }
+
+    pub fn adapter_id() -> Id<AdapterId> {
+        Id::new("groups@link.mozilla.org")
+    }
+
+    fn service_id(id: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.groups@link.mozilla.org", id))
+    }
+
+    pub fn channel_id(id: &str) -> Id<Channel> {
+        Id::new(&format!("channel:{}.groups@link.mozilla.org", id))
+    }
+
+    /// The live tag of the members of a group.
+    pub fn tag(id: &str) -> Id<TagId> {
+        Id::new(&format!("group:{}", id))
+    }
+
+    fn get_db(&self) -> GroupDb {
+        GroupDb::new(&self.db_path)
+    }
+
+    pub fn list(&self) -> Result<Vec<Group>, GroupError> {
+        Ok(try!(self.get_db().list()))
+    }
+
+    pub fn get(&self, id: &str) -> Result<Group, GroupError> {
+        match try!(self.get_db().get(id)) {
+            Some(group) => Ok(group),
+            None => Err(GroupError::NoSuchGroup(id.to_owned())),
+        }
+    }
+
+    /// Adds a group with an id derived from its name.
+    pub fn create(&self,
+                  name: &str,
+                  selectors: serde_json::Value,
+                  feature: &str,
+                  aggregate: Aggregate)
+                  -> Result<Group, GroupError> {
+        let db = self.get_db();
+        let base = utils::slug(name, "group");
+        let mut id = base.clone();
+        let mut suffix = 1;
+        while try!(db.get(&id)).is_some() {
+            suffix += 1;
+            id = format!("{}-{}", base, suffix);
+        }
+
+        let group = Group {
+            id: id,
+            name: name.to_owned(),
+            selectors: selectors,
+            feature: feature.to_owned(),
+            aggregate: aggregate,
+        };
+        try!(self.start(&group));
+        if let Err(err) = db.put(&group) {
+            self.stop(&group.id);
+            return Err(GroupError::Database(err));
+        }
+        Ok(group)
+    }
+
+    /// Replaces an existing group.
+    pub fn update(&self, group: &Group) -> Result<(), GroupError> {
+        let db = self.get_db();
+        let previous = match try!(db.get(&group.id)) {
+            Some(previous) => previous,
+            None => return Err(GroupError::NoSuchGroup(group.id.clone())),
+        };
+        // Check before removing the service, so that an invalid edit leaves the group alone.
+        try!(Self::check(group));
+        self.stop(&group.id);
+        let result = self.start(group).and_then(|()| db.put(group).map_err(GroupError::from));
+        if result.is_err() {
+            // Put the group back as it was.
+            self.stop(&group.id);
+            if let Err(err) = self.start(&previous) {
+                error!("[groups] Could not restart group {}: {}", group.id, err);
+            }
+        }
+        result
+    }
+
+    pub fn remove(&self, id: &str) -> Result<(), GroupError> {
+        if !try!(self.get_db().remove(id)) {
+            return Err(GroupError::NoSuchGroup(id.to_owned()));
+        }
+        self.stop(id);
+        Ok(())
+    }
+
+    /// The channels of the members, excluding the channels of groups.
+    pub fn members(&self, group: &Group) -> Vec<Channel> {
+        let selector = ChannelSelector::new()
+            .with_service_tags(vec![Self::tag(&group.id)])
+            .with_feature(&Id::new(&group.feature));
+        self.manager
+            .get_channels(vec![selector])
+            .into_iter()
+            .filter(|channel| channel.adapter != Self::adapter_id())
+            .collect()
+    }
+
+    /// Combines the current values of the members.
+    pub fn fetch(&self, group: &Group, user: &User) -> Result<Option<Value>, Error> {
+        let selectors: Vec<_> = self.members(group)
+            .iter()
+            .filter(|channel| channel.supports_fetch.is_some())
+            .map(|channel| ChannelSelector::new().with_id(&channel.id))
+            .collect();
+        if selectors.is_empty() {
+            return Ok(None);
+        }
+        let mut values = vec![];
+        for (id, result) in self.manager.fetch_values(selectors, user.clone()) {
+            match result.and_then(|fetched| match fetched {
+                Some((payload, format)) => payload.to_value(&format).map(Some),
+                None => Ok(None),
+            }) {
+                Ok(Some(value)) => values.push(value),
+                Ok(None) => {}
+                Err(err) => warn!("[groups] Could not fetch {}: {:?}", id, err),
+            }
+        }
+        Ok(group.aggregate.combine(&values))
+    }
+
+    /// Sends a value to all the members, in a single batch.
+    pub fn send(&self, group: &Group, value: &Value, user: &User) -> Result<(), Error> {
+        let select: Vec<_> = self.members(group)
+            .iter()
+            .filter(|channel| channel.supports_send.is_some())
+            .map(|channel| ChannelSelector::new().with_id(&channel.id))
+            .collect();
+        if select.is_empty() {
+            return Ok(());
+        }
+        let payload = try!(Payload::from_value(value, &group.aggregate.format()));
+        let errors: Vec<_> = self.manager
+            .send_values(vec![Targetted {
+                                  select: select,
+                                  payload: payload,
+                              }],
+                         user.clone())
+            .into_iter()
+            .filter_map(|(id, result)| result.err().map(|err| format!("{}: {:?}", id, err)))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Internal(InternalError::GenericError(format!("Some members failed: {}",
+                                                                    errors.join("; ")))))
+        }
+    }
+
+    fn check(group: &Group) -> Result<Vec<ServiceSelector>, GroupError> {
+        if group.name.trim().is_empty() {
+            return Err(GroupError::InvalidGroup("The name is empty".to_owned()));
+        }
+        if group.feature.is_empty() {
+            return Err(GroupError::InvalidGroup("The feature is empty".to_owned()));
+        }
+        parse_selectors(group)
+    }
+
+    /// Starts maintaining the live tag of a group and exposes its service.
+    fn start(&self, group: &Group) -> Result<(), GroupError> {
+        let selectors = try!(Self::check(group));
+        self.manager.set_live_service_tag(&Self::tag(&group.id), selectors);
+        if let Err(err) = self.add_service(group) {
+            // Don't leave the live tag or the service of a half-started group around.
+            self.stop(&group.id);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Removes the live tag and the service of a group.
+    fn stop(&self, id: &str) {
+        self.manager.remove_live_service_tag(&Self::tag(id));
+        let _ = self.manager.remove_service(&Self::service_id(id));
+    }
+
+    fn add_service(&self, group: &Group) -> Result<(), GroupError> {
+        let service_id = Self::service_id(&group.id);
+        let mut service = Service::empty(&service_id, &Self::adapter_id());
+        service.properties.insert("name".to_owned(), group.name.clone());
+        service.properties.insert("aggregate".to_owned(), group.aggregate.name().to_owned());
+        service.tags.insert(tag_id!("type:Group"));
+        try!(self.manager.add_service(service));
+        let format = group.aggregate.format();
+        try!(self.manager.add_channel(Channel {
+            id: Self::channel_id(&group.id),
+            service: service_id,
+            adapter: Self::adapter_id(),
+            feature: Id::new(&group.feature),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format.clone()))),
+            supports_send: Some(Signature::accepts(Maybe::Required(format))),
+            ..Channel::default()
+        }));
+        Ok(())
+    }
+}
+
+pub struct GroupAdapter {
+    groups: Groups,
+}
+
+impl GroupAdapter {
+    pub fn init<C: Controller>(controller: C, manager: &Arc<AdapterManager>) -> Result<(), Error> {
+        let db_path = controller.get_profile().path_for("groups.sqlite");
+        let groups = Groups::new(manager, &db_path);
+        try!(manager.add_adapter(Arc::new(GroupAdapter { groups: groups.clone() })));
+
+        let stored = try!(groups.list().map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        for group in stored {
+            if let Err(err) = groups.start(&group) {
+                error!("[groups] Could not start group {}: {}", group.id, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the groups, indexed by channel id.
+    fn groups_by_channel(&self) -> Result<HashMap<Id<Channel>, Group>, Error> {
+        let groups = try!(self.groups.list().map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        Ok(groups.into_iter()
+            .map(|group| (Groups::channel_id(&group.id), group))
+            .collect())
+    }
+}
+
+impl Adapter for GroupAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Groups::adapter_id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    user: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        let groups = match self.groups_by_channel() {
+            Ok(groups) => groups,
+            Err(err) => return set.drain(..).map(|id| (id, Err(err.clone()))).collect(),
+        };
+        set.drain(..)
+            .map(|id| {
+                let result = match groups.get(&id) {
+                    Some(group) => self.groups.fetch(group, &user),
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   user: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        let groups = match self.groups_by_channel() {
+            Ok(groups) => groups,
+            Err(err) => return values.drain().map(|(id, _)| (id, Err(err.clone()))).collect(),
+        };
+        values.drain()
+            .map(|(id, value)| {
+                let result = match groups.get(&id) {
+                    Some(group) => self.groups.send(group, &value, &user),
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+describe! groups {
+    before_each {
+        use foxbox_taxonomy::api::{API, Targetted, User};
+        use foxbox_taxonomy::channel::*;
+        use foxbox_taxonomy::fake_adapter::{Effect, FakeAdapter, Tweak};
+        use foxbox_taxonomy::io::Payload;
+        use foxbox_taxonomy::manager::AdapterManager;
+        use foxbox_taxonomy::selector::ChannelSelector;
+        use foxbox_taxonomy::services::{AdapterId, Id, Service, ServiceId};
+        use foxbox_taxonomy::util::Maybe;
+        use foxbox_taxonomy::values::{format, Number, OnOff, Value};
+        use serde_json;
+        use std::sync::Arc;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("groups").unwrap();
+        let db_path = dir.path().join("groups.sqlite");
+        let manager = Arc::new(AdapterManager::new(None));
+        let groups = Groups::new(&manager, db_path.to_str().unwrap());
+        manager.add_adapter(Arc::new(GroupAdapter { groups: groups.clone() })).unwrap();
+
+        let adapter_id: Id<AdapterId> = Id::new("fake@link.mozilla.org");
+        let adapter = FakeAdapter::new(&adapter_id);
+        let tweak = adapter.get_tweak();
+        let rx = adapter.take_rx();
+        manager.add_adapter(Arc::new(adapter)).unwrap();
+
+        // Adds a light in a room, with a fetchable and sendable channel.
+        let add_light = |name: &str, room: &str| {
+            let service_id: Id<ServiceId> =
+                Id::new(&format!("service:{}@link.mozilla.org", name));
+            let mut service = Service::empty(&service_id, &adapter_id);
+            service.tags.insert(Id::new(&format!("room:{}", room)));
+            manager.add_service(service).unwrap();
+            let id: Id<Channel> = Id::new(&format!("channel:{}@link.mozilla.org", name));
+            manager.add_channel(Channel {
+                id: id.clone(),
+                service: service_id,
+                adapter: adapter_id.clone(),
+                feature: Id::new("light/is-on"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+                supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+                ..Channel::default()
+            }).unwrap();
+            id
+        };
+        let fetch_group = |id: &str| {
+            let selector = ChannelSelector::new().with_id(&Groups::channel_id(id));
+            let (_, result) = manager.fetch_values(vec![selector], User::None)
+                .into_iter()
+                .next()
+                .unwrap();
+            result.unwrap().map(|(payload, format)| payload.to_value(&format).unwrap())
+        };
+        let selectors: serde_json::Value =
+            serde_json::from_str(r#"[{"tags": ["room:living-room"]}]"#).unwrap();
+    }
+
+    it "should include devices added after the group" {
+        let lamp = add_light("lamp", "living-room");
+        groups.create("Living room lights", selectors, "light/is-on", Aggregate::AnyOn).unwrap();
+        let group = groups.get("living-room-lights").unwrap();
+        assert_eq!(groups.members(&group).len(), 1);
+
+        let ceiling = add_light("ceiling", "living-room");
+        add_light("kitchen", "kitchen");
+        let mut members: Vec<_> = groups.members(&group)
+            .iter()
+            .map(|channel| channel.id.clone())
+            .collect();
+        members.sort_by(|a, b| a.to_string().cmp(&b.to_string()));
+        assert_eq!(members, vec![ceiling, lamp]);
+
+        // Any selector can target the members with the tag of the group.
+        let tagged = ChannelSelector::new()
+            .with_service_tags(vec![Groups::tag("living-room-lights")]);
+        assert_eq!(manager.get_channels(vec![tagged]).len(), 2);
+    }
+
+    it "should include devices tagged after the group" {
+        use foxbox_taxonomy::selector::ServiceSelector;
+
+        let lamp = add_light("lamp", "hallway");
+        groups.create("Living room lights", selectors, "light/is-on", Aggregate::AnyOn).unwrap();
+        let group = groups.get("living-room-lights").unwrap();
+        assert!(groups.members(&group).is_empty());
+
+        let service = ServiceSelector::new().with_id(&Id::new("service:lamp@link.mozilla.org"));
+        manager.add_service_tags(vec![service.clone()], vec![Id::new("room:living-room")]);
+        let members: Vec<_> = groups.members(&group)
+            .iter()
+            .map(|channel| channel.id.clone())
+            .collect();
+        assert_eq!(members, vec![lamp]);
+
+        manager.remove_service_tags(vec![service], vec![Id::new("room:living-room")]);
+        assert!(groups.members(&group).is_empty());
+    }
+
+    it "should not leave a half-started group around" {
+        use foxbox_taxonomy::selector::ServiceSelector;
+
+        add_light("lamp", "living-room");
+        // The service of the group can't be added over this one.
+        manager.add_service(Service::empty(&Groups::service_id("lights"), &Groups::adapter_id()))
+            .unwrap();
+        match groups.create("Lights", selectors, "light/is-on", Aggregate::AnyOn) {
+            Err(GroupError::Api(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        let tagged = ServiceSelector::new().with_tags(vec![Groups::tag("lights")]);
+        assert!(manager.get_services(vec![tagged]).is_empty());
+        assert!(groups.list().unwrap().is_empty());
+    }
+
+    it "should combine the values of the members" {
+        let lamp = add_light("lamp", "living-room");
+        let ceiling = add_light("ceiling", "living-room");
+        groups.create("Any", selectors.clone(), "light/is-on", Aggregate::AnyOn).unwrap();
+        groups.create("All", selectors, "light/is-on", Aggregate::AllOn).unwrap();
+        tweak(Tweak::InjectGetterValue(lamp.clone(), Ok(Some(Value::new(OnOff::On)))));
+        tweak(Tweak::InjectGetterValue(ceiling.clone(), Ok(Some(Value::new(OnOff::Off)))));
+        assert_eq!(fetch_group("any"), Some(Value::new(OnOff::On)));
+        assert_eq!(fetch_group("all"), Some(Value::new(OnOff::Off)));
+
+        tweak(Tweak::InjectGetterValue(ceiling.clone(), Ok(Some(Value::new(OnOff::On)))));
+        assert_eq!(fetch_group("all"), Some(Value::new(OnOff::On)));
+    }
+
+    it "should average numbers" {
+        let temperatures = [Value::new(Number(19.0)), Value::new(Number(22.0))];
+        assert_eq!(Aggregate::Average.combine(&temperatures), Some(Value::new(Number(20.5))));
+        assert_eq!(Aggregate::Average.combine(&[]), None);
+        assert_eq!(Aggregate::AnyOn.combine(&[Value::new(Number(1.0))]), None);
+    }
+
+    it "should send to all the members" {
+        let lamp = add_light("lamp", "living-room");
+        let ceiling = add_light("ceiling", "living-room");
+        add_light("kitchen", "kitchen");
+        groups.create("Living room lights", selectors, "light/is-on", Aggregate::AnyOn).unwrap();
+
+        let selector = ChannelSelector::new().with_id(&Groups::channel_id("living-room-lights"));
+        let payload = Payload::from_value(&Value::new(OnOff::Off), &format::ON_OFF).unwrap();
+        let results = manager.send_values(vec![Targetted {
+                                                   select: vec![selector],
+                                                   payload: payload,
+                                               }],
+                                          User::None);
+        for (_, result) in results {
+            result.unwrap();
+        }
+
+        let mut sent = vec![];
+        for _ in 0..2 {
+            match rx.recv().unwrap() {
+                Effect::ValueSent(id, value) => sent.push((id, value)),
+            }
+        }
+        sent.sort_by(|a, b| a.0.to_string().cmp(&b.0.to_string()));
+        assert_eq!(sent,
+                   vec![(ceiling, Value::new(OnOff::Off)), (lamp, Value::new(OnOff::Off))]);
+        assert!(rx.try_recv().is_err());
+    }
+
+    it "should edit and remove groups" {
+        add_light("lamp", "living-room");
+        let kitchen = add_light("kitchen", "kitchen");
+        let mut group = groups.create("Lights", selectors, "light/is-on", Aggregate::AnyOn)
+            .unwrap();
+        group.selectors = serde_json::from_str(r#"[{"tags": ["room:kitchen"]}]"#).unwrap();
+        groups.update(&group).unwrap();
+        let members: Vec<_> = groups.members(&group)
+            .iter()
+            .map(|channel| channel.id.clone())
+            .collect();
+        assert_eq!(members, vec![kitchen]);
+
+        group.selectors = serde_json::from_str(r#"{"tags": 3}"#).unwrap();
+        match groups.update(&group) {
+            Err(GroupError::InvalidGroup(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(fetch_group("lights"), None);
+
+        groups.remove("lights").unwrap();
+        assert!(groups.members(&group).is_empty());
+        assert!(groups.list().unwrap().is_empty());
+        match groups.remove("lights") {
+            Err(GroupError::NoSuchGroup(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
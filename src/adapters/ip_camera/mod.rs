@@ -24,8 +24,6 @@ use self::upnp_listener::IpCameraUpnpListener;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-const CUSTOM_PROPERTY_MANUFACTURER: &'static str = "manufacturer";
-const CUSTOM_PROPERTY_MODEL: &'static str = "model";
 const CUSTOM_PROPERTY_NAME: &'static str = "name";
 const CUSTOM_PROPERTY_URL: &'static str = "url";
 const CUSTOM_PROPERTY_UDN: &'static str = "udn";
@@ -94,13 +92,14 @@ impl IPCameraAdapter {
         let adapter_id = Self::id();
         let mut service = Service::empty(&service_id, &adapter_id);
 
-        service.properties.insert(CUSTOM_PROPERTY_MANUFACTURER.to_owned(),
+        service.properties.insert(properties::MANUFACTURER.to_owned(),
                                   description.manufacturer.clone());
-        service.properties.insert(CUSTOM_PROPERTY_MODEL.to_owned(),
+        service.properties.insert(properties::MODEL.to_owned(),
                                   description.model_name.clone());
         service.properties.insert(CUSTOM_PROPERTY_NAME.to_owned(), description.name.clone());
         service.properties.insert(CUSTOM_PROPERTY_URL.to_owned(), description.url.clone());
         service.properties.insert(CUSTOM_PROPERTY_UDN.to_owned(), description.udn.clone());
+        service.properties.insert(properties::ICON.to_owned(), "camera".to_owned());
         service.tags.insert(tag_id!(&format!("name:{}", description.name)));
 
         // Since the upnp_discover will be called about once very 3 minutes we want to ignore
@@ -0,0 +1,264 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Plays audio on the local ALSA output.
+//!
+//! Urls are played by an external program, e.g. mpv, which is paused and
+//! resumed with SIGSTOP/SIGCONT. The volume and mute are handled with `amixer`.
+
+use adapters::media::{MediaError, NowPlaying, PlaybackState, Player};
+use adapters::media::clips::Clip;
+use libc;
+use rand;
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+
+struct Playback {
+    child: Child,
+    url: String,
+    paused: bool,
+    /// Whether the child has exited and been reaped.
+    exited: bool,
+    /// A file written for the playback, removed once it is over.
+    file: Option<PathBuf>,
+}
+
+impl Playback {
+    fn is_running(&mut self) -> bool {
+        if !self.exited {
+            let mut status = 0;
+            let pid = self.child.id() as libc::pid_t;
+            self.exited = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } != 0;
+        }
+        !self.exited
+    }
+
+    fn signal(&self, signal: libc::c_int) -> Result<(), MediaError> {
+        if unsafe { libc::kill(self.child.id() as libc::pid_t, signal) } == 0 {
+            Ok(())
+        } else {
+            Err(MediaError::Io(::std::io::Error::last_os_error()))
+        }
+    }
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        if !self.exited {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+        if let Some(ref file) = self.file {
+            let _ = fs::remove_file(file);
+        }
+    }
+}
+
+pub struct AlsaPlayer {
+    /// The player command, with a `{url}` placeholder after the options.
+    command: String,
+    mixer: String,
+    playback: Mutex<Option<Playback>>,
+}
+
+impl AlsaPlayer {
+    pub fn new(command: &str, mixer: &str) -> Self {
+        AlsaPlayer {
+            command: command.to_owned(),
+            mixer: mixer.to_owned(),
+            playback: Mutex::new(None),
+        }
+    }
+
+    fn start(&self, url: &str, file: Option<PathBuf>) -> Result<(), MediaError> {
+        let mut args = self.command.split_whitespace().map(|arg| arg.replace("{url}", url));
+        let program = match args.next() {
+            Some(program) => program,
+            None => return Err(MediaError::InvalidValue("Empty player command".to_owned())),
+        };
+
+        let mut playback = self.playback.lock().unwrap();
+        // Dropping the previous playback stops it.
+        *playback = None;
+        let child = try!(Command::new(program)
+            .args(&args.collect::<Vec<_>>())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn());
+        *playback = Some(Playback {
+            child: child,
+            url: url.to_owned(),
+            paused: false,
+            exited: false,
+            file: file,
+        });
+        Ok(())
+    }
+
+    fn amixer(&self, args: &[&str]) -> Result<String, MediaError> {
+        let output = try!(Command::new("amixer")
+            .arg("-M")
+            .args(args)
+            .output());
+        if !output.status.success() {
+            return Err(MediaError::Device(format!("amixer failed: {}",
+                                                  String::from_utf8_lossy(&output.stderr))));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn mixer_state(&self) -> Result<(u32, bool), MediaError> {
+        let output = try!(self.amixer(&["sget", &self.mixer]));
+        match parse_mixer_state(&output) {
+            Some(state) => Ok(state),
+            None => Err(MediaError::Device(format!("Unexpected amixer output: {}", output))),
+        }
+    }
+}
+
+/// Extracts the volume and mute state from the output of `amixer sget`, e.g.
+/// `  Front Left: Playback 39321 [60%] [-12.00dB] [on]`.
+fn parse_mixer_state(output: &str) -> Option<(u32, bool)> {
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split('[')
+            .skip(1)
+            .filter_map(|field| field.split(']').next())
+            .collect();
+        let volume = fields.iter()
+            .filter(|field| field.ends_with('%'))
+            .filter_map(|field| field.trim_right_matches('%').parse::<u32>().ok())
+            .next();
+        if let Some(volume) = volume {
+            let muted = fields.contains(&"off");
+            return Some((volume, muted));
+        }
+    }
+    None
+}
+
+impl Player for AlsaPlayer {
+    fn play(&self, url: &str) -> Result<(), MediaError> {
+        self.start(url, None)
+    }
+
+    /// Clips are played from a file rather than through the http server, which
+    /// may only listen with TLS.
+    fn play_clip(&self, clip: &Clip) -> Result<(), MediaError> {
+        let path = env::temp_dir().join(format!("foxbox-media-{:016x}", rand::random::<u64>()));
+        try!(try!(File::create(&path)).write_all(&clip.data));
+        let url = path.to_string_lossy().into_owned();
+        self.start(&url, Some(path))
+    }
+
+    fn pause(&self) -> Result<(), MediaError> {
+        match *self.playback.lock().unwrap() {
+            Some(ref mut playback) if playback.is_running() => {
+                try!(playback.signal(libc::SIGSTOP));
+                playback.paused = true;
+                Ok(())
+            }
+            _ => Err(MediaError::InvalidValue("Nothing is playing".to_owned())),
+        }
+    }
+
+    fn resume(&self) -> Result<(), MediaError> {
+        match *self.playback.lock().unwrap() {
+            Some(ref mut playback) if playback.is_running() => {
+                try!(playback.signal(libc::SIGCONT));
+                playback.paused = false;
+                Ok(())
+            }
+            _ => Err(MediaError::InvalidValue("Nothing is playing".to_owned())),
+        }
+    }
+
+    fn stop(&self) -> Result<(), MediaError> {
+        *self.playback.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn get_volume(&self) -> Result<u32, MediaError> {
+        self.mixer_state().map(|(volume, _)| volume)
+    }
+
+    fn set_volume(&self, volume: u32) -> Result<(), MediaError> {
+        self.amixer(&["sset", &self.mixer, &format!("{}%", volume)]).map(|_| ())
+    }
+
+    fn is_muted(&self) -> Result<bool, MediaError> {
+        self.mixer_state().map(|(_, muted)| muted)
+    }
+
+    fn set_muted(&self, muted: bool) -> Result<(), MediaError> {
+        let state = if muted { "mute" } else { "unmute" };
+        self.amixer(&["sset", &self.mixer, state]).map(|_| ())
+    }
+
+    fn now_playing(&self) -> Result<NowPlaying, MediaError> {
+        let mut playback = self.playback.lock().unwrap();
+        let running = playback.as_mut().map_or(false, |playback| playback.is_running());
+        if !running {
+            *playback = None;
+            return Ok(NowPlaying {
+                state: PlaybackState::Stopped,
+                url: None,
+            });
+        }
+        let playback = playback.as_ref().unwrap();
+        Ok(NowPlaying {
+            state: if playback.paused {
+                PlaybackState::Paused
+            } else {
+                PlaybackState::Playing
+            },
+            url: Some(playback.url.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+describe! alsa_player {
+    it "should parse the mixer state" {
+        let output = "Simple mixer control 'Master',0\n  \
+                      Capabilities: pvolume pswitch pswitch-joined\n  \
+                      Playback channels: Front Left - Front Right\n  \
+                      Limits: Playback 0 - 65536\n  \
+                      Mono:\n  \
+                      Front Left: Playback 39321 [60%] [on]\n  \
+                      Front Right: Playback 39321 [60%] [on]\n";
+        assert_eq!(parse_mixer_state(output), Some((60, false)));
+
+        let output = "  Mono: Playback 31 [100%] [0.00dB] [off]\n";
+        assert_eq!(parse_mixer_state(output), Some((100, true)));
+
+        assert_eq!(parse_mixer_state("amixer: Unable to find simple control 'Foo',0\n"), None);
+    }
+
+    it "should track the playback" {
+        let player = AlsaPlayer::new("sleep 10 {url}", "Master");
+        player.play("5").unwrap();
+        let now = player.now_playing().unwrap();
+        assert_eq!(now.state, PlaybackState::Playing);
+        assert_eq!(now.url, Some("5".to_owned()));
+
+        player.pause().unwrap();
+        assert_eq!(player.now_playing().unwrap().state, PlaybackState::Paused);
+        player.resume().unwrap();
+
+        player.stop().unwrap();
+        assert_eq!(player.now_playing().unwrap().state, PlaybackState::Stopped);
+        assert!(player.pause().is_err());
+    }
+
+    it "should notice when the playback ends" {
+        let player = AlsaPlayer::new("true {url}", "Master");
+        player.play("http://example.org/chime.mp3").unwrap();
+        ::std::thread::sleep(::std::time::Duration::from_millis(200));
+        assert_eq!(player.now_playing().unwrap().state, PlaybackState::Stopped);
+    }
+}
@@ -0,0 +1,165 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Short-lived audio clips, e.g. rendered speech, that network renderers
+//! fetch by url.
+//!
+//! GET /media/clips/<token> : returns the clip. This doesn't require any
+//!                            authentication since renderers can't log in,
+//!                            the token is random and expires after
+//!                            `CLIP_LIFETIME_S` seconds instead.
+//!
+//! Renderers usually don't trust the certificate of the box, so clips are
+//! only reachable by them when TLS is disabled.
+
+extern crate get_if_addrs;
+
+use self::get_if_addrs::IfAddr;
+use foxbox_core::traits::Controller;
+use hyper::mime::Mime;
+use iron::{Handler, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::method::Method;
+use iron::status::Status;
+use rand::{self, Rng};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a clip can be fetched after it has been stored.
+pub const CLIP_LIFETIME_S: u64 = 600;
+
+/// A clip, ready to be played.
+#[derive(Clone)]
+pub struct Clip {
+    pub url: String,
+    pub data: Arc<Vec<u8>>,
+    pub mimetype: String,
+}
+
+struct StoredClip {
+    data: Arc<Vec<u8>>,
+    mimetype: String,
+    expires: Instant,
+}
+
+lazy_static! {
+    static ref CLIPS: Mutex<HashMap<String, StoredClip>> = Mutex::new(HashMap::new());
+}
+
+/// Stores a clip, and returns it along with the url it is served at.
+/// `base_url` is typically the result of `base_url()`.
+pub fn store(data: Vec<u8>, mimetype: &str, base_url: &str) -> Clip {
+    let token: String = rand::thread_rng().gen_ascii_chars().take(32).collect();
+    let data = Arc::new(data);
+    let now = Instant::now();
+
+    let mut clips = CLIPS.lock().unwrap();
+    let expired: Vec<String> = clips.iter()
+        .filter(|&(_, clip)| clip.expires <= now)
+        .map(|(token, _)| token.clone())
+        .collect();
+    for token in expired {
+        clips.remove(&token);
+    }
+    clips.insert(token.clone(),
+                 StoredClip {
+                     data: data.clone(),
+                     mimetype: mimetype.to_owned(),
+                     expires: now + Duration::from_secs(CLIP_LIFETIME_S),
+                 });
+
+    Clip {
+        url: format!("{}/{}", base_url, token),
+        data: data,
+        mimetype: mimetype.to_owned(),
+    }
+}
+
+fn get(token: &str) -> Option<(Arc<Vec<u8>>, String)> {
+    match CLIPS.lock().unwrap().get(token) {
+        Some(clip) if clip.expires > Instant::now() => {
+            Some((clip.data.clone(), clip.mimetype.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// The url of the clips, as seen from the local network.
+pub fn base_url<C: Controller>(controller: &C) -> Option<String> {
+    let port = match controller.http_as_addrs().ok().and_then(|mut addrs| addrs.next()) {
+        Some(addr) => addr.port(),
+        None => return None,
+    };
+    let scheme = if controller.get_tls_enabled() {
+        "https"
+    } else {
+        "http"
+    };
+    let ifaces = match get_if_addrs::get_if_addrs() {
+        Ok(ifaces) => ifaces,
+        Err(_) => return None,
+    };
+    ifaces.iter()
+        .filter(|iface| !iface.is_loopback())
+        .filter_map(|iface| match iface.addr {
+            IfAddr::V4(ref v4) => Some(v4.ip),
+            IfAddr::V6(_) => None,
+        })
+        .next()
+        .map(|ip| format!("{}://{}:{}/media/clips", scheme, ip, port))
+}
+
+pub struct ClipRouter;
+
+impl Handler for ClipRouter {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if req.method != Method::Get {
+            return Ok(Response::with((Status::MethodNotAllowed,
+                                      format!("Bad method: {}", req.method))));
+        }
+
+        let path = req.url.path();
+        if path.len() != 1 {
+            return Ok(Response::with((Status::NotFound, format!("Unknown url: {}", req.url))));
+        }
+        match get(path[0]) {
+            Some((data, mimetype)) => {
+                let mut response = Response::with((Status::Ok, (*data).clone()));
+                if let Ok(mime) = mimetype.parse::<Mime>() {
+                    response.headers.set(ContentType(mime));
+                }
+                Ok(response)
+            }
+            None => Ok(Response::with((Status::NotFound, "No such clip"))),
+        }
+    }
+}
+
+#[cfg(test)]
+describe! clip_router {
+    before_each {
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{request, response};
+        use mount::Mount;
+
+        let mut mount = Mount::new();
+        mount.mount("/media/clips", ClipRouter);
+    }
+
+    it "should serve stored clips" {
+        let clip = store(vec![1, 2, 3], "audio/wav", "http://localhost:3000/media/clips");
+        let response = request::get(&clip.url, Headers::new(), &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+        assert_eq!(response::extract_body_to_bytes(response), vec![1, 2, 3]);
+    }
+
+    it "should reject unknown tokens" {
+        let response = request::get("http://localhost:3000/media/clips/nope",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+    }
+}
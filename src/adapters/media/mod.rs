@@ -0,0 +1,631 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An adapter playing audio on the local ALSA output and on the `UPnP`/DLNA
+//! MediaRenderers of the local network.
+//!
+//! Each player is a service with the following channels:
+//! - `media/play-url` (send a String): plays the given http or https url;
+//! - `media/transport` (send a String): "pause", "resume" or "stop";
+//! - `media/volume` (fetch and send a Json integer from 0 to 100);
+//! - `media/muted` (fetch and send On/Off);
+//! - `media/now-playing` (fetch a Json object `{"state": "playing", "url": "..."}`);
+//! - `media/say` (send a Json speak request, as `speak/request`): renders the
+//!   speech with the text to speech adapter and plays it. This requires an
+//!   engine that can render to a file.
+//!
+//! Example cUrl request, to ring a chime on all the players:
+//! curl -X PUT -d '[[[{"feature":"media/play-url"}], {"String": "http://example.org/chime.mp3"}]]' http://localhost:3000/api/v1/channels/set
+
+pub mod alsa;
+pub mod clips;
+pub mod upnp;
+
+use foxbox_core::config_schema::ConfigKey;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::adapter::*;
+use foxbox_taxonomy::api::{self, API, Error, InternalError, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::services::{AdapterId, Id, Service, ServiceId, TagId};
+use foxbox_taxonomy::util::Maybe;
+use foxbox_taxonomy::values::{format, Binary, Json, OnOff, Value};
+use serde_json;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+use self::clips::Clip;
+
+static ADAPTER_NAME: &'static str = "Media adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+pub fn alsa_enabled_config() -> ConfigKey {
+    ConfigKey::boolean("media", "alsa_enabled", true)
+        .description("Whether the local audio output is available as a player")
+}
+
+pub fn alsa_player_config() -> ConfigKey {
+    ConfigKey::string("media", "alsa_player", "mpv --no-video --really-quiet -- {url}")
+        .description("The command that plays {url} on the local audio output. The options \
+                      should end with -- before {url}")
+}
+
+pub fn alsa_mixer_config() -> ConfigKey {
+    ConfigKey::string("media", "alsa_mixer", "Master")
+        .description("The ALSA mixer control used for the volume and mute")
+}
+
+pub fn upnp_enabled_config() -> ConfigKey {
+    ConfigKey::boolean("media", "upnp_enabled", true)
+        .description("Whether UPnP MediaRenderers are discovered")
+}
+
+#[derive(Debug)]
+pub enum MediaError {
+    Io(io::Error),
+    /// The device could not be reached or answered with an error.
+    Device(String),
+    InvalidValue(String),
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MediaError::Io(ref err) => write!(f, "I/O error: {}", err),
+            MediaError::Device(ref msg) => write!(f, "Device error: {}", msg),
+            MediaError::InvalidValue(ref msg) => write!(f, "Invalid value: {}", msg),
+            MediaError::Unsupported(what) => write!(f, "Unsupported by this player: {}", what),
+        }
+    }
+}
+
+impl StdError for MediaError {
+    fn description(&self) -> &str {
+        match *self {
+            MediaError::Io(ref err) => err.description(),
+            MediaError::Device(_) => "Device error",
+            MediaError::InvalidValue(_) => "Invalid value",
+            MediaError::Unsupported(_) => "Unsupported by this player",
+        }
+    }
+}
+
+impl From<io::Error> for MediaError {
+    fn from(err: io::Error) -> Self {
+        MediaError::Io(err)
+    }
+}
+
+/// Players may only be asked to play remote urls, not local files, and the
+/// url must not pass for an option of the ALSA player command.
+fn check_url(url: &str) -> Result<(), MediaError> {
+    match Url::parse(url) {
+        Ok(ref parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(()),
+        _ => Err(MediaError::InvalidValue(format!("Expected an http or https url, got {}", url))),
+    }
+}
+
+fn media_error(err: MediaError) -> Error {
+    Error::Internal(InternalError::GenericError(format!("{}", err)))
+}
+
+/// Renders speak requests (see `speak/request`) to audio.
+pub trait SpeechRenderer: Send + Sync {
+    fn render_speech(&self, request: &serde_json::Value) -> Result<Binary, Error>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlaybackState {
+    Stopped,
+    Playing,
+    Paused,
+    /// The player is loading or buffering.
+    Transitioning,
+}
+
+impl PlaybackState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PlaybackState::Stopped => "stopped",
+            PlaybackState::Playing => "playing",
+            PlaybackState::Paused => "paused",
+            PlaybackState::Transitioning => "transitioning",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NowPlaying {
+    pub state: PlaybackState,
+    pub url: Option<String>,
+}
+
+/// Something that plays audio.
+pub trait Player: Send + Sync {
+    fn play(&self, url: &str) -> Result<(), MediaError>;
+
+    /// Plays a clip stored by the box. Players that can't reach the clip url
+    /// may use its data directly instead.
+    fn play_clip(&self, clip: &Clip) -> Result<(), MediaError> {
+        self.play(&clip.url)
+    }
+
+    fn pause(&self) -> Result<(), MediaError>;
+    fn resume(&self) -> Result<(), MediaError>;
+    fn stop(&self) -> Result<(), MediaError>;
+
+    /// From 0 to 100.
+    fn get_volume(&self) -> Result<u32, MediaError>;
+    fn set_volume(&self, volume: u32) -> Result<(), MediaError>;
+
+    fn is_muted(&self) -> Result<bool, MediaError>;
+    fn set_muted(&self, muted: bool) -> Result<(), MediaError>;
+
+    fn now_playing(&self) -> Result<NowPlaying, MediaError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    PlayUrl,
+    Transport,
+    Volume,
+    Muted,
+    NowPlaying,
+    Say,
+}
+
+impl Operation {
+    fn all() -> &'static [Operation] {
+        static ALL: [Operation; 6] = [Operation::PlayUrl,
+                                      Operation::Transport,
+                                      Operation::Volume,
+                                      Operation::Muted,
+                                      Operation::NowPlaying,
+                                      Operation::Say];
+        &ALL
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Operation::PlayUrl => "play-url",
+            Operation::Transport => "transport",
+            Operation::Volume => "volume",
+            Operation::Muted => "muted",
+            Operation::NowPlaying => "now-playing",
+            Operation::Say => "say",
+        }
+    }
+
+    fn channel(&self, id: Id<Channel>, service: &Id<ServiceId>) -> Channel {
+        let (fetch, send) = match *self {
+            Operation::PlayUrl | Operation::Transport => (None, Some(format::STRING.clone())),
+            Operation::Volume => (Some(format::JSON.clone()), Some(format::JSON.clone())),
+            Operation::Muted => (Some(format::ON_OFF.clone()), Some(format::ON_OFF.clone())),
+            Operation::NowPlaying => (Some(format::JSON.clone()), None),
+            Operation::Say => (None, Some(format::JSON.clone())),
+        };
+        Channel {
+            id: id,
+            service: service.clone(),
+            adapter: MediaAdapter::<()>::id(),
+            feature: Id::new(&format!("media/{}", self.name())),
+            supports_fetch: fetch.map(|format| Signature::returns(Maybe::Required(format))),
+            supports_send: send.map(|format| Signature::accepts(Maybe::Required(format))),
+            ..Channel::default()
+        }
+    }
+}
+
+type ChannelMap = HashMap<Id<Channel>, (Arc<Player>, Operation)>;
+
+#[derive(Clone)]
+pub struct MediaAdapter<C> {
+    controller: C,
+    manager: Arc<AdapterManager>,
+    /// The player and operation behind each channel.
+    channels: Arc<Mutex<ChannelMap>>,
+    /// The channels of each player, by local id.
+    players: Arc<Mutex<HashMap<String, Vec<Id<Channel>>>>>,
+    /// Renders `media/say` requests, if a text to speech engine can.
+    speech: Option<Arc<SpeechRenderer>>,
+}
+
+impl<C> MediaAdapter<C> {
+    pub fn id() -> Id<AdapterId> {
+        Id::new("media@link.mozilla.org")
+    }
+
+    fn service_id(local_id: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.media@link.mozilla.org", local_id))
+    }
+
+    fn channel_id(operation: Operation, local_id: &str) -> Id<Channel> {
+        let prefix = if operation == Operation::NowPlaying {
+            "getter"
+        } else {
+            "setter"
+        };
+        Id::new(&format!("{}:{}.{}.media@link.mozilla.org",
+                         prefix,
+                         operation.name(),
+                         local_id))
+    }
+}
+
+impl<C: Controller> MediaAdapter<C> {
+    pub fn init(controller: C,
+                manager: &Arc<AdapterManager>,
+                speech: Option<Arc<SpeechRenderer>>)
+                -> Result<(), Error> {
+        let config = controller.get_config();
+        config.register(alsa_enabled_config());
+        config.register(alsa_player_config());
+        config.register(alsa_mixer_config());
+        config.register(upnp_enabled_config());
+
+        let adapter = MediaAdapter {
+            controller: controller.clone(),
+            manager: manager.clone(),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            players: Arc::new(Mutex::new(HashMap::new())),
+            speech: speech,
+        };
+        try!(manager.add_adapter(Arc::new(adapter.clone())));
+
+        if config.get_bool(&alsa_enabled_config()) {
+            let player = alsa::AlsaPlayer::new(&config.get_value(&alsa_player_config()),
+                                               &config.get_value(&alsa_mixer_config()));
+            try!(adapter.add_player("alsa", "Local audio output", "media/alsa", Arc::new(player)));
+        }
+
+        if config.get_bool(&upnp_enabled_config()) {
+            let upnp = controller.get_upnp_manager();
+            upnp.add_listener("MediaRenderers".to_owned(),
+                              upnp::RendererListener::new(adapter.clone()));
+            if let Err(err) = upnp.search(Some(upnp::MEDIA_RENDERER_TYPE.to_owned())) {
+                warn!("[Media] UPnP search failed: {}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn has_player(&self, local_id: &str) -> bool {
+        self.players.lock().unwrap().contains_key(local_id)
+    }
+
+    /// Registers a player as a service. `local_id` must be unique among the players.
+    pub fn add_player(&self,
+                      local_id: &str,
+                      name: &str,
+                      kind: &str,
+                      player: Arc<Player>)
+                      -> Result<(), Error> {
+        let service_id = Self::service_id(local_id);
+        let mut service = Service::empty(&service_id, &Self::id());
+        service.properties.insert("name".to_owned(), name.to_owned());
+        service.properties.insert("type".to_owned(), kind.to_owned());
+        service.tags.insert(tag_id!("type:Media/Player"));
+        try!(self.manager.add_service(service));
+
+        let ids: Vec<_> = Operation::all()
+            .iter()
+            .map(|operation| Self::channel_id(*operation, local_id))
+            .collect();
+        {
+            let mut channels = self.channels.lock().unwrap();
+            for (id, operation) in ids.iter().zip(Operation::all()) {
+                channels.insert(id.clone(), (player.clone(), *operation));
+            }
+        }
+        self.players.lock().unwrap().insert(local_id.to_owned(), ids.clone());
+
+        for (id, operation) in ids.into_iter().zip(Operation::all()) {
+            if let Err(err) = self.manager.add_channel(operation.channel(id, &service_id)) {
+                let _ = self.remove_player(local_id);
+                return Err(err);
+            }
+        }
+        info!("[Media] Added player {} ({})", name, kind);
+        Ok(())
+    }
+
+    pub fn remove_player(&self, local_id: &str) -> Result<(), Error> {
+        if let Some(ids) = self.players.lock().unwrap().remove(local_id) {
+            let mut channels = self.channels.lock().unwrap();
+            for id in ids {
+                channels.remove(&id);
+            }
+        }
+        self.manager.remove_service(&Self::service_id(local_id))
+    }
+
+    fn say(&self, player: &Arc<Player>, value: &Value) -> Result<(), Error> {
+        let request = try!(value.cast::<Json>());
+        let speech = match self.speech {
+            Some(ref speech) => try!(speech.render_speech(&request.0)),
+            None => {
+                return Err(Error::Internal(InternalError::GenericError("No text to speech \
+                                                                        engine can render speech"
+                    .to_owned())))
+            }
+        };
+        let base_url = match clips::base_url(&self.controller) {
+            Some(base_url) => base_url,
+            None => {
+                return Err(Error::Internal(InternalError::GenericError("The box has no local \
+                                                                        network address"
+                    .to_owned())))
+            }
+        };
+        let clip = clips::store(speech.data, &speech.mimetype.to_string(), &base_url);
+        player.play_clip(&clip).map_err(media_error)
+    }
+
+    fn send(&self,
+            id: &Id<Channel>,
+            player: &Arc<Player>,
+            operation: Operation,
+            value: &Value)
+            -> Result<(), Error> {
+        match operation {
+            Operation::PlayUrl => {
+                let url = try!(value.cast::<String>());
+                try!(check_url(url).map_err(media_error));
+                player.play(url).map_err(media_error)
+            }
+            Operation::Transport => {
+                let command = try!(value.cast::<String>());
+                match command as &str {
+                        "pause" => player.pause(),
+                        "resume" => player.resume(),
+                        "stop" => player.stop(),
+                        _ => {
+                            Err(MediaError::InvalidValue(format!("Unknown transport command {}, \
+                                                                  expected pause, resume or stop",
+                                                                 command)))
+                        }
+                    }
+                    .map_err(media_error)
+            }
+            Operation::Volume => {
+                let json = try!(value.cast::<Json>());
+                match json.0.as_u64() {
+                    Some(volume) if volume <= 100 => {
+                        player.set_volume(volume as u32).map_err(media_error)
+                    }
+                    _ => {
+                        Err(media_error(MediaError::InvalidValue(format!("The volume must be \
+                                                                          an integer between \
+                                                                          0 and 100, got {}",
+                                                                         json.0))))
+                    }
+                }
+            }
+            Operation::Muted => {
+                let muted = try!(value.cast::<OnOff>());
+                player.set_muted(*muted == OnOff::On).map_err(media_error)
+            }
+            Operation::Say => self.say(player, value),
+            Operation::NowPlaying => {
+                Err(Error::OperationNotSupported(api::Operation::Send, id.clone()))
+            }
+        }
+    }
+
+    fn fetch(&self,
+             id: &Id<Channel>,
+             player: &Arc<Player>,
+             operation: Operation)
+             -> Result<Option<Value>, Error> {
+        match operation {
+            Operation::Volume => {
+                let volume = try!(player.get_volume().map_err(media_error));
+                Ok(Some(Value::new(Json(serde_json::Value::U64(volume as u64)))))
+            }
+            Operation::Muted => {
+                let muted = try!(player.is_muted().map_err(media_error));
+                Ok(Some(Value::new(if muted { OnOff::On } else { OnOff::Off })))
+            }
+            Operation::NowPlaying => {
+                let now = try!(player.now_playing().map_err(media_error));
+                Ok(Some(Value::new(Json(json_value!({ state: now.state.name(), url: now.url })))))
+            }
+            _ => Err(Error::OperationNotSupported(api::Operation::Fetch, id.clone())),
+        }
+    }
+
+    fn lookup(&self, id: &Id<Channel>) -> Option<(Arc<Player>, Operation)> {
+        self.channels.lock().unwrap().get(id).cloned()
+    }
+}
+
+impl<C: Controller> Adapter for MediaAdapter<C> {
+    fn id(&self) -> Id<AdapterId> {
+        Self::id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> OpResult<Value> {
+        set.drain(..)
+            .map(|id| {
+                let result = match self.lookup(&id) {
+                    Some((player, operation)) => self.fetch(&id, &player, operation),
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        values.drain()
+            .map(|(id, value)| {
+                let result = match self.lookup(&id) {
+                    Some((player, operation)) => self.send(&id, &player, operation, &value),
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+describe! media_adapter {
+    before_each {
+        use foxbox_taxonomy::api::{API, Targetted, User};
+        use foxbox_taxonomy::io::Payload;
+        use foxbox_taxonomy::manager::AdapterManager;
+        use foxbox_taxonomy::selector::ChannelSelector;
+        use foxbox_taxonomy::services::Id;
+        use foxbox_taxonomy::values::{format, Json, OnOff, Value};
+        use serde_json;
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+        use stubs::controller::ControllerStub;
+
+        #[derive(Default)]
+        struct FakePlayer {
+            log: Mutex<Vec<String>>,
+            volume: Mutex<u32>,
+        }
+
+        impl Player for FakePlayer {
+            fn play(&self, url: &str) -> Result<(), MediaError> {
+                self.log.lock().unwrap().push(format!("play {}", url));
+                Ok(())
+            }
+            fn pause(&self) -> Result<(), MediaError> {
+                self.log.lock().unwrap().push("pause".to_owned());
+                Ok(())
+            }
+            fn resume(&self) -> Result<(), MediaError> {
+                Ok(())
+            }
+            fn stop(&self) -> Result<(), MediaError> {
+                Ok(())
+            }
+            fn get_volume(&self) -> Result<u32, MediaError> {
+                Ok(*self.volume.lock().unwrap())
+            }
+            fn set_volume(&self, volume: u32) -> Result<(), MediaError> {
+                *self.volume.lock().unwrap() = volume;
+                Ok(())
+            }
+            fn is_muted(&self) -> Result<bool, MediaError> {
+                Ok(false)
+            }
+            fn set_muted(&self, _: bool) -> Result<(), MediaError> {
+                Ok(())
+            }
+            fn now_playing(&self) -> Result<NowPlaying, MediaError> {
+                Ok(NowPlaying { state: PlaybackState::Stopped, url: None })
+            }
+        }
+
+        let manager = Arc::new(AdapterManager::new(None));
+        let adapter = MediaAdapter {
+            controller: ControllerStub::new(),
+            manager: manager.clone(),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            players: Arc::new(Mutex::new(HashMap::new())),
+            speech: None,
+        };
+        manager.add_adapter(Arc::new(adapter.clone())).unwrap();
+        let player = Arc::new(FakePlayer::default());
+        adapter.add_player("fake", "Fake player", "media/fake", player.clone()).unwrap();
+
+        let send = |feature: &str, value: Value, format| {
+            let payload = Payload::from_value(&value, format).unwrap();
+            let selector = ChannelSelector::new().with_feature(&Id::new(feature));
+            manager.send_values(vec![Targetted { select: vec![selector], payload: payload }],
+                                User::None)
+        };
+    }
+
+    it "should expose the players as services" {
+        assert!(adapter.has_player("fake"));
+        let channels = manager.get_channels(vec![ChannelSelector::new()]);
+        assert_eq!(channels.len(), Operation::all().len());
+    }
+
+    it "should forward commands to the player" {
+        for (_, result) in send("media/play-url",
+                                Value::new("http://example.org/chime.mp3".to_owned()),
+                                &format::STRING) {
+            result.unwrap();
+        }
+        let command = Value::new("pause".to_owned());
+        for (_, result) in send("media/transport", command, &format::STRING) {
+            result.unwrap();
+        }
+        assert_eq!(*player.log.lock().unwrap(),
+                   vec!["play http://example.org/chime.mp3".to_owned(), "pause".to_owned()]);
+    }
+
+    it "should reject invalid values" {
+        let command = Value::new("rewind".to_owned());
+        for (_, result) in send("media/transport", command, &format::STRING) {
+            assert!(result.is_err());
+        }
+        let volume = Value::new(Json(serde_json::Value::U64(150)));
+        for (_, result) in send("media/volume", volume, &format::JSON) {
+            assert!(result.is_err());
+        }
+        for url in &["file:///etc/passwd", "--input-ipc-server=/tmp/mpv", "/tmp/chime.mp3"] {
+            let value = Value::new(url.to_string());
+            for (_, result) in send("media/play-url", value, &format::STRING) {
+                assert!(result.is_err());
+            }
+        }
+        assert!(player.log.lock().unwrap().is_empty());
+    }
+
+    it "should set and fetch the volume" {
+        let volume = Value::new(Json(serde_json::Value::U64(30)));
+        for (_, result) in send("media/volume", volume, &format::JSON) {
+            result.unwrap();
+        }
+        let selector = ChannelSelector::new().with_feature(&Id::new("media/volume"));
+        let values = manager.fetch_values(vec![selector], User::None);
+        let (payload, format) = values.values().next().unwrap().as_ref().unwrap().clone().unwrap();
+        let value = payload.to_value(&format).unwrap();
+        assert_eq!(value.cast::<Json>().unwrap().0, serde_json::Value::U64(30));
+
+        let selector = ChannelSelector::new().with_feature(&Id::new("media/muted"));
+        let values = manager.fetch_values(vec![selector], User::None);
+        let (payload, format) = values.values().next().unwrap().as_ref().unwrap().clone().unwrap();
+        assert_eq!(*payload.to_value(&format).unwrap().cast::<OnOff>().unwrap(), OnOff::Off);
+    }
+
+    it "should remove players" {
+        adapter.remove_player("fake").unwrap();
+        assert!(!adapter.has_player("fake"));
+        assert!(manager.get_channels(vec![ChannelSelector::new()]).is_empty());
+    }
+}
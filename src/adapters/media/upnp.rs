@@ -0,0 +1,454 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Plays audio on the `UPnP`/DLNA MediaRenderers of the local network, through
+//! the SOAP actions of their AVTransport and RenderingControl services.
+
+use adapters::media::{MediaAdapter, MediaError, NowPlaying, PlaybackState, Player};
+use foxbox_core::traits::Controller;
+use foxbox_core::upnp::{UpnpListener, UpnpService};
+use foxbox_core::utils::{parse_simple_xml, parse_xml_elements};
+use hyper;
+use hyper::header::{Connection, ContentType, Headers};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+pub static MEDIA_RENDERER_TYPE: &'static str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+static MEDIA_RENDERER_DEVICE: &'static str = "urn:schemas-upnp-org:device:MediaRenderer:";
+static AV_TRANSPORT_TYPE: &'static str = "urn:schemas-upnp-org:service:AVTransport:";
+static RENDERING_CONTROL_TYPE: &'static str = "urn:schemas-upnp-org:service:RenderingControl:";
+
+/// How long to wait for a renderer to answer.
+const TIMEOUT_S: u64 = 5;
+
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for chr in value.chars() {
+        match chr {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(chr),
+        }
+    }
+    escaped
+}
+
+/// A service of a renderer, identified by its control url and type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoapService {
+    pub control_url: String,
+    pub service_type: String,
+}
+
+impl SoapService {
+    /// Invokes an action, and returns the output arguments by name.
+    pub fn call(&self,
+                action: &str,
+                args: &[(&str, &str)])
+                -> Result<HashMap<String, String>, MediaError> {
+        let mut body = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                                <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+                                s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+                                <s:Body><u:{} xmlns:u=\"{}\">",
+                               action,
+                               self.service_type);
+        for &(name, value) in args {
+            body.push_str(&format!("<{0}>{1}</{0}>", name, xml_escape(value)));
+        }
+        body.push_str(&format!("</u:{}></s:Body></s:Envelope>", action));
+
+        let mut headers = Headers::new();
+        headers.set(ContentType("text/xml; charset=utf-8".parse().unwrap()));
+        headers.set(Connection::close());
+        headers.set_raw("SOAPAction",
+                        vec![format!("\"{}#{}\"", self.service_type, action).into_bytes()]);
+
+        let mut client = hyper::Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(TIMEOUT_S)));
+        client.set_write_timeout(Some(Duration::from_secs(TIMEOUT_S)));
+        let mut response = try!(client.post(&self.control_url)
+            .headers(headers)
+            .body(body.as_str())
+            .send()
+            .map_err(|err| MediaError::Device(format!("{}: {}", self.control_url, err))));
+        let mut content = String::new();
+        try!(response.read_to_string(&mut content));
+
+        let values = try!(parse_simple_xml(Cursor::new(content))
+            .map_err(|err| MediaError::Device(format!("Invalid {} response: {}", action, err))));
+        // Faults come with a 500 status.
+        if values.keys().any(|key| key.contains("/Fault/")) {
+            let description = values.iter()
+                .find(|&(key, _)| key.ends_with("/errorDescription"))
+                .or_else(|| values.iter().find(|&(key, _)| key.ends_with("/faultstring")))
+                .map_or("Unknown error".to_owned(), |(_, value)| value.clone());
+            return Err(MediaError::Device(format!("{} failed: {}", action, description)));
+        }
+        if !response.status.is_success() {
+            return Err(MediaError::Device(format!("{} failed: {}", action, response.status)));
+        }
+        Ok(values.into_iter()
+            .filter_map(|(key, value)| key.rsplit('/').next().map(|name| (name.to_owned(), value)))
+            .collect())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpnpRenderer {
+    pub name: String,
+    pub av_transport: SoapService,
+    pub rendering_control: Option<SoapService>,
+}
+
+impl UpnpRenderer {
+    /// Finds the services of a renderer in its description, at `location`.
+    pub fn from_description(location: &str, description: &str) -> Result<Self, String> {
+        let values = try!(parse_simple_xml(Cursor::new(description)));
+        let base = values.get("/root/URLBase").map_or(location, |base| base.as_str());
+        let base = try!(Url::parse(base).map_err(|err| format!("Invalid url {}: {}", base, err)));
+
+        let mut av_transport = None;
+        let mut rendering_control = None;
+        for service in try!(parse_xml_elements(Cursor::new(description), "/serviceList/service")) {
+            let (service_type, control_url) = match (service.get("serviceType"),
+                                                     service.get("controlURL")) {
+                (Some(service_type), Some(control_url)) => (service_type, control_url),
+                _ => continue,
+            };
+            let control_url = try!(base.join(control_url.trim())
+                .map_err(|err| format!("Invalid control url {}: {}", control_url, err)));
+            let service = SoapService {
+                control_url: control_url.to_string(),
+                service_type: service_type.trim().to_owned(),
+            };
+            if service.service_type.starts_with(AV_TRANSPORT_TYPE) {
+                av_transport = Some(service);
+            } else if service.service_type.starts_with(RENDERING_CONTROL_TYPE) {
+                rendering_control = Some(service);
+            }
+        }
+
+        match av_transport {
+            Some(av_transport) => {
+                Ok(UpnpRenderer {
+                    name: values.get("/root/device/friendlyName")
+                        .map_or("Media renderer".to_owned(), |name| name.trim().to_owned()),
+                    av_transport: av_transport,
+                    rendering_control: rendering_control,
+                })
+            }
+            None => Err("No AVTransport service".to_owned()),
+        }
+    }
+
+    fn transport(&self,
+                 action: &str,
+                 args: &[(&str, &str)])
+                 -> Result<HashMap<String, String>, MediaError> {
+        let mut all_args = vec![("InstanceID", "0")];
+        all_args.extend_from_slice(args);
+        self.av_transport.call(action, &all_args)
+    }
+
+    fn rendering(&self,
+                 action: &str,
+                 args: &[(&str, &str)])
+                 -> Result<HashMap<String, String>, MediaError> {
+        match self.rendering_control {
+            Some(ref service) => {
+                let mut all_args = vec![("InstanceID", "0"), ("Channel", "Master")];
+                all_args.extend_from_slice(args);
+                service.call(action, &all_args)
+            }
+            None => Err(MediaError::Unsupported("volume control")),
+        }
+    }
+}
+
+fn get_output(values: &HashMap<String, String>, name: &str) -> Result<String, MediaError> {
+    match values.get(name) {
+        Some(value) => Ok(value.trim().to_owned()),
+        None => Err(MediaError::Device(format!("Missing {} in the response", name))),
+    }
+}
+
+impl Player for UpnpRenderer {
+    fn play(&self, url: &str) -> Result<(), MediaError> {
+        try!(self.transport("SetAVTransportURI",
+                            &[("CurrentURI", url), ("CurrentURIMetaData", "")]));
+        self.resume()
+    }
+
+    fn pause(&self) -> Result<(), MediaError> {
+        self.transport("Pause", &[]).map(|_| ())
+    }
+
+    fn resume(&self) -> Result<(), MediaError> {
+        self.transport("Play", &[("Speed", "1")]).map(|_| ())
+    }
+
+    fn stop(&self) -> Result<(), MediaError> {
+        self.transport("Stop", &[]).map(|_| ())
+    }
+
+    fn get_volume(&self) -> Result<u32, MediaError> {
+        let values = try!(self.rendering("GetVolume", &[]));
+        let volume = try!(get_output(&values, "CurrentVolume"));
+        volume.parse::<u32>()
+            .map_err(|_| MediaError::Device(format!("Invalid volume {}", volume)))
+    }
+
+    fn set_volume(&self, volume: u32) -> Result<(), MediaError> {
+        self.rendering("SetVolume", &[("DesiredVolume", &volume.to_string())]).map(|_| ())
+    }
+
+    fn is_muted(&self) -> Result<bool, MediaError> {
+        let values = try!(self.rendering("GetMute", &[]));
+        let muted = try!(get_output(&values, "CurrentMute"));
+        Ok(muted == "1" || muted == "true")
+    }
+
+    fn set_muted(&self, muted: bool) -> Result<(), MediaError> {
+        self.rendering("SetMute", &[("DesiredMute", if muted { "1" } else { "0" })]).map(|_| ())
+    }
+
+    fn now_playing(&self) -> Result<NowPlaying, MediaError> {
+        let info = try!(self.transport("GetTransportInfo", &[]));
+        let state = match &try!(get_output(&info, "CurrentTransportState")) as &str {
+            "PLAYING" => PlaybackState::Playing,
+            "PAUSED_PLAYBACK" | "PAUSED_RECORDING" => PlaybackState::Paused,
+            "TRANSITIONING" => PlaybackState::Transitioning,
+            _ => PlaybackState::Stopped,
+        };
+        let url = if state == PlaybackState::Stopped {
+            None
+        } else {
+            let media = try!(self.transport("GetMediaInfo", &[]));
+            media.get("CurrentURI").map(|url| url.trim().to_owned()).and_then(|url| {
+                if url.is_empty() { None } else { Some(url) }
+            })
+        };
+        Ok(NowPlaying {
+            state: state,
+            url: url,
+        })
+    }
+}
+
+/// Extracts the device UDN from an USN such as
+/// `uuid:4d696e69-444c-164e-9d41-b827eb54e939::urn:schemas-upnp-org:device:MediaRenderer:1`.
+fn udn_from_usn(usn: &str) -> String {
+    usn.split("::").next().unwrap_or(usn).trim_left_matches("uuid:").to_owned()
+}
+
+/// The local id of the player of a renderer.
+fn local_id(udn: &str) -> String {
+    let udn: String = udn.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    format!("upnp-{}", udn)
+}
+
+/// Adds the MediaRenderers as they show up, and removes them when they leave.
+pub struct RendererListener<C> {
+    adapter: MediaAdapter<C>,
+}
+
+impl<C: Controller> RendererListener<C> {
+    pub fn new(adapter: MediaAdapter<C>) -> Box<Self> {
+        Box::new(RendererListener { adapter: adapter })
+    }
+}
+
+impl<C: Controller> UpnpListener for RendererListener<C> {
+    fn upnp_discover(&self, service: &UpnpService) -> bool {
+        let local_id = local_id(&udn_from_usn(&service.msearch.device_id));
+        if !service.msearch.alive {
+            if self.adapter.has_player(&local_id) {
+                info!("[Media] Renderer {} left", local_id);
+                let _ = self.adapter.remove_player(&local_id);
+                return true;
+            }
+            return false;
+        }
+
+        match service.description.get("/root/device/deviceType") {
+            Some(device_type) if device_type.starts_with(MEDIA_RENDERER_DEVICE) => {}
+            _ => return false,
+        }
+        if self.adapter.has_player(&local_id) {
+            return true;
+        }
+
+        match UpnpRenderer::from_description(&service.msearch.location,
+                                             &service.description_data) {
+            Ok(renderer) => {
+                let name = renderer.name.clone();
+                if let Err(err) = self.adapter
+                    .add_player(&local_id, &name, "media/upnp", Arc::new(renderer)) {
+                    warn!("[Media] Could not add renderer {}: {:?}", name, err);
+                }
+            }
+            Err(err) => {
+                warn!("[Media] Unsupported renderer at {}: {}",
+                      service.msearch.location,
+                      err)
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+describe! upnp_renderer {
+    before_each {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+
+        // A stand-in renderer, which records the actions it receives and
+        // answers with canned responses.
+        let requests = Arc::new(Mutex::new(Vec::<(String, String)>::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        {
+            let requests = requests.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut action = String::new();
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        let line = line.trim().to_owned();
+                        if line.is_empty() {
+                            break;
+                        }
+                        let lower = line.to_lowercase();
+                        if lower.starts_with("soapaction:") {
+                            action = line.rsplit('#').next().unwrap().trim_matches('"').to_owned();
+                        } else if lower.starts_with("content-length:") {
+                            length = line[15..].trim().parse().unwrap();
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    let body = String::from_utf8(body).unwrap();
+                    requests.lock().unwrap().push((action.clone(), body));
+
+                    let (status, content) = match &action as &str {
+                        "GetVolume" => ("200 OK", "<CurrentVolume>42</CurrentVolume>"),
+                        "GetMute" => ("200 OK", "<CurrentMute>1</CurrentMute>"),
+                        "GetTransportInfo" => {
+                            ("200 OK",
+                             "<CurrentTransportState>PAUSED_PLAYBACK</CurrentTransportState>")
+                        }
+                        "GetMediaInfo" => {
+                            ("200 OK", "<CurrentURI>http://example.org/a.mp3</CurrentURI>")
+                        }
+                        "Seek" => {
+                            ("500 Internal Server Error",
+                             "<s:Fault><faultstring>UPnPError</faultstring><detail><UPnPError>\
+                              <errorCode>710</errorCode><errorDescription>Seek mode not supported\
+                              </errorDescription></UPnPError></detail></s:Fault>")
+                        }
+                        _ => ("200 OK", ""),
+                    };
+                    let response = format!("<?xml version=\"1.0\"?><s:Envelope \
+                                            xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+                                            <s:Body>{}</s:Body></s:Envelope>",
+                                           content);
+                    write!(stream,
+                           "HTTP/1.1 {}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\
+                            Connection: close\r\n\r\n{}",
+                           status,
+                           response.len(),
+                           response)
+                        .unwrap();
+                }
+            });
+        }
+
+        let description = "<?xml version=\"1.0\"?>\
+            <root xmlns=\"urn:schemas-upnp-org:device-1-0\">\
+            <device>\
+            <deviceType>urn:schemas-upnp-org:device:MediaRenderer:1</deviceType>\
+            <friendlyName>Living room speaker</friendlyName>\
+            <UDN>uuid:5f9ec1b3-ed59-1900-4530-00a0dea0b7c1</UDN>\
+            <serviceList>\
+            <service>\
+            <serviceType>urn:schemas-upnp-org:service:RenderingControl:1</serviceType>\
+            <controlURL>/RenderingControl/ctrl</controlURL>\
+            </service>\
+            <service>\
+            <serviceType>urn:schemas-upnp-org:service:AVTransport:1</serviceType>\
+            <controlURL>AVTransport/ctrl</controlURL>\
+            </service>\
+            </serviceList>\
+            </device>\
+            </root>";
+        let location = format!("http://127.0.0.1:{}/description.xml", port);
+        let renderer = UpnpRenderer::from_description(&location, description).unwrap();
+    }
+
+    it "should find the services in the description" {
+        assert_eq!(renderer.name, "Living room speaker");
+        assert_eq!(renderer.av_transport.control_url,
+                   format!("http://127.0.0.1:{}/AVTransport/ctrl", port));
+        assert_eq!(renderer.rendering_control.as_ref().unwrap().control_url,
+                   format!("http://127.0.0.1:{}/RenderingControl/ctrl", port));
+    }
+
+    it "should set the uri then play" {
+        renderer.play("http://example.org/chime.mp3?a=1&b=2").unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].0, "SetAVTransportURI");
+        assert!(requests[0].1
+            .contains("<CurrentURI>http://example.org/chime.mp3?a=1&amp;b=2</CurrentURI>"));
+        assert!(requests[0].1.contains("xmlns:u=\"urn:schemas-upnp-org:service:AVTransport:1\""));
+        assert_eq!(requests[1].0, "Play");
+        assert!(requests[1].1.contains("<InstanceID>0</InstanceID><Speed>1</Speed>"));
+    }
+
+    it "should control the volume" {
+        assert_eq!(renderer.get_volume().unwrap(), 42);
+        assert_eq!(renderer.is_muted().unwrap(), true);
+        renderer.set_volume(10).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[2].0, "SetVolume");
+        assert!(requests[2].1
+            .contains("<Channel>Master</Channel><DesiredVolume>10</DesiredVolume>"));
+    }
+
+    it "should report what is playing" {
+        assert_eq!(renderer.now_playing().unwrap(),
+                   NowPlaying {
+                       state: PlaybackState::Paused,
+                       url: Some("http://example.org/a.mp3".to_owned()),
+                   });
+    }
+
+    it "should report faults" {
+        match renderer.av_transport.call("Seek", &[("InstanceID", "0")]) {
+            Err(MediaError::Device(msg)) => assert!(msg.contains("Seek mode not supported")),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    it "should extract the udn of the renderers" {
+        assert_eq!(udn_from_usn("uuid:5f9ec1b3-ed59::urn:schemas-upnp-org:device:MediaRenderer:1"),
+                   "5f9ec1b3-ed59");
+        assert_eq!(local_id("5f9ec1b3-ed59"), "upnp-5f9ec1b3-ed59");
+    }
+}
@@ -5,9 +5,27 @@
 /// An adapter providing time services.
 pub mod clock;
 
+/// An adapter exposing the state of the TLS certificates.
+pub mod certificates;
+
 /// An adapter displaying messages on the console.
 pub mod console;
 
+/// An adapter exposing groups of devices as aggregate services.
+pub mod groups;
+
+/// An adapter playing audio locally and on `UPnP` MediaRenderers.
+pub mod media;
+
+/// An adapter exposing scenes, i.e. stored values of several channels.
+pub mod scenes;
+
+/// An adapter providing virtual switches and variables.
+pub mod virtual_devices;
+
+/// An adapter POSTing the events of channels to HTTP callbacks.
+pub mod webhooks;
+
 /// A Text To Speak adapter
 #[cfg(target_os = "linux")]
 pub mod tts;
@@ -30,6 +48,9 @@ pub mod webpush;
 
 use foxbox_taxonomy::manager::AdapterManager as TaxoManager;
 
+#[cfg(feature = "zwave")]
+use foxbox_core::config_schema::ConfigKey;
+
 #[cfg(feature = "thinkerbell")]
 use self::thinkerbell::ThinkerbellAdapter;
 use foxbox_core::traits::Controller;
@@ -39,6 +60,13 @@ use openzwave;
 
 use std::sync::Arc;
 
+/// The Z-Wave controller devices, e.g. `/dev/ttyUSB0`. Empty to look for them.
+#[cfg(feature = "zwave")]
+fn openzwave_devices_config() -> ConfigKey {
+    ConfigKey::string("openzwave", "devices", "")
+        .description("Comma separated list of Z-Wave controller devices")
+}
+
 #[allow(dead_code)] // workaround for buggy "struct field is never used: `controller`" warning.
 pub struct AdapterManager<T> {
     controller: T,
@@ -51,20 +79,38 @@ impl<T: Controller> AdapterManager<T> {
     }
 
     #[cfg(target_os = "linux")]
-    fn start_tts(&self, manager: &Arc<TaxoManager>) {
-        tts::init(manager).unwrap();
+    fn start_tts(&self, manager: &Arc<TaxoManager>) -> Option<Arc<media::SpeechRenderer>> {
+        match tts::init(self.controller.clone(), manager) {
+            Ok(renderer) => renderer,
+            Err(err) => {
+                error!("Failed to start the text to speech adapter: {:?}", err);
+                None
+            }
+        }
     }
 
     #[cfg(not(target_os = "linux"))]
-    fn start_tts(&self, _: &Arc<TaxoManager>) {
+    fn start_tts(&self, _: &Arc<TaxoManager>) -> Option<Arc<media::SpeechRenderer>> {
         info!("No tts support on this platform.");
+        None
+    }
+
+    fn start_media(&self,
+                   manager: &Arc<TaxoManager>,
+                   speech: Option<Arc<media::SpeechRenderer>>) {
+        if let Err(err) = media::MediaAdapter::init(self.controller.clone(), manager, speech) {
+            error!("Failed to start the media adapter: {:?}", err);
+        }
     }
 
     #[cfg(feature = "zwave")]
     fn start_zwave(&self, manager: &Arc<TaxoManager>) {
         let profile_openzwave = &self.controller.get_profile().path_for("openzwave");
 
-        let openzwave_devices = self.controller.clone().get_config().get("openzwave", "devices");
+        let config = self.controller.get_config();
+        config.register(openzwave_devices_config());
+        let devices = config.get_value(&openzwave_devices_config());
+        let openzwave_devices = if devices.is_empty() { None } else { Some(devices) };
         openzwave::Adapter::init(manager, profile_openzwave, openzwave_devices).unwrap(); // FIXME convert to a local error
     }
 
@@ -117,14 +163,20 @@ impl<T: Controller> AdapterManager<T> {
     /// Start all the adapters.
     pub fn start(&mut self, manager: &Arc<TaxoManager>) {
         console::Console::init(manager).unwrap(); // FIXME: We should have a way to report errors
-        clock::Clock::init(manager).unwrap(); // FIXME: We should have a way to report errors
+        clock::Clock::init(self.controller.clone(), manager).unwrap(); // FIXME: We should have a way to report errors
+        certificates::Certificates::init(self.controller.clone(), manager).unwrap(); // FIXME: We should have a way to report errors
+        scenes::SceneAdapter::init(self.controller.clone(), manager).unwrap(); // FIXME: We should have a way to report errors
+        virtual_devices::VirtualAdapter::init(self.controller.clone(), manager).unwrap(); // FIXME: We should have a way to report errors
+        groups::GroupAdapter::init(self.controller.clone(), manager).unwrap(); // FIXME: We should have a way to report errors
+        webhooks::WebhookAdapter::init(self.controller.clone(), manager).unwrap(); // FIXME: We should have a way to report errors
 
         self.start_webpush(manager);
         self.start_ip_camera(manager);
         self.start_thinkerbell(manager);
         self.start_philips_hue(manager);
         self.start_zwave(manager);
-        self.start_tts(manager);
+        let speech = self.start_tts(manager);
+        self.start_media(manager, speech);
     }
 
     /// Stop all the adapters.
@@ -18,7 +18,7 @@ use foxbox_core::upnp::{UpnpListener, UpnpManager, UpnpService};
 use serde_json;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use super::{HueAction, http, PhilipsHueAdapter};
+use super::{HueAction, http, nupnp_enabled_config, nupnp_url_config, PhilipsHueAdapter};
 use transformable_channels::mpsc::*;
 
 static UPNP_MODEL_PATH: &'static str = "/root/device/modelName";
@@ -49,13 +49,9 @@ impl<C: Controller> Discovery<C> {
         let controller = self.adapter.controller.clone();
         let tx = self.adapter.tx.clone();
         thread::spawn(move || {
-            let nupnp_enabled = controller.get_config()
-                .get_or_set_default("philips_hue", "nupnp_enabled", "true");
-            if nupnp_enabled == "true" {
-                let nupnp_url = controller.get_config().get_or_set_default("philips_hue",
-                                                                           "nupnp_url",
-                                                                           "https://www.meethue.\
-                                                                            com/api/nupnp");
+            let config = controller.get_config();
+            if config.get_bool(&nupnp_enabled_config()) {
+                let nupnp_url = config.get_value(&nupnp_url_config());
                 let nupnp_hubs = nupnp_query(&nupnp_url);
                 for nupnp in nupnp_hubs {
                     let _ = tx.lock().unwrap().send(HueAction::AddHub(nupnp.id.to_owned(),
@@ -15,7 +15,7 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use super::hub_api::HubApi;
-use super::{HueAction, PhilipsHueAdapter, structs};
+use super::{bridge_token_config, HueAction, PhilipsHueAdapter, structs};
 use foxbox_core::traits::Controller;
 
 pub struct Hub<C> {
@@ -27,14 +27,12 @@ pub struct Hub<C> {
 
 impl<C: Controller> Hub<C> {
     pub fn new(adapter: PhilipsHueAdapter<C>, id: &str, ip: &str) -> Self {
-        // Get API token from config store, default to a random UUID.
+        // Get API token from config store, "unauthorized" until paired.
         // The API token is used like a password when pairing with a
         // Philips Hue bridge. Once paired, it is sent with every
         // API request for authentication purposes, so it is crucial
         // that it is not predictable.
-        let token = adapter.controller
-            .get_config()
-            .get_or_set_default("philips_hue", &format!("token_{}", id), "unauthorized");
+        let token = adapter.controller.get_config().get_value(&bridge_token_config(id));
         Hub {
             adapter: adapter,
             id: id.to_owned(),
@@ -73,9 +71,11 @@ impl<C: Controller> Hub<C> {
                             Ok(Some(new_token)) => {
                                 info!("Pairing success with Philips Hue Bridge {}", id);
                                 // Save the new token
-                                adapter.controller
+                                if let Err(err) = adapter.controller
                                     .get_config()
-                                    .set("philips_hue", &format!("token_{}", id), &new_token);
+                                    .set_value(&bridge_token_config(&id), &new_token) {
+                                    error!("Could not save the Philips Hue token: {}", err);
+                                }
                                 api.lock().unwrap().update_token(&new_token);
                                 break;
                             }
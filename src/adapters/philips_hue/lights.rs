@@ -16,8 +16,6 @@ use super::*;
 use super::hub_api::HubApi;
 use std::sync::{Arc, Mutex};
 
-const CUSTOM_PROPERTY_MANUFACTURER: &'static str = "manufacturer";
-const CUSTOM_PROPERTY_MODEL: &'static str = "model";
 const CUSTOM_PROPERTY_NAME: &'static str = "name";
 const CUSTOM_PROPERTY_TYPE: &'static str = "type";
 
@@ -63,9 +61,12 @@ impl Light {
                 self.light_id, self.hub_id);
 
             let mut service = Service::empty(&self.service_id, &adapter_id);
-            service.properties.insert(CUSTOM_PROPERTY_MANUFACTURER.to_owned(),
+            service.properties.insert(properties::MANUFACTURER.to_owned(),
                                       status.manufacturername.to_owned());
-            service.properties.insert(CUSTOM_PROPERTY_MODEL.to_owned(), status.modelid.to_owned());
+            service.properties.insert(properties::MODEL.to_owned(), status.modelid.to_owned());
+            service.properties.insert(properties::FIRMWARE.to_owned(),
+                                      status.swversion.to_owned());
+            service.properties.insert(properties::ICON.to_owned(), "light".to_owned());
             service.properties.insert(CUSTOM_PROPERTY_NAME.to_owned(), status.name.to_owned());
             service.properties.insert(CUSTOM_PROPERTY_TYPE.to_owned(),
                                       "Light/ColorLight".to_owned());
@@ -111,9 +112,12 @@ impl Light {
             info!("New Philips Hue `Dimmable Light` service for light {} on bridge {}",
                 self.light_id, self.hub_id);
             let mut service = Service::empty(&self.service_id, &adapter_id);
-            service.properties.insert(CUSTOM_PROPERTY_MANUFACTURER.to_owned(),
+            service.properties.insert(properties::MANUFACTURER.to_owned(),
                                       status.manufacturername.to_owned());
-            service.properties.insert(CUSTOM_PROPERTY_MODEL.to_owned(), status.modelid.to_owned());
+            service.properties.insert(properties::MODEL.to_owned(), status.modelid.to_owned());
+            service.properties.insert(properties::FIRMWARE.to_owned(),
+                                      status.swversion.to_owned());
+            service.properties.insert(properties::ICON.to_owned(), "light".to_owned());
             service.properties.insert(CUSTOM_PROPERTY_NAME.to_owned(), status.name.to_owned());
             service.properties.insert(CUSTOM_PROPERTY_TYPE.to_owned(),
                                       "Light/DimmerLight".to_owned());
@@ -17,6 +17,7 @@ pub mod hub_api;
 pub mod lights;
 pub mod structs;
 
+use foxbox_core::config_schema::ConfigKey;
 use foxbox_core::traits::Controller;
 use foxbox_taxonomy::api::{Error, InternalError, User};
 use foxbox_taxonomy::channel::*;
@@ -35,6 +36,28 @@ static ADAPTER_NAME: &'static str = "Philips Hue adapter (built-in)";
 static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
 static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
 
+/// Whether bridges are discovered through the Philips nUPnP web service.
+pub fn nupnp_enabled_config() -> ConfigKey {
+    ConfigKey::boolean("philips_hue", "nupnp_enabled", true)
+}
+
+/// The URL of the Philips nUPnP web service.
+pub fn nupnp_url_config() -> ConfigKey {
+    ConfigKey::string("philips_hue",
+                      "nupnp_url",
+                      "https://www.meethue.com/api/nupnp")
+}
+
+/// The per-bridge API tokens, stored as `token_<bridge id>`.
+pub fn token_config() -> ConfigKey {
+    ConfigKey::string("philips_hue", "token_*", "unauthorized").secret()
+}
+
+/// The API token of one bridge, declared by `token_config()`.
+pub fn bridge_token_config(hub_id: &str) -> ConfigKey {
+    ConfigKey::string("philips_hue", &format!("token_{}", hub_id), "unauthorized").secret()
+}
+
 /// Philips Hue Adapter's main loop handles messages of these types.
 #[allow(dead_code)]
 pub enum HueAction {
@@ -72,6 +95,13 @@ pub struct PhilipsHueAdapter<C> {
 impl<C: Controller> PhilipsHueAdapter<C> {
     #[allow(dead_code)]
     pub fn init(manager: &Arc<AdapterManager>, controller: C) -> Result<(), Error> {
+        {
+            let config = controller.get_config();
+            config.register(nupnp_enabled_config());
+            config.register(nupnp_url_config());
+            config.register(token_config());
+        }
+
         let services = Arc::new(Mutex::new(LightServiceMapInternal {
             getters: HashMap::new(),
             setters: HashMap::new(),
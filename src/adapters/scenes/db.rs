@@ -0,0 +1,159 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Stores the scenes.
+//!
+//! # The scenes database
+//!
+//! The "scenes" table has one row per scene. The values of a scene are
+//! stored as a JSON array of `{"channel": ..., "value": ...}` objects, in
+//! the format of the REST API.
+
+use foxbox_core::migrations::{self, Migration};
+use rusqlite::{self, Connection};
+use serde_json;
+use super::{Scene, SceneValue};
+
+/// The schema version of the scenes database written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The schema history of the scenes database.
+static MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    Migration {
+        version: 1,
+        description: "Create the scenes table",
+        sql: "CREATE TABLE IF NOT EXISTS scenes (
+                  id            TEXT NOT NULL PRIMARY KEY,
+                  name          TEXT NOT NULL,
+                  scene_values  TEXT NOT NULL
+              );",
+    },
+];
+
+pub struct SceneDb {
+    db: Connection,
+}
+
+fn parse_values(id: &str, source: &str) -> Vec<SceneValue> {
+    serde_json::from_str(source).unwrap_or_else(|err| {
+        warn!("[scenes] The values of scene {} cannot be parsed: {}", id, err);
+        vec![]
+    })
+}
+
+impl SceneDb {
+    /// Opens the database at `path` and creates it if not available yet.
+    /// Panics if the database can't be opened or migrated to the current schema.
+    pub fn new(path: &str) -> Self {
+        let mut db = Connection::open(path).unwrap();
+        migrations::migrate(&mut db, "scenes", &MIGRATIONS).unwrap_or_else(|err| {
+            panic!("Unable to migrate the scenes database: {}", err);
+        });
+
+        SceneDb { db: db }
+    }
+
+    /// Gets all the scenes, sorted by id.
+    pub fn list(&self) -> rusqlite::Result<Vec<Scene>> {
+        let mut scenes = Vec::new();
+        let mut stmt = try!(self.db
+            .prepare("SELECT id, name, scene_values FROM scenes ORDER BY id"));
+        let mut rows = try!(stmt.query(&[]));
+        while let Some(result_row) = rows.next() {
+            let row = try!(result_row);
+            let id: String = row.get(0);
+            let values: String = row.get(2);
+            scenes.push(Scene {
+                values: parse_values(&id, &values),
+                id: id,
+                name: row.get(1),
+            });
+        }
+        Ok(scenes)
+    }
+
+    pub fn get(&self, id: &str) -> rusqlite::Result<Option<Scene>> {
+        let mut stmt = try!(self.db.prepare("SELECT name, scene_values FROM scenes WHERE id=$1"));
+        let mut rows = try!(stmt.query(&[&id]));
+        match rows.next() {
+            Some(result_row) => {
+                let row = try!(result_row);
+                let values: String = row.get(1);
+                Ok(Some(Scene {
+                    id: id.to_owned(),
+                    name: row.get(0),
+                    values: parse_values(id, &values),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Adds a scene, or replaces the scene with the same id.
+    pub fn put(&self, scene: &Scene) -> rusqlite::Result<()> {
+        // Serializing a Vec of plain structs can't fail.
+        let values = serde_json::to_string(&scene.values).unwrap();
+        try!(self.db.execute("INSERT OR REPLACE INTO scenes VALUES ($1, $2, $3)",
+                             &[&scene.id, &scene.name, &values]));
+        Ok(())
+    }
+
+    /// Removes a scene. Returns false if there was no such scene.
+    pub fn remove(&self, id: &str) -> rusqlite::Result<bool> {
+        let count = try!(self.db.execute("DELETE FROM scenes WHERE id=$1", &[&id]));
+        Ok(count > 0)
+    }
+}
+
+#[cfg(test)]
+describe! scene_db {
+    before_each {
+        use serde_json;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("scenes").unwrap();
+        let path = dir.path().join("scenes.sqlite");
+        let db = SceneDb::new(path.to_str().unwrap());
+
+        let scene = Scene {
+            id: "movie-night".to_owned(),
+            name: "Movie night".to_owned(),
+            values: vec![SceneValue {
+                channel: "setter:light-1@link.mozilla.org".to_owned(),
+                value: serde_json::Value::String("Off".to_owned()),
+            }],
+        };
+    }
+
+    it "should store scenes" {
+        assert_eq!(db.list().unwrap(), vec![]);
+        db.put(&scene).unwrap();
+        assert_eq!(db.get("movie-night").unwrap(), Some(scene.clone()));
+        assert_eq!(db.list().unwrap(), vec![scene.clone()]);
+        assert_eq!(db.get("good-night").unwrap(), None);
+    }
+
+    it "should replace scenes with the same id" {
+        db.put(&scene).unwrap();
+        let mut renamed = scene.clone();
+        renamed.name = "Cinema".to_owned();
+        renamed.values.clear();
+        db.put(&renamed).unwrap();
+        assert_eq!(db.list().unwrap(), vec![renamed]);
+    }
+
+    it "should remove scenes" {
+        db.put(&scene).unwrap();
+        assert!(db.remove("movie-night").unwrap());
+        assert!(!db.remove("movie-night").unwrap());
+        assert_eq!(db.get("movie-night").unwrap(), None);
+    }
+
+    it "should keep scenes across reopening" {
+        db.put(&scene).unwrap();
+        drop(db);
+        let db = SceneDb::new(path.to_str().unwrap());
+        assert_eq!(db.list().unwrap(), vec![scene]);
+    }
+}
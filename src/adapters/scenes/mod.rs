@@ -0,0 +1,538 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Scenes: named snapshots of the values of several channels, e.g.
+//! "Movie night" or "Good night".
+//!
+//! A scene is captured from the current values of the channels matching a
+//! set of `ChannelSelector`s, and stored in the profile. Each scene is exposed
+//! as a service with a `scene/activate` setter, which sends all the values
+//! back at once. Scenes are edited with the REST API, see `scenes_router`.
+//!
+//! A scene may not activate scenes, including itself, as that could recurse
+//! without end.
+
+pub mod db;
+
+use foxbox_core::traits::Controller;
+use foxbox_core::utils;
+use foxbox_taxonomy::adapter::*;
+use foxbox_taxonomy::api::{API, Error, InternalError, Targetted, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::parse::{Parser, Path, ToJSON};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::services::{AdapterId, Id, Service, ServiceId};
+use foxbox_taxonomy::util::Maybe;
+use foxbox_taxonomy::values::Value;
+use rusqlite;
+use serde_json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use self::db::SceneDb;
+
+static ADAPTER_NAME: &'static str = "Scenes adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+/// The value of a single channel in a scene.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SceneValue {
+    /// The id of the channel.
+    pub channel: String,
+    /// The value to send, as returned by `channels/get`.
+    pub value: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Scene {
+    pub id: String,
+    pub name: String,
+    pub values: Vec<SceneValue>,
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    NoSuchScene(String),
+    InvalidScene(String),
+    Database(rusqlite::Error),
+    /// Some values could not be sent when activating a scene.
+    Activation(Vec<(String, Error)>),
+    Api(Error),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SceneError::NoSuchScene(ref id) => write!(f, "No such scene: {}", id),
+            SceneError::InvalidScene(ref msg) => write!(f, "Invalid scene: {}", msg),
+            SceneError::Database(ref err) => write!(f, "Database error: {}", err),
+            SceneError::Activation(ref errors) => {
+                try!(write!(f, "Some values could not be sent:"));
+                for &(ref channel, ref err) in errors {
+                    try!(write!(f, " {}: {:?};", channel, err));
+                }
+                Ok(())
+            }
+            SceneError::Api(ref err) => write!(f, "{:?}", err),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for SceneError {
+    fn from(err: rusqlite::Error) -> Self {
+        SceneError::Database(err)
+    }
+}
+
+impl From<Error> for SceneError {
+    fn from(err: Error) -> Self {
+        SceneError::Api(err)
+    }
+}
+
+/// Access to the stored scenes and to their services. The REST API and the
+/// adapter each have their own instance, the database being shared.
+#[derive(Clone)]
+pub struct Scenes {
+    manager: Arc<AdapterManager>,
+    db_path: String,
+}
+
+impl Scenes {
+    pub fn new(manager: &Arc<AdapterManager>, db_path: &str) -> Self {
+        Scenes {
+            manager: manager.clone(),
+            db_path: db_path.to_owned(),
+        }
+    }
+
+    pub fn adapter_id() -> Id<AdapterId> {
+        Id::new("scenes@link.mozilla.org")
+    }
+
+    fn service_id(id: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.scenes@link.mozilla.org", id))
+    }
+
+    fn activate_id(id: &str) -> Id<Channel> {
+        Id::new(&format!("setter:activate.{}.scenes@link.mozilla.org", id))
+    }
+
+    fn get_db(&self) -> SceneDb {
+        SceneDb::new(&self.db_path)
+    }
+
+    pub fn list(&self) -> Result<Vec<Scene>, SceneError> {
+        Ok(try!(self.get_db().list()))
+    }
+
+    pub fn get(&self, id: &str) -> Result<Scene, SceneError> {
+        match try!(self.get_db().get(id)) {
+            Some(scene) => Ok(scene),
+            None => Err(SceneError::NoSuchScene(id.to_owned())),
+        }
+    }
+
+    /// Returns an id derived from `name` that no scene uses yet.
+    fn new_id(&self, db: &SceneDb, name: &str) -> Result<String, SceneError> {
+        let base = utils::slug(name, "scene");
+        let mut id = base.clone();
+        let mut suffix = 1;
+        while try!(db.get(&id)).is_some() {
+            suffix += 1;
+            id = format!("{}-{}", base, suffix);
+        }
+        Ok(id)
+    }
+
+    /// Stores the current values of the channels matching `selectors` as a new scene.
+    /// Only the channels that support both fetch and send are captured.
+    pub fn capture(&self,
+                   name: &str,
+                   selectors: Vec<ChannelSelector>,
+                   user: &User)
+                   -> Result<Scene, SceneError> {
+        let selectors: Vec<_> = self.manager
+            .get_channels(selectors)
+            .iter()
+            .filter(|channel| channel.supports_fetch.is_some() && channel.supports_send.is_some())
+            .map(|channel| ChannelSelector::new().with_id(&channel.id))
+            .collect();
+        if selectors.is_empty() {
+            return Err(SceneError::InvalidScene("No channel can be captured".to_owned()));
+        }
+
+        let mut values = vec![];
+        for (id, result) in self.manager.fetch_values(selectors, user.clone()) {
+            match result {
+                Ok(Some((payload, _))) => {
+                    values.push(SceneValue {
+                        channel: id.to_string(),
+                        value: payload.to_json(),
+                    })
+                }
+                Ok(None) => {}
+                Err(err) => warn!("[scenes] Could not capture {}: {:?}", id, err),
+            }
+        }
+        values.sort_by(|a, b| a.channel.cmp(&b.channel));
+
+        let db = self.get_db();
+        let scene = Scene {
+            id: try!(self.new_id(&db, name)),
+            name: name.to_owned(),
+            values: values,
+        };
+        try!(self.check(&scene));
+        try!(db.put(&scene));
+        try!(self.add_service(&scene));
+        Ok(scene)
+    }
+
+    /// Adds a scene given as JSON, with an id derived from its name.
+    pub fn create(&self, name: &str, values: Vec<SceneValue>) -> Result<Scene, SceneError> {
+        let db = self.get_db();
+        let scene = Scene {
+            id: try!(self.new_id(&db, name)),
+            name: name.to_owned(),
+            values: values,
+        };
+        try!(self.check(&scene));
+        try!(db.put(&scene));
+        try!(self.add_service(&scene));
+        Ok(scene)
+    }
+
+    /// Replaces an existing scene.
+    pub fn update(&self, scene: &Scene) -> Result<(), SceneError> {
+        let db = self.get_db();
+        if try!(db.get(&scene.id)).is_none() {
+            return Err(SceneError::NoSuchScene(scene.id.clone()));
+        }
+        try!(self.check(scene));
+        try!(db.put(scene));
+        // Service properties can't change, so the service is added again with the new name.
+        let _ = self.manager.remove_service(&Self::service_id(&scene.id));
+        try!(self.add_service(scene));
+        Ok(())
+    }
+
+    pub fn remove(&self, id: &str) -> Result<(), SceneError> {
+        if !try!(self.get_db().remove(id)) {
+            return Err(SceneError::NoSuchScene(id.to_owned()));
+        }
+        let _ = self.manager.remove_service(&Self::service_id(id));
+        Ok(())
+    }
+
+    /// Sends all the values of a scene, in a single batch. The values of the
+    /// channels of scenes are never sent.
+    pub fn activate(&self, id: &str, user: &User) -> Result<(), SceneError> {
+        let scene = try!(self.get(id));
+        let targets = scene.values
+            .iter()
+            .filter(|value| {
+                let is_scene = self.is_scene_channel(&value.channel);
+                if is_scene {
+                    warn!("[scenes] Not activating {} from scene {}", value.channel, id);
+                }
+                !is_scene
+            })
+            .map(|value| {
+                Targetted {
+                    select: vec![ChannelSelector::new().with_id(&Id::new(&value.channel))],
+                    // Parsing a payload never fails, the value is checked against
+                    // the format of the channel when sending.
+                    payload: Payload::parse(Path::new(), &value.value).unwrap(),
+                }
+            })
+            .collect();
+        let errors: Vec<_> = self.manager
+            .send_values(targets, user.clone())
+            .into_iter()
+            .filter_map(|(id, result)| result.err().map(|err| (id.to_string(), err)))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(SceneError::Activation(errors))
+        }
+    }
+
+    /// Whether `channel` is the channel of a scene, whether it exists or not.
+    fn is_scene_channel(&self, channel: &str) -> bool {
+        if channel.starts_with("setter:activate.") &&
+           channel.ends_with(".scenes@link.mozilla.org") {
+            return true;
+        }
+        self.manager
+            .get_channels(vec![ChannelSelector::new().with_id(&Id::new(channel))])
+            .iter()
+            .any(|channel| channel.adapter == Self::adapter_id())
+    }
+
+    fn check(&self, scene: &Scene) -> Result<(), SceneError> {
+        if scene.name.trim().is_empty() {
+            return Err(SceneError::InvalidScene("The name is empty".to_owned()));
+        }
+        if scene.values.iter().any(|value| value.channel.is_empty()) {
+            return Err(SceneError::InvalidScene("A channel id is empty".to_owned()));
+        }
+        let nested = scene.values.iter().find(|value| self.is_scene_channel(&value.channel));
+        if let Some(value) = nested {
+            return Err(SceneError::InvalidScene(format!("A scene can't activate scenes, got {}",
+                                                        value.channel)));
+        }
+        Ok(())
+    }
+
+    fn add_service(&self, scene: &Scene) -> Result<(), Error> {
+        let service_id = Self::service_id(&scene.id);
+        let mut service = Service::empty(&service_id, &Self::adapter_id());
+        service.properties.insert("name".to_owned(), scene.name.clone());
+        try!(self.manager.add_service(service));
+        self.manager.add_channel(Channel {
+            id: Self::activate_id(&scene.id),
+            service: service_id,
+            adapter: Self::adapter_id(),
+            feature: Id::new("scene/activate"),
+            supports_send: Some(Signature::accepts(Maybe::Nothing)),
+            ..Channel::default()
+        })
+    }
+}
+
+pub struct SceneAdapter {
+    scenes: Scenes,
+}
+
+impl SceneAdapter {
+    pub fn init<C: Controller>(controller: C, manager: &Arc<AdapterManager>) -> Result<(), Error> {
+        let db_path = controller.get_profile().path_for("scenes.sqlite");
+        let scenes = Scenes::new(manager, &db_path);
+        try!(manager.add_adapter(Arc::new(SceneAdapter { scenes: scenes.clone() })));
+
+        let stored = try!(scenes.list().map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        for scene in stored {
+            try!(scenes.add_service(&scene));
+        }
+        Ok(())
+    }
+}
+
+impl Adapter for SceneAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Scenes::adapter_id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   user: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        let scenes = match self.scenes.list() {
+            Ok(scenes) => scenes,
+            Err(err) => {
+                return values.drain()
+                    .map(|(id, _)| {
+                        (id, Err(Error::Internal(InternalError::GenericError(format!("{}", err)))))
+                    })
+                    .collect()
+            }
+        };
+        values.drain()
+            .map(|(id, _)| {
+                let result = match scenes.iter()
+                    .find(|scene| Scenes::activate_id(&scene.id) == id) {
+                    Some(scene) => {
+                        self.scenes.activate(&scene.id, &user).map_err(|err| {
+                            Error::Internal(InternalError::GenericError(format!("{}", err)))
+                        })
+                    }
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+describe! scenes {
+    before_each {
+        use foxbox_taxonomy::api::{API, Targetted, User};
+        use foxbox_taxonomy::channel::*;
+        use foxbox_taxonomy::fake_adapter::{Effect, FakeAdapter, Tweak};
+        use foxbox_taxonomy::io::Payload;
+        use foxbox_taxonomy::manager::AdapterManager;
+        use foxbox_taxonomy::selector::{ChannelSelector, ServiceSelector};
+        use foxbox_taxonomy::services::{AdapterId, Id, Service, ServiceId};
+        use foxbox_taxonomy::util::Maybe;
+        use foxbox_taxonomy::values::{format, OnOff, Value};
+        use serde_json;
+        use std::sync::Arc;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("scenes").unwrap();
+        let db_path = dir.path().join("scenes.sqlite");
+        let manager = Arc::new(AdapterManager::new(None));
+        let scenes = Scenes::new(&manager, db_path.to_str().unwrap());
+        manager.add_adapter(Arc::new(SceneAdapter { scenes: scenes.clone() })).unwrap();
+
+        let adapter_id: Id<AdapterId> = Id::new("fake@link.mozilla.org");
+        let service_id: Id<ServiceId> = Id::new("service:lights@link.mozilla.org");
+        let light_1: Id<Channel> = Id::new("channel:light-1@link.mozilla.org");
+        let light_2: Id<Channel> = Id::new("channel:light-2@link.mozilla.org");
+        let adapter = FakeAdapter::new(&adapter_id);
+        let tweak = adapter.get_tweak();
+        let rx = adapter.take_rx();
+        manager.add_adapter(Arc::new(adapter)).unwrap();
+        manager.add_service(Service::empty(&service_id, &adapter_id)).unwrap();
+        for id in vec![&light_1, &light_2] {
+            manager.add_channel(Channel {
+                id: id.clone(),
+                service: service_id.clone(),
+                adapter: adapter_id.clone(),
+                feature: Id::new("light/is-on"),
+                supports_fetch: Some(Signature::returns(Maybe::Required(format::ON_OFF.clone()))),
+                supports_send: Some(Signature::accepts(Maybe::Required(format::ON_OFF.clone()))),
+                ..Channel::default()
+            }).unwrap();
+        }
+        tweak(Tweak::InjectGetterValue(light_1.clone(), Ok(Some(Value::new(OnOff::On)))));
+        tweak(Tweak::InjectGetterValue(light_2.clone(), Ok(Some(Value::new(OnOff::Off)))));
+    }
+
+    it "should capture the values of the matching channels" {
+        let selector = ChannelSelector::new().with_feature(&Id::new("light/is-on"));
+        let scene = scenes.capture("Movie night", vec![selector.clone()], &User::None).unwrap();
+        assert_eq!(scene.id, "movie-night");
+        assert_eq!(scene.values,
+                   vec![SceneValue {
+                            channel: light_1.to_string(),
+                            value: serde_json::Value::String("On".to_owned()),
+                        },
+                        SceneValue {
+                            channel: light_2.to_string(),
+                            value: serde_json::Value::String("Off".to_owned()),
+                        }]);
+        assert_eq!(scenes.get("movie-night").unwrap(), scene);
+
+        // Capturing again with the same name doesn't overwrite the scene.
+        let again = scenes.capture("Movie night", vec![selector], &User::None).unwrap();
+        assert_eq!(again.id, "movie-night-2");
+    }
+
+    it "should expose scenes as services and replay them" {
+        let selector = ChannelSelector::new().with_feature(&Id::new("light/is-on"));
+        scenes.capture("Movie night", vec![selector], &User::None).unwrap();
+
+        let activate = ChannelSelector::new().with_feature(&Id::new("scene/activate"));
+        assert_eq!(manager.get_channels(vec![activate.clone()]).len(), 1);
+
+        let results = manager.send_values(vec![Targetted {
+                                                   select: vec![activate],
+                                                   payload: Payload::empty(),
+                                               }],
+                                          User::None);
+        for (_, result) in results {
+            result.unwrap();
+        }
+
+        let mut sent = vec![];
+        for _ in 0..2 {
+            match rx.recv().unwrap() {
+                Effect::ValueSent(id, value) => sent.push((id, value)),
+            }
+        }
+        sent.sort_by(|a, b| a.0.to_string().cmp(&b.0.to_string()));
+        assert_eq!(sent,
+                   vec![(light_1.clone(), Value::new(OnOff::On)),
+                        (light_2.clone(), Value::new(OnOff::Off))]);
+    }
+
+    it "should edit and remove scenes" {
+        let scene = scenes.create("Good night",
+                    vec![SceneValue {
+                             channel: light_1.to_string(),
+                             value: serde_json::Value::String("Off".to_owned()),
+                         }])
+            .unwrap();
+        let mut renamed = scene.clone();
+        renamed.name = "Sleep".to_owned();
+        scenes.update(&renamed).unwrap();
+        assert_eq!(scenes.get(&scene.id).unwrap().name, "Sleep");
+
+        let service = manager.get_services(vec![ServiceSelector::new()
+                                                     .with_id(&Scenes::service_id(&scene.id))]);
+        assert_eq!(service[0].properties.get("name"), Some(&"Sleep".to_owned()));
+
+        scenes.remove(&scene.id).unwrap();
+        assert!(scenes.list().unwrap().is_empty());
+        let activate = ChannelSelector::new().with_feature(&Id::new("scene/activate"));
+        assert!(manager.get_channels(vec![activate]).is_empty());
+        match scenes.remove(&scene.id) {
+            Err(SceneError::NoSuchScene(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    it "should refuse scenes that activate scenes" {
+        let scene = scenes.create("Good night",
+                    vec![SceneValue {
+                             channel: light_1.to_string(),
+                             value: serde_json::Value::String("Off".to_owned()),
+                         }])
+            .unwrap();
+        let activate = SceneValue {
+            channel: Scenes::activate_id(&scene.id).to_string(),
+            value: serde_json::Value::Null,
+        };
+        let mut looping = scene.clone();
+        looping.values.push(activate.clone());
+        match scenes.update(&looping) {
+            Err(SceneError::InvalidScene(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        // Including scenes that don't exist yet.
+        let next = SceneValue {
+            channel: Scenes::activate_id("good-night-2").to_string(),
+            value: serde_json::Value::Null,
+        };
+        match scenes.create("Good night", vec![next]) {
+            Err(SceneError::InvalidScene(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    it "should report values that could not be sent" {
+        let scene = scenes.create("Broken",
+                    vec![SceneValue {
+                             channel: light_1.to_string(),
+                             value: serde_json::Value::String("Dimmed".to_owned()),
+                         }])
+            .unwrap();
+        match scenes.activate(&scene.id, &User::None) {
+            Err(SceneError::Activation(errors)) => assert_eq!(errors.len(), 1),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
@@ -1,5 +1,7 @@
 //! An adapter providing access to the Thinkerbell rules engine.
 
+use foxbox_core::metrics;
+
 use foxbox_taxonomy::api::{Error, InternalError, User};
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::io;
@@ -94,6 +96,15 @@ impl ExecutableDevEnv for ThinkerbellExecutionEnv {
     }
 }
 
+/// Publish the number of running rules as a metric.
+fn report_running_count(script_manager: &ScriptManager<ThinkerbellExecutionEnv,
+                                                       RawSender<(Id<ScriptId>, ExecutionEvent)>>) {
+    metrics::global().set("foxbox_thinkerbell_rules_running",
+                          "Number of Thinkerbell rules currently running.",
+                          vec![],
+                          script_manager.get_running_count() as f64);
+}
+
 /// Convert a `ScriptManagerError` into an API Error.
 /// We can't implement From<T> because `ScriptManagerError` is in a different crate.
 fn sm_error(e: ScriptManagerError) -> Error {
@@ -185,6 +196,9 @@ impl ThinkerbellAdapter {
         let mut rules: Vec<ThinkerbellRule> = Vec::new();
 
         'recv: for action in rx {
+            // Adding or removing a rule is always followed by an `AddRuleService` or
+            // `RemoveRuleService` action, so this is never more than one action late.
+            report_running_count(&script_manager);
             match action {
                 // After a script has been started, start a Service for that script.
                 // The script has already been started with ScriptManager at this point;
@@ -297,6 +311,7 @@ impl ThinkerbellAdapter {
                                         let _ = tx.send(Err(err));
                                     }
                                 }
+                                report_running_count(&script_manager);
                                 continue 'recv;
                             } else if setter_id == rule.setter_remove_id {
                                 let _ = tx.send(script_manager.remove(&rule.script_id)
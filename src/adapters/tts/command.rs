@@ -0,0 +1,294 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An engine running external programs, e.g. pico2wave.
+//!
+//! Commands are described by templates such as
+//! `pico2wave [--lang={language}] --wave={output} -- {text}`.
+//! The template is split on whitespace before the placeholders are replaced,
+//! so each placeholder ends up in a single argument and nothing goes through
+//! a shell. An argument in brackets is dropped if one of its placeholders has
+//! no value. The placeholders are `{text}`, `{voice}`, `{language}`, `{rate}`,
+//! `{pitch}`, `{volume}` and `{output}`, the path of the WAV file to write.
+//!
+//! The text comes from users, so templates should end the options with `--`
+//! before `{text}`: otherwise, a text such as `-w/some/file` would be taken as
+//! an option of the program.
+
+use adapters::tts::engine::{SpeakRequest, TtsEngine, TtsError};
+use rand;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Arg {
+    Required(String),
+    Optional(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandLine {
+    program: String,
+    args: Vec<Arg>,
+}
+
+impl CommandLine {
+    pub fn parse(template: &str) -> Result<Self, TtsError> {
+        let mut tokens = template.split_whitespace();
+        let program = match tokens.next() {
+            Some(program) => program.to_owned(),
+            None => return Err(TtsError::Engine("Empty command".to_owned())),
+        };
+        let args = tokens.map(|token| if token.starts_with('[') && token.ends_with(']') &&
+                                    token.len() > 1 {
+                Arg::Optional(token[1..token.len() - 1].to_owned())
+            } else {
+                Arg::Required(token.to_owned())
+            })
+            .collect();
+        Ok(CommandLine {
+            program: program,
+            args: args,
+        })
+    }
+
+    /// Replaces the placeholders. Returns None if a required argument uses a
+    /// placeholder that has no value.
+    fn expand(&self, vars: &HashMap<&'static str, String>) -> Option<Vec<String>> {
+        let mut result = vec![];
+        for arg in &self.args {
+            let (template, required) = match *arg {
+                Arg::Required(ref template) => (template, true),
+                Arg::Optional(ref template) => (template, false),
+            };
+            match Self::substitute(template, vars) {
+                Some(arg) => result.push(arg),
+                None if required => return None,
+                None => {}
+            }
+        }
+        Some(result)
+    }
+
+    fn substitute(template: &str, vars: &HashMap<&'static str, String>) -> Option<String> {
+        let mut result = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            result.push_str(&rest[..start]);
+            match vars.get(&rest[start + 1..end]) {
+                Some(value) => result.push_str(value),
+                None => return None,
+            }
+            rest = &rest[end + 1..];
+        }
+        result.push_str(rest);
+        Some(result)
+    }
+
+    fn run(&self, vars: &HashMap<&'static str, String>) -> Result<(), TtsError> {
+        let args = match self.expand(vars) {
+            Some(args) => args,
+            None => {
+                return Err(TtsError::Engine(format!("Missing value for the {} command",
+                                                    self.program)))
+            }
+        };
+        debug!("Running {} {:?}", self.program, args);
+        let status = try!(Command::new(&self.program).args(&args).status());
+        if status.success() {
+            Ok(())
+        } else {
+            Err(TtsError::Engine(format!("{} failed with {}", self.program, status)))
+        }
+    }
+}
+
+fn variables(request: &SpeakRequest, output: Option<&Path>) -> HashMap<&'static str, String> {
+    let mut vars = HashMap::new();
+    vars.insert("text", request.text.clone());
+    if let Some(ref voice) = request.voice {
+        vars.insert("voice", voice.clone());
+    }
+    if let Some(ref language) = request.language {
+        vars.insert("language", language.clone());
+    }
+    if let Some(rate) = request.rate {
+        vars.insert("rate", rate.to_string());
+    }
+    if let Some(pitch) = request.pitch {
+        vars.insert("pitch", pitch.to_string());
+    }
+    if let Some(volume) = request.volume {
+        vars.insert("volume", volume.to_string());
+    }
+    if let Some(output) = output {
+        vars.insert("output", output.to_string_lossy().into_owned());
+    }
+    vars
+}
+
+/// Removes the file once dropped.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new() -> Self {
+        TempFile(env::temp_dir().join(format!("foxbox-tts-{:016x}.wav", rand::random::<u64>())))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+pub struct CommandEngine {
+    name: String,
+    /// Speaks directly. If None, the speech is rendered then played with `player`.
+    speak: Option<CommandLine>,
+    render: Option<CommandLine>,
+    /// Plays `{output}`.
+    player: CommandLine,
+}
+
+impl CommandEngine {
+    pub fn new(name: &str,
+               speak: Option<&str>,
+               render: Option<&str>,
+               player: &str)
+               -> Result<Self, TtsError> {
+        if speak.is_none() && render.is_none() {
+            return Err(TtsError::Engine("Either a speak or a render command is needed"
+                .to_owned()));
+        }
+        Ok(CommandEngine {
+            name: name.to_owned(),
+            speak: match speak {
+                Some(speak) => Some(try!(CommandLine::parse(speak))),
+                None => None,
+            },
+            render: match render {
+                Some(render) => Some(try!(CommandLine::parse(render))),
+                None => None,
+            },
+            player: try!(CommandLine::parse(player)),
+        })
+    }
+
+    /// SVOX Pico, which has better voices than eSpeak but fewer languages.
+    pub fn pico2wave() -> Self {
+        Self::new("pico2wave",
+                  None,
+                  Some("pico2wave [--lang={language}] --wave={output} -- {text}"),
+                  "aplay -q {output}")
+            .unwrap()
+    }
+
+    /// The eSpeak command line program.
+    pub fn espeak() -> Self {
+        Self::new("espeak",
+                  Some("espeak [-v{voice}] [-s{rate}] [-p{pitch}] [-a{volume}] -- {text}"),
+                  Some("espeak [-v{voice}] [-s{rate}] [-p{pitch}] [-a{volume}] -w {output} -- \
+                        {text}"),
+                  "aplay -q {output}")
+            .unwrap()
+    }
+}
+
+impl TtsEngine for CommandEngine {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn init(&self) -> bool {
+        true
+    }
+
+    fn shutdown(&self) {}
+
+    fn say(&self, request: &SpeakRequest) -> Result<(), TtsError> {
+        if let Some(ref speak) = self.speak {
+            return speak.run(&variables(request, None));
+        }
+        let output = TempFile::new();
+        try!(self.render_to(request, &output.0));
+        self.player.run(&variables(request, Some(&output.0)))
+    }
+
+    fn render(&self, request: &SpeakRequest) -> Result<Vec<u8>, TtsError> {
+        let output = TempFile::new();
+        try!(self.render_to(request, &output.0));
+        let mut data = vec![];
+        try!(try!(File::open(&output.0)).read_to_end(&mut data));
+        Ok(data)
+    }
+
+    fn can_render(&self) -> bool {
+        self.render.is_some()
+    }
+}
+
+impl CommandEngine {
+    fn render_to(&self, request: &SpeakRequest, output: &Path) -> Result<(), TtsError> {
+        match self.render {
+            Some(ref render) => render.run(&variables(request, Some(output))),
+            None => Err(TtsError::Unsupported("rendering to a file")),
+        }
+    }
+}
+
+#[cfg(test)]
+describe! command_engine {
+    before_each {
+        use adapters::tts::engine::{SpeakRequest, TtsEngine};
+        use std::path::Path;
+
+        let pico = CommandLine::parse("pico2wave [--lang={language}] --wave={output} -- {text}")
+            .unwrap();
+        let mut request = SpeakRequest::new("Hello, world");
+    }
+
+    it "should keep the text in a single argument" {
+        let args = pico.expand(&variables(&request, Some(Path::new("/tmp/out.wav")))).unwrap();
+        assert_eq!(args, vec!["--wave=/tmp/out.wav", "--", "Hello, world"]);
+    }
+
+    it "should not let the text pass for an option" {
+        let request = SpeakRequest::new("-w/tmp/evil");
+        let args = pico.expand(&variables(&request, Some(Path::new("/tmp/out.wav")))).unwrap();
+        assert_eq!(args, vec!["--wave=/tmp/out.wav", "--", "-w/tmp/evil"]);
+    }
+
+    it "should fill in optional arguments" {
+        request.language = Some("fr-FR".to_owned());
+        let args = pico.expand(&variables(&request, Some(Path::new("/tmp/out.wav")))).unwrap();
+        assert_eq!(args, vec!["--lang=fr-FR", "--wave=/tmp/out.wav", "--", "Hello, world"]);
+    }
+
+    it "should fail when a required value is missing" {
+        assert!(pico.expand(&variables(&request, None)).is_none());
+    }
+
+    it "should render through the command" {
+        let engine = CommandEngine::new("test", None, Some("cp /dev/null {output}"), "true")
+            .unwrap();
+        assert!(engine.can_render());
+        assert_eq!(engine.render(&request).unwrap(), Vec::<u8>::new());
+    }
+
+    it "should report failing commands" {
+        let engine = CommandEngine::new("test", Some("false"), None, "true").unwrap();
+        assert!(!engine.can_render());
+        assert!(engine.say(&request).is_err());
+        assert!(engine.render(&request).is_err());
+    }
+}
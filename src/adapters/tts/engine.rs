@@ -2,9 +2,165 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-/// Simple trait to abstract the TTS engine implementation.
+use serde_json;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+/// The mime type of the audio returned by `TtsEngine::render`.
+pub const WAV_MIME_TYPE: &'static str = "audio/wav";
+
+/// Something to say, and how to say it.
+///
+/// Engines ignore the settings they don't support.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct SpeakRequest {
+    pub text: String,
+    /// An engine specific voice name, e.g. "en+f3" for eSpeak.
+    pub voice: Option<String>,
+    /// A language tag, e.g. "en-US".
+    pub language: Option<String>,
+    /// In words per minute.
+    pub rate: Option<u32>,
+    /// From 0 to 100, 50 being the normal pitch.
+    pub pitch: Option<u32>,
+    /// From 0 to 200, 100 being the normal volume.
+    pub volume: Option<u32>,
+}
+
+impl SpeakRequest {
+    pub fn new(text: &str) -> Self {
+        SpeakRequest { text: text.to_owned(), ..SpeakRequest::default() }
+    }
+
+    /// Parses a request from its json representation, e.g.
+    /// `{"text": "Hello", "language": "en-US", "rate": 150}`.
+    /// A json string is accepted as the text, with the default settings.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, TtsError> {
+        let request = if let serde_json::Value::String(ref text) = *json {
+            SpeakRequest::new(text)
+        } else {
+            try!(serde_json::from_value(json.clone())
+                .map_err(|err| TtsError::InvalidRequest(format!("{}", err))))
+        };
+        try!(request.validate());
+        Ok(request)
+    }
+
+    pub fn validate(&self) -> Result<(), TtsError> {
+        fn check(name: &str, value: Option<u32>, min: u32, max: u32) -> Result<(), TtsError> {
+            match value {
+                Some(value) if value < min || value > max => {
+                    Err(TtsError::InvalidRequest(format!("{} must be between {} and {}, got {}",
+                                                         name,
+                                                         min,
+                                                         max,
+                                                         value)))
+                }
+                _ => Ok(()),
+            }
+        }
+        if self.text.trim().is_empty() {
+            return Err(TtsError::InvalidRequest("Nothing to say".to_owned()));
+        }
+        try!(check("rate", self.rate, 20, 1000));
+        try!(check("pitch", self.pitch, 0, 100));
+        try!(check("volume", self.volume, 0, 200));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum TtsError {
+    InvalidRequest(String),
+    /// The engine can't do this, e.g. render to a file.
+    Unsupported(&'static str),
+    /// The speech queue is full.
+    Busy,
+    Io(io::Error),
+    /// The engine itself failed.
+    Engine(String),
+}
+
+impl fmt::Display for TtsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TtsError::InvalidRequest(ref msg) => write!(f, "Invalid speak request: {}", msg),
+            TtsError::Unsupported(what) => write!(f, "Unsupported by this engine: {}", what),
+            TtsError::Busy => write!(f, "Too many sentences are waiting to be spoken"),
+            TtsError::Io(ref err) => write!(f, "I/O error: {}", err),
+            TtsError::Engine(ref msg) => write!(f, "Engine error: {}", msg),
+        }
+    }
+}
+
+impl StdError for TtsError {
+    fn description(&self) -> &str {
+        match *self {
+            TtsError::InvalidRequest(_) => "Invalid speak request",
+            TtsError::Unsupported(_) => "Unsupported by this engine",
+            TtsError::Busy => "Speech queue full",
+            TtsError::Io(ref err) => err.description(),
+            TtsError::Engine(_) => "Engine error",
+        }
+    }
+}
+
+impl From<io::Error> for TtsError {
+    fn from(err: io::Error) -> Self {
+        TtsError::Io(err)
+    }
+}
+
+/// Abstracts the TTS engine implementation.
 pub trait TtsEngine: Send + Sync {
+    fn name(&self) -> &str;
+
     fn init(&self) -> bool;
+
     fn shutdown(&self);
-    fn say(&self, text: &str);
+
+    /// Speaks on the local audio output. Blocks until done, so that the
+    /// sentences of the queue don't overlap.
+    fn say(&self, request: &SpeakRequest) -> Result<(), TtsError>;
+
+    /// Renders the speech to a WAV file, returned as bytes.
+    fn render(&self, _: &SpeakRequest) -> Result<Vec<u8>, TtsError> {
+        Err(TtsError::Unsupported("rendering to a file"))
+    }
+
+    fn can_render(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+describe! speak_request {
+    before_each {
+        use serde_json;
+
+        let parse = |json: &str| SpeakRequest::from_json(&serde_json::from_str(json).unwrap());
+    }
+
+    it "should accept a bare string" {
+        assert_eq!(parse(r#""hello""#).unwrap(), SpeakRequest::new("hello"));
+    }
+
+    it "should accept all the settings" {
+        let request = parse(r#"{"text": "bonjour", "voice": "fr+f2", "language": "fr-FR",
+                                "rate": 150, "pitch": 60, "volume": 80}"#).unwrap();
+        assert_eq!(request.text, "bonjour");
+        assert_eq!(request.voice, Some("fr+f2".to_owned()));
+        assert_eq!(request.language, Some("fr-FR".to_owned()));
+        assert_eq!(request.rate, Some(150));
+        assert_eq!(request.pitch, Some(60));
+        assert_eq!(request.volume, Some(80));
+    }
+
+    it "should reject invalid requests" {
+        assert!(parse(r#"{"voice": "en"}"#).is_err());
+        assert!(parse(r#"{"text": "  "}"#).is_err());
+        assert!(parse(r#"{"text": "hello", "pitch": 101}"#).is_err());
+        assert!(parse(r#"{"text": "hello", "rate": 5}"#).is_err());
+    }
 }
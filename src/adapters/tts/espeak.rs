@@ -4,7 +4,7 @@
 
 extern crate libc;
 
-use adapters::tts::engine::TtsEngine;
+use adapters::tts::engine::{SpeakRequest, TtsEngine, TtsError};
 use libc::{c_int, c_char, c_void, size_t, c_uint};
 
 /// Basic espeak bindings.
@@ -37,6 +37,16 @@ pub enum espeak_ERROR {
     EE_NOT_FOUND = 2,
 }
 
+#[repr(C)]
+#[allow(dead_code)]
+pub enum espeak_PARAMETER {
+    espeakSILENCE = 0,
+    espeakRATE = 1,
+    espeakVOLUME = 2,
+    espeakPITCH = 3,
+    espeakRANGE = 4,
+}
+
 #[link(name = "espeak")]
 #[allow(dead_code)]
 extern "C" {
@@ -54,18 +64,52 @@ extern "C" {
                         unique_identifier: *mut c_uint,
                         user_data: *mut c_void)
                         -> espeak_ERROR;
+    pub fn espeak_SetParameter(parameter: espeak_PARAMETER,
+                               value: c_int,
+                               relative: c_int)
+                               -> espeak_ERROR;
+    pub fn espeak_SetVoiceByName(name: *const c_char) -> espeak_ERROR;
     pub fn espeak_Terminate() -> espeak_ERROR;
 }
 
+const DEFAULT_VOICE: &'static str = "default";
+const DEFAULT_RATE: u32 = 175;
+const DEFAULT_PITCH: u32 = 50;
+const DEFAULT_VOLUME: u32 = 100;
+
+/// Plays speech with libespeak. eSpeak can't render to a file in this mode,
+/// use the `espeak` command line engine for that.
 pub struct EspeakEngine;
 
+impl EspeakEngine {
+    fn check(what: &str, result: espeak_ERROR) -> Result<(), TtsError> {
+        match result {
+            espeak_ERROR::EE_OK => Ok(()),
+            espeak_ERROR::EE_NOT_FOUND => {
+                Err(TtsError::InvalidRequest(format!("{} not found", what)))
+            }
+            _ => Err(TtsError::Engine(format!("{} failed", what))),
+        }
+    }
+
+    fn set_parameter(parameter: espeak_PARAMETER, value: u32) -> Result<(), TtsError> {
+        Self::check("espeak_SetParameter",
+                    unsafe { espeak_SetParameter(parameter, value as c_int, 0) })
+    }
+}
+
 impl TtsEngine for EspeakEngine {
+    fn name(&self) -> &str {
+        "espeak"
+    }
+
     fn init(&self) -> bool {
         use std::ptr;
 
         let res;
         unsafe {
-            res = espeak_Initialize(espeak_AUDIO_OUTPUT::AUDIO_OUTPUT_PLAYBACK,
+            // Synchronous, so that `say` returns once the sentence has been spoken.
+            res = espeak_Initialize(espeak_AUDIO_OUTPUT::AUDIO_OUTPUT_SYNCH_PLAYBACK,
                                     0, // Buffer length. 0 == 200ms
                                     ptr::null(), // eSpeak-data dir
                                     0 /* Options. */);
@@ -73,27 +117,41 @@ impl TtsEngine for EspeakEngine {
         res != -1
     }
 
-    fn say(&self, text: &str) {
+    fn say(&self, request: &SpeakRequest) -> Result<(), TtsError> {
         use std::ffi::CString;
         use std::ptr;
-        use std::thread;
-
-        let text = String::from(text);
-        let len = text.len();
-        let s = CString::new(text.clone()).unwrap();
-
-        thread::spawn(move || {
-            unsafe {
-                espeak_Synth(s.as_ptr() as *const libc::c_void, // Sentence to speak.
-                             len + 1, // Size in bytes of the sentence. Not used in synchronous mode.
-                             0, // Start position.
-                             espeak_POSITION_TYPE::POS_CHARACTER, // Position type.
-                             0, // End position.
-                             ESPEAK_CHARS_UTF8, // Flags.
-                             ptr::null_mut(), // Unique id.
-                             ptr::null_mut() /* Opaque user data. */);
-            }
-        });
+
+        // eSpeak voices are named after their language, e.g. "fr" or "en-us".
+        let voice = match (&request.voice, &request.language) {
+            (&Some(ref voice), _) => voice.clone(),
+            (&None, &Some(ref language)) => language.to_lowercase(),
+            (&None, &None) => DEFAULT_VOICE.to_owned(),
+        };
+        let voice = try!(CString::new(voice)
+            .map_err(|_| TtsError::InvalidRequest("Invalid voice".to_owned())));
+        try!(Self::check("espeak_SetVoiceByName",
+                         unsafe { espeak_SetVoiceByName(voice.as_ptr()) }));
+        try!(Self::set_parameter(espeak_PARAMETER::espeakRATE,
+                                 request.rate.unwrap_or(DEFAULT_RATE)));
+        try!(Self::set_parameter(espeak_PARAMETER::espeakPITCH,
+                                 request.pitch.unwrap_or(DEFAULT_PITCH)));
+        try!(Self::set_parameter(espeak_PARAMETER::espeakVOLUME,
+                                 request.volume.unwrap_or(DEFAULT_VOLUME)));
+
+        let len = request.text.len();
+        let s = try!(CString::new(request.text.clone())
+            .map_err(|_| TtsError::InvalidRequest("Invalid text".to_owned())));
+        let result = unsafe {
+            espeak_Synth(s.as_ptr() as *const libc::c_void, // Sentence to speak.
+                         len + 1, // Size in bytes of the sentence. Not used in synchronous mode.
+                         0, // Start position.
+                         espeak_POSITION_TYPE::POS_CHARACTER, // Position type.
+                         0, // End position.
+                         ESPEAK_CHARS_UTF8, // Flags.
+                         ptr::null_mut(), // Unique id.
+                         ptr::null_mut() /* Opaque user data. */)
+        };
+        Self::check("espeak_Synth", result)
     }
 
     fn shutdown(&self) {
@@ -3,38 +3,121 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 ///
-/// Example cUrl request:
+/// Example cUrl requests:
 /// curl -X PUT -d '[[[{"id":"setter:talk@link.mozilla.org"}], {"String": "hello world"}]]' http://localhost:3000/api/v1/channels/set
+/// curl -X PUT -d '[[[{"id":"setter:speak@link.mozilla.org"}], {"Json": {"text": "bonjour", "language": "fr-FR", "rate": 150}}]]' http://localhost:3000/api/v1/channels/set
+///
+/// Engines that can render to a file also get a `speak/render` setter, taking
+/// the same requests as `speak/request`. The resulting WAV file can then be
+/// fetched from `getter:speech-audio@link.mozilla.org`, e.g. to be played on a
+/// phone or a networked speaker. Each user gets back the last speech they
+/// rendered. Other adapters render speech directly through `SpeechRenderer`.
 ///
 
+use adapters::media::SpeechRenderer;
+use foxbox_core::config_schema::{ConfigKey, ConfigKind};
+use foxbox_core::traits::Controller;
 use foxbox_taxonomy::adapter::*;
 use foxbox_taxonomy::manager::AdapterManager;
 use foxbox_taxonomy::api::{Error, InternalError, User};
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::services::{AdapterId, Id, Service, ServiceId};
 use foxbox_taxonomy::util::Maybe;
-use foxbox_taxonomy::values::{format, Value};
+use foxbox_taxonomy::values::{format, Binary, Json, Value};
+use serde_json;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 pub mod engine;
-pub use self::engine::TtsEngine;
+pub use self::engine::{SpeakRequest, TtsEngine, TtsError};
+
+mod command;
+use self::command::CommandEngine;
 
-// eSpeak is the only engine supported for now.
 mod espeak;
 use self::espeak::EspeakEngine;
 
+mod queue;
+use self::queue::{MAX_PENDING, SpeechQueue};
+
 static ADAPTER_ID: &'static str = "espeak_adapter@link.mozilla.org";
 static ADAPTER_NAME: &'static str = "eSpeak adapter";
 static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
 static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
 
-pub struct TtsAdapter<T> {
-    talk_setter_id: Id<Channel>,
-    engine: T,
+static TALK_SETTER_ID: &'static str = "setter:talk@link.mozilla.org";
+static SPEAK_SETTER_ID: &'static str = "setter:speak@link.mozilla.org";
+static RENDER_SETTER_ID: &'static str = "setter:render@link.mozilla.org";
+static AUDIO_GETTER_ID: &'static str = "getter:speech-audio@link.mozilla.org";
+
+pub fn engine_config() -> ConfigKey {
+    ConfigKey::new("tts",
+                   "engine",
+                   ConfigKind::Choice(vec!["espeak".to_owned(),
+                                           "espeak-cli".to_owned(),
+                                           "pico2wave".to_owned(),
+                                           "command".to_owned()]),
+                   "espeak")
+        .description("The text to speech engine. \"command\" uses the tts/*_command templates")
+}
+
+pub fn speak_command_config() -> ConfigKey {
+    ConfigKey::string("tts", "speak_command", "")
+        .description("The command that speaks {text} with the \"command\" engine")
+}
+
+pub fn render_command_config() -> ConfigKey {
+    ConfigKey::string("tts", "render_command", "")
+        .description("The command that writes {text} to the WAV file {output} with the \
+                      \"command\" engine")
+}
+
+pub fn player_command_config() -> ConfigKey {
+    ConfigKey::string("tts", "player_command", "aplay -q {output}")
+        .description("The command that plays the WAV file {output} with the \"command\" engine")
+}
+
+fn tts_error(err: TtsError) -> Error {
+    Error::Internal(InternalError::GenericError(format!("{}", err)))
+}
+
+pub struct TtsAdapter {
+    engine: Arc<TtsEngine>,
+    queue: SpeechQueue,
+    /// The last speech rendered by each user with the `speak/render` setter.
+    rendered: Mutex<HashMap<User, Vec<u8>>>,
+}
+
+impl TtsAdapter {
+    fn speak(&self, value: &Value) -> Result<(), Error> {
+        let json = try!(value.cast::<Json>());
+        let request = try!(SpeakRequest::from_json(&json.0).map_err(tts_error));
+        self.queue.push(request).map_err(tts_error)
+    }
+
+    fn render(&self, value: &Value, user: &User) -> Result<(), Error> {
+        let json = try!(value.cast::<Json>());
+        let data = try!(self.render_json(&json.0));
+        self.rendered.lock().unwrap().insert(user.clone(), data);
+        Ok(())
+    }
+
+    fn render_json(&self, json: &serde_json::Value) -> Result<Vec<u8>, Error> {
+        let request = try!(SpeakRequest::from_json(json).map_err(tts_error));
+        self.engine.render(&request).map_err(tts_error)
+    }
 }
 
-impl<T: TtsEngine> Adapter for TtsAdapter<T> {
+impl SpeechRenderer for TtsAdapter {
+    fn render_speech(&self, request: &serde_json::Value) -> Result<Binary, Error> {
+        Ok(Binary {
+            data: try!(self.render_json(request)),
+            mimetype: Id::new(engine::WAV_MIME_TYPE),
+        })
+    }
+}
+
+impl Adapter for TtsAdapter {
     fn id(&self) -> Id<AdapterId> {
         adapter_id!(ADAPTER_ID)
     }
@@ -53,29 +136,47 @@ impl<T: TtsEngine> Adapter for TtsAdapter<T> {
 
     fn fetch_values(&self,
                     mut set: Vec<Id<Channel>>,
-                    _: User)
+                    user: User)
                     -> ResultMap<Id<Channel>, Option<Value>, Error> {
         set.drain(..)
-            .map(|id| (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id)))))
+            .map(|id| {
+                if id == Id::new(AUDIO_GETTER_ID) {
+                    let value = self.rendered.lock().unwrap().get(&user).map(|data| {
+                        Value::new(Binary {
+                            data: data.clone(),
+                            mimetype: Id::new(engine::WAV_MIME_TYPE),
+                        })
+                    });
+                    return (id, Ok(value));
+                }
+                (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
+            })
             .collect()
     }
 
     fn send_values(&self,
                    mut values: HashMap<Id<Channel>, Value>,
-                   _: User)
+                   user: User)
                    -> ResultMap<Id<Channel>, (), Error> {
         use core::ops::Deref;
 
         values.drain()
             .map(|(id, value)| {
-                if id == self.talk_setter_id {
-                    match value.cast::<String>() {
-                        Ok(text) => {
-                            self.engine.say(text.deref());
-                            return (id, Ok(()));
-                        }
-                        Err(err) => return (id, Err(err)),
-                    }
+                if id == Id::new(TALK_SETTER_ID) {
+                    let result = value.cast::<String>().and_then(|text| {
+                        let request = SpeakRequest::new(text.deref());
+                        try!(request.validate().map_err(tts_error));
+                        self.queue.push(request).map_err(tts_error)
+                    });
+                    return (id, result);
+                }
+                if id == Id::new(SPEAK_SETTER_ID) {
+                    let result = self.speak(&value);
+                    return (id, result);
+                }
+                if id == Id::new(RENDER_SETTER_ID) {
+                    let result = self.render(&value, &user);
+                    return (id, result);
                 }
                 (id.clone(), Err(Error::Internal(InternalError::NoSuchChannel(id))))
             })
@@ -83,29 +184,92 @@ impl<T: TtsEngine> Adapter for TtsAdapter<T> {
     }
 }
 
-pub fn init(adapt: &Arc<AdapterManager>) -> Result<(), Error> {
-    let engine = EspeakEngine {};
+/// Builds the engine selected in the configuration.
+fn create_engine<C: Controller>(controller: &C) -> Result<Arc<TtsEngine>, TtsError> {
+    let config = controller.get_config();
+    config.register(engine_config());
+    config.register(speak_command_config());
+    config.register(render_command_config());
+    config.register(player_command_config());
+
+    let engine: Arc<TtsEngine> = match &config.get_value(&engine_config()) as &str {
+        "espeak-cli" => Arc::new(CommandEngine::espeak()),
+        "pico2wave" => Arc::new(CommandEngine::pico2wave()),
+        "command" => {
+            let non_empty = |value: String| if value.is_empty() { None } else { Some(value) };
+            let speak = non_empty(config.get_value(&speak_command_config()));
+            let render = non_empty(config.get_value(&render_command_config()));
+            Arc::new(try!(CommandEngine::new("command",
+                                             speak.as_ref().map(|s| s as &str),
+                                             render.as_ref().map(|s| s as &str),
+                                             &config.get_value(&player_command_config()))))
+        }
+        _ => Arc::new(EspeakEngine {}),
+    };
     if !engine.init() {
-        warn!("eSpeak initialization failed!");
-        return Err(Error::Internal(InternalError::GenericError("eSpeak initialization failed!"
-            .to_owned())));
+        return Err(TtsError::Engine(format!("{} initialization failed", engine.name())));
     }
+    Ok(engine)
+}
+
+/// Adds the text to speech adapter. Returns a renderer for the other adapters
+/// if the engine can render to a file.
+pub fn init<C: Controller>(controller: C,
+                           adapt: &Arc<AdapterManager>)
+                           -> Result<Option<Arc<SpeechRenderer>>, Error> {
+    let engine = match create_engine(&controller) {
+        Ok(engine) => engine,
+        Err(err) => {
+            warn!("Text to speech is not available: {}", err);
+            return Err(tts_error(err));
+        }
+    };
+    info!("Using the {} text to speech engine", engine.name());
 
-    let talk_setter_id = Id::new("setter:talk@link.mozilla.org");
-    try!(adapt.add_adapter(Arc::new(TtsAdapter {
-        talk_setter_id: talk_setter_id.clone(),
-        engine: engine,
-    })));
+    let can_render = engine.can_render();
+    let adapter = Arc::new(TtsAdapter {
+        engine: engine.clone(),
+        queue: SpeechQueue::new(engine, MAX_PENDING),
+        rendered: Mutex::new(HashMap::new()),
+    });
+    try!(adapt.add_adapter(adapter.clone()));
     let service_id = service_id!("espeak@link.mozilla.org");
     let adapter_id = adapter_id!(ADAPTER_ID);
     try!(adapt.add_service(Service::empty(&service_id, &adapter_id)));
     try!(adapt.add_channel(Channel {
         feature: Id::new("speak/sentence"),
         supports_send: Some(Signature::accepts(Maybe::Required(format::STRING.clone()))),
-        id: talk_setter_id,
-        service: service_id,
-        adapter: adapter_id,
+        id: Id::new(TALK_SETTER_ID),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
         ..Channel::default()
     }));
-    Ok(())
+    try!(adapt.add_channel(Channel {
+        feature: Id::new("speak/request"),
+        supports_send: Some(Signature::accepts(Maybe::Required(format::JSON.clone()))),
+        id: Id::new(SPEAK_SETTER_ID),
+        service: service_id.clone(),
+        adapter: adapter_id.clone(),
+        ..Channel::default()
+    }));
+    if can_render {
+        try!(adapt.add_channel(Channel {
+            feature: Id::new("speak/render"),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::JSON.clone()))),
+            id: Id::new(RENDER_SETTER_ID),
+            service: service_id.clone(),
+            adapter: adapter_id.clone(),
+            ..Channel::default()
+        }));
+        try!(adapt.add_channel(Channel {
+            feature: Id::new("speak/audio"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::BINARY.clone()))),
+            id: Id::new(AUDIO_GETTER_ID),
+            service: service_id,
+            adapter: adapter_id,
+            ..Channel::default()
+        }));
+        return Ok(Some(adapter));
+    }
+    Ok(None)
 }
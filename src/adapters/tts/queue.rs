@@ -0,0 +1,97 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Speaks sentences one after the other, so that they don't trample each other.
+
+use adapters::tts::engine::{SpeakRequest, TtsEngine, TtsError};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread;
+
+/// How many sentences may wait to be spoken.
+pub const MAX_PENDING: usize = 16;
+
+pub struct SpeechQueue {
+    tx: Mutex<SyncSender<SpeakRequest>>,
+}
+
+impl SpeechQueue {
+    pub fn new(engine: Arc<TtsEngine>, capacity: usize) -> Self {
+        let (tx, rx) = sync_channel::<SpeakRequest>(capacity);
+        thread::Builder::new()
+            .name("TtsQueue".to_owned())
+            .spawn(move || {
+                for request in rx {
+                    if let Err(err) = engine.say(&request) {
+                        error!("[{}] Could not say \"{}\": {}", engine.name(), request.text, err);
+                    }
+                }
+            })
+            .unwrap();
+        SpeechQueue { tx: Mutex::new(tx) }
+    }
+
+    /// Queues a sentence. Returns immediately, or fails if the queue is full.
+    pub fn push(&self, request: SpeakRequest) -> Result<(), TtsError> {
+        match self.tx.lock().unwrap().try_send(request) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(TtsError::Busy),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(TtsError::Engine("The speech queue has stopped".to_owned()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+describe! speech_queue {
+    before_each {
+        use adapters::tts::engine::{SpeakRequest, TtsEngine, TtsError};
+        use std::sync::{Arc, Mutex};
+        use std::sync::mpsc::{channel, Sender};
+        use std::thread;
+        use std::time::Duration;
+
+        // Reports the start and the end of each sentence.
+        struct SlowEngine {
+            tx: Mutex<Sender<String>>,
+        }
+
+        impl TtsEngine for SlowEngine {
+            fn name(&self) -> &str {
+                "slow"
+            }
+            fn init(&self) -> bool {
+                true
+            }
+            fn shutdown(&self) {}
+            fn say(&self, request: &SpeakRequest) -> Result<(), TtsError> {
+                self.tx.lock().unwrap().send(format!("start {}", request.text)).unwrap();
+                thread::sleep(Duration::from_millis(50));
+                self.tx.lock().unwrap().send(format!("end {}", request.text)).unwrap();
+                Ok(())
+            }
+        }
+
+        let (tx, rx) = channel();
+        let queue = SpeechQueue::new(Arc::new(SlowEngine { tx: Mutex::new(tx) }), 2);
+    }
+
+    it "should not overlap sentences" {
+        queue.push(SpeakRequest::new("one")).unwrap();
+        queue.push(SpeakRequest::new("two")).unwrap();
+        let events: Vec<String> = rx.iter().take(4).collect();
+        assert_eq!(events, vec!["start one", "end one", "start two", "end two"]);
+    }
+
+    it "should refuse sentences when full" {
+        let mut busy = false;
+        for _ in 0..10 {
+            if let Err(TtsError::Busy) = queue.push(SpeakRequest::new("again")) {
+                busy = true;
+            }
+        }
+        assert!(busy);
+    }
+}
@@ -0,0 +1,167 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Stores the virtual devices and their values.
+//!
+//! # The virtual devices database
+//!
+//! The "virtual_devices" table has one row per device. The value is stored
+//! as JSON, in the format of the channel of the device.
+
+use foxbox_core::migrations::{self, Migration};
+use rusqlite::{self, Connection};
+use serde_json;
+use super::{Kind, VirtualDevice};
+
+/// The schema version of the virtual devices database written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The schema history of the virtual devices database.
+static MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    Migration {
+        version: 1,
+        description: "Create the virtual_devices table",
+        sql: "CREATE TABLE IF NOT EXISTS virtual_devices (
+                  id     TEXT NOT NULL PRIMARY KEY,
+                  name   TEXT NOT NULL,
+                  kind   TEXT NOT NULL,
+                  value  TEXT NOT NULL
+              );",
+    },
+];
+
+pub struct VirtualDb {
+    db: Connection,
+}
+
+fn device_from_row(row: rusqlite::Row) -> Option<VirtualDevice> {
+    let id: String = row.get(0);
+    let kind: String = row.get(2);
+    let kind = match Kind::from_name(&kind) {
+        Some(kind) => kind,
+        None => {
+            warn!("[virtual] Device {} has an unknown kind {}", id, kind);
+            return None;
+        }
+    };
+    let value: String = row.get(3);
+    let value = serde_json::from_str(&value).unwrap_or_else(|err| {
+        warn!("[virtual] The value of device {} cannot be parsed: {}", id, err);
+        kind.default_value()
+    });
+    Some(VirtualDevice {
+        id: id,
+        name: row.get(1),
+        kind: kind,
+        value: value,
+    })
+}
+
+impl VirtualDb {
+    /// Opens the database at `path` and creates it if not available yet.
+    /// Panics if the database can't be opened or migrated to the current schema.
+    pub fn new(path: &str) -> Self {
+        let mut db = Connection::open(path).unwrap();
+        migrations::migrate(&mut db, "virtual devices", &MIGRATIONS).unwrap_or_else(|err| {
+            panic!("Unable to migrate the virtual devices database: {}", err);
+        });
+
+        VirtualDb { db: db }
+    }
+
+    /// Gets all the devices, sorted by id.
+    pub fn list(&self) -> rusqlite::Result<Vec<VirtualDevice>> {
+        let mut devices = Vec::new();
+        let mut stmt = try!(self.db
+            .prepare("SELECT id, name, kind, value FROM virtual_devices ORDER BY id"));
+        let mut rows = try!(stmt.query(&[]));
+        while let Some(result_row) = rows.next() {
+            if let Some(device) = device_from_row(try!(result_row)) {
+                devices.push(device);
+            }
+        }
+        Ok(devices)
+    }
+
+    pub fn get(&self, id: &str) -> rusqlite::Result<Option<VirtualDevice>> {
+        let mut stmt = try!(self.db
+            .prepare("SELECT id, name, kind, value FROM virtual_devices WHERE id=$1"));
+        let mut rows = try!(stmt.query(&[&id]));
+        match rows.next() {
+            Some(result_row) => Ok(device_from_row(try!(result_row))),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds a device, or replaces the device with the same id.
+    pub fn put(&self, device: &VirtualDevice) -> rusqlite::Result<()> {
+        let value = serde_json::to_string(&device.value).unwrap();
+        try!(self.db.execute("INSERT OR REPLACE INTO virtual_devices VALUES ($1, $2, $3, $4)",
+                             &[&device.id, &device.name, &device.kind.name(), &value]));
+        Ok(())
+    }
+
+    /// Updates the value of a device. Returns false if there was no such device.
+    pub fn set_value(&self, id: &str, value: &serde_json::Value) -> rusqlite::Result<bool> {
+        let value = serde_json::to_string(value).unwrap();
+        let count = try!(self.db
+            .execute("UPDATE virtual_devices SET value=$1 WHERE id=$2", &[&value, &id]));
+        Ok(count > 0)
+    }
+
+    /// Removes a device. Returns false if there was no such device.
+    pub fn remove(&self, id: &str) -> rusqlite::Result<bool> {
+        let count = try!(self.db.execute("DELETE FROM virtual_devices WHERE id=$1", &[&id]));
+        Ok(count > 0)
+    }
+}
+
+#[cfg(test)]
+describe! virtual_db {
+    before_each {
+        use serde_json;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("virtual").unwrap();
+        let path = dir.path().join("virtual.sqlite");
+        let db = VirtualDb::new(path.to_str().unwrap());
+
+        let device = VirtualDevice {
+            id: "away".to_owned(),
+            name: "Away".to_owned(),
+            kind: Kind::AwayMode,
+            value: serde_json::Value::String("Off".to_owned()),
+        };
+    }
+
+    it "should store devices" {
+        assert_eq!(db.list().unwrap(), vec![]);
+        db.put(&device).unwrap();
+        assert_eq!(db.get("away").unwrap(), Some(device.clone()));
+        assert_eq!(db.list().unwrap(), vec![device.clone()]);
+        assert_eq!(db.get("counter").unwrap(), None);
+    }
+
+    it "should update values" {
+        db.put(&device).unwrap();
+        let on = serde_json::Value::String("On".to_owned());
+        assert!(db.set_value("away", &on).unwrap());
+        assert!(!db.set_value("counter", &on).unwrap());
+        assert_eq!(db.get("away").unwrap().unwrap().value, on);
+    }
+
+    it "should remove devices" {
+        db.put(&device).unwrap();
+        assert!(db.remove("away").unwrap());
+        assert!(!db.remove("away").unwrap());
+        assert_eq!(db.get("away").unwrap(), None);
+    }
+
+    it "should keep values across reopening" {
+        db.put(&device).unwrap();
+        drop(db);
+        let db = VirtualDb::new(path.to_str().unwrap());
+        assert_eq!(db.list().unwrap(), vec![device]);
+    }
+}
@@ -0,0 +1,640 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Virtual devices: switches, variables and away mode flags that are not
+//! backed by any hardware.
+//!
+//! Each device is a service with a single channel supporting fetch, send and
+//! watch, so that Thinkerbell rules can keep some state and chain off each
+//! other, e.g. "if away mode is on and the door opens, notify me". Numbers are
+//! watched with ranges, e.g. `{"Geq": 10}`, the other devices with the value to
+//! wait for. The values are stored in the profile and survive reboots. Devices are created with
+//! the REST API, see `virtual_router`.
+
+pub mod db;
+
+use foxbox_core::traits::Controller;
+use foxbox_core::utils;
+use foxbox_taxonomy::adapter::*;
+use foxbox_taxonomy::api::{Error, InternalError, Operation, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::{Format, Payload};
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::parse::{Parser, Path, ToJSON};
+use foxbox_taxonomy::services::{AdapterId, Id, Service, ServiceId};
+use foxbox_taxonomy::util::Maybe;
+use foxbox_taxonomy::values::{format, Number, Range, Value};
+use rusqlite;
+use serde_json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use transformable_channels::mpsc::*;
+
+use self::db::VirtualDb;
+
+static ADAPTER_NAME: &'static str = "Virtual devices adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Kind {
+    /// An On/Off switch, with feature `switch/is-on`.
+    #[serde(rename = "switch")]
+    Switch,
+    /// A number, with feature `variable/number`.
+    #[serde(rename = "number")]
+    Number,
+    /// A string, with feature `variable/text`.
+    #[serde(rename = "text")]
+    Text,
+    /// An On/Off flag telling whether the house is empty, with feature `away-mode/is-on`.
+    #[serde(rename = "away-mode")]
+    AwayMode,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kind::Switch => "switch",
+            Kind::Number => "number",
+            Kind::Text => "text",
+            Kind::AwayMode => "away-mode",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Kind> {
+        match name {
+            "switch" => Some(Kind::Switch),
+            "number" => Some(Kind::Number),
+            "text" => Some(Kind::Text),
+            "away-mode" => Some(Kind::AwayMode),
+            _ => None,
+        }
+    }
+
+    fn feature(&self) -> &'static str {
+        match *self {
+            Kind::Switch => "switch/is-on",
+            Kind::Number => "variable/number",
+            Kind::Text => "variable/text",
+            Kind::AwayMode => "away-mode/is-on",
+        }
+    }
+
+    fn format(&self) -> Arc<Format> {
+        match *self {
+            Kind::Switch | Kind::AwayMode => format::ON_OFF.clone(),
+            Kind::Number => format::NUMBER.clone(),
+            Kind::Text => format::STRING.clone(),
+        }
+    }
+
+    /// The format of the ranges watched.
+    fn range_format(&self) -> Arc<Format> {
+        match *self {
+            Kind::Number => Arc::new(Format::new::<Range<Number>>()),
+            _ => self.format(),
+        }
+    }
+
+    /// The value of a new device, as JSON.
+    pub fn default_value(&self) -> serde_json::Value {
+        match *self {
+            Kind::Switch | Kind::AwayMode => serde_json::Value::String("Off".to_owned()),
+            Kind::Number => serde_json::Value::F64(0.0),
+            Kind::Text => serde_json::Value::String(String::new()),
+        }
+    }
+
+    /// Checks that `json` is a valid value for this kind of device.
+    fn parse_value(&self, json: &serde_json::Value) -> Result<Value, Error> {
+        let payload = try!(Payload::parse(Path::new(), json).map_err(Error::Parsing));
+        payload.to_value(&self.format())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct VirtualDevice {
+    pub id: String,
+    pub name: String,
+    pub kind: Kind,
+    /// The current value, as returned by `channels/get`.
+    pub value: serde_json::Value,
+}
+
+#[derive(Debug)]
+pub enum VirtualError {
+    NoSuchDevice(String),
+    InvalidDevice(String),
+    Database(rusqlite::Error),
+    Api(Error),
+}
+
+impl fmt::Display for VirtualError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VirtualError::NoSuchDevice(ref id) => write!(f, "No such device: {}", id),
+            VirtualError::InvalidDevice(ref msg) => write!(f, "Invalid device: {}", msg),
+            VirtualError::Database(ref err) => write!(f, "Database error: {}", err),
+            VirtualError::Api(ref err) => write!(f, "{:?}", err),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for VirtualError {
+    fn from(err: rusqlite::Error) -> Self {
+        VirtualError::Database(err)
+    }
+}
+
+impl From<Error> for VirtualError {
+    fn from(err: Error) -> Self {
+        VirtualError::Api(err)
+    }
+}
+
+/// Access to the stored devices and to their services. The REST API and the
+/// adapter each have their own instance, the database being shared.
+#[derive(Clone)]
+pub struct VirtualDevices {
+    manager: Arc<AdapterManager>,
+    db_path: String,
+}
+
+impl VirtualDevices {
+    pub fn new(manager: &Arc<AdapterManager>, db_path: &str) -> Self {
+        VirtualDevices {
+            manager: manager.clone(),
+            db_path: db_path.to_owned(),
+        }
+    }
+
+    pub fn adapter_id() -> Id<AdapterId> {
+        Id::new("virtual@link.mozilla.org")
+    }
+
+    fn service_id(id: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.virtual@link.mozilla.org", id))
+    }
+
+    pub fn channel_id(id: &str) -> Id<Channel> {
+        Id::new(&format!("channel:{}.virtual@link.mozilla.org", id))
+    }
+
+    fn get_db(&self) -> VirtualDb {
+        VirtualDb::new(&self.db_path)
+    }
+
+    pub fn list(&self) -> Result<Vec<VirtualDevice>, VirtualError> {
+        Ok(try!(self.get_db().list()))
+    }
+
+    pub fn get(&self, id: &str) -> Result<VirtualDevice, VirtualError> {
+        match try!(self.get_db().get(id)) {
+            Some(device) => Ok(device),
+            None => Err(VirtualError::NoSuchDevice(id.to_owned())),
+        }
+    }
+
+    /// Adds a device with an id derived from its name. Without a value, the
+    /// device starts as `Off`, 0 or an empty string depending on its kind.
+    pub fn create(&self,
+                  name: &str,
+                  kind: Kind,
+                  value: Option<serde_json::Value>)
+                  -> Result<VirtualDevice, VirtualError> {
+        if name.trim().is_empty() {
+            return Err(VirtualError::InvalidDevice("The name is empty".to_owned()));
+        }
+        let value = value.unwrap_or_else(|| kind.default_value());
+        if let Err(err) = kind.parse_value(&value) {
+            return Err(VirtualError::InvalidDevice(format!("Invalid value: {:?}", err)));
+        }
+
+        let db = self.get_db();
+        let base = utils::slug(name, kind.name());
+        let mut id = base.clone();
+        let mut suffix = 1;
+        while try!(db.get(&id)).is_some() {
+            suffix += 1;
+            id = format!("{}-{}", base, suffix);
+        }
+
+        let device = VirtualDevice {
+            id: id,
+            name: name.to_owned(),
+            kind: kind,
+            value: value,
+        };
+        try!(db.put(&device));
+        try!(self.add_service(&device));
+        Ok(device)
+    }
+
+    pub fn rename(&self, id: &str, name: &str) -> Result<VirtualDevice, VirtualError> {
+        if name.trim().is_empty() {
+            return Err(VirtualError::InvalidDevice("The name is empty".to_owned()));
+        }
+        let mut device = try!(self.get(id));
+        device.name = name.to_owned();
+        try!(self.get_db().put(&device));
+        // Service properties can't change, so the service is added again with the new name.
+        let _ = self.manager.remove_service(&Self::service_id(id));
+        try!(self.add_service(&device));
+        Ok(device)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<(), VirtualError> {
+        if !try!(self.get_db().remove(id)) {
+            return Err(VirtualError::NoSuchDevice(id.to_owned()));
+        }
+        let _ = self.manager.remove_service(&Self::service_id(id));
+        Ok(())
+    }
+
+    fn add_service(&self, device: &VirtualDevice) -> Result<(), Error> {
+        let service_id = Self::service_id(&device.id);
+        let mut service = Service::empty(&service_id, &Self::adapter_id());
+        service.properties.insert("name".to_owned(), device.name.clone());
+        service.properties.insert("kind".to_owned(), device.kind.name().to_owned());
+        service.tags.insert(tag_id!("type:Virtual"));
+        try!(self.manager.add_service(service));
+        let format = device.kind.format();
+        self.manager.add_channel(Channel {
+            id: Self::channel_id(&device.id),
+            service: service_id,
+            adapter: Self::adapter_id(),
+            feature: Id::new(device.kind.feature()),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format.clone()))),
+            supports_send: Some(Signature::accepts(Maybe::Required(format.clone()))),
+            supports_watch: Some(Signature {
+                accepts: Maybe::Required(device.kind.range_format()),
+                returns: Maybe::Required(format),
+            }),
+            ..Channel::default()
+        })
+    }
+}
+
+type Watcher = (Id<Channel>, Option<Value>, Box<ExtSender<WatchEvent<Value>>>);
+
+/// Whether `value` is in a watched `range`, i.e. in the `Range<Number>` of a
+/// number device or equal to the value watched for the other devices.
+fn in_range(range: &Value, value: &Value) -> bool {
+    match range.downcast::<Range<Number>>() {
+        Some(range) => range.contains(value),
+        None => range == value,
+    }
+}
+
+struct Watchers {
+    current_index: usize,
+    map: HashMap<usize, Watcher>,
+}
+
+impl Watchers {
+    /// Tells the watchers of `id` that its value changed from `previous` to `value`.
+    fn notify(&self, id: &Id<Channel>, previous: &Value, value: &Value) {
+        if previous == value {
+            return;
+        }
+        for &(ref watched, ref range, ref tx) in self.map.values() {
+            if watched != id {
+                continue;
+            }
+            let (was_in, is_in) = match *range {
+                None => (false, true),
+                Some(ref range) => (in_range(range, previous), in_range(range, value)),
+            };
+            let event = match (was_in, is_in) {
+                (false, true) => {
+                    Some(WatchEvent::Enter {
+                        id: id.clone(),
+                        value: value.clone(),
+                    })
+                }
+                (true, false) => {
+                    Some(WatchEvent::Exit {
+                        id: id.clone(),
+                        value: value.clone(),
+                    })
+                }
+                _ => None,
+            };
+            if let Some(event) = event {
+                let _ = tx.send(event);
+            }
+        }
+    }
+}
+
+/// A guard used to stop watching a virtual device.
+struct VirtualGuard {
+    key: usize,
+    watchers: Arc<Mutex<Watchers>>,
+}
+
+impl Drop for VirtualGuard {
+    fn drop(&mut self) {
+        self.watchers.lock().unwrap().map.remove(&self.key);
+    }
+}
+
+impl AdapterWatchGuard for VirtualGuard {}
+
+pub struct VirtualAdapter {
+    devices: VirtualDevices,
+    watchers: Arc<Mutex<Watchers>>,
+}
+
+impl VirtualAdapter {
+    pub fn init<C: Controller>(controller: C, manager: &Arc<AdapterManager>) -> Result<(), Error> {
+        let db_path = controller.get_profile().path_for("virtual_devices.sqlite");
+        let devices = VirtualDevices::new(manager, &db_path);
+        try!(manager.add_adapter(Arc::new(VirtualAdapter::new(devices.clone()))));
+
+        let stored = try!(devices.list().map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        for device in stored {
+            try!(devices.add_service(&device));
+        }
+        Ok(())
+    }
+
+    fn new(devices: VirtualDevices) -> Self {
+        VirtualAdapter {
+            devices: devices,
+            watchers: Arc::new(Mutex::new(Watchers {
+                current_index: 0,
+                map: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Gets the devices, indexed by channel id.
+    fn devices_by_channel(&self) -> Result<HashMap<Id<Channel>, VirtualDevice>, Error> {
+        let devices = try!(self.devices.list().map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        Ok(devices.into_iter()
+            .map(|device| (VirtualDevices::channel_id(&device.id), device))
+            .collect())
+    }
+
+    fn set_value(&self, device: &VirtualDevice, value: &Value) -> Result<(), Error> {
+        let previous = try!(device.kind.parse_value(&device.value));
+        let json = try!(Payload::from_value(value, &device.kind.format())).to_json();
+        try!(self.devices.get_db().set_value(&device.id, &json).map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        self.watchers
+            .lock()
+            .unwrap()
+            .notify(&VirtualDevices::channel_id(&device.id), &previous, value);
+        Ok(())
+    }
+}
+
+impl Adapter for VirtualAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        VirtualDevices::adapter_id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        let devices = match self.devices_by_channel() {
+            Ok(devices) => devices,
+            Err(err) => return set.drain(..).map(|id| (id, Err(err.clone()))).collect(),
+        };
+        set.drain(..)
+            .map(|id| {
+                let result = match devices.get(&id) {
+                    Some(device) => device.kind.parse_value(&device.value).map(Some),
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        let devices = match self.devices_by_channel() {
+            Ok(devices) => devices,
+            Err(err) => return values.drain().map(|(id, _)| (id, Err(err.clone()))).collect(),
+        };
+        values.drain()
+            .map(|(id, value)| {
+                let result = match devices.get(&id) {
+                    Some(device) => self.set_value(device, &value),
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, mut watch: Vec<WatchTarget>) -> WatchResult {
+        let devices = match self.devices_by_channel() {
+            Ok(devices) => devices,
+            Err(err) => return watch.drain(..).map(|(id, _, _)| (id, Err(err.clone()))).collect(),
+        };
+        watch.drain(..)
+            .map(|(id, range, tx)| {
+                if !devices.contains_key(&id) {
+                    return (id.clone(), Err(Error::OperationNotSupported(Operation::Watch, id)));
+                }
+
+                let mut watchers = self.watchers.lock().unwrap();
+                let key = watchers.current_index;
+                watchers.current_index += 1;
+                watchers.map.insert(key, (id.clone(), range, tx));
+
+                let guard = VirtualGuard {
+                    key: key,
+                    watchers: self.watchers.clone(),
+                };
+                (id, Ok(Box::new(guard) as Box<AdapterWatchGuard>))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+describe! virtual_devices {
+    before_each {
+        use foxbox_taxonomy::api::{API, Targetted, User, WatchEvent as Event};
+        use foxbox_taxonomy::io::{Format, Payload};
+        use foxbox_taxonomy::manager::AdapterManager;
+        use foxbox_taxonomy::selector::ChannelSelector;
+        use foxbox_taxonomy::util::Exactly;
+        use foxbox_taxonomy::values::{format, Number, OnOff, Value};
+        use serde_json;
+        use std::sync::Arc;
+        use tempdir::TempDir;
+        use transformable_channels::mpsc::*;
+
+        let dir = TempDir::new("virtual").unwrap();
+        let db_path = dir.path().join("virtual.sqlite");
+        let manager = Arc::new(AdapterManager::new(None));
+        let devices = VirtualDevices::new(&manager, db_path.to_str().unwrap());
+        manager.add_adapter(Arc::new(VirtualAdapter::new(devices.clone()))).unwrap();
+
+        let fetch = |id: &str| {
+            let selector = ChannelSelector::new().with_id(&VirtualDevices::channel_id(id));
+            let (_, result) = manager.fetch_values(vec![selector], User::None)
+                .into_iter()
+                .next()
+                .unwrap();
+            let (payload, format) = result.unwrap().unwrap();
+            payload.to_value(&format).unwrap()
+        };
+        let send = |id: &str, value: Value, format: &Arc<Format>| {
+            let selector = ChannelSelector::new().with_id(&VirtualDevices::channel_id(id));
+            let results = manager.send_values(vec![Targetted {
+                                                       select: vec![selector],
+                                                       payload: Payload::from_value(&value, format)
+                                                           .unwrap(),
+                                                   }],
+                                              User::None);
+            for (_, result) in results {
+                result.unwrap();
+            }
+        };
+    }
+
+    it "should create devices with default values" {
+        let switch = devices.create("Movie mode", Kind::Switch, None).unwrap();
+        assert_eq!(switch.id, "movie-mode");
+        assert_eq!(fetch("movie-mode"), Value::new(OnOff::Off));
+
+        let counter = devices.create("Visitors", Kind::Number, Some(serde_json::Value::U64(3)))
+            .unwrap();
+        assert_eq!(fetch(&counter.id), Value::new(Number(3.0)));
+
+        let again = devices.create("Visitors", Kind::Text, None).unwrap();
+        assert_eq!(again.id, "visitors-2");
+        assert_eq!(fetch(&again.id), Value::new(String::new()));
+    }
+
+    it "should reject invalid values" {
+        match devices.create("Away", Kind::AwayMode, Some(serde_json::Value::U64(1))) {
+            Err(VirtualError::InvalidDevice(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match devices.create(" ", Kind::Switch, None) {
+            Err(VirtualError::InvalidDevice(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    it "should store the values that are sent" {
+        devices.create("Away", Kind::AwayMode, None).unwrap();
+        send("away", Value::new(OnOff::On), &format::ON_OFF);
+        assert_eq!(fetch("away"), Value::new(OnOff::On));
+        assert_eq!(devices.get("away").unwrap().value,
+                   serde_json::Value::String("On".to_owned()));
+    }
+
+    it "should notify watchers when the value changes" {
+        devices.create("Away", Kind::AwayMode, None).unwrap();
+        let (tx, rx) = channel();
+        let selector = ChannelSelector::new().with_id(&VirtualDevices::channel_id("away"));
+        let _guard = manager.watch_values(vec![Targetted {
+                                                   select: vec![selector],
+                                                   payload: Exactly::Exactly(Payload::from_value(
+                                                       &Value::new(OnOff::On),
+                                                       &format::ON_OFF).unwrap()),
+                                               }],
+                                          Box::new(tx));
+
+        send("away", Value::new(OnOff::On), &format::ON_OFF);
+        match rx.recv().unwrap() {
+            Event::EnterRange { value, format, .. } => {
+                assert_eq!(value.to_value(&format).unwrap(), Value::new(OnOff::On))
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+
+        // Sending the same value again doesn't notify anything.
+        send("away", Value::new(OnOff::On), &format::ON_OFF);
+        send("away", Value::new(OnOff::Off), &format::ON_OFF);
+        match rx.recv().unwrap() {
+            Event::ExitRange { value, format, .. } => {
+                assert_eq!(value.to_value(&format).unwrap(), Value::new(OnOff::Off))
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+    }
+
+    it "should notify the watchers of ranges of numbers" {
+        use foxbox_taxonomy::values::Range;
+
+        devices.create("Visitors", Kind::Number, None).unwrap();
+        let (tx, rx) = channel();
+        let selector = ChannelSelector::new().with_id(&VirtualDevices::channel_id("visitors"));
+        let range = Format::new::<Range<Number>>();
+        let _guard = manager.watch_values(vec![Targetted {
+                                                   select: vec![selector],
+                                                   payload: Exactly::Exactly(Payload::from_value(
+                                                       &Value::new(Range::Geq(Number(10.0))),
+                                                       &Arc::new(range)).unwrap()),
+                                               }],
+                                          Box::new(tx));
+
+        send("visitors", Value::new(Number(5.0)), &format::NUMBER);
+        send("visitors", Value::new(Number(12.0)), &format::NUMBER);
+        match rx.recv().unwrap() {
+            Event::EnterRange { value, format, .. } => {
+                assert_eq!(value.to_value(&format).unwrap(), Value::new(Number(12.0)))
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+
+        // Moving within the range doesn't notify anything.
+        send("visitors", Value::new(Number(15.0)), &format::NUMBER);
+        send("visitors", Value::new(Number(3.0)), &format::NUMBER);
+        match rx.recv().unwrap() {
+            Event::ExitRange { value, format, .. } => {
+                assert_eq!(value.to_value(&format).unwrap(), Value::new(Number(3.0)))
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+    }
+
+    it "should rename and remove devices" {
+        devices.create("Counter", Kind::Number, None).unwrap();
+        send("counter", Value::new(Number(2.0)), &format::NUMBER);
+        let renamed = devices.rename("counter", "Guests").unwrap();
+        assert_eq!(renamed.name, "Guests");
+        assert_eq!(fetch("counter"), Value::new(Number(2.0)));
+
+        devices.remove("counter").unwrap();
+        assert!(devices.list().unwrap().is_empty());
+        let selector = ChannelSelector::new().with_id(&VirtualDevices::channel_id("counter"));
+        assert!(manager.get_channels(vec![selector]).is_empty());
+        match devices.remove("counter") {
+            Err(VirtualError::NoSuchDevice(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,336 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Stores the webhooks, the log of their deliveries and the incoming webhooks.
+//!
+//! # The webhooks database
+//!
+//! The "webhooks" table has one row per webhook, with the watched selectors
+//! and ranges stored as JSON. The "webhook_deliveries" table has one row per
+//! attempt to deliver an event, only the most recent ones being kept. The
+//! "incoming_webhooks" table has one row per incoming webhook, with its token.
+
+use foxbox_core::migrations::{self, Migration};
+use rusqlite::{self, Connection};
+use serde_json;
+use super::{Delivery, IncomingWebhook, Webhook};
+
+/// The number of deliveries kept in the log of each webhook.
+const LOG_SIZE: u32 = 100;
+
+/// The schema version of the webhooks database written by this build.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// The schema history of the webhooks database.
+static MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    Migration {
+        version: 1,
+        description: "Create the webhooks and webhook_deliveries tables",
+        sql: "CREATE TABLE IF NOT EXISTS webhooks (
+                  id        TEXT NOT NULL PRIMARY KEY,
+                  name      TEXT NOT NULL,
+                  url       TEXT NOT NULL,
+                  watch     TEXT NOT NULL,
+                  secret    TEXT,
+                  enabled   INTEGER NOT NULL,
+                  failures  INTEGER NOT NULL
+              );
+              CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                  id        INTEGER PRIMARY KEY AUTOINCREMENT,
+                  webhook   TEXT NOT NULL,
+                  event     TEXT NOT NULL,
+                  attempt   INTEGER NOT NULL,
+                  status    INTEGER,
+                  error     TEXT,
+                  date      TEXT NOT NULL
+              );
+              CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook
+                  ON webhook_deliveries (webhook, id);",
+    },
+    Migration {
+        version: 2,
+        description: "Create the incoming_webhooks table",
+        sql: "CREATE TABLE IF NOT EXISTS incoming_webhooks (
+                  id     TEXT NOT NULL PRIMARY KEY,
+                  name   TEXT NOT NULL,
+                  token  TEXT NOT NULL UNIQUE
+              );",
+    },
+];
+
+pub struct WebhookDb {
+    db: Connection,
+}
+
+fn webhook_from_row(row: rusqlite::Row) -> Option<Webhook> {
+    let id: String = row.get(0);
+    let watch: String = row.get(3);
+    let watch = match serde_json::from_str(&watch) {
+        Ok(watch) => watch,
+        Err(err) => {
+            warn!("[webhooks] The watch of webhook {} cannot be parsed: {}", id, err);
+            return None;
+        }
+    };
+    let failures: i64 = row.get(6);
+    Some(Webhook {
+        id: id,
+        name: row.get(1),
+        url: row.get(2),
+        watch: watch,
+        secret: row.get(4),
+        enabled: row.get(5),
+        failures: failures as u32,
+    })
+}
+
+fn delivery_from_row(row: rusqlite::Row) -> Delivery {
+    let attempt: i64 = row.get(2);
+    let status: Option<i64> = row.get(3);
+    Delivery {
+        webhook: row.get(0),
+        event: row.get(1),
+        attempt: attempt as u32,
+        status: status.map(|status| status as u16),
+        error: row.get(4),
+        date: row.get(5),
+    }
+}
+
+fn incoming_from_row(row: rusqlite::Row) -> IncomingWebhook {
+    IncomingWebhook {
+        id: row.get(0),
+        name: row.get(1),
+        token: row.get(2),
+    }
+}
+
+impl WebhookDb {
+    /// Opens the database at `path` and creates it if not available yet.
+    /// Panics if the database can't be opened or migrated to the current schema.
+    pub fn new(path: &str) -> Self {
+        let mut db = Connection::open(path).unwrap();
+        migrations::migrate(&mut db, "webhooks", &MIGRATIONS).unwrap_or_else(|err| {
+            panic!("Unable to migrate the webhooks database: {}", err);
+        });
+
+        WebhookDb { db: db }
+    }
+
+    /// Gets all the webhooks, sorted by id.
+    pub fn list(&self) -> rusqlite::Result<Vec<Webhook>> {
+        let mut webhooks = Vec::new();
+        let mut stmt = try!(self.db.prepare("SELECT id, name, url, watch, secret, enabled, \
+                                             failures FROM webhooks ORDER BY id"));
+        let mut rows = try!(stmt.query(&[]));
+        while let Some(result_row) = rows.next() {
+            if let Some(webhook) = webhook_from_row(try!(result_row)) {
+                webhooks.push(webhook);
+            }
+        }
+        Ok(webhooks)
+    }
+
+    pub fn get(&self, id: &str) -> rusqlite::Result<Option<Webhook>> {
+        let mut stmt = try!(self.db.prepare("SELECT id, name, url, watch, secret, enabled, \
+                                             failures FROM webhooks WHERE id=$1"));
+        let mut rows = try!(stmt.query(&[&id]));
+        match rows.next() {
+            Some(result_row) => Ok(webhook_from_row(try!(result_row))),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds a webhook, or replaces the webhook with the same id.
+    pub fn put(&self, webhook: &Webhook) -> rusqlite::Result<()> {
+        let watch = serde_json::to_string(&webhook.watch).unwrap();
+        try!(self.db.execute("INSERT OR REPLACE INTO webhooks VALUES ($1, $2, $3, $4, $5, $6, \
+                              $7)",
+                             &[&webhook.id,
+                               &webhook.name,
+                               &webhook.url,
+                               &watch,
+                               &webhook.secret,
+                               &webhook.enabled,
+                               &(webhook.failures as i64)]));
+        Ok(())
+    }
+
+    /// Updates the number of consecutive failed deliveries of a webhook and
+    /// whether it is enabled. Returns false if there was no such webhook.
+    pub fn set_failures(&self, id: &str, failures: u32, enabled: bool) -> rusqlite::Result<bool> {
+        let count = try!(self.db.execute("UPDATE webhooks SET failures=$1, enabled=$2 WHERE id=$3",
+                                         &[&(failures as i64), &enabled, &id]));
+        Ok(count > 0)
+    }
+
+    /// Removes a webhook and its deliveries. Returns false if there was no such webhook.
+    pub fn remove(&self, id: &str) -> rusqlite::Result<bool> {
+        try!(self.db.execute("DELETE FROM webhook_deliveries WHERE webhook=$1", &[&id]));
+        let count = try!(self.db.execute("DELETE FROM webhooks WHERE id=$1", &[&id]));
+        Ok(count > 0)
+    }
+
+    /// Records an attempt to deliver an event, forgetting the oldest ones.
+    pub fn log_delivery(&self, delivery: &Delivery) -> rusqlite::Result<()> {
+        try!(self.db.execute("INSERT INTO webhook_deliveries (webhook, event, attempt, status, \
+                              error, date) VALUES ($1, $2, $3, $4, $5, $6)",
+                             &[&delivery.webhook,
+                               &delivery.event,
+                               &(delivery.attempt as i64),
+                               &delivery.status.map(|status| status as i64),
+                               &delivery.error,
+                               &delivery.date]));
+        try!(self.db.execute("DELETE FROM webhook_deliveries WHERE webhook=$1 AND id NOT IN \
+                              (SELECT id FROM webhook_deliveries WHERE webhook=$1 \
+                               ORDER BY id DESC LIMIT $2)",
+                             &[&delivery.webhook, &(LOG_SIZE as i64)]));
+        Ok(())
+    }
+
+    /// Gets the logged deliveries of a webhook, most recent first.
+    pub fn deliveries(&self, id: &str) -> rusqlite::Result<Vec<Delivery>> {
+        let mut deliveries = Vec::new();
+        let mut stmt = try!(self.db.prepare("SELECT webhook, event, attempt, status, error, date \
+                                             FROM webhook_deliveries WHERE webhook=$1 \
+                                             ORDER BY id DESC"));
+        let mut rows = try!(stmt.query(&[&id]));
+        while let Some(result_row) = rows.next() {
+            deliveries.push(delivery_from_row(try!(result_row)));
+        }
+        Ok(deliveries)
+    }
+
+    /// Gets all the incoming webhooks, sorted by id.
+    pub fn list_incoming(&self) -> rusqlite::Result<Vec<IncomingWebhook>> {
+        let mut hooks = Vec::new();
+        let mut stmt = try!(self.db
+            .prepare("SELECT id, name, token FROM incoming_webhooks ORDER BY id"));
+        let mut rows = try!(stmt.query(&[]));
+        while let Some(result_row) = rows.next() {
+            hooks.push(incoming_from_row(try!(result_row)));
+        }
+        Ok(hooks)
+    }
+
+    /// Gets an incoming webhook by id or by token.
+    pub fn get_incoming(&self, field: IncomingField, value: &str)
+                        -> rusqlite::Result<Option<IncomingWebhook>> {
+        let query = match field {
+            IncomingField::Id => "SELECT id, name, token FROM incoming_webhooks WHERE id=$1",
+            IncomingField::Token => "SELECT id, name, token FROM incoming_webhooks WHERE token=$1",
+        };
+        let mut stmt = try!(self.db.prepare(query));
+        let mut rows = try!(stmt.query(&[&value]));
+        match rows.next() {
+            Some(result_row) => Ok(Some(incoming_from_row(try!(result_row)))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put_incoming(&self, hook: &IncomingWebhook) -> rusqlite::Result<()> {
+        try!(self.db.execute("INSERT OR REPLACE INTO incoming_webhooks VALUES ($1, $2, $3)",
+                             &[&hook.id, &hook.name, &hook.token]));
+        Ok(())
+    }
+
+    /// Removes an incoming webhook. Returns false if there was no such webhook.
+    pub fn remove_incoming(&self, id: &str) -> rusqlite::Result<bool> {
+        let count = try!(self.db.execute("DELETE FROM incoming_webhooks WHERE id=$1", &[&id]));
+        Ok(count > 0)
+    }
+}
+
+/// How to find an incoming webhook.
+pub enum IncomingField {
+    Id,
+    Token,
+}
+
+#[cfg(test)]
+describe! webhook_db {
+    before_each {
+        use serde_json;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("webhooks").unwrap();
+        let path = dir.path().join("webhooks.sqlite");
+        let db = WebhookDb::new(path.to_str().unwrap());
+
+        let webhook = Webhook {
+            id: "door-opened".to_owned(),
+            name: "Door opened".to_owned(),
+            url: "https://example.com/hooks/door".to_owned(),
+            watch: serde_json::from_str(r#"[{"select": {"feature": "door/is-open"}}]"#)
+                .unwrap(),
+            secret: Some("s3cr3t".to_owned()),
+            enabled: true,
+            failures: 0,
+        };
+        let delivery = |attempt: u32, status: Option<u16>| {
+            Delivery {
+                webhook: "door-opened".to_owned(),
+                event: "range/enter".to_owned(),
+                attempt: attempt,
+                status: status,
+                error: None,
+                date: "2016-10-18T10:00:00+00:00".to_owned(),
+            }
+        };
+    }
+
+    it "should store webhooks" {
+        assert_eq!(db.list().unwrap(), vec![]);
+        db.put(&webhook).unwrap();
+        assert_eq!(db.get("door-opened").unwrap(), Some(webhook.clone()));
+        assert_eq!(db.list().unwrap(), vec![webhook.clone()]);
+        assert_eq!(db.get("window").unwrap(), None);
+    }
+
+    it "should update the failures" {
+        db.put(&webhook).unwrap();
+        assert!(db.set_failures("door-opened", 3, false).unwrap());
+        assert!(!db.set_failures("window", 3, false).unwrap());
+        let stored = db.get("door-opened").unwrap().unwrap();
+        assert_eq!(stored.failures, 3);
+        assert!(!stored.enabled);
+    }
+
+    it "should keep the most recent deliveries" {
+        db.put(&webhook).unwrap();
+        for attempt in 0..LOG_SIZE + 5 {
+            db.log_delivery(&delivery(attempt, Some(200))).unwrap();
+        }
+        let deliveries = db.deliveries("door-opened").unwrap();
+        assert_eq!(deliveries.len(), LOG_SIZE as usize);
+        assert_eq!(deliveries[0], delivery(LOG_SIZE + 4, Some(200)));
+        assert_eq!(db.deliveries("window").unwrap(), vec![]);
+    }
+
+    it "should store incoming webhooks" {
+        let hook = IncomingWebhook {
+            id: "doorbell".to_owned(),
+            name: "Doorbell".to_owned(),
+            token: "0123456789abcdef".to_owned(),
+        };
+        assert_eq!(db.list_incoming().unwrap(), vec![]);
+        db.put_incoming(&hook).unwrap();
+        assert_eq!(db.list_incoming().unwrap(), vec![hook.clone()]);
+        assert_eq!(db.get_incoming(IncomingField::Token, "0123456789abcdef").unwrap(),
+                   Some(hook.clone()));
+        assert_eq!(db.get_incoming(IncomingField::Id, "doorbell").unwrap(), Some(hook));
+        assert_eq!(db.get_incoming(IncomingField::Token, "doorbell").unwrap(), None);
+        assert!(db.remove_incoming("doorbell").unwrap());
+        assert!(!db.remove_incoming("doorbell").unwrap());
+    }
+
+    it "should remove webhooks and their deliveries" {
+        db.put(&webhook).unwrap();
+        db.log_delivery(&delivery(1, None)).unwrap();
+        assert!(db.remove("door-opened").unwrap());
+        assert!(!db.remove("door-opened").unwrap());
+        assert_eq!(db.get("door-opened").unwrap(), None);
+        assert_eq!(db.deliveries("door-opened").unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,1255 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Webhooks: HTTP callbacks notified of the events of some channels, e.g. to
+//! integrate IFTTT-style services.
+//!
+//! A webhook is a URL and a set of selectors and ranges, watched like with
+//! `channels/watch`. Each event is POSTed to the URL as a json object
+//! {"webhook": id, "event": "range/enter", "data": {...}, "date": ...}, where the
+//! event and data are those of the server-sent events of `watch_router`. When the
+//! webhook has a secret, the body is signed with HMAC-SHA256 in the
+//! `X-Foxbox-Signature` header, as `sha256=<hex digest>`.
+//!
+//! Each webhook has a delivery queue of its own, so that a slow or unreachable
+//! endpoint doesn't delay the events of the others. Failed deliveries are retried
+//! with an exponential backoff, holding the later events back, each attempt being
+//! logged, and webhooks are disabled after too many failed deliveries in a row.
+//! The queues are bounded: the oldest events are dropped, and logged as such, when
+//! a webhook can't keep up.
+//!
+//! Each webhook is also exposed as a service with a `webhook/post` channel, so
+//! that Thinkerbell rules can send any json value to it, as a `send` event.
+//! Webhooks are managed with the REST API, see `webhooks_router`.
+//!
+//! Incoming webhooks go the other way: each one is an unguessable token, so that
+//! external systems can `POST /api/v1/hooks/<token>` a json value without a user
+//! session, see `hooks_router`. They are exposed as services with a watchable
+//! `webhook/trigger` channel carrying the posted value, so that Thinkerbell rules
+//! can be triggered by them. A watched range matches the posted values equal to it
+//! or, for objects, having at least its fields; each matching post is notified as
+//! entering and then leaving the range, so that rules fire on every post.
+
+extern crate crypto;
+
+pub mod db;
+
+use self::crypto::hmac::Hmac;
+use self::crypto::mac::Mac;
+use self::crypto::sha2::Sha256;
+
+use chrono::UTC;
+use foxbox_core::metrics;
+use foxbox_core::traits::Controller;
+use foxbox_core::utils;
+use foxbox_taxonomy::adapter::*;
+use foxbox_taxonomy::api::{API, Error, InternalError, Operation, TargetMap, User};
+use foxbox_taxonomy::channel::*;
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::manager::{AdapterManager, WatchGuard};
+use foxbox_taxonomy::parse::{JSON, Parser, Path, ToJSON};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::services::{AdapterId, Id, Service, ServiceId};
+use foxbox_taxonomy::util::{Exactly, Maybe};
+use foxbox_taxonomy::values::{format, Json, Value};
+use hyper;
+use hyper::Client;
+use hyper::header::ContentType;
+use hyper::net::{HttpStream, HttpsConnector, NetworkConnector, Openssl};
+use rand::Rng;
+use rand::os::OsRng;
+use rusqlite;
+use rustc_serialize::hex::ToHex;
+use serde_json;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::net::TcpStream;
+use std::sync::{mpsc, Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use transformable_channels::mpsc::*;
+use url::Url;
+use watch_router::event_data;
+
+use self::db::{IncomingField, WebhookDb};
+
+header! { (WebhookEvent, "X-Foxbox-Event") => [String] }
+header! { (WebhookSignature, "X-Foxbox-Signature") => [String] }
+
+static ADAPTER_NAME: &'static str = "Webhooks adapter (built-in)";
+static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
+static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
+
+/// The number of attempts to deliver an event before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry, doubled after each attempt.
+const RETRY_DELAY_S: u64 = 5;
+
+/// The number of failed deliveries in a row after which a webhook is disabled.
+const MAX_FAILURES: u32 = 5;
+
+/// The timeouts of the connections and requests to the webhooks.
+const CONNECT_TIMEOUT_S: u64 = 10;
+const REQUEST_TIMEOUT_S: u64 = 10;
+
+/// How long the delivery thread of a webhook waits for events before exiting.
+const QUEUE_IDLE_S: u64 = 60;
+
+/// The number of events waiting for delivery to a webhook, past which the
+/// oldest ones are dropped.
+const MAX_QUEUED_JOBS: usize = 100;
+
+lazy_static! {
+    /// The webhooks of each database, shared by the adapter and the REST API
+    /// since they own the watches and the delivery queue.
+    static ref SHARED: Mutex<HashMap<String, Webhooks>> = Mutex::new(HashMap::new());
+}
+
+/// Counts a delivery as `delivered`, `retried`, `failure` or `dropped`.
+fn record_delivery(result: &'static str) {
+    metrics::global().inc("foxbox_webhook_deliveries_total",
+                          "Number of events delivered to webhooks, by result.",
+                          vec![("result", result.to_owned())]);
+}
+
+/// Signs a body with HMAC-SHA256, as a hex digest.
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::new(Sha256::new(), secret.as_bytes());
+    mac.input(body.as_bytes());
+    mac.result().code().to_hex()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    /// The watched `ChannelSelector`s and ranges, as the JSON of a
+    /// `TargetMap<ChannelSelector, Exactly<Payload>>`. Empty when the webhook
+    /// is only used by Thinkerbell rules.
+    pub watch: serde_json::Value,
+    /// The key signing the deliveries. Never sent back to the clients.
+    #[serde(skip_serializing)]
+    pub secret: Option<String>,
+    /// False once the webhook has been disabled, by a user or after too many failures.
+    pub enabled: bool,
+    /// The number of failed deliveries in a row.
+    pub failures: u32,
+}
+
+/// An attempt to deliver an event, as logged.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Delivery {
+    pub webhook: String,
+    pub event: String,
+    /// Starts at 1.
+    pub attempt: u32,
+    /// The status of the response, if any.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub date: String,
+}
+
+/// An endpoint triggering the watchers of its channel when json values are posted to it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct IncomingWebhook {
+    pub id: String,
+    pub name: String,
+    /// The secret part of the url of the endpoint, which authenticates the posts.
+    pub token: String,
+}
+
+#[derive(Debug)]
+pub enum WebhookError {
+    NoSuchWebhook(String),
+    InvalidWebhook(String),
+    Database(rusqlite::Error),
+    Api(Error),
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WebhookError::NoSuchWebhook(ref id) => write!(f, "No such webhook: {}", id),
+            WebhookError::InvalidWebhook(ref msg) => write!(f, "Invalid webhook: {}", msg),
+            WebhookError::Database(ref err) => write!(f, "Database error: {}", err),
+            WebhookError::Api(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for WebhookError {
+    fn from(err: rusqlite::Error) -> Self {
+        WebhookError::Database(err)
+    }
+}
+
+impl From<Error> for WebhookError {
+    fn from(err: Error) -> Self {
+        WebhookError::Api(err)
+    }
+}
+
+type Watch = TargetMap<ChannelSelector, Exactly<Payload>>;
+
+fn parse_watch(webhook: &Webhook) -> Result<Watch, WebhookError> {
+    Watch::parse(Path::new(), &webhook.watch)
+        .map_err(|err| WebhookError::InvalidWebhook(format!("Invalid watch: {}", err)))
+}
+
+/// An event waiting to be delivered.
+struct Job {
+    webhook: String,
+    event: &'static str,
+    /// The json body, the same for all the attempts.
+    body: String,
+    attempt: u32,
+    due: Instant,
+}
+
+impl Job {
+    fn new(webhook: &str, event: &'static str, data: JSON) -> Self {
+        let body = vec![("webhook", webhook.to_json()),
+                        ("event", event.to_json()),
+                        ("data", data),
+                        ("date", UTC::now().to_rfc3339().to_json())]
+            .to_json();
+        Job {
+            webhook: webhook.to_owned(),
+            event: event,
+            body: serde_json::to_string(&body).unwrap(),
+            attempt: 1,
+            due: Instant::now(),
+        }
+    }
+}
+
+/// Opens the connections to the webhooks, giving up after a timeout.
+struct TimeoutConnector(Duration);
+
+impl NetworkConnector for TimeoutConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, _: &str) -> hyper::Result<HttpStream> {
+        // Connecting can't be interrupted, so it happens in a thread of its own that
+        // is left to finish by itself when it takes too long.
+        let (tx, rx) = mpsc::channel();
+        let host = host.to_owned();
+        try!(thread::Builder::new()
+            .name("WebhookConnect".to_owned())
+            .spawn(move || {
+                let _ = tx.send(TcpStream::connect((&host as &str, port)));
+            }));
+        match rx.recv_timeout(self.0) {
+            Ok(Ok(stream)) => Ok(HttpStream(stream)),
+            Ok(Err(err)) => Err(hyper::Error::Io(err)),
+            Err(_) => {
+                Err(hyper::Error::Io(io::Error::new(io::ErrorKind::TimedOut,
+                                                    "Connection timed out")))
+            }
+        }
+    }
+}
+
+/// POSTs a job to a webhook, returning the status of the response.
+fn post(webhook: &Webhook, job: &Job) -> Result<u16, String> {
+    let connector = TimeoutConnector(Duration::from_secs(CONNECT_TIMEOUT_S));
+    let mut client = Client::with_connector(HttpsConnector::with_connector(Openssl::default(),
+                                                                           connector));
+    client.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_S)));
+    client.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_S)));
+    let mut req = client.post(&webhook.url)
+        .header(ContentType::json())
+        .header(WebhookEvent(job.event.to_owned()))
+        .body(&job.body as &str);
+    if let Some(ref secret) = webhook.secret {
+        req = req.header(WebhookSignature(format!("sha256={}", sign(secret, &job.body))));
+    }
+    match req.send() {
+        Ok(response) => Ok(response.status.to_u16()),
+        Err(err) => Err(format!("{}", err)),
+    }
+}
+
+/// Whether a posted json value is in a watched range: equal to it or, for objects,
+/// having at least the fields of the range.
+fn in_range(range: &serde_json::Value, value: &serde_json::Value) -> bool {
+    match (range, value) {
+        (&serde_json::Value::Object(ref range), &serde_json::Value::Object(ref value)) => {
+            range.iter().all(|(key, expected)| {
+                value.get(key).map_or(false, |field| in_range(expected, field))
+            })
+        }
+        _ => range == value,
+    }
+}
+
+type Trigger = (Id<Channel>, Option<Value>, Box<ExtSender<WatchEvent<Value>>>);
+
+/// The watchers of the incoming webhooks, and the last value posted to each of them.
+struct Triggers {
+    current_index: usize,
+    map: HashMap<usize, Trigger>,
+    last: HashMap<Id<Channel>, Value>,
+}
+
+impl Triggers {
+    /// Tells the watchers of `id` that `json` was posted to it.
+    fn notify(&mut self, id: &Id<Channel>, json: &serde_json::Value) {
+        let value = Value::new(Json(json.clone()));
+        for &(ref watched, ref range, ref tx) in self.map.values() {
+            if watched != id {
+                continue;
+            }
+            let range = match *range {
+                None => {
+                    let _ = tx.send(WatchEvent::Enter {
+                        id: id.clone(),
+                        value: value.clone(),
+                    });
+                    continue;
+                }
+                Some(ref range) => range,
+            };
+            if range.downcast::<Json>().map_or(false, |range| in_range(&range.0, json)) {
+                let _ = tx.send(WatchEvent::Enter {
+                    id: id.clone(),
+                    value: value.clone(),
+                });
+                let _ = tx.send(WatchEvent::Exit {
+                    id: id.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+        self.last.insert(id.clone(), value);
+    }
+}
+
+/// A guard used to stop watching an incoming webhook.
+struct TriggerGuard {
+    key: usize,
+    runtime: Weak<Runtime>,
+}
+
+impl Drop for TriggerGuard {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.upgrade() {
+            runtime.triggers.lock().unwrap().map.remove(&self.key);
+        }
+    }
+}
+
+impl AdapterWatchGuard for TriggerGuard {}
+
+/// The state shared by all the handles on the webhooks of a database.
+struct Runtime {
+    watches: Mutex<HashMap<String, WatchGuard>>,
+    tx: Mutex<RawSender<Job>>,
+    triggers: Mutex<Triggers>,
+}
+
+/// The delivery queues of the webhooks having events to deliver.
+#[derive(Default)]
+struct Queues {
+    jobs: Mutex<HashMap<String, VecDeque<Job>>>,
+    /// Notified whenever a job is queued.
+    queued: Condvar,
+}
+
+/// Forgets the queue of a webhook if its delivery thread panics, so that the
+/// next job starts a new one.
+struct QueueGuard<'a> {
+    queues: &'a Queues,
+    webhook: &'a str,
+}
+
+impl<'a> Drop for QueueGuard<'a> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let mut jobs = self.queues.jobs.lock().unwrap_or_else(|err| err.into_inner());
+            jobs.remove(self.webhook);
+        }
+    }
+}
+
+/// Dispatches the events in a thread of its own, and delivers them in a thread
+/// per webhook.
+#[derive(Clone)]
+struct Dispatcher {
+    db_path: String,
+    runtime: Weak<Runtime>,
+    retry_delay: Duration,
+    queues: Arc<Queues>,
+}
+
+impl Dispatcher {
+    fn run(&self, rx: Receiver<Job>) {
+        while let Ok(job) = rx.recv() {
+            self.enqueue(job);
+        }
+    }
+
+    /// Queues a job for delivery, starting the delivery thread of its webhook if
+    /// needed. Drops the oldest job of the queue if it is full.
+    fn enqueue(&self, job: Job) {
+        let dropped = {
+            let mut jobs = self.queues.jobs.lock().unwrap();
+            if jobs.contains_key(&job.webhook) {
+                let queue = jobs.get_mut(&job.webhook).unwrap();
+                let dropped = if queue.len() >= MAX_QUEUED_JOBS {
+                    queue.pop_front()
+                } else {
+                    None
+                };
+                queue.push_back(job);
+                self.queues.queued.notify_all();
+                dropped
+            } else {
+                let id = job.webhook.clone();
+                let webhook = id.clone();
+                let dispatcher = self.clone();
+                let spawned = thread::Builder::new()
+                    .name(format!("Webhook {}", id))
+                    .spawn(move || dispatcher.deliver_queue(&webhook));
+                if let Err(err) = spawned {
+                    error!("[webhooks] Could not start the delivery of webhook {}: {}", id, err);
+                    return;
+                }
+                let mut queue = VecDeque::new();
+                queue.push_back(job);
+                jobs.insert(id, queue);
+                None
+            }
+        };
+
+        if let Some(job) = dropped {
+            warn!("[webhooks] Dropping a {} event of webhook {}, too many are queued",
+                  job.event,
+                  job.webhook);
+            record_delivery("dropped");
+            let delivery = Delivery {
+                webhook: job.webhook.clone(),
+                event: job.event.to_owned(),
+                attempt: job.attempt,
+                status: None,
+                error: Some("Dropped, too many events were queued".to_owned()),
+                date: UTC::now().to_rfc3339(),
+            };
+            if let Err(err) = WebhookDb::new(&self.db_path).log_delivery(&delivery) {
+                warn!("[webhooks] Could not log a delivery to {}: {}", job.webhook, err);
+            }
+        }
+    }
+
+    /// Waits for the next job of a webhook. Returns `None`, forgetting the queue,
+    /// once idle for a while.
+    fn next_job(&self, webhook: &str) -> Option<Job> {
+        let idle_until = Instant::now() + Duration::from_secs(QUEUE_IDLE_S);
+        let mut jobs = self.queues.jobs.lock().unwrap();
+        loop {
+            let job = jobs.get_mut(webhook).and_then(|queue| queue.pop_front());
+            if job.is_some() {
+                return job;
+            }
+            let now = Instant::now();
+            if now >= idle_until {
+                // Holding the lock, so that no job is queued while we exit.
+                jobs.remove(webhook);
+                return None;
+            }
+            jobs = self.queues.queued.wait_timeout(jobs, idle_until - now).unwrap().0;
+        }
+    }
+
+    /// Delivers the jobs of a webhook in order, retrying each one until it is
+    /// delivered or given up on before moving on to the next. Exits once idle for
+    /// a while.
+    fn deliver_queue(&self, webhook: &str) {
+        let _guard = QueueGuard {
+            queues: &self.queues,
+            webhook: webhook,
+        };
+        while let Some(mut job) = self.next_job(webhook) {
+            while let Some(retry) = self.deliver(job) {
+                if self.runtime.upgrade().is_none() {
+                    return;
+                }
+                let now = Instant::now();
+                if retry.due > now {
+                    thread::sleep(retry.due - now);
+                }
+                job = retry;
+            }
+        }
+    }
+
+    /// Attempts to deliver a job, returning the job to retry if it failed.
+    fn deliver(&self, job: Job) -> Option<Job> {
+        let db = WebhookDb::new(&self.db_path);
+        let webhook = match db.get(&job.webhook) {
+            Ok(Some(webhook)) => webhook,
+            // The webhook has been removed in the meantime.
+            Ok(None) => return None,
+            Err(err) => {
+                error!("[webhooks] Could not read webhook {}: {}", job.webhook, err);
+                return None;
+            }
+        };
+        if !webhook.enabled {
+            return None;
+        }
+
+        let result = post(&webhook, &job);
+        let delivery = Delivery {
+            webhook: job.webhook.clone(),
+            event: job.event.to_owned(),
+            attempt: job.attempt,
+            status: result.as_ref().ok().cloned(),
+            error: result.as_ref().err().cloned(),
+            date: UTC::now().to_rfc3339(),
+        };
+        if let Err(err) = db.log_delivery(&delivery) {
+            warn!("[webhooks] Could not log a delivery to {}: {}", job.webhook, err);
+        }
+
+        let delivered = match result {
+            Ok(status) => status >= 200 && status < 300,
+            Err(_) => false,
+        };
+        if delivered {
+            record_delivery("delivered");
+            if webhook.failures > 0 {
+                let _ = db.set_failures(&webhook.id, 0, true);
+            }
+            return None;
+        }
+
+        if job.attempt < MAX_ATTEMPTS {
+            record_delivery("retried");
+            let delay = self.retry_delay * (1 << (job.attempt - 1));
+            return Some(Job {
+                attempt: job.attempt + 1,
+                due: Instant::now() + delay,
+                ..job
+            });
+        }
+
+        record_delivery("failure");
+        let failures = webhook.failures + 1;
+        let enabled = failures < MAX_FAILURES;
+        if let Err(err) = db.set_failures(&webhook.id, failures, enabled) {
+            error!("[webhooks] Could not update webhook {}: {}", webhook.id, err);
+        }
+        if !enabled {
+            warn!("[webhooks] Disabling webhook {} after {} failed deliveries",
+                  webhook.id,
+                  failures);
+            if let Some(runtime) = self.runtime.upgrade() {
+                runtime.watches.lock().unwrap().remove(&webhook.id);
+            }
+        }
+        None
+    }
+}
+
+/// Access to the stored webhooks, to their services and to their watches.
+#[derive(Clone)]
+pub struct Webhooks {
+    manager: Arc<AdapterManager>,
+    db_path: String,
+    runtime: Arc<Runtime>,
+}
+
+impl Webhooks {
+    /// Creates the handle on the webhooks of a database, along with its dispatcher.
+    /// Use `shared()` outside of tests.
+    fn new(manager: &Arc<AdapterManager>, db_path: &str, retry_delay: Duration) -> Self {
+        let (tx, rx) = channel();
+        let runtime = Arc::new(Runtime {
+            watches: Mutex::new(HashMap::new()),
+            tx: Mutex::new(tx),
+            triggers: Mutex::new(Triggers {
+                current_index: 0,
+                map: HashMap::new(),
+                last: HashMap::new(),
+            }),
+        });
+        let dispatcher = Dispatcher {
+            db_path: db_path.to_owned(),
+            runtime: Arc::downgrade(&runtime),
+            retry_delay: retry_delay,
+            queues: Arc::new(Queues::default()),
+        };
+        thread::Builder::new()
+            .name("WebhookDispatcher".to_owned())
+            .spawn(move || dispatcher.run(rx))
+            .unwrap();
+
+        Webhooks {
+            manager: manager.clone(),
+            db_path: db_path.to_owned(),
+            runtime: runtime,
+        }
+    }
+
+    /// Gets the handle on the webhooks of a database.
+    pub fn shared(manager: &Arc<AdapterManager>, db_path: &str) -> Self {
+        SHARED.lock()
+            .unwrap()
+            .entry(db_path.to_owned())
+            .or_insert_with(|| {
+                Webhooks::new(manager, db_path, Duration::from_secs(RETRY_DELAY_S))
+            })
+            .clone()
+    }
+
+    pub fn adapter_id() -> Id<AdapterId> {
+        Id::new("webhooks@link.mozilla.org")
+    }
+
+    fn service_id(id: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:{}.webhooks@link.mozilla.org", id))
+    }
+
+    pub fn channel_id(id: &str) -> Id<Channel> {
+        Id::new(&format!("channel:{}.webhooks@link.mozilla.org", id))
+    }
+
+    fn incoming_service_id(id: &str) -> Id<ServiceId> {
+        Id::new(&format!("service:incoming.{}.webhooks@link.mozilla.org", id))
+    }
+
+    pub fn incoming_channel_id(id: &str) -> Id<Channel> {
+        Id::new(&format!("channel:incoming.{}.webhooks@link.mozilla.org", id))
+    }
+
+    fn get_db(&self) -> WebhookDb {
+        WebhookDb::new(&self.db_path)
+    }
+
+    pub fn list(&self) -> Result<Vec<Webhook>, WebhookError> {
+        Ok(try!(self.get_db().list()))
+    }
+
+    pub fn get(&self, id: &str) -> Result<Webhook, WebhookError> {
+        match try!(self.get_db().get(id)) {
+            Some(webhook) => Ok(webhook),
+            None => Err(WebhookError::NoSuchWebhook(id.to_owned())),
+        }
+    }
+
+    /// The recent attempts to deliver events to a webhook, most recent first.
+    pub fn deliveries(&self, id: &str) -> Result<Vec<Delivery>, WebhookError> {
+        try!(self.get(id));
+        Ok(try!(self.get_db().deliveries(id)))
+    }
+
+    /// Adds a webhook with an id derived from its name. Without a watch, the
+    /// webhook is only notified by Thinkerbell rules.
+    pub fn create(&self,
+                  name: &str,
+                  url: &str,
+                  watch: Option<serde_json::Value>,
+                  secret: Option<String>)
+                  -> Result<Webhook, WebhookError> {
+        let db = self.get_db();
+        let base = utils::slug(name, "webhook");
+        let mut id = base.clone();
+        let mut suffix = 1;
+        while try!(db.get(&id)).is_some() {
+            suffix += 1;
+            id = format!("{}-{}", base, suffix);
+        }
+
+        let webhook = Webhook {
+            id: id,
+            name: name.to_owned(),
+            url: url.to_owned(),
+            watch: watch.unwrap_or_else(|| serde_json::Value::Array(vec![])),
+            secret: secret,
+            enabled: true,
+            failures: 0,
+        };
+        try!(Self::check(&webhook));
+        try!(db.put(&webhook));
+        try!(self.start(&webhook));
+        Ok(webhook)
+    }
+
+    /// Replaces an existing webhook. Enabling a webhook forgets its failures.
+    pub fn update(&self, webhook: &Webhook) -> Result<Webhook, WebhookError> {
+        let db = self.get_db();
+        let previous = match try!(db.get(&webhook.id)) {
+            Some(previous) => previous,
+            None => return Err(WebhookError::NoSuchWebhook(webhook.id.clone())),
+        };
+        try!(Self::check(webhook));
+        let mut webhook = webhook.clone();
+        if webhook.enabled && !previous.enabled {
+            webhook.failures = 0;
+        }
+        try!(db.put(&webhook));
+        // Service properties can't change, so the service is added again with the new name.
+        let _ = self.manager.remove_service(&Self::service_id(&webhook.id));
+        try!(self.start(&webhook));
+        Ok(webhook)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<(), WebhookError> {
+        if !try!(self.get_db().remove(id)) {
+            return Err(WebhookError::NoSuchWebhook(id.to_owned()));
+        }
+        self.runtime.watches.lock().unwrap().remove(id);
+        let _ = self.manager.remove_service(&Self::service_id(id));
+        Ok(())
+    }
+
+    /// Queues a json value sent to a webhook by Thinkerbell or the channels API.
+    pub fn send(&self, webhook: &Webhook, value: &Value) -> Result<(), Error> {
+        if !webhook.enabled {
+            return Err(Error::Internal(InternalError::GenericError(format!("The webhook {} \
+                                                                            is disabled",
+                                                                           webhook.id))));
+        }
+        let data = try!(Payload::from_value(value, &format::JSON)).to_json();
+        let _ = self.runtime.tx.lock().unwrap().send(Job::new(&webhook.id, "send", data));
+        Ok(())
+    }
+
+    pub fn list_incoming(&self) -> Result<Vec<IncomingWebhook>, WebhookError> {
+        Ok(try!(self.get_db().list_incoming()))
+    }
+
+    /// Adds an incoming webhook with an id derived from its name and a random token.
+    pub fn create_incoming(&self, name: &str) -> Result<IncomingWebhook, WebhookError> {
+        if name.trim().is_empty() {
+            return Err(WebhookError::InvalidWebhook("The name is empty".to_owned()));
+        }
+        let db = self.get_db();
+        let base = utils::slug(name, "hook");
+        let mut id = base.clone();
+        let mut suffix = 1;
+        while try!(db.get_incoming(IncomingField::Id, &id)).is_some() {
+            suffix += 1;
+            id = format!("{}-{}", base, suffix);
+        }
+
+        let mut rng = try!(OsRng::new().map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        let mut token = [0u8; 16];
+        rng.fill_bytes(&mut token);
+
+        let hook = IncomingWebhook {
+            id: id,
+            name: name.to_owned(),
+            token: token.to_hex(),
+        };
+        try!(db.put_incoming(&hook));
+        try!(self.add_incoming_service(&hook));
+        Ok(hook)
+    }
+
+    /// Removes the incoming webhook of a token, which can't be posted to anymore.
+    /// Returns the removed webhook.
+    pub fn revoke(&self, token: &str) -> Result<IncomingWebhook, WebhookError> {
+        let db = self.get_db();
+        let hook = match try!(db.get_incoming(IncomingField::Token, token)) {
+            Some(hook) => hook,
+            None => return Err(WebhookError::NoSuchWebhook(token.to_owned())),
+        };
+        try!(db.remove_incoming(&hook.id));
+        let _ = self.manager.remove_service(&Self::incoming_service_id(&hook.id));
+        self.runtime.triggers.lock().unwrap().last.remove(&Self::incoming_channel_id(&hook.id));
+        Ok(hook)
+    }
+
+    /// Notifies the watchers of the incoming webhook of a token that `json` was posted.
+    /// Returns the triggered webhook.
+    pub fn trigger(&self,
+                   token: &str,
+                   json: &serde_json::Value)
+                   -> Result<IncomingWebhook, WebhookError> {
+        let hook = match try!(self.get_db().get_incoming(IncomingField::Token, token)) {
+            Some(hook) => hook,
+            None => return Err(WebhookError::NoSuchWebhook(token.to_owned())),
+        };
+        self.runtime
+            .triggers
+            .lock()
+            .unwrap()
+            .notify(&Self::incoming_channel_id(&hook.id), json);
+        Ok(hook)
+    }
+
+    fn check(webhook: &Webhook) -> Result<(), WebhookError> {
+        if webhook.name.trim().is_empty() {
+            return Err(WebhookError::InvalidWebhook("The name is empty".to_owned()));
+        }
+        match Url::parse(&webhook.url) {
+            Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            _ => {
+                return Err(WebhookError::InvalidWebhook(format!("Invalid url: {}", webhook.url)))
+            }
+        }
+        try!(parse_watch(webhook));
+        Ok(())
+    }
+
+    /// Adds the service of a webhook and watches its channels if it's enabled.
+    fn start(&self, webhook: &Webhook) -> Result<(), WebhookError> {
+        try!(self.add_service(webhook));
+        let watch = try!(parse_watch(webhook));
+        let mut watches = self.runtime.watches.lock().unwrap();
+        watches.remove(&webhook.id);
+        if !webhook.enabled || watch.is_empty() {
+            return Ok(());
+        }
+
+        let id = webhook.id.clone();
+        let tx = self.runtime.tx.lock().unwrap().map(move |event| {
+            let (name, data) = event_data(event);
+            Job::new(&id, name, data)
+        });
+        let guard = self.manager.watch_values(watch, Box::new(tx));
+        watches.insert(webhook.id.clone(), guard);
+        Ok(())
+    }
+
+    fn add_service(&self, webhook: &Webhook) -> Result<(), Error> {
+        let service_id = Self::service_id(&webhook.id);
+        let mut service = Service::empty(&service_id, &Self::adapter_id());
+        service.properties.insert("name".to_owned(), webhook.name.clone());
+        service.tags.insert(tag_id!("type:Webhook"));
+        try!(self.manager.add_service(service));
+        self.manager.add_channel(Channel {
+            id: Self::channel_id(&webhook.id),
+            service: service_id,
+            adapter: Self::adapter_id(),
+            feature: Id::new("webhook/post"),
+            supports_send: Some(Signature::accepts(Maybe::Required(format::JSON.clone()))),
+            ..Channel::default()
+        })
+    }
+
+    fn add_incoming_service(&self, hook: &IncomingWebhook) -> Result<(), Error> {
+        let service_id = Self::incoming_service_id(&hook.id);
+        let mut service = Service::empty(&service_id, &Self::adapter_id());
+        service.properties.insert("name".to_owned(), hook.name.clone());
+        service.tags.insert(tag_id!("type:IncomingWebhook"));
+        try!(self.manager.add_service(service));
+        self.manager.add_channel(Channel {
+            id: Self::incoming_channel_id(&hook.id),
+            service: service_id,
+            adapter: Self::adapter_id(),
+            feature: Id::new("webhook/trigger"),
+            supports_fetch: Some(Signature::returns(Maybe::Required(format::JSON.clone()))),
+            supports_watch: Some(Signature {
+                accepts: Maybe::Optional(format::JSON.clone()),
+                returns: Maybe::Required(format::JSON.clone()),
+            }),
+            ..Channel::default()
+        })
+    }
+}
+
+pub struct WebhookAdapter {
+    webhooks: Webhooks,
+}
+
+impl WebhookAdapter {
+    pub fn init<C: Controller>(controller: C, manager: &Arc<AdapterManager>) -> Result<(), Error> {
+        let db_path = controller.get_profile().path_for("webhooks.sqlite");
+        let webhooks = Webhooks::shared(manager, &db_path);
+        try!(manager.add_adapter(Arc::new(WebhookAdapter { webhooks: webhooks.clone() })));
+
+        let stored = try!(webhooks.list().map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        for webhook in stored {
+            if let Err(err) = webhooks.start(&webhook) {
+                error!("[webhooks] Could not start webhook {}: {}", webhook.id, err);
+            }
+        }
+
+        let incoming = try!(webhooks.list_incoming().map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        for hook in incoming {
+            try!(webhooks.add_incoming_service(&hook));
+        }
+        Ok(())
+    }
+
+    /// Gets the channels of the incoming webhooks.
+    fn incoming_channels(&self) -> Result<Vec<Id<Channel>>, Error> {
+        let hooks = try!(self.webhooks.list_incoming().map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        Ok(hooks.iter().map(|hook| Webhooks::incoming_channel_id(&hook.id)).collect())
+    }
+
+    /// Gets the webhooks, indexed by channel id.
+    fn webhooks_by_channel(&self) -> Result<HashMap<Id<Channel>, Webhook>, Error> {
+        let webhooks = try!(self.webhooks.list().map_err(|err| {
+            Error::Internal(InternalError::GenericError(format!("{}", err)))
+        }));
+        Ok(webhooks.into_iter()
+            .map(|webhook| (Webhooks::channel_id(&webhook.id), webhook))
+            .collect())
+    }
+}
+
+impl Adapter for WebhookAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        Webhooks::adapter_id()
+    }
+
+    fn name(&self) -> &str {
+        ADAPTER_NAME
+    }
+
+    fn vendor(&self) -> &str {
+        ADAPTER_VENDOR
+    }
+
+    fn version(&self) -> &[u32; 4] {
+        &ADAPTER_VERSION
+    }
+
+    fn fetch_values(&self,
+                    mut set: Vec<Id<Channel>>,
+                    _: User)
+                    -> ResultMap<Id<Channel>, Option<Value>, Error> {
+        let channels = match self.incoming_channels() {
+            Ok(channels) => channels,
+            Err(err) => return set.drain(..).map(|id| (id, Err(err.clone()))).collect(),
+        };
+        let triggers = self.webhooks.runtime.triggers.lock().unwrap();
+        set.drain(..)
+            .map(|id| {
+                let result = if channels.contains(&id) {
+                    Ok(triggers.last.get(&id).cloned())
+                } else {
+                    Err(Error::OperationNotSupported(Operation::Fetch, id.clone()))
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn send_values(&self,
+                   mut values: HashMap<Id<Channel>, Value>,
+                   _: User)
+                   -> ResultMap<Id<Channel>, (), Error> {
+        let webhooks = match self.webhooks_by_channel() {
+            Ok(webhooks) => webhooks,
+            Err(err) => return values.drain().map(|(id, _)| (id, Err(err.clone()))).collect(),
+        };
+        values.drain()
+            .map(|(id, value)| {
+                let result = match webhooks.get(&id) {
+                    Some(webhook) => self.webhooks.send(webhook, &value),
+                    None => Err(Error::Internal(InternalError::NoSuchChannel(id.clone()))),
+                };
+                (id, result)
+            })
+            .collect()
+    }
+
+    fn register_watch(&self, mut watch: Vec<WatchTarget>) -> WatchResult {
+        let channels = match self.incoming_channels() {
+            Ok(channels) => channels,
+            Err(err) => return watch.drain(..).map(|(id, _, _)| (id, Err(err.clone()))).collect(),
+        };
+        watch.drain(..)
+            .map(|(id, range, tx)| {
+                if !channels.contains(&id) {
+                    return (id.clone(), Err(Error::OperationNotSupported(Operation::Watch, id)));
+                }
+
+                let mut triggers = self.webhooks.runtime.triggers.lock().unwrap();
+                let key = triggers.current_index;
+                triggers.current_index += 1;
+                triggers.map.insert(key, (id.clone(), range, tx));
+
+                let guard = TriggerGuard {
+                    key: key,
+                    runtime: Arc::downgrade(&self.webhooks.runtime),
+                };
+                (id, Ok(Box::new(guard) as Box<AdapterWatchGuard>))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+describe! webhooks {
+    before_each {
+        use adapters::virtual_devices::{Kind, VirtualAdapter, VirtualDevices};
+        use foxbox_taxonomy::api::{API, Targetted, User};
+        use foxbox_taxonomy::io::Payload;
+        use foxbox_taxonomy::manager::AdapterManager;
+        use foxbox_taxonomy::selector::ChannelSelector;
+        use foxbox_core::traits::Controller;
+        use foxbox_taxonomy::values::{format, Json, OnOff, Value};
+        use hyper::server::{Request, Response, Server};
+        use hyper::status::StatusCode;
+        use serde_json;
+        use std::io::Read;
+        use std::sync::{Arc, Mutex};
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+        use stubs::controller::ControllerStub;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("webhooks").unwrap();
+        let db_path = dir.path().join("webhooks.sqlite");
+        let manager = Arc::new(AdapterManager::new(None));
+        let webhooks = Webhooks::new(&manager,
+                                     db_path.to_str().unwrap(),
+                                     Duration::from_millis(10));
+        manager.add_adapter(Arc::new(WebhookAdapter { webhooks: webhooks.clone() })).unwrap();
+        let controller = ControllerStub::new();
+        VirtualAdapter::init(controller.clone(), &manager).unwrap();
+        let db_path = controller.get_profile().path_for("virtual_devices.sqlite");
+        let devices = VirtualDevices::new(&manager, &db_path);
+
+        // A local stand-in for the webhook services, answering with `status` and
+        // forwarding the signature and the body of the requests.
+        let status = Arc::new(Mutex::new(StatusCode::Ok));
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        let server_status = status.clone();
+        let mut listening = Server::http("127.0.0.1:0").unwrap()
+            .handle(move |mut req: Request, mut res: Response| {
+                let mut body = String::new();
+                req.read_to_string(&mut body).unwrap();
+                let signature = req.headers.get::<WebhookSignature>().map(|sig| sig.0.clone());
+                let _ = tx.lock().unwrap().send((signature, body));
+                *res.status_mut() = *server_status.lock().unwrap();
+                res.send(b"").unwrap();
+            })
+            .unwrap();
+        let url = format!("http://{}/hook", listening.socket);
+
+        let send = |id: &str, value: Value| {
+            let selector = ChannelSelector::new().with_id(&Webhooks::channel_id(id));
+            let results = manager.send_values(vec![Targetted {
+                                                       select: vec![selector],
+                                                       payload: Payload::from_value(&value,
+                                                                                    &format::JSON)
+                                                           .unwrap(),
+                                                   }],
+                                              User::None);
+            for (_, result) in results {
+                result.unwrap();
+            }
+        };
+        let wait_for = |condition: &Fn() -> bool| {
+            for _ in 0..200 {
+                if condition() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("Timed out");
+        };
+    }
+
+    after_each {
+        listening.close().unwrap();
+    }
+
+    it "should deliver signed events of the watched channels" {
+        devices.create("Away", Kind::AwayMode, None).unwrap();
+        let watch = serde_json::from_str(r#"[{"select": {"feature": "away-mode/is-on"}}]"#)
+            .unwrap();
+        webhooks.create("Away", &url, Some(watch), Some("s3cr3t".to_owned())).unwrap();
+
+        let selector = ChannelSelector::new().with_id(&VirtualDevices::channel_id("away"));
+        manager.send_values(vec![Targetted {
+                                     select: vec![selector],
+                                     payload: Payload::from_value(&Value::new(OnOff::On),
+                                                                  &format::ON_OFF)
+                                         .unwrap(),
+                                 }],
+                            User::None);
+
+        let (signature, body) = rx.recv().unwrap();
+        assert_eq!(signature, Some(format!("sha256={}", sign("s3cr3t", &body))));
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.find("webhook").unwrap().as_str(), Some("away"));
+        assert_eq!(json.find("event").unwrap().as_str(), Some("range/enter"));
+        assert_eq!(json.find_path(&["data", "value"]).unwrap().as_str(), Some("On"));
+
+        wait_for(&|| !webhooks.deliveries("away").unwrap().is_empty());
+        let deliveries = webhooks.deliveries("away").unwrap();
+        assert_eq!(deliveries[0].status, Some(200));
+        assert_eq!(deliveries[0].attempt, 1);
+    }
+
+    it "should deliver the values sent to the webhook channel" {
+        webhooks.create("IFTTT", &url, None, None).unwrap();
+        send("ifttt", Value::new(Json(serde_json::from_str(r#"{"value1": 3}"#).unwrap())));
+
+        let (signature, body) = rx.recv().unwrap();
+        assert_eq!(signature, None);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.find("event").unwrap().as_str(), Some("send"));
+        assert_eq!(json.find_path(&["data", "value1"]).unwrap().as_u64(), Some(3));
+    }
+
+    it "should not delay the events of a webhook behind a slow one" {
+        use std::net::TcpListener;
+
+        // Accepts the connections but never answers.
+        let slow = TcpListener::bind("127.0.0.1:0").unwrap();
+        let slow_url = format!("http://{}/hook", slow.local_addr().unwrap());
+        webhooks.create("Slow", &slow_url, None, None).unwrap();
+        webhooks.create("Fast", &url, None, None).unwrap();
+
+        send("slow", Value::new(Json(serde_json::Value::Null)));
+        send("fast", Value::new(Json(serde_json::Value::Null)));
+
+        let (_, body) = rx.recv_timeout(Duration::from_secs(REQUEST_TIMEOUT_S / 2)).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.find("webhook").unwrap().as_str(), Some("fast"));
+    }
+
+    it "should drop the oldest events of a webhook that can't keep up" {
+        use std::net::TcpListener;
+
+        let slow = TcpListener::bind("127.0.0.1:0").unwrap();
+        let slow_url = format!("http://{}/hook", slow.local_addr().unwrap());
+        webhooks.create("Slow", &slow_url, None, None).unwrap();
+        for _ in 0..MAX_QUEUED_JOBS + 5 {
+            send("slow", Value::new(Json(serde_json::Value::Null)));
+        }
+
+        // The first event is being delivered, the next 4 don't fit in the queue.
+        wait_for(&|| webhooks.deliveries("slow").unwrap().len() >= 4);
+        let deliveries = webhooks.deliveries("slow").unwrap();
+        assert!(deliveries.iter().all(|delivery| delivery.status.is_none()));
+        assert!(deliveries.iter()
+            .all(|delivery| delivery.error.as_ref().map_or(false, |err| err.contains("Dropped"))));
+    }
+
+    it "should retry and then disable failing webhooks" {
+        *status.lock().unwrap() = StatusCode::InternalServerError;
+        webhooks.create("Broken", &url, None, None).unwrap();
+        for _ in 0..MAX_FAILURES {
+            send("broken", Value::new(Json(serde_json::Value::Null)));
+        }
+        wait_for(&|| !webhooks.get("broken").unwrap().enabled);
+
+        let webhook = webhooks.get("broken").unwrap();
+        assert_eq!(webhook.failures, MAX_FAILURES);
+        let deliveries = webhooks.deliveries("broken").unwrap();
+        assert_eq!(deliveries.len(), (MAX_ATTEMPTS * MAX_FAILURES) as usize);
+        assert!(deliveries.iter().all(|delivery| delivery.status == Some(500)));
+        assert_eq!(deliveries.iter().map(|delivery| delivery.attempt).max(),
+                   Some(MAX_ATTEMPTS));
+
+        // Disabled webhooks refuse the values, until they are enabled again.
+        let selector = ChannelSelector::new().with_id(&Webhooks::channel_id("broken"));
+        let results = manager.send_values(vec![Targetted {
+                                                   select: vec![selector],
+                                                   payload: Payload::from_value(
+                                                       &Value::new(Json(serde_json::Value::Null)),
+                                                       &format::JSON).unwrap(),
+                                               }],
+                                          User::None);
+        assert!(results.values().all(|result| result.is_err()));
+
+        let mut webhook = webhook.clone();
+        webhook.enabled = true;
+        assert_eq!(webhooks.update(&webhook).unwrap().failures, 0);
+    }
+
+    it "should reject invalid webhooks" {
+        match webhooks.create("Hook", "ftp://example.com", None, None) {
+            Err(WebhookError::InvalidWebhook(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match webhooks.create(" ", &url, None, None) {
+            Err(WebhookError::InvalidWebhook(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match webhooks.create("Hook", &url, Some(serde_json::Value::U64(1)), None) {
+            Err(WebhookError::InvalidWebhook(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(webhooks.list().unwrap().is_empty());
+    }
+
+    it "should notify the watchers of incoming webhooks" {
+        use foxbox_taxonomy::api::WatchEvent as Event;
+        use foxbox_taxonomy::util::Exactly;
+        use transformable_channels::mpsc;
+
+        let hook = webhooks.create_incoming("Doorbell").unwrap();
+        assert_eq!(hook.id, "doorbell");
+        assert_eq!(hook.token.len(), 32);
+        assert!(webhooks.create_incoming("Doorbell").unwrap().token != hook.token);
+
+        let (tx, watch_rx) = mpsc::channel();
+        let selector = ChannelSelector::new()
+            .with_id(&Webhooks::incoming_channel_id("doorbell"));
+        let range = Value::new(Json(serde_json::from_str(r#"{"button": "front"}"#).unwrap()));
+        let _guard = manager.watch_values(vec![Targetted {
+                                                   select: vec![selector.clone()],
+                                                   payload: Exactly::Exactly(Payload::from_value(
+                                                       &range,
+                                                       &format::JSON).unwrap()),
+                                               }],
+                                          Box::new(tx));
+
+        // Values outside of the range are ignored, matching ones enter and exit it.
+        let back = serde_json::from_str(r#"{"button": "back"}"#).unwrap();
+        webhooks.trigger(&hook.token, &back).unwrap();
+        let front = serde_json::from_str(r#"{"button": "front", "count": 2}"#).unwrap();
+        webhooks.trigger(&hook.token, &front).unwrap();
+        match watch_rx.recv().unwrap() {
+            Event::EnterRange { value, format, .. } => {
+                assert_eq!(value.to_value(&format).unwrap(), Value::new(Json(front.clone())))
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+        match watch_rx.recv().unwrap() {
+            Event::ExitRange { .. } => {}
+            other => panic!("Unexpected event {:?}", other),
+        }
+
+        let (_, result) = manager.fetch_values(vec![selector.clone()], User::None)
+            .into_iter()
+            .next()
+            .unwrap();
+        let (payload, format) = result.unwrap().unwrap();
+        assert_eq!(payload.to_value(&format).unwrap(), Value::new(Json(front)));
+
+        webhooks.revoke(&hook.token).unwrap();
+        assert!(manager.get_channels(vec![selector]).is_empty());
+        match webhooks.trigger(&hook.token, &back) {
+            Err(WebhookError::NoSuchWebhook(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(webhooks.list_incoming().unwrap().len(), 1);
+    }
+
+    it "should remove webhooks" {
+        webhooks.create("Hook", &url, None, None).unwrap();
+        webhooks.remove("hook").unwrap();
+        assert!(webhooks.list().unwrap().is_empty());
+        let selector = ChannelSelector::new().with_id(&Webhooks::channel_id("hook"));
+        assert!(manager.get_channels(vec![selector]).is_empty());
+        match webhooks.remove("hook") {
+            Err(WebhookError::NoSuchWebhook(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
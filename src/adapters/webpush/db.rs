@@ -18,6 +18,7 @@
 //! issued a push notification on each of their subscriptions.
 //!
 
+use foxbox_core::migrations::{self, Migration};
 use foxbox_taxonomy::api::User;
 use super::Subscription;
 use libc::c_int;
@@ -46,29 +47,35 @@ pub struct WebPushDb {
     db: Connection,
 }
 
+/// The schema version of the `WebPush` database written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The schema history of the `WebPush` database.
+static MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [
+    Migration {
+        version: 1,
+        description: "Create the subscriptions and resources tables",
+        sql: "CREATE TABLE IF NOT EXISTS subscriptions (
+                  user_id     TEXT,
+                  push_uri    TEXT NOT NULL UNIQUE,
+                  public_key  TEXT NOT NULL,
+                  auth        TEXT
+              );
+              CREATE TABLE IF NOT EXISTS resources (
+                  user_id     TEXT,
+                  resource    TEXT NOT NULL
+              );",
+    },
+];
+
 impl WebPushDb {
     /// Opens the database at `path` and creates it if not available yet.
+    /// Panics if the database can't be opened or migrated to the current schema.
     pub fn new(path: &str) -> Self {
-        let db = Connection::open(path).unwrap();
-        db.execute("CREATE TABLE IF NOT EXISTS subscriptions (
-                    user_id     \
-                      TEXT,
-                    push_uri    TEXT NOT NULL UNIQUE,
-                    \
-                      public_key  TEXT NOT NULL,
-                    auth        TEXT
-            \
-                      )",
-                     &[])
-            .unwrap();
-
-        db.execute("CREATE TABLE IF NOT EXISTS resources (
-                    user_id     \
-                      TEXT,
-                    resource    TEXT NOT NULL
-            )",
-                     &[])
-            .unwrap();
+        let mut db = Connection::open(path).unwrap();
+        migrations::migrate(&mut db, "webpush", &MIGRATIONS).unwrap_or_else(|err| {
+            panic!("Unable to migrate the webpush database: {}", err);
+        });
 
         WebPushDb { db: db }
     }
@@ -263,6 +270,30 @@ describe! tests {
         assert_eq!(subs4.len(), 0);
     }
 
+    it "should upgrade an unversioned database" {
+        use foxbox_core::migrations::schema_version;
+        use super::super::Subscription;
+
+        let sub = Subscription {
+            push_uri: "legacy_push_uri".to_owned(),
+            public_key: "legacy_public_key".to_owned(),
+            auth: None
+        };
+        db.subscribe(&User::Id(String::from("1")), &sub).unwrap();
+        drop(db);
+
+        // Databases created before versioning have no user_version.
+        {
+            let legacy = Connection::open(&get_db_environment()).unwrap();
+            legacy.execute_batch("PRAGMA user_version = 0").unwrap();
+        }
+
+        let db = WebPushDb::new(&get_db_environment());
+        assert_eq!(schema_version(&db.db).unwrap(), MIGRATIONS.len() as u32);
+        let subs = db.get_subscriptions(&User::Id(String::from("1"))).unwrap();
+        assert_eq!(subs, vec![sub]);
+    }
+
     after_each {
         remove_test_db();
     }
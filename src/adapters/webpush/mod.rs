@@ -13,7 +13,7 @@
 //!
 
 mod crypto;
-mod db;
+pub mod db;
 
 use foxbox_taxonomy::api::{Error, InternalError, User};
 use foxbox_taxonomy::channel::*;
@@ -34,6 +34,8 @@ use std::cmp::max;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::thread;
+use foxbox_core::config_schema::ConfigKey;
+use foxbox_core::metrics;
 use foxbox_core::traits::Controller;
 
 header! { (Encryption, "Encryption") => [String] }
@@ -45,6 +47,19 @@ static ADAPTER_NAME: &'static str = "WebPush adapter (built-in)";
 static ADAPTER_VENDOR: &'static str = "team@link.mozilla.org";
 static ADAPTER_VERSION: [u32; 4] = [0, 0, 0, 0];
 
+/// Counts a notification as `delivered` or `failure`.
+fn record_notification(result: &'static str) {
+    metrics::global().inc("foxbox_webpush_notifications_total",
+                          "Number of push notifications sent, by result.",
+                          vec![("result", result.to_owned())]);
+}
+
+fn gcm_api_key_config() -> ConfigKey {
+    ConfigKey::string("webpush", "gcm_api_key", "")
+        .secret()
+        .description("Google Cloud Messaging API key, in base64")
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Subscription {
     pub push_uri: String,
@@ -104,6 +119,7 @@ impl Subscription {
                 warn!("notity subscription {} failed for {}",
                       self.push_uri,
                       message);
+                record_notification("failure");
                 return;
             }
         };
@@ -132,6 +148,7 @@ impl Subscription {
             if gcm_api_key.is_empty() {
                 warn!("cannot notify subscription {}, GCM API key missing from foxbox.conf",
                       push_uri);
+                record_notification("failure");
                 return;
             }
             req = req.header(Authorization(format!("key={}", gcm_api_key)));
@@ -168,6 +185,7 @@ impl Subscription {
             Ok(x) => x,
             Err(e) => {
                 warn!("notify subscription {} failed: {:?}", push_uri, e);
+                record_notification("failure");
                 return;
             }
         };
@@ -175,6 +193,11 @@ impl Subscription {
         info!("notified subscription {} (status {:?})",
               push_uri,
               rsp.status);
+        record_notification(if rsp.status.is_success() {
+            "delivered"
+        } else {
+            "failure"
+        });
     }
 }
 
@@ -312,6 +335,8 @@ impl<C: Controller> Adapter for WebPush<C> {
 
 impl<C: Controller> WebPush<C> {
     pub fn init(controller: C, adapt: &Arc<AdapterManager>) -> Result<(), Error> {
+        controller.get_config().register(gcm_api_key_config());
+
         let wp = Arc::new(Self::new(controller));
         let id = WebPush::<C>::id();
         let service_id = WebPush::<C>::service_webpush_id();
@@ -419,8 +444,7 @@ impl<C: Controller> WebPush<C> {
         } else {
             let json = json!({resource: setter.resource, message: setter.message});
             let crypto = self.crypto.clone();
-            let gcm_api_key =
-                self.controller.get_config().get_or_set_default("webpush", "gcm_api_key", "");
+            let gcm_api_key = self.controller.get_config().get_value(&gcm_api_key_config());
 
             thread::spawn(move || {
                 for sub in subscriptions {
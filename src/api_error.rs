@@ -0,0 +1,145 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The error responses of the REST API.
+//!
+//! All the errors are sent as json objects like
+//! {"error": {"code": "missing_field", "message": "Missing field `value`", "path": "body[0]"}}
+//! where `code` is stable and meant for programs, `message` is meant for humans, and the
+//! optional `path` and `channel` are the offending value of the request body and the
+//! offending channel. The errors of individual channels in the results of the taxonomy
+//! API are embedded in the same way, e.g. {"channel id": {"error": {...}}}.
+//!
+//! This also provides the helpers shared by the routers to read json bodies and
+//! send json responses.
+
+use foxbox_taxonomy::api::Error;
+use foxbox_taxonomy::parse::{JSON, ParseError, ToJSON};
+use iron::{IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::status::Status;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::io::Read;
+
+/// The maximum size of the json bodies read by `read_json`.
+pub const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// The code of the errors that don't have a more specific one.
+fn default_code(status: Status) -> &'static str {
+    match status {
+        Status::BadRequest => "bad_request",
+        Status::Unauthorized => "unauthorized",
+        Status::Forbidden => "forbidden",
+        Status::NotFound => "not_found",
+        Status::MethodNotAllowed => "method_not_allowed",
+        Status::Conflict => "conflict",
+        Status::PayloadTooLarge => "payload_too_large",
+        Status::TooManyRequests => "too_many_requests",
+        Status::BadGateway => "bad_gateway",
+        Status::GatewayTimeout => "timeout",
+        _ => "internal_error",
+    }
+}
+
+/// Builds the response for an error already converted to json.
+fn error_response(status: Status, error: JSON) -> Response {
+    let body = vec![("error", error)].to_json();
+    let mut response = Response::with((status, serde_json::to_string(&body).unwrap()));
+    response.headers.set(ContentType::json());
+    response
+}
+
+/// Builds an error response with the default code of `status`.
+pub fn response<M: Into<String>>(status: Status, message: M) -> Response {
+    with_code(status, default_code(status), message)
+}
+
+/// Builds an error response with a specific code.
+pub fn with_code<M: Into<String>>(status: Status, code: &str, message: M) -> Response {
+    let error = vec![("code", code.to_json()), ("message", message.into().to_json())];
+    error_response(status, error.to_json())
+}
+
+/// Builds the response for an error of the taxonomy API.
+pub fn from_api_error(err: &Error) -> Response {
+    error_response(Status::from_u16(err.status()), err.to_json())
+}
+
+/// Builds the response for a request body that can't be parsed.
+pub fn from_parse_error(err: &ParseError) -> Response {
+    error_response(Status::BadRequest, err.to_json())
+}
+
+/// Builds the json response of a successful request.
+pub fn json_response<T: Serialize>(status: Status, value: &T) -> IronResult<Response> {
+    let serialized = itry!(serde_json::to_string(value));
+    let mut response = Response::with((status, serialized));
+    response.headers.set(ContentType::json());
+    Ok(response)
+}
+
+/// Reads a json body of at most `MAX_BODY_SIZE` bytes, an empty body being null.
+/// `what` is the expected value in the error messages, e.g. "scene".
+pub fn read_json<T: Deserialize>(req: &mut Request, what: &str) -> Result<T, Response> {
+    let mut source = String::new();
+    if let Err(err) = req.body.by_ref().take(MAX_BODY_SIZE + 1).read_to_string(&mut source) {
+        return Err(response(Status::BadRequest, format!("{}", err)));
+    }
+    if source.len() as u64 > MAX_BODY_SIZE {
+        return Err(response(Status::PayloadTooLarge,
+                            format!("The body exceeds {} bytes", MAX_BODY_SIZE)));
+    }
+    let source = if source.trim().is_empty() { "null" } else { &source };
+    serde_json::from_str(source)
+        .map_err(|err| response(Status::BadRequest, format!("Invalid {}: {}", what, err)))
+}
+
+#[cfg(test)]
+describe! api_error {
+    before_each {
+        use foxbox_taxonomy::api::{Error, InternalError};
+        use foxbox_taxonomy::parse::{ParseError, Path};
+        use foxbox_taxonomy::util::Id;
+        use iron::status::Status;
+        use iron_test::response::extract_body_to_string;
+        use serde_json;
+    }
+
+    it "should use the default code of the status" {
+        let response = response(Status::NotFound, "Unknown url: /foo");
+        assert_eq!(response.status, Some(Status::NotFound));
+        assert_eq!(extract_body_to_string(response),
+                   r#"{"error":{"code":"not_found","message":"Unknown url: /foo"}}"#);
+    }
+
+    it "should map the taxonomy errors to http statuses" {
+        let no_such_channel = Error::Internal(InternalError::NoSuchChannel(Id::new("door")));
+        let response = from_api_error(&no_such_channel);
+        assert_eq!(response.status, Some(Status::NotFound));
+        let json: serde_json::Value = serde_json::from_str(&extract_body_to_string(response))
+            .unwrap();
+        assert_eq!(json.find_path(&["error", "code"]).unwrap().as_str(), Some("no_such_channel"));
+        assert_eq!(json.find_path(&["error", "channel"]).unwrap().as_str(), Some("door"));
+
+        let timeout = Error::Timeout(Id::new("adapter@link.mozilla.org"));
+        assert_eq!(from_api_error(&timeout).status, Some(Status::GatewayTimeout));
+        let duplicate = Error::Internal(InternalError::DuplicateService(Id::new("service")));
+        assert_eq!(from_api_error(&duplicate).status, Some(Status::Conflict));
+        let adapter = Error::Internal(InternalError::GenericError("Unreachable".to_owned()));
+        assert_eq!(from_api_error(&adapter).status, Some(Status::BadGateway));
+    }
+
+    it "should include the path of parse errors" {
+        let error = Path::new().push_str("body", |path| {
+            path.push_index(0, |path| ParseError::missing_field("value", &path))
+        });
+        let response = from_parse_error(&error);
+        assert_eq!(response.status, Some(Status::BadRequest));
+        let json: serde_json::Value = serde_json::from_str(&extract_body_to_string(response))
+            .unwrap();
+        assert_eq!(json.find_path(&["error", "code"]).unwrap().as_str(), Some("missing_field"));
+        assert_eq!(json.find_path(&["error", "path"]).unwrap().as_str(), Some("body[0]"));
+    }
+}
@@ -0,0 +1,286 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Admin endpoint to query the audit log of state-changing operations.
+//!
+//! GET /api/v1/audit : returns the matching entries, most recent first. The optional
+//!                     query parameters are `user`, `action`, `target` (a service or
+//!                     channel id), `since` and `until` (RFC 3339 dates) and `limit`.
+//!
+//! This also provides `Audited`, a handler recording the requests handled
+//! outside of the taxonomy API, e.g. those managing users, the configuration,
+//! backups, certificates, webhooks, hooks, scenes and groups. Only the
+//! requests of authenticated users are recorded, so that anybody can't fill
+//! the log, e.g. with failed logins.
+
+use api_error;
+use auth;
+use chrono::{DateTime, UTC};
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::api::User;
+use foxbox_taxonomy::audit::{AuditEntry, AuditFilter, AuditLog};
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::parse::ToJSON;
+use foxbox_users::AuthEndpoint;
+use iron::{Handler, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use iron::typemap::Key;
+use serde_json;
+use std::sync::Arc;
+use url::form_urlencoded;
+
+pub struct AuditRouter<T> {
+    controller: T,
+    audit: Arc<AuditLog>,
+}
+
+impl<T: Controller> AuditRouter<T> {
+    pub fn new(controller: T, adapter_api: &Arc<AdapterManager>) -> Self {
+        AuditRouter {
+            controller: controller,
+            audit: adapter_api.get_audit_log(),
+        }
+    }
+}
+
+/// Reads the filter from a query string, e.g. `user=1&since=2016-10-01T00:00:00Z`.
+fn parse_filter(query: Option<&str>) -> Result<AuditFilter, String> {
+    let mut filter = AuditFilter::default();
+    let query = match query {
+        Some(query) => query,
+        None => return Ok(filter),
+    };
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        let parse_date = |value: &str| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|date| date.with_timezone(&UTC))
+                .map_err(|_| format!("Invalid {}: {}", key, value))
+        };
+        match &*key {
+            "user" => filter.user = Some(value.into_owned()),
+            "action" => filter.action = Some(value.into_owned()),
+            "target" => filter.target = Some(value.into_owned()),
+            "since" => filter.since = Some(try!(parse_date(&value))),
+            "until" => filter.until = Some(try!(parse_date(&value))),
+            "limit" => {
+                filter.limit = Some(try!(value.parse::<u32>()
+                    .map_err(|_| format!("Invalid limit: {}", value))))
+            }
+            _ => return Err(format!("Unknown parameter: {}", key)),
+        }
+    }
+    Ok(filter)
+}
+
+impl<T: Controller> Handler for AuditRouter<T> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if let Err(response) = auth::check_admin(req, &self.controller.get_users_manager()) {
+            return Ok(response);
+        }
+
+        if req.method != Method::Get {
+            return Ok(api_error::response(Status::MethodNotAllowed,
+                                          format!("Bad method: {}", req.method)));
+        }
+
+        let filter = match parse_filter(req.url.query()) {
+            Ok(filter) => filter,
+            Err(err) => return Ok(api_error::response(Status::BadRequest, err)),
+        };
+
+        match self.audit.query(&filter) {
+            Ok(entries) => {
+                let serialized = itry!(serde_json::to_string(&entries.to_json()));
+                let mut response = Response::with((Status::Ok, serialized));
+                response.headers.set(ContentType::json());
+                Ok(response)
+            }
+            Err(err) => {
+                Ok(api_error::response(Status::InternalServerError,
+                                       format!("Unable to read the audit log: {}", err)))
+            }
+        }
+    }
+}
+
+/// Set by the handlers wrapped in `Audited` to record a request at another
+/// path than its url, e.g. at the id of an incoming webhook rather than at its
+/// secret token. The anonymous requests that have one are recorded too, as
+/// their handler authenticated them by other means.
+pub struct AuditedPath;
+
+impl Key for AuditedPath {
+    type Value = String;
+}
+
+/// Records the requests handled by `handler` that may change the state of
+/// the box, i.e. all but GET ones, as `action`.
+pub struct Audited<H> {
+    handler: H,
+    action: &'static str,
+    audit: Arc<AuditLog>,
+}
+
+impl<H: Handler> Audited<H> {
+    pub fn new(handler: H, action: &'static str, adapter_api: &Arc<AdapterManager>) -> Self {
+        Audited {
+            handler: handler,
+            action: action,
+            audit: adapter_api.get_audit_log(),
+        }
+    }
+}
+
+impl<H: Handler> Handler for Audited<H> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        // The user is only known when the request carries a valid session token.
+        let user = auth::user_from_request(req).ok();
+        let result = self.handler.handle(req);
+        if req.method == Method::Get {
+            return result;
+        }
+
+        let status = match result {
+            Ok(ref response) => response.status,
+            Err(ref err) => err.response.status,
+        };
+        let path = req.extensions.get::<AuditedPath>().cloned();
+        let user = match (user, path.is_some()) {
+            (Some(User::Id(id)), _) => User::Id(id),
+            (_, true) => User::None,
+            // Nobody to hold accountable, e.g. a failed login.
+            _ => return result,
+        };
+        let payload = json_value!({
+            method: req.method.to_string(),
+            path: path.unwrap_or_else(|| format!("/{}", req.url.path().join("/"))),
+            status: status.map(|status| status.to_u16())
+        });
+        let mut entry = AuditEntry::new(&user, self.action).with_payload(None, payload);
+        if !status.map_or(false, |status| status.is_success()) {
+            entry = entry.with_error(format!("Request failed: {:?}", status));
+        }
+        self.audit.record(entry);
+        result
+    }
+}
+
+pub fn create<T>(controller: T,
+                 adapter_api: &Arc<AdapterManager>)
+                 -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    // Keep these in sync with the handle() method. They are relative to api/v1.
+    let endpoints = vec![
+        (vec![Method::Get], "audit".to_owned()),
+    ];
+
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        endpoints.iter().map(|item| AuthEndpoint(item.0.clone(), item.1.clone())).collect()
+    } else {
+        vec![]
+    };
+
+    let mut chain = Chain::new(AuditRouter::new(controller.clone(), adapter_api));
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! audit_router {
+    before_each {
+        use foxbox_taxonomy::api::User;
+        use foxbox_taxonomy::audit::AuditEntry;
+        use foxbox_taxonomy::manager::AdapterManager;
+        use foxbox_taxonomy::util::Id;
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{request, response};
+        use mount::Mount;
+        use serde_json;
+        use std::sync::Arc;
+        use stubs::controller::ControllerStub;
+
+        let taxo_manager = Arc::new(AdapterManager::new(None));
+        let audit = taxo_manager.get_audit_log();
+        audit.record(AuditEntry::new(&User::Id("1".to_owned()), "send_values")
+            .with_channel(&Id::new("door")));
+        audit.record(AuditEntry::new(&User::None, "add_service_tags"));
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1/audit", create(ControllerStub::new(), &taxo_manager).0);
+    }
+
+    it "should return all the entries, most recent first" {
+        let response = request::get("http://localhost:3000/api/v1/audit",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let entries = json.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].find("action").unwrap().as_str(), Some("add_service_tags"));
+    }
+
+    it "should filter the entries" {
+        let response = request::get("http://localhost:3000/api/v1/audit?user=1&target=door",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let entries = json.as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].find("channel").unwrap().as_str(), Some("door"));
+    }
+
+    it "should record the requests changing the state of the box" {
+        use iron::{IronResult, Request, Response};
+
+        fn handler(req: &mut Request) -> IronResult<Response> {
+            if req.url.path().contains(&"secret") {
+                req.extensions.insert::<AuditedPath>("/foo/:secret".to_owned());
+            }
+            Ok(Response::with(Status::Created))
+        }
+        mount.mount("/api/v1/config", Audited::new(handler, "config", &taxo_manager));
+
+        request::get("http://localhost:3000/api/v1/config", Headers::new(), &mount).unwrap();
+        request::put("http://localhost:3000/api/v1/config/foo/secret",
+                     Headers::new(),
+                     "baz",
+                     &mount).unwrap();
+
+        let entries = audit.query(&Default::default()).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].action, "config");
+        assert_eq!(entries[0].payload.find("method").unwrap().as_str(), Some("PUT"));
+        assert_eq!(entries[0].payload.find("path").unwrap().as_str(), Some("/foo/:secret"));
+    }
+
+    it "should not record the requests of nobody" {
+        use iron::{IronResult, Request, Response};
+
+        fn handler(_: &mut Request) -> IronResult<Response> {
+            Ok(Response::with(Status::Unauthorized))
+        }
+        mount.mount("/api/v1/users", Audited::new(handler, "users", &taxo_manager));
+
+        request::post("http://localhost:3000/api/v1/users/login",
+                      Headers::new(),
+                      "",
+                      &mount).unwrap();
+        assert_eq!(audit.query(&Default::default()).unwrap().len(), 2);
+    }
+
+    it "should reject invalid filters" {
+        let response = request::get("http://localhost:3000/api/v1/audit?since=yesterday",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,55 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Helpers shared by the HTTP routers to identify the user making a request.
+
+use api_error;
+use foxbox_taxonomy::api::User;
+use foxbox_users::{ReadFilter, SessionToken, UsersManager};
+
+use iron::{headers, Request, Response};
+use iron::status::Status;
+
+/// Extracts the user from the bearer token of a request.
+/// Returns `User::None` when there is no token and a 401 response if
+/// the token is invalid.
+pub fn user_from_request(req: &Request) -> Result<User, Response> {
+    match req.headers.get::<headers::Authorization<headers::Bearer>>() {
+        Some(&headers::Authorization(headers::Bearer { ref token })) => {
+            match SessionToken::from_string(token) {
+                Ok(token) => Ok(User::Id(token.claims.id)),
+                Err(_) => Err(api_error::response(Status::Unauthorized, "Invalid token")),
+            }
+        }
+        _ => Ok(User::None),
+    }
+}
+
+/// Checks that a request is made by an admin user, returning the response to
+/// send back otherwise.
+/// Always succeeds when the authentication feature is disabled, and in tests.
+pub fn check_admin(req: &Request, users_manager: &UsersManager) -> Result<User, Response> {
+    let user = try!(user_from_request(req));
+    if !cfg!(feature = "authentication") || cfg!(test) {
+        return Ok(user);
+    }
+
+    let id = match user {
+        User::Id(ref id) => id.clone(),
+        User::None => {
+            return Err(api_error::response(Status::Unauthorized, "Authentication required"))
+        }
+    };
+
+    match users_manager.get_db().read(ReadFilter::IsAdmin(true)) {
+        Ok(admins) => {
+            if admins.iter().any(|admin| admin.id == id) {
+                Ok(user)
+            } else {
+                Err(api_error::response(Status::Forbidden, "Admin users only"))
+            }
+        }
+        Err(_) => Err(api_error::response(Status::InternalServerError, "Unable to read the users")),
+    }
+}
@@ -0,0 +1,655 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Backup and restore of the profile directory.
+//!
+//! A backup is a gzipped tarball of the profile directory with a
+//! `manifest.json` at its root. `SQLite` databases are copied with the online
+//! backup API so that the archive is consistent even while the box runs.
+//!
+//! When a passphrase is provided the tarball is encrypted with AES-256-GCM,
+//! using a key derived from the passphrase with PBKDF2. The encrypted file is
+//! laid out as: magic | salt | nonce | tag | ciphertext.
+
+extern crate crypto;
+
+use self::crypto::aead::{AeadDecryptor, AeadEncryptor};
+use self::crypto::aes::KeySize;
+use self::crypto::aes_gcm::AesGcm;
+use self::crypto::hmac::Hmac;
+use self::crypto::pbkdf2::pbkdf2;
+use self::crypto::sha2::Sha256;
+
+use chrono::UTC;
+use foxbox_core::migrations;
+use foxbox_core::profile_service::ProfileService;
+use rand::Rng;
+use rand::os::OsRng;
+use rusqlite::{self, Connection, DatabaseName};
+use serde_json;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+/// Bump this when the layout of the archive changes.
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &'static str = "manifest.json";
+const ENCRYPTED_MAGIC: &'static [u8] = b"FXBKENC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const PBKDF2_ROUNDS: u32 = 10000;
+
+// Temporary directories created in the profile while backing up and restoring.
+const STAGING_PREFIX: &'static str = ".backup-";
+
+// The checked content of a backup to restore on the next start, skipped by the
+// backups since it starts with `STAGING_PREFIX`.
+const PENDING_RESTORE_DIR: &'static str = ".backup-pending";
+
+#[derive(Debug)]
+pub enum BackupError {
+    Io(io::Error),
+    Sqlite(rusqlite::Error),
+    InvalidArchive(String),
+    IncompatibleVersion(u32),
+    PassphraseRequired,
+    BadPassphrase,
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BackupError::Io(ref err) => write!(f, "I/O error: {}", err),
+            BackupError::Sqlite(ref err) => write!(f, "Database error: {}", err),
+            BackupError::InvalidArchive(ref reason) => write!(f, "Invalid backup: {}", reason),
+            BackupError::IncompatibleVersion(version) => {
+                write!(f,
+                       "Backup format version {} is not supported (expected {})",
+                       version,
+                       BACKUP_FORMAT_VERSION)
+            }
+            BackupError::PassphraseRequired => write!(f, "This backup is encrypted"),
+            BackupError::BadPassphrase => write!(f, "Wrong passphrase or corrupted backup"),
+        }
+    }
+}
+
+impl Error for BackupError {
+    fn description(&self) -> &str {
+        match *self {
+            BackupError::Io(ref err) => err.description(),
+            BackupError::Sqlite(ref err) => err.description(),
+            BackupError::InvalidArchive(_) => "Invalid backup",
+            BackupError::IncompatibleVersion(_) => "Unsupported backup version",
+            BackupError::PassphraseRequired => "This backup is encrypted",
+            BackupError::BadPassphrase => "Wrong passphrase or corrupted backup",
+        }
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> Self {
+        BackupError::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for BackupError {
+    fn from(err: rusqlite::Error) -> Self {
+        BackupError::Sqlite(err)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    format_version: u32,
+    foxbox_version: String,
+    created: String,
+    files: Vec<String>,
+}
+
+/// A directory removed when dropped.
+struct StagingDir(PathBuf);
+
+impl StagingDir {
+    fn new(profile: &ProfileService) -> io::Result<Self> {
+        let path = PathBuf::from(profile.path_for(&format!("{}{}",
+                                                           STAGING_PREFIX,
+                                                           ::rand::random::<u32>())));
+        try!(fs::create_dir_all(&path));
+        Ok(StagingDir(path))
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            warn!("Unable to remove {:?}: {}", self.0, err);
+        }
+    }
+}
+
+fn is_sqlite(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "sqlite")
+}
+
+/// Returns the files of a directory tree, relative to `root`, skipping
+/// transient files.
+fn collect_files(root: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(root.join(relative))) {
+        let entry = try!(entry);
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(STAGING_PREFIX) || name.ends_with(".updated") ||
+           name.ends_with("-journal") || name.ends_with("-wal") ||
+           name.ends_with("-shm") {
+            continue;
+        }
+        let path = relative.join(&name);
+        let file_type = try!(entry.file_type());
+        if file_type.is_dir() {
+            try!(collect_files(root, &path, files));
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn run_tar(command: &mut Command) -> Result<(), BackupError> {
+    let output = try!(command.output());
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(BackupError::InvalidArchive(String::from_utf8_lossy(&output.stderr).into_owned()))
+    }
+}
+
+fn create_tarball(directory: &Path, archive: &Path) -> Result<(), BackupError> {
+    run_tar(Command::new("tar").arg("-czf").arg(archive).arg("-C").arg(directory).arg("."))
+}
+
+fn extract_tarball(archive: &Path, directory: &Path) -> Result<(), BackupError> {
+    run_tar(Command::new("tar").arg("-xzf").arg(archive).arg("-C").arg(directory))
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; KEY_LEN] {
+    let mut mac = Hmac::new(Sha256::new(), passphrase.as_bytes());
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2(&mut mac, salt, PBKDF2_ROUNDS, &mut key);
+    key
+}
+
+fn encrypt(data: &[u8], passphrase: &str) -> Result<Vec<u8>, BackupError> {
+    let mut rng = try!(OsRng::new());
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut salt);
+    rng.fill_bytes(&mut nonce);
+
+    let key = derive_key(passphrase, &salt);
+    let mut cipher = AesGcm::new(KeySize::KeySize256, &key, &nonce, ENCRYPTED_MAGIC);
+    let mut output = vec![0u8; data.len()];
+    let mut tag = [0u8; TAG_LEN];
+    cipher.encrypt(data, &mut output, &mut tag);
+
+    let mut result = Vec::with_capacity(ENCRYPTED_MAGIC.len() + SALT_LEN + NONCE_LEN + TAG_LEN +
+                                        output.len());
+    result.extend_from_slice(ENCRYPTED_MAGIC);
+    result.extend_from_slice(&salt);
+    result.extend_from_slice(&nonce);
+    result.extend_from_slice(&tag);
+    result.extend_from_slice(&output);
+    Ok(result)
+}
+
+fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_MAGIC)
+}
+
+fn decrypt(data: &[u8], passphrase: &str) -> Result<Vec<u8>, BackupError> {
+    let header_len = ENCRYPTED_MAGIC.len() + SALT_LEN + NONCE_LEN + TAG_LEN;
+    if data.len() < header_len {
+        return Err(BackupError::InvalidArchive("truncated file".to_owned()));
+    }
+    let mut offset = ENCRYPTED_MAGIC.len();
+    let salt = &data[offset..offset + SALT_LEN];
+    offset += SALT_LEN;
+    let nonce = &data[offset..offset + NONCE_LEN];
+    offset += NONCE_LEN;
+    let tag = &data[offset..offset + TAG_LEN];
+    offset += TAG_LEN;
+    let input = &data[offset..];
+
+    let key = derive_key(passphrase, salt);
+    let mut cipher = AesGcm::new(KeySize::KeySize256, &key, nonce, ENCRYPTED_MAGIC);
+    let mut output = vec![0u8; input.len()];
+    if cipher.decrypt(input, &mut output, tag) {
+        Ok(output)
+    } else {
+        Err(BackupError::BadPassphrase)
+    }
+}
+
+/// Creates a backup of the profile and returns the archive content.
+pub fn create_backup(profile: &ProfileService,
+                     passphrase: Option<&str>)
+                     -> Result<Vec<u8>, BackupError> {
+    let root = PathBuf::from(profile.path_for(""));
+    let staging = try!(StagingDir::new(profile));
+    let content = staging.0.join("content");
+    try!(fs::create_dir_all(&content));
+
+    let mut files = vec![];
+    try!(collect_files(&root, Path::new(""), &mut files));
+
+    for file in &files {
+        let source = root.join(file);
+        let destination = content.join(file);
+        if let Some(parent) = destination.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        if is_sqlite(file) {
+            debug!("Backing up database {:?}", source);
+            let db = try!(Connection::open(&source));
+            try!(db.backup(DatabaseName::Main, &destination, None));
+        } else {
+            try!(fs::copy(&source, &destination));
+        }
+    }
+
+    let manifest = Manifest {
+        format_version: BACKUP_FORMAT_VERSION,
+        foxbox_version: env!("CARGO_PKG_VERSION").to_owned(),
+        created: UTC::now().to_rfc3339(),
+        files: files.iter().map(|file| file.to_string_lossy().into_owned()).collect(),
+    };
+    let manifest = serde_json::to_string_pretty(&manifest).unwrap();
+    try!(try!(File::create(content.join(MANIFEST_FILE))).write_all(manifest.as_bytes()));
+
+    let archive = staging.0.join("backup.tar.gz");
+    try!(create_tarball(&content, &archive));
+
+    let mut data = vec![];
+    try!(try!(File::open(&archive)).read_to_end(&mut data));
+    info!("Created a backup of {} files", files.len());
+
+    match passphrase {
+        Some(passphrase) => encrypt(&data, passphrase),
+        None => Ok(data),
+    }
+}
+
+/// The schema version this build writes for the databases of the profile, by file name.
+fn supported_schema_version(file: &Path) -> Option<u32> {
+    use adapters::{groups, scenes, virtual_devices, webhooks};
+    #[cfg(feature = "webpush")]
+    use adapters::webpush;
+    use controller::USERS_DB_SCHEMA_VERSION;
+    use foxbox_taxonomy::{audit, tag_storage};
+    #[cfg(feature = "thinkerbell")]
+    use foxbox_thinkerbell::manager as thinkerbell;
+
+    let name = match file.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return None,
+    };
+    match &name as &str {
+        "users_db.sqlite" => Some(USERS_DB_SCHEMA_VERSION),
+        "taxonomy_tags.sqlite" => Some(tag_storage::SCHEMA_VERSION),
+        "audit.sqlite" => Some(audit::SCHEMA_VERSION),
+        #[cfg(feature = "thinkerbell")]
+        "thinkerbell_scripts.sqlite" => Some(thinkerbell::SCHEMA_VERSION),
+        "scenes.sqlite" => Some(scenes::db::SCHEMA_VERSION),
+        "virtual_devices.sqlite" => Some(virtual_devices::db::SCHEMA_VERSION),
+        "groups.sqlite" => Some(groups::db::SCHEMA_VERSION),
+        "webhooks.sqlite" => Some(webhooks::db::SCHEMA_VERSION),
+        #[cfg(feature = "webpush")]
+        "webpush.sqlite" => Some(webpush::db::SCHEMA_VERSION),
+        _ => None,
+    }
+}
+
+/// Only accepts regular files and directories in the archive, before extracting it.
+fn check_tarball(archive: &Path) -> Result<(), BackupError> {
+    let output = try!(Command::new("tar").arg("-tvzf").arg(archive).output());
+    if !output.status.success() {
+        return Err(BackupError::InvalidArchive(String::from_utf8_lossy(&output.stderr)
+            .into_owned()));
+    }
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if !line.starts_with('-') && !line.starts_with('d') {
+            return Err(BackupError::InvalidArchive(format!("unsupported entry {}", line)));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that an extracted entry is a regular file that isn't linked from elsewhere.
+fn check_regular_file(path: &Path, file: &str) -> Result<(), BackupError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Err(BackupError::InvalidArchive(format!("missing file {}", file))),
+    };
+    if !metadata.file_type().is_file() || metadata.nlink() > 1 {
+        return Err(BackupError::InvalidArchive(format!("{} is not a regular file", file)));
+    }
+    Ok(())
+}
+
+/// Checks the files listed by the manifest of an extracted backup, and returns them.
+fn check_content(content: &Path) -> Result<Vec<PathBuf>, BackupError> {
+    let manifest_path = content.join(MANIFEST_FILE);
+    try!(check_regular_file(&manifest_path, MANIFEST_FILE));
+    let manifest: Manifest = {
+        let file = try!(File::open(&manifest_path)
+            .map_err(|_| BackupError::InvalidArchive("missing manifest".to_owned())));
+        try!(serde_json::from_reader(file)
+            .map_err(|err| BackupError::InvalidArchive(format!("bad manifest: {}", err))))
+    };
+    if manifest.format_version != BACKUP_FORMAT_VERSION {
+        return Err(BackupError::IncompatibleVersion(manifest.format_version));
+    }
+
+    let mut files = vec![];
+    for file in &manifest.files {
+        let path = PathBuf::from(file);
+        if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            return Err(BackupError::InvalidArchive(format!("invalid path {}", file)));
+        }
+        let staged = content.join(&path);
+        try!(check_regular_file(&staged, file));
+        if is_sqlite(&path) {
+            let db = try!(Connection::open(&staged));
+            let check: String = try!(db.query_row("PRAGMA integrity_check", &[], |row| row.get(0)));
+            if check != "ok" {
+                return Err(BackupError::InvalidArchive(format!("corrupted database {}", file)));
+            }
+            let version = try!(migrations::schema_version(&db));
+            if let Some(supported) = supported_schema_version(&path) {
+                if version > supported {
+                    return Err(BackupError::InvalidArchive(format!("{} has schema version {} \
+                                                                    but only version {} is \
+                                                                    supported",
+                                                                   file,
+                                                                   version,
+                                                                   supported)));
+                }
+            }
+        }
+        files.push(path);
+    }
+
+    info!("Found a backup of {} files from foxbox {} created on {}",
+          files.len(),
+          manifest.foxbox_version,
+          manifest.created);
+    Ok(files)
+}
+
+/// Decrypts, extracts and checks a backup in a staging directory, returning the
+/// directory of the content and the files to restore.
+fn unpack(profile: &ProfileService,
+          data: &[u8],
+          passphrase: Option<&str>)
+          -> Result<(StagingDir, PathBuf, Vec<PathBuf>), BackupError> {
+    let decrypted;
+    let data = if is_encrypted(data) {
+        match passphrase {
+            Some(passphrase) => {
+                decrypted = try!(decrypt(data, passphrase));
+                &decrypted[..]
+            }
+            None => return Err(BackupError::PassphraseRequired),
+        }
+    } else {
+        data
+    };
+
+    let staging = try!(StagingDir::new(profile));
+    let archive = staging.0.join("backup.tar.gz");
+    try!(try!(File::create(&archive)).write_all(data));
+    try!(check_tarball(&archive));
+    let content = staging.0.join("content");
+    try!(fs::create_dir_all(&content));
+    try!(extract_tarball(&archive, &content));
+
+    let files = try!(check_content(&content));
+    Ok((staging, content, files))
+}
+
+/// Replaces the files of the profile with the checked content of a backup, and
+/// removes the files that are not part of the backup.
+fn install(root: &Path, content: &Path, files: &[PathBuf]) -> Result<(), BackupError> {
+    let mut previous = vec![];
+    try!(collect_files(root, Path::new(""), &mut previous));
+
+    for file in files {
+        let staged = content.join(file);
+        let destination = root.join(file);
+        if let Some(parent) = destination.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        if is_sqlite(file) {
+            let mut db = try!(Connection::open(&destination));
+            try!(db.restore(DatabaseName::Main, &staged, None));
+        } else {
+            // Copy next to the destination then rename, to replace it atomically.
+            let mut temporary = destination.clone().into_os_string();
+            temporary.push(".updated");
+            try!(fs::copy(&staged, &temporary));
+            try!(fs::rename(&temporary, &destination));
+        }
+    }
+
+    for file in previous.iter().filter(|file| !files.contains(file)) {
+        debug!("Removing {:?}, which is not in the backup", file);
+        let path = root.join(file);
+        try!(fs::remove_file(&path));
+        if is_sqlite(file) {
+            for suffix in &["-journal", "-wal", "-shm"] {
+                let mut transient = path.clone().into_os_string();
+                transient.push(suffix);
+                let _ = fs::remove_file(&transient);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Restores a backup created by `create_backup` into the profile.
+///
+/// The archive is fully extracted and checked before anything is replaced.
+/// This must only be used while the box isn't running, since the services keep
+/// their databases open; use `schedule_restore` otherwise.
+pub fn restore_backup(profile: &ProfileService,
+                      data: &[u8],
+                      passphrase: Option<&str>)
+                      -> Result<(), BackupError> {
+    let (_staging, content, files) = try!(unpack(profile, data, passphrase));
+    install(Path::new(&profile.path_for("")), &content, &files)
+}
+
+/// Checks a backup and keeps it in the profile, to be restored by
+/// `apply_pending_restore` the next time the box starts.
+pub fn schedule_restore(profile: &ProfileService,
+                        data: &[u8],
+                        passphrase: Option<&str>)
+                        -> Result<(), BackupError> {
+    let (_staging, content, _) = try!(unpack(profile, data, passphrase));
+    let pending = PathBuf::from(profile.path_for(PENDING_RESTORE_DIR));
+    if fs::symlink_metadata(&pending).is_ok() {
+        try!(fs::remove_dir_all(&pending));
+    }
+    try!(fs::rename(&content, &pending));
+    Ok(())
+}
+
+/// Restores the backup kept by `schedule_restore`, if any. This must be called
+/// before the services open their databases. Returns true if a backup was restored.
+///
+/// The backup is only forgotten once fully restored: if restoring fails, e.g.
+/// part way through because the disk is full, it is kept to be restored again
+/// on the next start rather than leaving the profile half restored for good.
+pub fn apply_pending_restore(profile: &ProfileService) -> Result<bool, BackupError> {
+    let pending = PathBuf::from(profile.path_for(PENDING_RESTORE_DIR));
+    match fs::symlink_metadata(&pending) {
+        Ok(ref metadata) if metadata.file_type().is_dir() => {}
+        _ => return Ok(false),
+    }
+    let files = try!(check_content(&pending));
+    try!(install(Path::new(&profile.path_for("")), &pending, &files));
+    if let Err(err) = fs::remove_dir_all(&pending) {
+        warn!("Unable to remove {:?}: {}", pending, err);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+describe! backup {
+    before_each {
+        use foxbox_core::profile_service::{ProfilePath, ProfileService};
+        use rusqlite::Connection;
+        use std::fs::{self, File};
+        use std::io::{Read, Write};
+        use tempdir::TempDir;
+
+        let source_dir = TempDir::new_in("/tmp", "foxbox").unwrap();
+        let source = ProfileService::new(ProfilePath::Custom(source_dir.path()
+            .to_str().unwrap().to_owned()));
+        let target_dir = TempDir::new_in("/tmp", "foxbox").unwrap();
+        let target = ProfileService::new(ProfilePath::Custom(target_dir.path()
+            .to_str().unwrap().to_owned()));
+
+        {
+            let db = Connection::open(source.path_for("test.sqlite")).unwrap();
+            db.execute("CREATE TABLE t (value TEXT)", &[]).unwrap();
+            db.execute("INSERT INTO t VALUES ('hello')", &[]).unwrap();
+        }
+        fs::create_dir_all(source.path_for("certs/foxbox.local")).unwrap();
+        File::create(source.path_for("certs/foxbox.local/cert.pem")).unwrap()
+            .write_all(b"certificate").unwrap();
+        File::create(source.path_for("foxbox.conf")).unwrap().write_all(b"{}").unwrap();
+    }
+
+    it "should restore a backup" {
+        let data = create_backup(&source, None).unwrap();
+        restore_backup(&target, &data, None).unwrap();
+
+        let db = Connection::open(target.path_for("test.sqlite")).unwrap();
+        let value: String = db.query_row("SELECT value FROM t", &[], |row| row.get(0)).unwrap();
+        assert_eq!(value, "hello");
+
+        let mut content = String::new();
+        File::open(target.path_for("certs/foxbox.local/cert.pem")).unwrap()
+            .read_to_string(&mut content).unwrap();
+        assert_eq!(content, "certificate");
+        assert!(File::open(target.path_for("manifest.json")).is_err());
+    }
+
+    it "should remove the files that are not in the backup" {
+        let data = create_backup(&source, None).unwrap();
+        {
+            let db = Connection::open(target.path_for("extra.sqlite")).unwrap();
+            db.execute("CREATE TABLE t (value TEXT)", &[]).unwrap();
+        }
+        fs::create_dir_all(target.path_for("certs/extra")).unwrap();
+        File::create(target.path_for("certs/extra/cert.pem")).unwrap()
+            .write_all(b"extra").unwrap();
+
+        restore_backup(&target, &data, None).unwrap();
+        assert!(File::open(target.path_for("extra.sqlite")).is_err());
+        assert!(File::open(target.path_for("certs/extra/cert.pem")).is_err());
+        assert!(File::open(target.path_for("certs/foxbox.local/cert.pem")).is_ok());
+    }
+
+    it "should restore an encrypted backup" {
+        let data = create_backup(&source, Some("secret")).unwrap();
+        match restore_backup(&target, &data, None) {
+            Err(BackupError::PassphraseRequired) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match restore_backup(&target, &data, Some("wrong")) {
+            Err(BackupError::BadPassphrase) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+        restore_backup(&target, &data, Some("secret")).unwrap();
+        assert!(File::open(target.path_for("test.sqlite")).is_ok());
+    }
+
+    it "should reject garbage" {
+        assert!(restore_backup(&target, b"not a backup", None).is_err());
+        assert!(File::open(target.path_for("foxbox.conf")).is_err());
+    }
+
+    it "should reject links" {
+        use std::os::unix::fs::symlink;
+        use std::path::Path;
+
+        let content = source_dir.path().join("crafted");
+        fs::create_dir_all(&content).unwrap();
+        symlink("/etc/passwd", content.join("foxbox.conf")).unwrap();
+        File::create(content.join(MANIFEST_FILE)).unwrap()
+            .write_all(br#"{"format_version": 1, "foxbox_version": "0.1.0",
+                            "created": "now", "files": ["foxbox.conf"]}"#).unwrap();
+        let archive = source_dir.path().join("crafted.tar.gz");
+        create_tarball(&content, &archive).unwrap();
+        let mut data = vec![];
+        File::open(&archive).unwrap().read_to_end(&mut data).unwrap();
+
+        match restore_backup(&target, &data, None) {
+            Err(BackupError::InvalidArchive(_)) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(fs::symlink_metadata(Path::new(&target.path_for("foxbox.conf"))).is_err());
+    }
+
+    it "should reject databases newer than this build" {
+        {
+            let db = Connection::open(source.path_for("scenes.sqlite")).unwrap();
+            db.execute_batch("PRAGMA user_version = 99").unwrap();
+        }
+        let data = create_backup(&source, None).unwrap();
+        match restore_backup(&target, &data, None) {
+            Err(BackupError::InvalidArchive(_)) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(File::open(target.path_for("test.sqlite")).is_err());
+    }
+
+    it "should restore a scheduled backup on the next start" {
+        assert_eq!(apply_pending_restore(&target).unwrap(), false);
+
+        let data = create_backup(&source, None).unwrap();
+        schedule_restore(&target, &data, None).unwrap();
+        assert!(File::open(target.path_for("test.sqlite")).is_err());
+
+        assert_eq!(apply_pending_restore(&target).unwrap(), true);
+        let db = Connection::open(target.path_for("test.sqlite")).unwrap();
+        let value: String = db.query_row("SELECT value FROM t", &[], |row| row.get(0)).unwrap();
+        assert_eq!(value, "hello");
+        assert_eq!(apply_pending_restore(&target).unwrap(), false);
+    }
+
+    it "should keep a scheduled backup that fails to be restored" {
+        let data = create_backup(&source, None).unwrap();
+        schedule_restore(&target, &data, None).unwrap();
+        // The configuration file can't replace a directory.
+        fs::create_dir_all(target.path_for("foxbox.conf/blocker")).unwrap();
+        assert!(apply_pending_restore(&target).is_err());
+
+        fs::remove_dir_all(target.path_for("foxbox.conf")).unwrap();
+        assert_eq!(apply_pending_restore(&target).unwrap(), true);
+        let mut content = String::new();
+        File::open(target.path_for("foxbox.conf")).unwrap()
+            .read_to_string(&mut content).unwrap();
+        assert_eq!(content, "{}");
+        assert_eq!(apply_pending_restore(&target).unwrap(), false);
+    }
+}
@@ -0,0 +1,178 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Admin endpoints to back up and restore the profile.
+//!
+//! POST /api/v1/backup         : returns a backup archive. The optional json body
+//!                               {"passphrase": "..."} encrypts the archive.
+//! POST /api/v1/backup/restore : checks the archive sent as the request body and
+//!                               restores it when the box restarts. The passphrase of
+//!                               encrypted archives is sent in the X-Backup-Passphrase
+//!                               header.
+
+use api_error;
+use auth;
+use backup::{self, BackupError};
+use foxbox_core::traits::Controller;
+use foxbox_users::AuthEndpoint;
+use hyper::mime::Mime;
+use iron::{Handler, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use serde_json;
+use std::io::Read;
+
+header! { (BackupPassphrase, "X-Backup-Passphrase") => [String] }
+
+#[derive(Debug, Default, Deserialize)]
+struct BackupRequest {
+    passphrase: Option<String>,
+}
+
+pub struct BackupRouter<T> {
+    controller: T,
+}
+
+impl<T: Controller> BackupRouter<T> {
+    pub fn new(controller: T) -> Self {
+        BackupRouter { controller: controller }
+    }
+
+    fn build_error_response(&self, err: &BackupError) -> IronResult<Response> {
+        let (status, code) = match *err {
+            BackupError::Io(_) |
+            BackupError::Sqlite(_) => (Status::InternalServerError, "internal_error"),
+            BackupError::PassphraseRequired => (Status::Forbidden, "passphrase_required"),
+            BackupError::BadPassphrase => (Status::Forbidden, "bad_passphrase"),
+            BackupError::InvalidArchive(_) => (Status::BadRequest, "invalid_archive"),
+            BackupError::IncompatibleVersion(_) => (Status::BadRequest, "incompatible_version"),
+        };
+        Ok(api_error::with_code(status, code, err.to_string()))
+    }
+
+    fn backup(&self, req: &mut Request) -> IronResult<Response> {
+        let mut source = String::new();
+        itry!(req.body.read_to_string(&mut source));
+        let request: BackupRequest = if source.trim().is_empty() {
+            BackupRequest::default()
+        } else {
+            match serde_json::from_str(&source) {
+                Ok(request) => request,
+                Err(err) => {
+                    return Ok(api_error::response(Status::BadRequest,
+                                                  format!("Invalid backup request: {}", err)))
+                }
+            }
+        };
+
+        let passphrase = request.passphrase.as_ref().map(|passphrase| passphrase as &str);
+        match backup::create_backup(self.controller.get_profile(), passphrase) {
+            Ok(data) => {
+                let mime: Mime = "application/octet-stream".parse().unwrap();
+                let mut response = Response::with((Status::Ok, data));
+                response.headers.set(ContentType(mime));
+                Ok(response)
+            }
+            Err(err) => self.build_error_response(&err),
+        }
+    }
+
+    fn restore(&self, req: &mut Request) -> IronResult<Response> {
+        let passphrase = req.headers.get::<BackupPassphrase>().map(|header| header.0.clone());
+        let mut data = Vec::new();
+        itry!(req.body.read_to_end(&mut data));
+
+        let passphrase = passphrase.as_ref().map(|passphrase| passphrase as &str);
+        // The services keep their databases open, so the backup is only checked here and
+        // restored when the box starts again.
+        match backup::schedule_restore(self.controller.get_profile(), &data, passphrase) {
+            Ok(_) => {
+                warn!("A backup will be restored when the box restarts");
+                Ok(Response::with(Status::Accepted))
+            }
+            Err(err) => self.build_error_response(&err),
+        }
+    }
+}
+
+impl<T: Controller> Handler for BackupRouter<T> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if let Err(response) = auth::check_admin(req, &self.controller.get_users_manager()) {
+            return Ok(response);
+        }
+
+        let path: Vec<String> = req.url
+            .path()
+            .iter()
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| (*segment).to_owned())
+            .collect();
+
+        if req.method != Method::Post {
+            return Ok(api_error::response(Status::MethodNotAllowed,
+                                          format!("Bad method: {}", req.method)));
+        }
+
+        if path.is_empty() {
+            self.backup(req)
+        } else if path == ["restore"] {
+            self.restore(req)
+        } else {
+            Ok(api_error::response(Status::NotFound, format!("Unknown url: {}", req.url)))
+        }
+    }
+}
+
+pub fn create<T>(controller: T) -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    // Keep these in sync with the handle() method. They are relative to api/v1.
+    let endpoints = vec![
+        (vec![Method::Post], "backup".to_owned()),
+        (vec![Method::Post], "backup/restore".to_owned()),
+    ];
+
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        endpoints.iter().map(|item| AuthEndpoint(item.0.clone(), item.1.clone())).collect()
+    } else {
+        vec![]
+    };
+
+    let mut chain = Chain::new(BackupRouter::new(controller.clone()));
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! backup_router {
+    before_each {
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::request;
+        use mount::Mount;
+        use stubs::controller::ControllerStub;
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1/backup", create(ControllerStub::new()).0);
+    }
+
+    it "should return an archive" {
+        let response = request::post("http://localhost:3000/api/v1/backup",
+                                     Headers::new(),
+                                     "",
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+    }
+
+    it "should reject invalid archives" {
+        let response = request::post("http://localhost:3000/api/v1/backup/restore",
+                                     Headers::new(),
+                                     "garbage",
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+    }
+}
@@ -29,7 +29,10 @@ extern crate rustc_serialize;
 extern crate time;
 extern crate tls;
 
+use foxboxlib::backup;
 use foxboxlib::controller::FoxBox;
+use foxboxlib::dns_sd::{self, ServiceAdvertiser};
+use foxboxlib::network_monitor::NetworkMonitor;
 use env_logger::LogBuilder;
 use foxboxlib::tunnel_controller::{TunnelConfig, Tunnel};
 use libc::{sighandler_t, SIGINT};
@@ -37,15 +40,23 @@ use log::{LogRecord, LogLevelFilter};
 
 use multicast_dns::errors::Error as HostManagerError;
 use multicast_dns::host::HostManager;
-use foxbox_core::profile_service::ProfilePath;
+use foxbox_core::profile_service::{ProfilePath, ProfileService};
 use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::Duration;
 use tls::TlsOption;
 use foxbox_core::traits::Controller;
 use foxbox_core::utils;
 
 docopt!(Args derive Debug, "
-Usage: foxbox [-v] [-h] [-l <hostname>] [-p <port>] [-w <wsport>] [-d <profile_path>] [-r <url>] [-i <iface>] [-t <tunnel>] [-s <secret>] [--disable-tls] [--dns-domain <domain>] [--dns-api <url>] [-c <namespace;key;value>]...
+Usage: foxbox [-v] [-h] [-l <hostname>] [-p <port>] [-w <wsport>] [-d <profile_path>] [-r <url>] [-i <iface>] [-t <tunnel>] [-s <secret>] [--disable-tls] [--local-only] [--dns-domain <domain>] [--dns-api <url>] [-c <namespace;key;value>]...
+       foxbox [-d <profile_path>] --backup <file> [--passphrase]
+       foxbox [-d <profile_path>] --restore <file> [--passphrase]
 
 Options:
     -v, --verbose            Toggle verbose output.
@@ -58,9 +69,13 @@ Options:
     -t, --tunnel <tunnel>    Set the tunnel endpoint's hostname. If omitted, the tunnel is disabled.
     -s, --tunnel-secret <secret>       Set the tunnel shared secret. [default: secret]
         --disable-tls                  Run as a plain HTTP server, disabling encryption.
+        --local-only                   Don't use the registration and DNS servers nor the tunnel, serve the self-signed certificate.
         --dns-domain <domain>          Set the top level domain for public DNS [default: box.knilxof.org]
         --dns-api <url>                Set the DNS API endpoint [default: https://knilxof.org:5300]
     -c, --config <namespace;key;value>  Set configuration override
+        --backup <file>                Write a backup of the profile to <file> and exit.
+        --restore <file>               Restore the profile from the backup <file> and exit.
+        --passphrase                   Encrypt or decrypt the backup with the passphrase of the FOXBOX_BACKUP_PASSPHRASE environment variable, or else read from the standard input.
     -h, --help               Print this help menu.
 ",
         flag_local_name: String,
@@ -72,9 +87,13 @@ Options:
         flag_tunnel: Option<String>,
         flag_tunnel_secret: String,
         flag_disable_tls: bool,
+        flag_local_only: bool,
         flag_dns_domain: String,
         flag_dns_api: String,
-        flag_config: Option<Vec<String>>);
+        flag_config: Option<Vec<String>>,
+        flag_backup: Option<String>,
+        flag_restore: Option<String>,
+        flag_passphrase: bool);
 
 /// Updates local host name with the provided host name string. If requested host name
 /// is not available (used by anyone else on the same network) then collision
@@ -123,6 +142,66 @@ fn tid_str() -> &'static str {
     ""
 }
 
+/// Reads the passphrase of a backup from the environment, or else from the
+/// standard input, so that it doesn't show in the process list.
+fn read_passphrase() -> io::Result<String> {
+    if let Ok(passphrase) = env::var("FOXBOX_BACKUP_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    let _ = writeln!(io::stderr(), "Backup passphrase:");
+    let mut passphrase = String::new();
+    try!(io::stdin().read_line(&mut passphrase));
+    Ok(passphrase.trim_right_matches(|c| c == '\n' || c == '\r').to_owned())
+}
+
+/// Runs the --backup and --restore commands, which don't start the box.
+/// Returns true if one of them was requested.
+fn run_backup_command(args: &Args) -> bool {
+    if args.flag_backup.is_none() && args.flag_restore.is_none() {
+        return false;
+    }
+
+    let profile = ProfileService::new(match args.flag_profile {
+        Some(ref p) => ProfilePath::Custom(p.clone()),
+        None => ProfilePath::Default,
+    });
+    let passphrase = if args.flag_passphrase {
+        match read_passphrase() {
+            Ok(passphrase) => Some(passphrase),
+            Err(err) => {
+                error!("Unable to read the passphrase: {}", err);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let passphrase = passphrase.as_ref().map(|p| p as &str);
+
+    let result = if let Some(ref file) = args.flag_backup {
+        backup::create_backup(&profile, passphrase).and_then(|data| {
+            try!(try!(File::create(file)).write_all(&data));
+            info!("Backup written to {}", file);
+            Ok(())
+        })
+    } else if let Some(ref file) = args.flag_restore {
+        let mut data = vec![];
+        File::open(file)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(backup::BackupError::from)
+            .and_then(|_| backup::restore_backup(&profile, &data, passphrase))
+            .map(|_| info!("Profile restored from {}", file))
+    } else {
+        Ok(())
+    };
+
+    if let Err(err) = result {
+        error!("{}", err);
+        process::exit(1);
+    }
+    true
+}
+
 fn main() {
     unsafe {
         libc::signal(SIGINT, handle_sigint as sighandler_t);
@@ -175,14 +254,37 @@ fn main() {
 
     let args: Args = Args::docopt().decode().unwrap_or_else(|e| e.exit());
 
-    let local_name = args.flag_local_name;
-    let local_name = update_hostname(&local_name)
+    if run_backup_command(&args) {
+        return;
+    }
+
+    // Restore a backup uploaded while the box was running, before any service
+    // opens its databases.
+    {
+        let profile = ProfileService::new(match args.flag_profile {
+            Some(ref p) => ProfilePath::Custom(p.clone()),
+            None => ProfilePath::Default,
+        });
+        match backup::apply_pending_restore(&profile) {
+            Ok(true) => info!("Restored the pending backup"),
+            Ok(false) => {}
+            Err(err) => {
+                // The box starts anyway, so that another backup can be uploaded.
+                error!("Unable to restore the pending backup, it will be retried on the next \
+                        start: {}",
+                       err)
+            }
+        }
+    }
+
+    let host_name = args.flag_local_name.clone();
+    let host_name = update_hostname(&host_name)
         .or_else(|err| {
             error!("Could not update local host name: {}", err);
-            Ok::<String, HostManagerError>(local_name)
+            Ok::<String, HostManagerError>(host_name)
         })
-        .and_then(|name| Ok(format!("{}.local", name)))
         .unwrap();
+    let local_name = format!("{}.local", host_name);
 
     let mut controller = FoxBox::new(args.flag_verbose,
                                      &local_name,
@@ -191,6 +293,8 @@ fn main() {
                                      args.flag_wsport,
                                      if args.flag_disable_tls {
                                          TlsOption::Disabled
+                                     } else if args.flag_local_only {
+                                         TlsOption::SelfSigned
                                      } else {
                                          TlsOption::Enabled
                                      },
@@ -217,6 +321,11 @@ fn main() {
         }
     }
 
+    // The network monitor notices ip address changes (e.g. a new DHCP lease),
+    // so that we can register again, advertise our host name and services on
+    // the new address and let websocket clients know.
+    let network_monitor = NetworkMonitor::new();
+
     // The registrar manages registration with the registration server, and DNS
     // server. The registration server is used to orchestrate box discovery by
     // clients via an "nUPNP like" method where the box registers itself with an
@@ -233,26 +342,91 @@ fn main() {
     // Once the names have been created in the DNS server, a LetsEncrypt client will
     // issue certificates for each name - the local name will be the common name of
     // the certificate, and every other name will be a subject alternative name.
-    let registrar = foxboxlib::registration::Registrar::new(controller.get_certificate_manager(),
-                                                            args.flag_register,
-                                                            args.flag_dns_api);
-
-    // Start the tunnel.
+    // In local-only mode, clients find the box with DNS-SD instead and pin
+    // the fingerprint of its self-signed certificate.
     let mut tunnel: Option<Tunnel> = None;
-    if let Some(tunnel_url) = args.flag_tunnel {
-        tunnel = Some(Tunnel::new(TunnelConfig::new(&tunnel_url,
-                                                    &args.flag_tunnel_secret,
-                                                    args.flag_port,
-                                                    args.flag_wsport,
-                                                    &controller.get_certificate_manager()
-                                                        .get_remote_dns_name())));
-        tunnel.as_mut().unwrap().start().unwrap();
+    if args.flag_local_only {
+        info!("Running in local-only mode, without registration or tunnel");
+        if args.flag_tunnel.is_some() {
+            warn!("Ignoring the tunnel in local-only mode");
+        }
+    } else {
+        let registrar = foxboxlib::registration::Registrar::new(controller.get_certificate_manager(),
+                                                                args.flag_register,
+                                                                args.flag_dns_api);
+
+        // Start the tunnel.
+        if let Some(tunnel_url) = args.flag_tunnel {
+            tunnel = Some(Tunnel::new(TunnelConfig::new(&tunnel_url,
+                                                        &args.flag_tunnel_secret,
+                                                        args.flag_port,
+                                                        args.flag_wsport,
+                                                        &controller.get_certificate_manager()
+                                                            .get_remote_dns_name())));
+            tunnel.as_mut().unwrap().start().unwrap();
+        }
+
+        registrar.start(args.flag_iface,
+                        &tunnel,
+                        args.flag_port,
+                        &controller,
+                        &network_monitor);
     }
 
-    registrar.start(args.flag_iface, &tunnel, args.flag_port, &controller);
+    // Advertise the box on the local network with DNS-SD, with the fingerprint
+    // of the certificate the HTTPS server actually serves.
+    let records = {
+        let controller = controller.clone();
+        let (http_port, ws_port) = (args.flag_port, args.flag_wsport);
+        Arc::new(move || {
+            let fingerprint = controller.get_served_certificate()
+                .map(|record| record.get_certificate_fingerprint());
+            dns_sd::service_records(&host_name,
+                                    http_port,
+                                    ws_port,
+                                    controller.get_tls_enabled(),
+                                    fingerprint.as_ref().map(|fingerprint| fingerprint as &str))
+        })
+    };
+    let advertiser = Arc::new(ServiceAdvertiser::new(records()));
+    advertiser.start();
+
+    // Like the HTTPS server, wait for the LetsEncrypt certificate when it hasn't
+    // been issued yet.
+    if controller.get_tls_enabled() && controller.get_served_certificate().is_none() {
+        let controller = controller.clone();
+        let records = records.clone();
+        let advertiser = advertiser.clone();
+        thread::Builder::new()
+            .name("DnsSdCertificate".to_owned())
+            .spawn(move || {
+                while controller.get_served_certificate().is_none() {
+                    thread::sleep(Duration::new(10, 0));
+                }
+                if advertiser.set_records(records()) {
+                    advertiser.restart();
+                }
+            })
+            .unwrap();
+    }
+
+    network_monitor.notify_websockets(&controller);
+    {
+        let hostname = args.flag_local_name;
+        let advertiser = advertiser.clone();
+        network_monitor.add_listener(move |_| {
+            if let Err(err) = update_hostname(&hostname) {
+                error!("Could not advertise local host name again: {}", err);
+            }
+            advertiser.set_records(records());
+            advertiser.restart();
+        });
+    }
+    network_monitor.start();
 
     controller.run(&SHUTDOWN_FLAG);
 
+    advertiser.stop();
     if let Some(mut tunnel) = tunnel {
         tunnel.stop().unwrap();
     }
@@ -276,6 +450,9 @@ describe! main {
             assert_eq!(args.flag_iface, None);
             assert_eq!(args.flag_tunnel, None);
             assert_eq!(args.flag_config, None);
+            assert_eq!(args.flag_backup, None);
+            assert_eq!(args.flag_restore, None);
+            assert_eq!(args.flag_local_only, false);
             assert_eq!(args.flag_help, false);
         }
 
@@ -312,6 +489,7 @@ describe! main {
                                "--register", "http://foo.bar:6868/register",
                                "--iface", "eth99",
                                "--tunnel", "tunnel.host",
+                               "--local-only",
                                "--config", "ns;key;value"];
 
             let args: super::super::Args = super::super::Args::docopt().argv(argv().into_iter())
@@ -324,8 +502,24 @@ describe! main {
             assert_eq!(args.flag_register, "http://foo.bar:6868/register");
             assert_eq!(args.flag_iface.unwrap(), "eth99");
             assert_eq!(args.flag_tunnel.unwrap(), "tunnel.host");
+            assert_eq!(args.flag_local_only, true);
             assert_eq!(args.flag_config.unwrap(), vec!["ns;key;value"]);
         }
+
+        it "should support backup commands" {
+            let argv = || vec!["foxbox",
+                               "-d", "/tmp/profile",
+                               "--backup", "profile.backup",
+                               "--passphrase"];
+
+            let args: super::super::Args = super::super::Args::docopt().argv(argv().into_iter())
+                .decode().unwrap();
+
+            assert_eq!(args.flag_profile.unwrap(), "/tmp/profile");
+            assert_eq!(args.flag_backup.unwrap(), "profile.backup");
+            assert_eq!(args.flag_passphrase, true);
+            assert_eq!(args.flag_restore, None);
+        }
     }
 
     describe! host_name {
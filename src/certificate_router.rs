@@ -0,0 +1,178 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Admin endpoints to manage the TLS certificates served by the box.
+//!
+//! GET    /api/v1/certificates           : list the installed certificates.
+//! PUT    /api/v1/certificates/:hostname : install a certificate, with a json body like
+//!                                         {"certificate": "<PEM>", "private_key": "<PEM>",
+//!                                          "chain": "<PEM>"}. The chain is optional.
+//! DELETE /api/v1/certificates/:hostname : remove a certificate.
+
+use adapters::certificates::CertificateDescription;
+use api_error;
+use auth;
+use foxbox_core::traits::Controller;
+use foxbox_users::AuthEndpoint;
+use iron::{Handler, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use serde_json;
+use std::io::{Error as IOError, ErrorKind, Read};
+
+#[derive(Debug, Deserialize)]
+struct CertificateUpload {
+    certificate: String,
+    private_key: String,
+    chain: Option<String>,
+}
+
+pub struct CertificateRouter<T> {
+    controller: T,
+}
+
+impl<T: Controller> CertificateRouter<T> {
+    pub fn new(controller: T) -> Self {
+        CertificateRouter { controller: controller }
+    }
+
+    fn build_json_response<S: ::serde::Serialize>(&self, obj: &S) -> IronResult<Response> {
+        let serialized = itry!(serde_json::to_string(obj));
+        let mut response = Response::with((Status::Ok, serialized));
+        response.headers.set(ContentType::json());
+        Ok(response)
+    }
+
+    fn build_error_response(&self, err: &IOError) -> IronResult<Response> {
+        let status = match err.kind() {
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => Status::BadRequest,
+            ErrorKind::NotFound => Status::NotFound,
+            ErrorKind::PermissionDenied => Status::Forbidden,
+            _ => Status::InternalServerError,
+        };
+        Ok(api_error::response(status, err.to_string()))
+    }
+
+    fn list(&self) -> IronResult<Response> {
+        let infos = self.controller.get_certificate_manager().list_certificate_infos();
+        let descriptions: Vec<CertificateDescription> =
+            infos.iter().map(CertificateDescription::from).collect();
+        self.build_json_response(&descriptions)
+    }
+
+    fn install(&self, hostname: &str, req: &mut Request) -> IronResult<Response> {
+        let mut source = String::new();
+        itry!(req.body.read_to_string(&mut source));
+        let upload: CertificateUpload = match serde_json::from_str(&source) {
+            Ok(upload) => upload,
+            Err(err) => {
+                return Ok(api_error::response(Status::BadRequest,
+                                              format!("Invalid certificate upload: {}", err)))
+            }
+        };
+
+        let chain = upload.chain.as_ref().map(|chain| chain as &str);
+        match self.controller
+            .get_certificate_manager()
+            .install_certificate(hostname, &upload.certificate, &upload.private_key, chain) {
+            Ok(info) => self.build_json_response(&CertificateDescription::from(&info)),
+            Err(err) => self.build_error_response(&err),
+        }
+    }
+
+    fn delete(&self, hostname: &str) -> IronResult<Response> {
+        match self.controller.get_certificate_manager().delete_certificate(hostname) {
+            Ok(_) => Ok(Response::with(Status::NoContent)),
+            Err(err) => self.build_error_response(&err),
+        }
+    }
+}
+
+impl<T: Controller> Handler for CertificateRouter<T> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if let Err(response) = auth::check_admin(req, &self.controller.get_users_manager()) {
+            return Ok(response);
+        }
+
+        // Paths are relative to the /api/v1/certificates mount point.
+        let path: Vec<String> = req.url
+            .path()
+            .iter()
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| (*segment).to_owned())
+            .collect();
+
+        match (req.method.clone(), path.len()) {
+            (Method::Get, 0) => self.list(),
+            (Method::Put, 1) => self.install(&path[0], req),
+            (Method::Delete, 1) => self.delete(&path[0]),
+            (_, 0) | (_, 1) => {
+                Ok(api_error::response(Status::MethodNotAllowed,
+                                       format!("Bad method: {}", req.method)))
+            }
+            _ => Ok(api_error::response(Status::NotFound, format!("Unknown url: {}", req.url))),
+        }
+    }
+}
+
+pub fn create<T>(controller: T) -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    // Keep these in sync with the handle() method. They are relative to api/v1.
+    let endpoints = vec![
+        (vec![Method::Get], "certificates".to_owned()),
+        (vec![Method::Put, Method::Delete], "certificates/:hostname".to_owned()),
+    ];
+
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        endpoints.iter().map(|item| AuthEndpoint(item.0.clone(), item.1.clone())).collect()
+    } else {
+        vec![]
+    };
+
+    let mut chain = Chain::new(CertificateRouter::new(controller.clone()));
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! certificate_router {
+    before_each {
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{ request, response };
+        use mount::Mount;
+        use stubs::controller::ControllerStub;
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1/certificates", create(ControllerStub::new()).0);
+    }
+
+    it "should list certificates as json" {
+        let response = request::get("http://localhost:3000/api/v1/certificates",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+        let body = response::extract_body_to_string(response);
+        assert!(body.starts_with("["));
+    }
+
+    it "should reject invalid uploads" {
+        let response = request::put("http://localhost:3000/api/v1/certificates/example.com",
+                                    Headers::new(),
+                                    r#"{"certificate": "not a certificate"}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+    }
+
+    it "should refuse to delete the box certificate" {
+        let response = request::delete("http://localhost:3000/api/v1/certificates/foxbox.local",
+                                       Headers::new(),
+                                       &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Forbidden));
+    }
+}
@@ -0,0 +1,207 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Admin endpoints to read and write the box configuration.
+//!
+//! GET /api/v1/config : list the declared and stored settings, secrets are masked.
+//! PUT /api/v1/config : update settings, with a json body like
+//!                      {"philips_hue": {"nupnp_enabled": "false"}}.
+//!                      All the values are validated before any of them is written.
+
+use api_error;
+use auth;
+use foxbox_core::config_schema::MASKED_SECRET;
+use foxbox_core::config_store::ConfigEntry;
+use foxbox_core::traits::Controller;
+use foxbox_users::AuthEndpoint;
+use iron::{Handler, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use serde_json;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+#[derive(Debug, Serialize)]
+struct ConfigEntryDescription {
+    namespace: String,
+    property: String,
+    value: Option<String>,
+    #[serde(rename="type")]
+    kind: Option<String>,
+    default: Option<String>,
+    secret: bool,
+    description: String,
+}
+
+impl From<ConfigEntry> for ConfigEntryDescription {
+    fn from(entry: ConfigEntry) -> Self {
+        ConfigEntryDescription {
+            namespace: entry.namespace,
+            property: entry.property,
+            value: entry.value,
+            kind: entry.kind,
+            default: entry.default,
+            secret: entry.secret,
+            description: entry.description,
+        }
+    }
+}
+
+pub struct ConfigRouter<T> {
+    controller: T,
+}
+
+impl<T: Controller> ConfigRouter<T> {
+    pub fn new(controller: T) -> Self {
+        ConfigRouter { controller: controller }
+    }
+
+    fn list(&self) -> IronResult<Response> {
+        let entries: Vec<ConfigEntryDescription> = self.controller
+            .get_config()
+            .entries()
+            .into_iter()
+            .map(ConfigEntryDescription::from)
+            .collect();
+        let serialized = itry!(serde_json::to_string(&entries));
+        let mut response = Response::with((Status::Ok, serialized));
+        response.headers.set(ContentType::json());
+        Ok(response)
+    }
+
+    fn update(&self, req: &mut Request) -> IronResult<Response> {
+        let mut source = String::new();
+        itry!(req.body.read_to_string(&mut source));
+        let tree: BTreeMap<String, BTreeMap<String, String>> =
+            match serde_json::from_str(&source) {
+                Ok(tree) => tree,
+                Err(err) => {
+                    return Ok(api_error::response(Status::BadRequest,
+                                                  format!("Invalid configuration: {}", err)))
+                }
+            };
+
+        let config = self.controller.get_config();
+        let mut values = vec![];
+        for (namespace, properties) in tree {
+            for (property, value) in properties {
+                // Clients send back what they got, don't overwrite secrets with the mask.
+                if value == MASKED_SECRET && config.is_secret(&namespace, &property) {
+                    continue;
+                }
+                values.push((namespace.clone(), property, value));
+            }
+        }
+
+        match config.set_many(&values, true) {
+            Ok(_) => self.list(),
+            Err(err) => Ok(api_error::response(Status::BadRequest, format!("{}", err))),
+        }
+    }
+}
+
+impl<T: Controller> Handler for ConfigRouter<T> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if let Err(response) = auth::check_admin(req, &self.controller.get_users_manager()) {
+            return Ok(response);
+        }
+
+        match req.method {
+            Method::Get => self.list(),
+            Method::Put => self.update(req),
+            _ => {
+                Ok(api_error::response(Status::MethodNotAllowed,
+                                       format!("Bad method: {}", req.method)))
+            }
+        }
+    }
+}
+
+pub fn create<T>(controller: T) -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    // Keep these in sync with the handle() method. They are relative to api/v1.
+    let endpoints = vec![
+        (vec![Method::Get, Method::Put], "config".to_owned()),
+    ];
+
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        endpoints.iter().map(|item| AuthEndpoint(item.0.clone(), item.1.clone())).collect()
+    } else {
+        vec![]
+    };
+
+    let mut chain = Chain::new(ConfigRouter::new(controller.clone()));
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! config_router {
+    before_each {
+        use foxbox_core::config_schema::ConfigKey;
+        use foxbox_core::traits::Controller;
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{ request, response };
+        use mount::Mount;
+        use stubs::controller::ControllerStub;
+
+        let controller = ControllerStub::new();
+        let config = controller.get_config();
+        config.register(ConfigKey::boolean("foo", "enabled", true));
+        config.register(ConfigKey::string("foo", "password", "").secret());
+        config.set("foo", "password", "hunter2");
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1/config", create(controller.clone()).0);
+    }
+
+    it "should list settings with secrets masked" {
+        let response = request::get("http://localhost:3000/api/v1/config",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let s = r#"[{"namespace":"foo","property":"enabled","value":null,"type":"bool","default":"true","secret":false,"description":""},{"namespace":"foo","property":"password","value":"********","type":"string","default":"","secret":true,"description":""}]"#;
+        assert_eq!(body, s);
+    }
+
+    it "should update settings" {
+        let response = request::put("http://localhost:3000/api/v1/config",
+                                    Headers::new(),
+                                    r#"{"foo": {"enabled": "false", "password": "********"}}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+        assert_eq!(config.get("foo", "enabled"), Some("false".to_owned()));
+        assert_eq!(config.get("foo", "password"), Some("hunter2".to_owned()));
+    }
+
+    it "should only ignore the mask for secrets" {
+        config.register(ConfigKey::string("foo", "name", ""));
+        let response = request::put("http://localhost:3000/api/v1/config",
+                                    Headers::new(),
+                                    r#"{"foo": {"name": "********"}}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+        assert_eq!(config.get("foo", "name"), Some("********".to_owned()));
+    }
+
+    it "should reject invalid settings" {
+        let response = request::put("http://localhost:3000/api/v1/config",
+                                    Headers::new(),
+                                    r#"{"foo": {"enabled": "maybe"}}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+
+        let response = request::put("http://localhost:3000/api/v1/config",
+                                    Headers::new(),
+                                    r#"{"foo": {"unknown": "value"}}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+        assert_eq!(config.get("foo", "enabled"), None);
+    }
+}
@@ -6,17 +6,24 @@ extern crate serde_json;
 extern crate mio;
 
 use adapters::AdapterManager;
+use foxbox_core::config_schema::ConfigKey;
 use foxbox_core::config_store::ConfigService;
+use foxbox_core::metrics;
+use foxbox_core::migrations::{self, Migration};
 use foxbox_core::profile_service::{ProfilePath, ProfileService};
+use foxbox_core::rate_limit::RateLimiter;
 use foxbox_core::traits::Controller;
 use foxbox_core::upnp::UpnpManager;
 use foxbox_taxonomy::api::{API, Targetted, WatchEvent};
-use foxbox_taxonomy::manager::{AdapterManager as TaxoManager, WatchGuard};
-use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::audit::AuditLog;
+use foxbox_taxonomy::manager::{AdapterManager as TaxoManager, DEFAULT_ADAPTER_TIMEOUT_S,
+                               WatchGuard};
+use foxbox_taxonomy::selector::{ChannelSelector, ServiceSelector};
 use foxbox_taxonomy::util::Exactly;
 use foxbox_users::UsersManager;
 use http_server::HttpServer;
 use mio::{Events, Poll};
+use rusqlite::Connection;
 use std::collections::hash_map::HashMap;
 use std::io;
 use std::net::SocketAddr;
@@ -25,12 +32,51 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::Duration;
 use std::vec::IntoIter;
 use tls::{CertificateManager, CertificateRecord, SniSslContextProvider, TlsOption};
 use transformable_channels::mpsc;
 use ws_server::WsServer;
 use ws;
 
+/// How often foxbox.conf is checked for changes, in seconds.
+const CONFIG_WATCH_PERIOD_S: u64 = 5;
+
+/// How long fetching or sending values waits for each adapter.
+fn adapter_timeout_config() -> ConfigKey {
+    ConfigKey::integer("taxonomy",
+                       "adapter_timeout_s",
+                       DEFAULT_ADAPTER_TIMEOUT_S as i64,
+                       Some(1),
+                       Some(300))
+        .description("Seconds to wait for each adapter when fetching or sending values")
+}
+
+/// The schema version of the users database written by this build.
+pub const USERS_DB_SCHEMA_VERSION: u32 = 1;
+
+/// The tables of the users database are created by `foxbox_users` itself. We
+/// only keep track of its schema version once they exist, so that a database
+/// written by a newer box is refused and later migrations have a known
+/// starting point.
+static USERS_DB_MIGRATIONS: [Migration; USERS_DB_SCHEMA_VERSION as usize] = [
+    Migration {
+        version: 1,
+        description: "Adopt the schema created by foxbox_users",
+        sql: "",
+    },
+];
+
+/// Brings the users database at `path` up to date, panicking on failure.
+fn migrate_users_db(path: &str) {
+    let mut db = Connection::open(path).unwrap_or_else(|err| {
+        panic!("Unable to open the users database: {}", err);
+    });
+    migrations::migrate(&mut db, "users", &USERS_DB_MIGRATIONS).unwrap_or_else(|err| {
+        panic!("Unable to migrate the users database: {}", err);
+    });
+}
+
 #[derive(Clone)]
 pub struct FoxBox {
     pub verbose: bool,
@@ -44,6 +90,7 @@ pub struct FoxBox {
     pub config: Arc<ConfigService>,
     upnp: Arc<UpnpManager>,
     users_manager: Arc<UsersManager>,
+    rate_limiter: Arc<RateLimiter>,
     profile_service: Arc<ProfileService>,
 }
 
@@ -64,6 +111,14 @@ impl FoxBox {
                                 "certificate_directory",
                                 &profile_service.path_for("certs/")));
 
+        // The users manager creates its tables when opening the database, so that
+        // the schema version is only recorded once they exist.
+        let users_db_path = profile_service.path_for("users_db.sqlite");
+        let users_manager = Arc::new(UsersManager::new(&users_db_path));
+        migrate_users_db(&users_db_path);
+
+        let rate_limiter = Arc::new(RateLimiter::new(config.clone()));
+
         FoxBox {
             certificate_manager: CertificateManager::new(certificate_directory,
                                                          domain,
@@ -77,12 +132,41 @@ impl FoxBox {
             ws_port: ws_port,
             config: config,
             upnp: Arc::new(UpnpManager::new()),
-            users_manager:
-                Arc::new(UsersManager::new(&profile_service.path_for("users_db.sqlite"))),
+            users_manager: users_manager,
+            rate_limiter: rate_limiter,
             profile_service: Arc::new(profile_service),
         }
     }
 
+    /// Exposes the state of the box, i.e. services, channels, watches and
+    /// websocket clients, as gauges refreshed whenever the metrics are read.
+    fn collect_metrics(&self, taxo_manager: &Arc<TaxoManager>) {
+        let taxo_manager = taxo_manager.clone();
+        let websockets = self.websockets.clone();
+        metrics::global().add_collector(move |metrics| {
+            metrics.set("foxbox_services",
+                        "Number of services.",
+                        vec![],
+                        taxo_manager.get_services(vec![ServiceSelector::new()]).len() as f64);
+            metrics.set("foxbox_channels",
+                        "Number of channels.",
+                        vec![],
+                        taxo_manager.get_channels(vec![ChannelSelector::new()]).len() as f64);
+            metrics.set_all("foxbox_watches",
+                            "Number of active watches, by adapter.",
+                            taxo_manager.count_watches()
+                                .into_iter()
+                                .map(|(adapter, count)| {
+                                    (vec![("adapter", adapter.to_string())], count as f64)
+                                })
+                                .collect());
+            metrics.set("foxbox_websocket_clients",
+                        "Number of connected websocket clients.",
+                        vec![],
+                        websockets.lock().unwrap().len() as f64);
+        });
+    }
+
     #[allow(unused_variables)] // for `format`
     fn watch_values(&self, taxo_manager: &Arc<TaxoManager>) -> WatchGuard {
         let (tx, rx) = mpsc::channel::<WatchEvent>();
@@ -139,9 +223,32 @@ impl Controller for FoxBox {
             Arc::get_mut(&mut self.upnp).unwrap().start().unwrap();
         }
 
+        // Pick up manual edits of foxbox.conf while running.
+        ConfigService::watch_file(&self.config, Duration::from_secs(CONFIG_WATCH_PERIOD_S));
+
         // Create the taxonomy based AdapterManager
         let tags_db_path = PathBuf::from(self.profile_service.path_for("taxonomy_tags.sqlite"));
-        let taxo_manager = Arc::new(TaxoManager::new(Some(tags_db_path)));
+        let audit_db_path = PathBuf::from(self.profile_service.path_for("audit.sqlite"));
+        let taxo_manager = Arc::new(TaxoManager::new(Some(tags_db_path))
+            .with_audit_log(AuditLog::new(Some(audit_db_path))));
+
+        let adapter_timeout = adapter_timeout_config();
+        self.config.register(adapter_timeout.clone());
+        taxo_manager.set_adapter_timeout(
+            Duration::from_secs(self.config.get_integer(&adapter_timeout) as u64));
+        {
+            let taxo_manager = taxo_manager.clone();
+            self.config.add_listener(move |change| {
+                if change.namespace == adapter_timeout.namespace &&
+                   change.property == adapter_timeout.property {
+                    let timeout = change.value
+                        .as_ref()
+                        .and_then(|value| value.parse::<u64>().ok())
+                        .unwrap_or(DEFAULT_ADAPTER_TIMEOUT_S);
+                    taxo_manager.set_adapter_timeout(Duration::from_secs(timeout));
+                }
+            });
+        }
 
         // We can't use let _ = self.watch_values(...) because that would drop the
         // guard immediately and remove the watcher.
@@ -152,6 +259,7 @@ impl Controller for FoxBox {
 
         HttpServer::new(self.clone()).start(&taxo_manager);
         WsServer::start(self.clone());
+        self.collect_metrics(&taxo_manager);
 
         let poll = Poll::new().unwrap();
         let mut events = Events::with_capacity(1024);
@@ -218,6 +326,10 @@ impl Controller for FoxBox {
         self.users_manager.clone()
     }
 
+    fn get_rate_limiter(&self) -> Arc<RateLimiter> {
+        self.rate_limiter.clone()
+    }
+
     fn get_certificate_manager(&self) -> CertificateManager {
         self.certificate_manager.clone()
     }
@@ -231,8 +343,22 @@ impl Controller for FoxBox {
         self.certificate_manager.get_box_certificate()
     }
 
+    /// The box certificate in self-signed mode, and the LetsEncrypt certificate
+    /// of the remote name once it has been issued otherwise.
+    fn get_served_certificate(&self) -> Option<CertificateRecord> {
+        match self.tls_option {
+            TlsOption::Enabled => self.certificate_manager.get_remote_hostname_certificate(),
+            TlsOption::SelfSigned => self.get_box_certificate().ok(),
+            TlsOption::Disabled => None,
+        }
+    }
+
     fn get_tls_enabled(&self) -> bool {
-        self.tls_option == TlsOption::Enabled
+        self.tls_option != TlsOption::Disabled
+    }
+
+    fn get_tls_option(&self) -> TlsOption {
+        self.tls_option.clone()
     }
 
     fn get_hostname(&self) -> String {
@@ -243,3 +369,50 @@ impl Controller for FoxBox {
         self.domain.clone()
     }
 }
+
+#[cfg(test)]
+describe! users_db {
+    before_each {
+        use foxbox_core::migrations::schema_version;
+        use foxbox_users::UsersManager;
+        use rusqlite::Connection;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("users_db").unwrap();
+        let path = dir.path().join("users_db.sqlite");
+        let path = path.to_str().unwrap();
+
+        // A database from before versioning, created by `foxbox_users` alone.
+        let _users_manager = UsersManager::new(path);
+        let tables = || {
+            let db = Connection::open(path).unwrap();
+            let mut statement = db.prepare("SELECT name, sql FROM sqlite_master ORDER BY name")
+                .unwrap();
+            let rows = statement.query_map(&[], |row| {
+                    (row.get::<i32, String>(0), row.get::<i32, Option<String>>(1))
+                })
+                .unwrap();
+            rows.map(|row| row.unwrap()).collect::<Vec<_>>()
+        };
+    }
+
+    it "should version an unversioned database and keep its schema" {
+        let legacy = tables();
+        assert!(!legacy.is_empty());
+        assert_eq!(schema_version(&Connection::open(path).unwrap()).unwrap(), 0);
+
+        migrate_users_db(path);
+        assert_eq!(schema_version(&Connection::open(path).unwrap()).unwrap(),
+                   USERS_DB_SCHEMA_VERSION);
+        assert_eq!(tables(), legacy);
+
+        // Migrating again is a no-op.
+        migrate_users_db(path);
+        assert_eq!(tables(), legacy);
+    }
+
+    failing "should refuse a database written by a newer box" {
+        Connection::open(path).unwrap().execute_batch("PRAGMA user_version = 99").unwrap();
+        migrate_users_db(path);
+    }
+}
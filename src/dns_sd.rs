@@ -0,0 +1,184 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+/// This advertises the box on the local network with DNS-SD, so that clients
+/// can find it without the registration server, e.g. in local-only mode.
+/// `multicast_dns` only manages the host name, so the services are published
+/// by the system mDNS responder tools (`avahi-publish-service` on Linux,
+/// `dns-sd` on macOS), which we keep running for as long as we advertise.
+
+use std::io;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+
+/// The version of the HTTP API, advertised in the TXT records.
+pub const API_VERSION: &'static str = "v1";
+
+/// A DNS-SD service published for the box.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceRecord {
+    pub name: String,
+    pub service_type: &'static str,
+    pub port: u16,
+    pub txt: Vec<(&'static str, String)>,
+}
+
+impl ServiceRecord {
+    #[cfg(target_os = "macos")]
+    fn publish_command(&self) -> Command {
+        let mut command = Command::new("dns-sd");
+        command.arg("-R")
+            .arg(&self.name)
+            .arg(self.service_type)
+            .arg("local")
+            .arg(self.port.to_string());
+        command.args(&self.txt_strings());
+        command
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn publish_command(&self) -> Command {
+        let mut command = Command::new("avahi-publish-service");
+        command.arg(&self.name).arg(self.service_type).arg(self.port.to_string());
+        command.args(&self.txt_strings());
+        command
+    }
+
+    fn txt_strings(&self) -> Vec<String> {
+        self.txt.iter().map(|&(key, ref value)| format!("{}={}", key, value)).collect()
+    }
+}
+
+/// Builds the services advertised for a box called `name`:
+/// `_foxbox._tcp` for foxbox clients, and `_https._tcp` (or `_http._tcp`
+/// without TLS) for browsers. Both carry the fingerprint of the served
+/// certificate, if any, the API version and the websocket port in their
+/// TXT records.
+pub fn service_records(name: &str,
+                       http_port: u16,
+                       ws_port: u16,
+                       tls_enabled: bool,
+                       fingerprint: Option<&str>)
+                       -> Vec<ServiceRecord> {
+    let mut txt = vec![];
+    if let Some(fingerprint) = fingerprint {
+        txt.push(("fingerprint", fingerprint.to_owned()));
+    }
+    txt.push(("api", API_VERSION.to_owned()));
+    txt.push(("ws_port", ws_port.to_string()));
+    txt.push(("scheme", if tls_enabled { "https" } else { "http" }.to_owned()));
+    vec![ServiceRecord {
+             name: name.to_owned(),
+             service_type: "_foxbox._tcp",
+             port: http_port,
+             txt: txt.clone(),
+         },
+         ServiceRecord {
+             name: name.to_owned(),
+             service_type: if tls_enabled { "_https._tcp" } else { "_http._tcp" },
+             port: http_port,
+             txt: txt,
+         }]
+}
+
+pub struct ServiceAdvertiser {
+    records: Mutex<Vec<ServiceRecord>>,
+    publishers: Mutex<Vec<Child>>,
+}
+
+impl ServiceAdvertiser {
+    pub fn new(records: Vec<ServiceRecord>) -> Self {
+        ServiceAdvertiser {
+            records: Mutex::new(records),
+            publishers: Mutex::new(vec![]),
+        }
+    }
+
+    /// Starts publishing all the services.
+    /// Failures are logged, since the box remains usable without DNS-SD.
+    pub fn start(&self) {
+        let mut publishers = self.publishers.lock().unwrap();
+        for record in self.records.lock().unwrap().iter() {
+            match record.publish_command()
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn() {
+                Ok(child) => {
+                    info!("DNS-SD: Advertising {} as {} on port {}",
+                          record.service_type,
+                          record.name,
+                          record.port);
+                    publishers.push(child);
+                }
+                Err(err) => {
+                    error!("DNS-SD: Unable to advertise {}: {}", record.service_type, err)
+                }
+            }
+        }
+    }
+
+    /// Stops publishing all the services.
+    pub fn stop(&self) {
+        for mut child in self.publishers.lock().unwrap().drain(..) {
+            if let Err(err) = kill(&mut child) {
+                warn!("DNS-SD: Unable to stop advertising: {}", err);
+            }
+        }
+    }
+
+    /// Publishes the services again, e.g. after a network change.
+    pub fn restart(&self) {
+        self.stop();
+        self.start();
+    }
+
+    /// Replaces the published services, e.g. once the certificate they
+    /// advertise is available. Returns whether they changed, in which case
+    /// they should be published again.
+    pub fn set_records(&self, records: Vec<ServiceRecord>) -> bool {
+        let mut current = self.records.lock().unwrap();
+        if *current == records {
+            return false;
+        }
+        *current = records;
+        true
+    }
+}
+
+impl Drop for ServiceAdvertiser {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn kill(child: &mut Child) -> io::Result<()> {
+    try!(child.kill());
+    try!(child.wait());
+    Ok(())
+}
+
+#[cfg(test)]
+describe! dns_sd {
+    it "should advertise foxbox and https services with TXT records" {
+        let records = service_records("foxbox", 3000, 4000, true, Some("abcdef"));
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].service_type, "_foxbox._tcp");
+        assert_eq!(records[1].service_type, "_https._tcp");
+        assert_eq!(records[0].port, 3000);
+        assert_eq!(records[0].txt_strings(),
+                   vec!["fingerprint=abcdef", "api=v1", "ws_port=4000", "scheme=https"]);
+    }
+
+    it "should advertise an http service without TLS" {
+        let records = service_records("foxbox", 3000, 4000, false, None);
+        assert_eq!(records[1].service_type, "_http._tcp");
+        assert_eq!(records[1].txt_strings(), vec!["api=v1", "ws_port=4000", "scheme=http"]);
+    }
+
+    it "should only publish again changed records" {
+        let advertiser = ServiceAdvertiser::new(service_records("foxbox", 3000, 4000, true, None));
+        assert!(!advertiser.set_records(service_records("foxbox", 3000, 4000, true, None)));
+        assert!(advertiser.set_records(service_records("foxbox", 3000, 4000, true, Some("ab"))));
+    }
+}
@@ -0,0 +1,256 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Endpoints to manage the groups.
+//!
+//! GET    /api/v1/groups      : lists the groups.
+//! POST   /api/v1/groups      : adds a group. The json body is e.g.
+//!                              {"name": "Living room lights",
+//!                               "selectors": [{"tags": ["room:living-room"]}],
+//!                               "feature": "light/is-on", "aggregate": "any-on"}
+//!                              where "selectors" are service selectors and "aggregate"
+//!                              is one of "any-on", "all-on" or "average".
+//! GET    /api/v1/groups/<id> : returns a group, with the ids of the channels of its
+//!                              current members in "members".
+//! PUT    /api/v1/groups/<id> : replaces the definition of a group.
+//! DELETE /api/v1/groups/<id> : removes a group.
+
+use adapters::groups::{Aggregate, Group, GroupError, Groups};
+use api_error::{self, json_response, read_json};
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_users::AuthEndpoint;
+use iron::{Handler, IronResult, Request, Response};
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use serde_json;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+struct GroupEdit {
+    name: String,
+    selectors: serde_json::Value,
+    feature: String,
+    aggregate: Aggregate,
+}
+
+pub struct GroupsRouter {
+    groups: Groups,
+}
+
+fn error_response(err: &GroupError) -> IronResult<Response> {
+    let status = match *err {
+        GroupError::NoSuchGroup(_) => Status::NotFound,
+        GroupError::InvalidGroup(_) => Status::BadRequest,
+        GroupError::Database(_) |
+        GroupError::Api(_) => Status::InternalServerError,
+    };
+    Ok(api_error::response(status, err.to_string()))
+}
+
+impl GroupsRouter {
+    pub fn new(groups: Groups) -> Self {
+        GroupsRouter { groups: groups }
+    }
+
+    fn get(&self, id: &str) -> IronResult<Response> {
+        let group = match self.groups.get(id) {
+            Ok(group) => group,
+            Err(err) => return error_response(&err),
+        };
+        let members: Vec<String> = self.groups
+            .members(&group)
+            .iter()
+            .map(|channel| channel.id.to_string())
+            .collect();
+        let mut json = serde_json::to_value(&group);
+        if let serde_json::Value::Object(ref mut map) = json {
+            map.insert("members".to_owned(), serde_json::to_value(&members));
+        }
+        json_response(Status::Ok, &json)
+    }
+
+    fn add(&self, req: &mut Request) -> IronResult<Response> {
+        let edit = match read_json::<GroupEdit>(req, "group") {
+            Ok(edit) => edit,
+            Err(response) => return Ok(response),
+        };
+        match self.groups.create(&edit.name, edit.selectors, &edit.feature, edit.aggregate) {
+            Ok(group) => json_response(Status::Created, &group),
+            Err(err) => error_response(&err),
+        }
+    }
+
+    fn update(&self, id: &str, req: &mut Request) -> IronResult<Response> {
+        let edit = match read_json::<GroupEdit>(req, "group") {
+            Ok(edit) => edit,
+            Err(response) => return Ok(response),
+        };
+        let group = Group {
+            id: id.to_owned(),
+            name: edit.name,
+            selectors: edit.selectors,
+            feature: edit.feature,
+            aggregate: edit.aggregate,
+        };
+        match self.groups.update(&group) {
+            Ok(()) => json_response(Status::Ok, &group),
+            Err(err) => error_response(&err),
+        }
+    }
+}
+
+impl Handler for GroupsRouter {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path: Vec<String> = req.url
+            .path()
+            .iter()
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| (*segment).to_owned())
+            .collect();
+
+        match (req.method.clone(), path.len()) {
+            (Method::Get, 0) => {
+                match self.groups.list() {
+                    Ok(groups) => json_response(Status::Ok, &groups),
+                    Err(err) => error_response(&err),
+                }
+            }
+            (Method::Post, 0) => self.add(req),
+            (Method::Get, 1) => self.get(&path[0]),
+            (Method::Put, 1) => self.update(&path[0], req),
+            (Method::Delete, 1) => {
+                match self.groups.remove(&path[0]) {
+                    Ok(()) => Ok(Response::with(Status::NoContent)),
+                    Err(err) => error_response(&err),
+                }
+            }
+            (_, 0) | (_, 1) => {
+                Ok(api_error::response(Status::MethodNotAllowed,
+                                       format!("Bad method: {}", req.method)))
+            }
+            _ => Ok(api_error::response(Status::NotFound, format!("Unknown url: {}", req.url))),
+        }
+    }
+}
+
+pub fn create<T>(controller: T,
+                 adapter_api: &Arc<AdapterManager>)
+                 -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    // Keep these in sync with the handle() method. They are relative to api/v1.
+    let endpoints = vec![
+        (vec![Method::Get, Method::Post], "groups".to_owned()),
+        (vec![Method::Get, Method::Put, Method::Delete], "groups/:id".to_owned()),
+    ];
+
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        endpoints.iter().map(|item| AuthEndpoint(item.0.clone(), item.1.clone())).collect()
+    } else {
+        vec![]
+    };
+
+    let db_path = controller.get_profile().path_for("groups.sqlite");
+    let mut chain = Chain::new(GroupsRouter::new(Groups::new(adapter_api, &db_path)));
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! groups_router {
+    before_each {
+        use adapters::clock;
+        use adapters::groups::GroupAdapter;
+        use foxbox_taxonomy::manager::AdapterManager;
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{request, response};
+        use mount::Mount;
+        use serde_json;
+        use std::sync::Arc;
+        use stubs::controller::ControllerStub;
+
+        let controller = ControllerStub::new();
+        let taxo_manager = Arc::new(AdapterManager::new(None));
+        clock::Clock::init(controller.clone(), &taxo_manager).unwrap();
+        GroupAdapter::init(controller.clone(), &taxo_manager).unwrap();
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1/groups", create(controller.clone(), &taxo_manager).0);
+
+        let body = r#"{"name": "Clocks", "selectors": [{"id": "service:clock@link.mozilla.org"}],
+                       "feature": "clock/is-daylight", "aggregate": "any-on"}"#;
+        let response = request::post("http://localhost:3000/api/v1/groups",
+                                     Headers::new(),
+                                     body,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Created));
+    }
+
+    it "should list the groups" {
+        let response = request::get("http://localhost:3000/api/v1/groups",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert_eq!(body,
+                   r#"[{"id":"clocks","name":"Clocks","selectors":[{"id":"service:clock@link.mozilla.org"}],"feature":"clock/is-daylight","aggregate":"any-on"}]"#);
+    }
+
+    it "should return the members" {
+        let response = request::get("http://localhost:3000/api/v1/groups/clocks",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.find("members").unwrap(),
+                   &serde_json::to_value(&vec!["getter:is-daylight.clock@link.mozilla.org"]));
+    }
+
+    it "should edit groups" {
+        let response = request::put("http://localhost:3000/api/v1/groups/clocks",
+                                    Headers::new(),
+                                    r#"{"name": "Nothing", "selectors": [{"tags": ["none"]}],
+                                        "feature": "light/is-on", "aggregate": "all-on"}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+
+        let response = request::get("http://localhost:3000/api/v1/groups/clocks",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.find("members").unwrap(), &serde_json::Value::Array(vec![]));
+    }
+
+    it "should reject invalid groups" {
+        let response = request::post("http://localhost:3000/api/v1/groups",
+                                     Headers::new(),
+                                     r#"{"name": "Lights", "selectors": "all",
+                                         "feature": "light/is-on", "aggregate": "any-on"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+
+        let response = request::post("http://localhost:3000/api/v1/groups",
+                                     Headers::new(),
+                                     r#"{"name": "Lights", "selectors": [],
+                                         "feature": "light/is-on", "aggregate": "median"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+    }
+
+    it "should remove groups" {
+        let response = request::delete("http://localhost:3000/api/v1/groups/clocks",
+                                       Headers::new(),
+                                       &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NoContent));
+
+        let response = request::get("http://localhost:3000/api/v1/groups/clocks",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+    }
+}
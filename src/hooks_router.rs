@@ -0,0 +1,273 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Endpoints of the incoming webhooks, see `adapters::webhooks`.
+//!
+//! GET    /api/v1/hooks         : lists the incoming webhooks, with their tokens (admin).
+//! POST   /api/v1/hooks         : adds an incoming webhook with a random token, from a
+//!                                json body {"name": "Doorbell"} (admin).
+//! POST   /api/v1/hooks/<token> : triggers the `webhook/trigger` channel of an incoming
+//!                                webhook with the json body, null if empty. This one
+//!                                doesn't need a session, the token being the secret.
+//! DELETE /api/v1/hooks/<token> : revokes an incoming webhook (admin).
+
+use adapters::webhooks::{Webhooks, WebhookError};
+use api_error::{self, json_response, read_json};
+use audit_router::AuditedPath;
+use auth;
+use foxbox_core::rate_limit::FailureKey;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_users::AuthEndpoint;
+use iron::{Handler, IronResult, Request, Response};
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use serde_json;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+struct HookCreation {
+    name: String,
+}
+
+pub struct HooksRouter<T> {
+    controller: T,
+    webhooks: Webhooks,
+}
+
+fn error_response(err: &WebhookError) -> Response {
+    let status = match *err {
+        WebhookError::NoSuchWebhook(_) => Status::NotFound,
+        WebhookError::InvalidWebhook(_) => Status::BadRequest,
+        WebhookError::Database(_) |
+        WebhookError::Api(_) => Status::InternalServerError,
+    };
+    api_error::response(status, err.to_string())
+}
+
+impl<T: Controller> HooksRouter<T> {
+    pub fn new(controller: T, webhooks: Webhooks) -> Self {
+        HooksRouter {
+            controller: controller,
+            webhooks: webhooks,
+        }
+    }
+
+    fn list(&self) -> IronResult<Response> {
+        match self.webhooks.list_incoming() {
+            Ok(hooks) => json_response(Status::Ok, &hooks),
+            Err(err) => Ok(error_response(&err)),
+        }
+    }
+
+    fn add(&self, req: &mut Request) -> IronResult<Response> {
+        let creation: HookCreation = match read_json(req, "hook") {
+            Ok(creation) => creation,
+            Err(response) => return Ok(response),
+        };
+        match self.webhooks.create_incoming(&creation.name) {
+            Ok(hook) => json_response(Status::Created, &hook),
+            Err(err) => Ok(error_response(&err)),
+        }
+    }
+
+    fn trigger(&self, token: &str, req: &mut Request) -> IronResult<Response> {
+        // Clients guessing tokens get locked out, but only from the incoming
+        // webhooks: these posts are anonymous, and the clients coming through
+        // the tunnel can't be told apart.
+        let limiter = self.controller.get_rate_limiter();
+        let failure_keys: Vec<_> = FailureKey::hook_client(&req.remote_addr.ip())
+            .into_iter()
+            .collect();
+        if limiter.check_lockout("http", &failure_keys).is_err() {
+            return Ok(api_error::response(Status::TooManyRequests, "Too many requests"));
+        }
+        let json = match read_json::<serde_json::Value>(req, "json") {
+            Ok(json) => json,
+            Err(response) => return Ok(response),
+        };
+        match self.webhooks.trigger(token, &json) {
+            Ok(hook) => {
+                // Audited under the id of the hook, the token being a secret.
+                req.extensions.insert::<AuditedPath>(format!("/{}", hook.id));
+                Ok(Response::with(Status::NoContent))
+            }
+            Err(WebhookError::NoSuchWebhook(_)) => {
+                limiter.record_failure(&failure_keys);
+                Ok(api_error::response(Status::NotFound, "No such hook"))
+            }
+            Err(err) => Ok(error_response(&err)),
+        }
+    }
+}
+
+impl<T: Controller> Handler for HooksRouter<T> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path: Vec<String> = req.url
+            .path()
+            .iter()
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| (*segment).to_owned())
+            .collect();
+
+        // Posting to a hook is authenticated by its token, everything else is for admins.
+        if let (Method::Post, 1) = (req.method.clone(), path.len()) {
+            return self.trigger(&path[0], req);
+        }
+        if let Err(response) = auth::check_admin(req, &self.controller.get_users_manager()) {
+            return Ok(response);
+        }
+
+        match (req.method.clone(), path.len()) {
+            (Method::Get, 0) => self.list(),
+            (Method::Post, 0) => self.add(req),
+            (Method::Delete, 1) => {
+                match self.webhooks.revoke(&path[0]) {
+                    Ok(hook) => {
+                        req.extensions.insert::<AuditedPath>(format!("/{}", hook.id));
+                        Ok(Response::with(Status::NoContent))
+                    }
+                    Err(err) => Ok(error_response(&err)),
+                }
+            }
+            (_, 0) | (_, 1) => {
+                Ok(api_error::response(Status::MethodNotAllowed,
+                                       format!("Bad method: {}", req.method)))
+            }
+            _ => Ok(api_error::response(Status::NotFound, format!("Unknown url: {}", req.url))),
+        }
+    }
+}
+
+pub fn create<T>(controller: T,
+                 adapter_api: &Arc<AdapterManager>)
+                 -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    // Keep these in sync with the handle() method. They are relative to api/v1.
+    let endpoints = vec![
+        (vec![Method::Get, Method::Post], "hooks".to_owned()),
+        (vec![Method::Post, Method::Delete], "hooks/:token".to_owned()),
+    ];
+
+    // Posting to a hook doesn't need a session.
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        vec![AuthEndpoint(vec![Method::Get, Method::Post], "hooks".to_owned()),
+             AuthEndpoint(vec![Method::Delete], "hooks/:token".to_owned())]
+    } else {
+        vec![]
+    };
+
+    let db_path = controller.get_profile().path_for("webhooks.sqlite");
+    let webhooks = Webhooks::shared(adapter_api, &db_path);
+    let mut chain = Chain::new(HooksRouter::new(controller.clone(), webhooks));
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! hooks_router {
+    before_each {
+        use adapters::webhooks::{WebhookAdapter, Webhooks};
+        use foxbox_taxonomy::api::{API, User};
+        use foxbox_taxonomy::manager::AdapterManager;
+        use foxbox_taxonomy::selector::ChannelSelector;
+        use foxbox_taxonomy::values::{Json, Value};
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{request, response};
+        use mount::Mount;
+        use serde_json;
+        use std::sync::Arc;
+        use stubs::controller::ControllerStub;
+
+        let controller = ControllerStub::new();
+        let taxo_manager = Arc::new(AdapterManager::new(None));
+        WebhookAdapter::init(controller.clone(), &taxo_manager).unwrap();
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1/hooks", create(controller.clone(), &taxo_manager).0);
+
+        let response = request::post("http://localhost:3000/api/v1/hooks",
+                                     Headers::new(),
+                                     r#"{"name": "Doorbell"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Created));
+        let body = response::extract_body_to_string(response);
+        let hook: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let token = hook.find("token").unwrap().as_str().unwrap().to_owned();
+        let url = format!("http://localhost:3000/api/v1/hooks/{}", token);
+    }
+
+    it "should list the incoming webhooks" {
+        let response = request::get("http://localhost:3000/api/v1/hooks",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert_eq!(body,
+                   format!(r#"[{{"id":"doorbell","name":"Doorbell","token":"{}"}}]"#, token));
+    }
+
+    it "should trigger the channel of the hook" {
+        let response = request::post(&url, Headers::new(), r#"{"button": "front"}"#, &mount)
+            .unwrap();
+        assert_eq!(response.status, Some(Status::NoContent));
+
+        let selector = ChannelSelector::new()
+            .with_id(&Webhooks::incoming_channel_id("doorbell"));
+        let (_, result) = taxo_manager.fetch_values(vec![selector], User::None)
+            .into_iter()
+            .next()
+            .unwrap();
+        let (payload, format) = result.unwrap().unwrap();
+        assert_eq!(payload.to_value(&format).unwrap(),
+                   Value::new(Json(serde_json::from_str(r#"{"button": "front"}"#).unwrap())));
+
+        let response = request::post(&url, Headers::new(), "", &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NoContent));
+        let response = request::post(&url, Headers::new(), "{", &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+        let large = String::from_utf8(vec![b' '; 70000]).unwrap();
+        let response = request::post(&url, Headers::new(), &large, &mount).unwrap();
+        assert_eq!(response.status, Some(Status::PayloadTooLarge));
+    }
+
+    it "should audit the posts under the id of the hook" {
+        use audit_router::Audited;
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1/hooks",
+                    Audited::new(create(controller.clone(), &taxo_manager).0,
+                                 "hooks",
+                                 &taxo_manager));
+        let response = request::post(&url, Headers::new(), "{}", &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NoContent));
+        let response = request::post("http://localhost:3000/api/v1/hooks/unknown",
+                                     Headers::new(),
+                                     "{}",
+                                     &mount)
+            .unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+
+        use foxbox_taxonomy::audit::AuditFilter;
+
+        let filter = AuditFilter { action: Some("hooks".to_owned()), ..Default::default() };
+        let entries = taxo_manager.get_audit_log().query(&filter).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "hooks");
+        assert_eq!(entries[0].payload.find("path").unwrap().as_str(), Some("/doorbell"));
+    }
+
+    it "should revoke hooks" {
+        let response = request::delete(&url, Headers::new(), &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NoContent));
+
+        let response = request::post(&url, Headers::new(), "{}", &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+        let response = request::delete(&url, Headers::new(), &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+    }
+}
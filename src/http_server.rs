@@ -2,21 +2,41 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use adapters::media::clips::ClipRouter;
+use api_error;
+use audit_router::{self, Audited};
+use auth;
+use backup_router;
+use certificate_router;
+use config_router;
+use foxbox_core::metrics;
+use foxbox_core::rate_limit::{Blocked, FailureKey, RateLimiter};
 use foxbox_core::traits::Controller;
+use foxbox_taxonomy::api::User;
 use foxbox_taxonomy::manager::*;
-use iron::{AfterMiddleware, Chain, Handler, Iron, IronResult, Request, Response, Protocol};
+use groups_router;
+use hooks_router;
+use iron::{AfterMiddleware, BeforeMiddleware, Chain, Handler, Iron, IronResult, Request, Response,
+           Protocol};
 use iron_cors::CORS;
 use iron::error::IronError;
 use iron::method::Method;
 use iron::status::Status;
 use mount::Mount;
+use openapi::OpenApi;
 use router::NoRoute;
+use scenes_router;
 use static_router;
+use std::error::Error;
+use std::fmt;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use std::thread;
 use taxonomy_router;
+use virtual_router;
+use watch_router;
+use webhooks_router;
 
 const THREAD_COUNT: usize = 8;
 
@@ -30,11 +50,12 @@ impl AfterMiddleware for Custom404 {
 
         if err.error.downcast::<NoRoute>().is_some() {
             // Router error
-            return Ok(Response::with((Status::NotFound, format!("Unknown resource: {}", err))));
+            return Ok(api_error::response(Status::NotFound, format!("Unknown resource: {}", err)));
         } else if let Some(err) = err.error.downcast::<StdError>() {
             // StaticFile error
             if err.kind() == ErrorKind::NotFound {
-                return Ok(Response::with((Status::NotFound, format!("Unknown resource: {}", err))));
+                return Ok(api_error::response(Status::NotFound,
+                                              format!("Unknown resource: {}", err)));
             }
         }
 
@@ -85,6 +106,111 @@ impl AfterMiddleware for SecurityHeaders {
     }
 }
 
+header! { (RetryAfter, "Retry-After") => [u64] }
+
+#[derive(Debug)]
+struct TooManyRequests;
+
+impl fmt::Display for TooManyRequests {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for TooManyRequests {
+    fn description(&self) -> &str {
+        "Too many requests"
+    }
+}
+
+// Middleware refusing the requests of the clients that exceed their rate
+// limit or that are locked out after failing to authenticate.
+#[derive(Clone)]
+struct RateLimit {
+    limiter: Arc<RateLimiter>,
+}
+
+fn is_login(req: &Request) -> bool {
+    let path = req.url.path();
+    path.len() >= 2 && path[0] == "users" && path[1] == "login"
+}
+
+impl RateLimit {
+    // The failed authentications of a request count against the client, if
+    // its real address is known, and against the account it logs in as from
+    // that client.
+    fn failure_keys(req: &Request) -> Vec<FailureKey> {
+        use iron::headers::{Authorization, Basic};
+
+        let remote = req.remote_addr.ip();
+        let mut keys: Vec<_> = FailureKey::client(&remote).into_iter().collect();
+        if is_login(req) {
+            if let Some(&Authorization(Basic { ref username, .. })) =
+                   req.headers.get::<Authorization<Basic>>() {
+                keys.push(FailureKey::account(username, &remote));
+            }
+        }
+        keys
+    }
+
+    // Only wrong passwords and invalid tokens count as failures, not the
+    // requests made before logging in.
+    fn record(&self, req: &Request, status: Option<Status>) {
+        use iron::headers::{Authorization, Bearer};
+
+        let is_login = is_login(req);
+        match status {
+            Some(Status::Unauthorized) if is_login ||
+                                          req.headers.has::<Authorization<Bearer>>() => {
+                self.limiter.record_failure(&Self::failure_keys(req))
+            }
+            Some(status) if is_login && status.is_success() => {
+                self.limiter.record_success(&Self::failure_keys(req))
+            }
+            _ => {}
+        }
+    }
+
+    fn blocked(blocked: Blocked) -> IronError {
+        let retry_after = blocked.retry_after();
+        let retry_after = retry_after.as_secs() +
+                          if retry_after.subsec_nanos() > 0 { 1 } else { 0 };
+        let mut response = api_error::response(Status::TooManyRequests,
+                                               TooManyRequests.description());
+        response.headers.set(RetryAfter(retry_after));
+        IronError {
+            error: Box::new(TooManyRequests),
+            response: response,
+        }
+    }
+}
+
+impl BeforeMiddleware for RateLimit {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let user = match auth::user_from_request(req) {
+            Ok(User::Id(id)) => Some(id),
+            _ => None,
+        };
+        let user = user.as_ref().map(|id| id as &str);
+        try!(self.limiter
+            .check_lockout("http", &Self::failure_keys(req))
+            .map_err(Self::blocked));
+        self.limiter.check("http", &req.remote_addr.ip(), user).map_err(Self::blocked)
+    }
+}
+
+impl AfterMiddleware for RateLimit {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.record(req, res.status);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.record(req, err.response.status);
+        Err(err)
+    }
+}
+
 struct Ping;
 
 impl Handler for Ping {
@@ -93,6 +219,99 @@ impl Handler for Ping {
     }
 }
 
+// Middleware counting the requests by route, method and status.
+struct RequestMetrics;
+
+impl RequestMetrics {
+    fn record(req: &Request, status: Option<Status>) {
+        let status = status.map_or("".to_owned(), |status| status.to_u16().to_string());
+        metrics::global().inc("foxbox_http_requests_total",
+                              "Number of REST requests, by route, method and status.",
+                              vec![("route", normalize_route(&req.url.path())),
+                                   ("method", req.method.to_string()),
+                                   ("status", status)]);
+    }
+}
+
+impl AfterMiddleware for RequestMetrics {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        RequestMetrics::record(req, res.status);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        RequestMetrics::record(req, err.response.status);
+        Err(err)
+    }
+}
+
+/// Turns a request path into a route with a bounded number of values, so
+/// that each route is a single series: ids (anything with a digit, a dot,
+/// a dash or an @) become `:id` and only the first 4 segments are kept.
+fn normalize_route(path: &[&str]) -> String {
+    let segments: Vec<&str> = path.iter()
+        .filter(|segment| !segment.is_empty())
+        .take(4)
+        .map(|segment| {
+            if segment.chars().any(|c| c.is_digit(10) || c == '.' || c == '-' || c == '@') {
+                ":id"
+            } else {
+                segment
+            }
+        })
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+// Serves the metrics to admins. Note that requests coming through the tunnel
+// also come from localhost, so they can't be trusted any more than others.
+struct Metrics<T: Controller> {
+    controller: T,
+}
+
+impl<T: Controller> Handler for Metrics<T> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        use iron::headers::ContentType;
+
+        if let Err(response) = auth::check_admin(req, &self.controller.get_users_manager()) {
+            return Ok(response);
+        }
+
+        let mut response = Response::with((Status::Ok, metrics::global().render()));
+        response.headers.set(ContentType::plaintext());
+        Ok(response)
+    }
+}
+
+/// Creates the routers of the REST API, with the path each of them is mounted at
+/// and the endpoints it handles, relative to api/v1.
+#[allow(type_complexity)]
+pub fn api_routers<T: Controller>(controller: &T,
+                                  adapter_api: &Arc<AdapterManager>)
+                                  -> Vec<(&'static str, (Chain, Vec<(Vec<Method>, String)>))> {
+    // The taxonomy router records its own calls, since it knows their targets.
+    let audited = |action, (chain, endpoints): (Chain, Vec<(Vec<Method>, String)>)| {
+        (Chain::new(Audited::new(chain, action, adapter_api)), endpoints)
+    };
+    vec![("/api/v1", taxonomy_router::create(controller.clone(), adapter_api)),
+         ("/api/v1/certificates",
+          audited("certificates", certificate_router::create(controller.clone()))),
+         ("/api/v1/config", audited("config", config_router::create(controller.clone()))),
+         ("/api/v1/backup", audited("backup", backup_router::create(controller.clone()))),
+         ("/api/v1/scenes",
+          audited("scenes", scenes_router::create(controller.clone(), adapter_api))),
+         ("/api/v1/virtual",
+          audited("virtual", virtual_router::create(controller.clone(), adapter_api))),
+         ("/api/v1/groups",
+          audited("groups", groups_router::create(controller.clone(), adapter_api))),
+         ("/api/v1/audit", audit_router::create(controller.clone(), adapter_api)),
+         ("/api/v1/channels/watch", watch_router::create(controller.clone(), adapter_api)),
+         ("/api/v1/webhooks",
+          audited("webhooks", webhooks_router::create(controller.clone(), adapter_api))),
+         ("/api/v1/hooks",
+          audited("hooks", hooks_router::create(controller.clone(), adapter_api)))]
+}
+
 pub struct HttpServer<T: Controller> {
     controller: T,
 }
@@ -103,25 +322,39 @@ impl<T: Controller> HttpServer<T> {
     }
 
     pub fn start(&mut self, adapter_api: &Arc<AdapterManager>) {
-        let (taxonomy_chain, mut taxonomy_endpoints) =
-            taxonomy_router::create(self.controller.clone(), adapter_api);
+        let routers = api_routers(&self.controller, adapter_api);
+        let mut api_endpoints: Vec<(Vec<Method>, String)> = routers.iter()
+            .flat_map(|&(_, (_, ref endpoints))| endpoints.clone())
+            .collect();
+        api_endpoints.push((vec![Method::Get], "openapi.json".to_owned()));
 
         let users_manager = self.controller.get_users_manager();
         let mut mount = Mount::new();
         mount.mount("/", static_router::create(users_manager.clone()))
             .mount("/ping", Ping)
-            .mount("/api/v1", taxonomy_chain)
-            .mount("/users", users_manager.get_router_chain());
+            .mount("/metrics", Metrics { controller: self.controller.clone() })
+            .mount("/media/clips", ClipRouter)
+            .mount("/api/v1/openapi.json", OpenApi::new(&api_endpoints))
+            .mount("/users",
+                   Audited::new(users_manager.get_router_chain(), "users", adapter_api));
+        for (path, (chain, _)) in routers {
+            mount.mount(path, chain);
+        }
 
+        let rate_limit = RateLimit { limiter: self.controller.get_rate_limiter() };
         let mut chain = Chain::new(mount);
+        chain.link_before(rate_limit.clone());
         chain.link_after(Custom404);
+        chain.link_after(rate_limit);
+        chain.link_after(RequestMetrics);
 
-        // Build the set of CORS endpoints by prefixing the taxonomy ones with api/v1 and
-        // adding the /ping handler.
-        let mut cors_endpoints: Vec<(Vec<Method>, String)> = taxonomy_endpoints.drain(..)
+        // Build the set of CORS endpoints by prefixing the api ones with api/v1 and adding
+        // the /ping and /metrics handlers.
+        let mut cors_endpoints: Vec<(Vec<Method>, String)> = api_endpoints.drain(..)
             .map(|item| (item.0, format!("api/v1/{}", item.1)))
             .collect();
         cors_endpoints.push((vec![Method::Get], "ping".to_owned()));
+        cors_endpoints.push((vec![Method::Get], "metrics".to_owned()));
 
         let cors = CORS::new(cors_endpoints);
         chain.link_after(cors);
@@ -135,11 +368,9 @@ impl<T: Controller> HttpServer<T> {
             // This will fail when starting without a certificate, so for now just loop until we generate one.
             loop {
                 // Get the certificate record for the remote hostname, and use its certificate and
-                // private key files.
-                let record =
-                    self.controller.get_certificate_manager().get_remote_hostname_certificate();
-                if record.is_some() {
-                    let record = record.unwrap();
+                // private key files. In self-signed mode, there is no remote hostname and we
+                // use the box certificate instead.
+                if let Some(record) = self.controller.get_served_certificate() {
                     start_server(addrs,
                                  chain,
                                  Protocol::Https {
@@ -190,6 +421,117 @@ describe! ping {
     }
 }
 
+#[cfg(test)]
+describe! metrics {
+    it "should normalize routes to a bounded set" {
+        use super::normalize_route;
+
+        assert_eq!(normalize_route(&["api", "v1", "services"]), "/api/v1/services");
+        assert_eq!(normalize_route(&["api", "v1", "scenes", "3f2a"]), "/api/v1/scenes/:id");
+        assert_eq!(normalize_route(&["api", "v1", "channels", "get", "extra"]),
+                   "/api/v1/channels/get");
+        assert_eq!(normalize_route(&[""]), "/");
+    }
+
+    it "should serve the metrics to admins" {
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{request, response};
+        use mount::Mount;
+        use stubs::controller::ControllerStub;
+        use super::Metrics;
+        use foxbox_core::metrics;
+
+        let mut mount = Mount::new();
+        mount.mount("/metrics", Metrics { controller: ControllerStub::new() });
+        metrics::global().inc("foxbox_test_total", "Test", vec![]);
+        let response = request::get("http://localhost:3000/metrics",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status.unwrap(), Status::Ok);
+        assert!(response::extract_body_to_string(response).contains("foxbox_test_total 1"));
+    }
+}
+
+#[cfg(test)]
+describe! rate_limit {
+    before_each {
+        use foxbox_core::traits::Controller;
+        use iron::{Chain, Headers};
+        use iron::status::Status;
+        use iron_test::request;
+        use mount::Mount;
+        use stubs::controller::ControllerStub;
+        use super::{Ping, RateLimit, RetryAfter};
+
+        let controller = ControllerStub::new();
+        let rate_limit = RateLimit { limiter: controller.get_rate_limiter() };
+        controller.config.set("rate_limit", "global_requests_per_minute", "2");
+
+        let mut mount = Mount::new();
+        mount.mount("/ping", Ping);
+        let mut chain = Chain::new(mount);
+        chain.link_before(rate_limit.clone());
+        chain.link_after(rate_limit);
+    }
+
+    it "should refuse requests over the limit" {
+        for _ in 0..2 {
+            let response = request::get("http://localhost:3000/ping", Headers::new(), &chain)
+                .unwrap();
+            assert_eq!(response.status.unwrap(), Status::NoContent);
+        }
+        let response = request::get("http://localhost:3000/ping", Headers::new(), &chain);
+        let response = response.unwrap_err().response;
+        assert_eq!(response.status.unwrap(), Status::TooManyRequests);
+        assert_eq!(response.headers.get::<RetryAfter>(), Some(&RetryAfter(30)));
+    }
+
+    it "should lock out the accounts that fail to log in, not everybody on localhost" {
+        use iron::{IronResult, Request, Response};
+        use iron::headers::{Authorization, Basic};
+
+        fn login(_: &mut Request) -> IronResult<Response> {
+            Ok(Response::with(Status::Unauthorized))
+        }
+        let mut mount = Mount::new();
+        mount.mount("/users/login", login);
+        let mut chain = Chain::new(mount);
+        let rate_limit = RateLimit { limiter: controller.get_rate_limiter() };
+        chain.link_before(rate_limit.clone());
+        chain.link_after(rate_limit);
+        controller.config.set("rate_limit", "global_requests_per_minute", "100");
+
+        let headers_for = |username: &str| {
+            let mut headers = Headers::new();
+            headers.set(Authorization(Basic {
+                username: username.to_owned(),
+                password: Some("wrong".to_owned()),
+            }));
+            headers
+        };
+        for _ in 0..5 {
+            let response = request::post("http://localhost:3000/users/login",
+                                         headers_for("victim@example.com"),
+                                         "",
+                                         &chain)
+                .unwrap();
+            assert_eq!(response.status.unwrap(), Status::Unauthorized);
+        }
+        let response = request::post("http://localhost:3000/users/login",
+                                     headers_for("victim@example.com"),
+                                     "",
+                                     &chain);
+        assert_eq!(response.unwrap_err().response.status.unwrap(), Status::TooManyRequests);
+        let response = request::post("http://localhost:3000/users/login",
+                                     headers_for("other@example.com"),
+                                     "",
+                                     &chain)
+            .unwrap();
+        assert_eq!(response.status.unwrap(), Status::Unauthorized);
+    }
+}
+
 #[cfg(test)]
 describe! http_server {
     before_each {
@@ -239,7 +581,7 @@ describe! http_server {
         assert_eq!(res.status, Status::NotFound);
         let mut body = String::new();
         res.read_to_string(&mut body).unwrap();
-        assert_eq!(body, "Unknown resource: No such file or \
-                   directory (os error 2)".to_owned());
+        assert_eq!(body, "{\"error\":{\"code\":\"not_found\",\"message\":\"Unknown \
+                   resource: No such file or directory (os error 2)\"}}".to_owned());
     }
 }
@@ -81,10 +81,26 @@ mod stubs {
 }
 
 mod adapters;
+mod api_error;
+mod audit_router;
+mod auth;
+pub mod backup;
+mod backup_router;
+mod certificate_router;
+mod config_router;
 pub mod controller;
+pub mod dns_sd;
+mod groups_router;
+mod hooks_router;
 mod http_server;
+pub mod network_monitor;
+mod openapi;
 pub mod registration;
+mod scenes_router;
 mod static_router;
 mod taxonomy_router;
 pub mod tunnel_controller;
+mod virtual_router;
+mod watch_router;
+mod webhooks_router;
 mod ws_server;
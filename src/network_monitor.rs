@@ -0,0 +1,173 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+/// This watches the network interfaces of the box and tells interested parties
+/// when their addresses change, e.g. after a new DHCP lease or when switching
+/// from ethernet to wifi.
+/// For now it simply compares the output of `get_if_addrs` every few seconds,
+/// which works on every platform we support without requiring netlink.
+
+extern crate get_if_addrs;
+
+use self::get_if_addrs::{IfAddr, Interface};
+use foxbox_core::traits::Controller;
+use serde_json;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const NETWORK_CHECK_INTERVAL_IN_SECONDS: u64 = 10;
+
+/// An ip address bound to a network interface.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Address {
+    pub iface: String,
+    pub ip: String,
+}
+
+/// The addresses that appeared and disappeared between two checks.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NetworkChange {
+    pub added: Vec<Address>,
+    pub removed: Vec<Address>,
+}
+
+impl NetworkChange {
+    /// Returns the change from `old` to `new`, or `None` if they are the same.
+    pub fn between(old: &BTreeSet<Address>, new: &BTreeSet<Address>) -> Option<NetworkChange> {
+        if old == new {
+            return None;
+        }
+        Some(NetworkChange {
+            added: new.difference(old).cloned().collect(),
+            removed: old.difference(new).cloned().collect(),
+        })
+    }
+}
+
+type Listener = Box<Fn(&NetworkChange) + Send>;
+
+#[derive(Clone, Default)]
+pub struct NetworkMonitor {
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
+
+impl NetworkMonitor {
+    pub fn new() -> Self {
+        NetworkMonitor::default()
+    }
+
+    /// Registers a function called from the monitor thread after each change.
+    pub fn add_listener<F>(&self, listener: F)
+        where F: Fn(&NetworkChange) + Send + 'static
+    {
+        self.listeners.lock().unwrap().push(Box::new(listener));
+    }
+
+    /// Relays changes to the websocket clients of `controller` as
+    /// `core/network/change` messages.
+    pub fn notify_websockets<T: Controller>(&self, controller: &T) {
+        let controller = controller.clone();
+        self.add_listener(move |change| {
+            controller.broadcast_to_websockets(json_value!({
+                type: "core/network/change",
+                added: change.added,
+                removed: change.removed
+            }));
+        });
+    }
+
+    /// Spawns a thread checking for changes every
+    /// NETWORK_CHECK_INTERVAL_IN_SECONDS.
+    pub fn start(&self) {
+        let listeners = self.listeners.clone();
+        thread::Builder::new()
+            .name("NetworkMonitor".to_owned())
+            .spawn(move || {
+                let mut known = current_addresses();
+                loop {
+                    thread::sleep(Duration::from_secs(NETWORK_CHECK_INTERVAL_IN_SECONDS));
+                    let addresses = current_addresses();
+                    if let Some(change) = NetworkChange::between(&known, &addresses) {
+                        info!("Network change: added {:?}, removed {:?}",
+                              change.added,
+                              change.removed);
+                        for listener in listeners.lock().unwrap().iter() {
+                            listener(&change);
+                        }
+                        known = addresses;
+                    }
+                }
+            })
+            .unwrap();
+    }
+}
+
+fn current_addresses() -> BTreeSet<Address> {
+    match get_if_addrs::get_if_addrs() {
+        Ok(ifaces) => addresses_from_ifaces(&ifaces),
+        Err(err) => {
+            warn!("Unable to list the network interfaces: {}", err);
+            BTreeSet::new()
+        }
+    }
+}
+
+/// The addresses of all non-loopback interfaces.
+fn addresses_from_ifaces(ifaces: &[Interface]) -> BTreeSet<Address> {
+    ifaces.iter()
+        .filter_map(|iface| {
+            let ip = match iface.addr {
+                IfAddr::V4(ref v4) if !v4.ip.is_loopback() => format!("{}", v4.ip),
+                IfAddr::V6(ref v6) if !v6.ip.is_loopback() => format!("{}", v6.ip),
+                _ => return None,
+            };
+            Some(Address {
+                iface: iface.name.clone(),
+                ip: ip,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+describe! network_monitor {
+
+    before_each {
+        use super::get_if_addrs::*;
+        use std::net::Ipv4Addr;
+
+        let iface = |name: &str, ip: Ipv4Addr| {
+            Interface {
+                name: name.to_owned(),
+                addr: IfAddr::V4(Ifv4Addr {
+                    ip: ip,
+                    netmask: Ipv4Addr::new(255,255,255,0),
+                    broadcast: None
+                })
+            }
+        };
+        let before = addresses_from_ifaces(&[iface("lo", Ipv4Addr::new(127,0,0,1)),
+                                             iface("eth0", Ipv4Addr::new(192,168,0,4))]);
+    }
+
+    it "should ignore loopback interfaces" {
+        assert_eq!(before.len(), 1);
+        assert_eq!(before.iter().next().unwrap().iface, "eth0");
+    }
+
+    it "should report no change when the addresses are the same" {
+        let after = addresses_from_ifaces(&[iface("eth0", Ipv4Addr::new(192,168,0,4))]);
+        assert_eq!(NetworkChange::between(&before, &after), None);
+    }
+
+    it "should report added and removed addresses" {
+        let after = addresses_from_ifaces(&[iface("lo", Ipv4Addr::new(127,0,0,1)),
+                                            iface("eth0", Ipv4Addr::new(192,168,0,27))]);
+        let change = NetworkChange::between(&before, &after).unwrap();
+        assert_eq!(change.added, vec![Address { iface: "eth0".to_owned(), ip: "192.168.0.27".to_owned() }]);
+        assert_eq!(change.removed, vec![Address { iface: "eth0".to_owned(), ip: "192.168.0.4".to_owned() }]);
+    }
+}
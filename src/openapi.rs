@@ -0,0 +1,423 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An OpenAPI 3 description of the REST API.
+//!
+//! GET /api/v1/openapi.json : returns the description of all the /api/v1 endpoints.
+//!
+//! The paths are generated from the endpoints returned by the `create()` function of
+//! each router, and the request bodies of the taxonomy endpoints from the `Parser`
+//! schemas of the selectors and payloads. Each endpoint must be described in
+//! `describe()`, which is checked by the tests.
+
+use foxbox_taxonomy::api::Targetted;
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::parse::{JSON, Parser, ToJSON};
+use foxbox_taxonomy::selector::{ChannelSelector, ChannelSelectorWithFeature, ServiceSelector};
+use foxbox_taxonomy::services::TagId;
+use foxbox_taxonomy::util::Id;
+use iron::{Handler, IronResult, Request, Response};
+use iron::headers::ContentType;
+use iron::method::Method;
+use iron::status::Status;
+use serde_json;
+use std::collections::BTreeMap;
+
+/// The version of the REST API, i.e. the prefix of its endpoints.
+pub const API_VERSION: &'static str = "v1";
+
+/// The description of an operation of the REST API.
+pub struct Operation {
+    summary: &'static str,
+    /// The schema of the json body of the request, if any.
+    body: Option<JSON>,
+}
+
+fn op(summary: &'static str) -> Option<Operation> {
+    Some(Operation {
+        summary: summary,
+        body: None,
+    })
+}
+
+fn op_with_body(summary: &'static str, body: JSON) -> Option<Operation> {
+    Some(Operation {
+        summary: summary,
+        body: Some(body),
+    })
+}
+
+/// The schema of an array of values parsed by `P`, also accepting a single value.
+fn array_of<T, P: Parser<T>>() -> JSON {
+    <Vec<P> as Parser<Vec<T>>>::schema()
+}
+
+fn object_of(properties: Vec<(&str, JSON)>) -> JSON {
+    let required: Vec<_> = properties.iter().map(|&(name, _)| name).collect();
+    vec![("type", "object".to_json()),
+         ("required", required.to_json()),
+         ("properties", properties.to_json())]
+        .to_json()
+}
+
+/// The body of the requests changing the tags of services or channels.
+fn tags_body(selectors: &str, selector_schema: JSON) -> JSON {
+    object_of(vec![(selectors, selector_schema), ("tags", array_of::<Id<TagId>, Id<TagId>>())])
+}
+
+/// The body of the requests naming services or channels, or placing services in rooms.
+fn name_body(selectors: &str, selector_schema: JSON, field: &str) -> JSON {
+    object_of(vec![(selectors, selector_schema), (field, Option::<String>::schema())])
+}
+
+/// Any json object, for the endpoints whose body is described in their summary.
+fn any_object() -> JSON {
+    vec![("type", "object")].to_json()
+}
+
+/// The schema of the error responses, see `api_error`.
+fn error_schema() -> JSON {
+    let string = || vec![("type", "string")].to_json();
+    let error = vec![("type", "object".to_json()),
+                     ("required", vec!["code", "message"].to_json()),
+                     ("properties",
+                      vec![("code", string()),
+                           ("message", string()),
+                           ("path", string()),
+                           ("channel", string())]
+                          .to_json())];
+    object_of(vec![("error", error.to_json())])
+}
+
+fn responses() -> JSON {
+    let error = vec![("$ref", "#/components/schemas/Error")];
+    vec![("2XX", vec![("description", "Success".to_json())]),
+         ("default",
+          vec![("description", "An error".to_json()),
+               ("content",
+                vec![("application/json", vec![("schema", error)])].to_json())])]
+        .to_json()
+}
+
+/// Describes an endpoint, given as a method and a path relative to api/v1.
+///
+/// Add a description here when adding an endpoint to a router.
+pub fn describe(method: &Method, path: &str) -> Option<Operation> {
+    match (method, path) {
+        // Taxonomy.
+        (&Method::Get, "services") => op("List all the services"),
+        (&Method::Post, "services") => {
+            op_with_body("List the services matching any of the selectors",
+                         array_of::<ServiceSelector, ServiceSelector>())
+        }
+        (&Method::Post, "services/tags") => {
+            op_with_body("Add tags to the services matching the selectors",
+                         tags_body("services", array_of::<ServiceSelector, ServiceSelector>()))
+        }
+        (&Method::Delete, "services/tags") => {
+            op_with_body("Remove tags from the services matching the selectors",
+                         tags_body("services", array_of::<ServiceSelector, ServiceSelector>()))
+        }
+        (&Method::Put, "services/name") => {
+            op_with_body("Name the services matching the selectors, a null name removes it",
+                         name_body("services",
+                                   array_of::<ServiceSelector, ServiceSelector>(),
+                                   "name"))
+        }
+        (&Method::Put, "services/room") => {
+            op_with_body("Place the services matching the selectors in a room, a null room \
+                          removes it",
+                         name_body("services",
+                                   array_of::<ServiceSelector, ServiceSelector>(),
+                                   "room"))
+        }
+        (&Method::Get, "channels") => op("List all the channels"),
+        (&Method::Post, "channels") => {
+            op_with_body("List the channels matching any of the selectors",
+                         array_of::<ChannelSelector, ChannelSelector>())
+        }
+        (&Method::Put, "channels/get") => {
+            op_with_body("Fetch the values of the channels matching the selectors",
+                         array_of::<ChannelSelector, ChannelSelectorWithFeature>())
+        }
+        (&Method::Put, "channels/set") => {
+            op_with_body("Send values to the channels matching the selectors",
+                         array_of::<Targetted<ChannelSelector, Payload>,
+                                    Targetted<ChannelSelectorWithFeature, Payload>>())
+        }
+        (&Method::Post, "channels/tags") => {
+            op_with_body("Add tags to the channels matching the selectors",
+                         tags_body("channels", array_of::<ChannelSelector, ChannelSelector>()))
+        }
+        (&Method::Delete, "channels/tags") => {
+            op_with_body("Remove tags from the channels matching the selectors",
+                         tags_body("channels", array_of::<ChannelSelector, ChannelSelector>()))
+        }
+        (&Method::Put, "channels/name") => {
+            op_with_body("Name the channels matching the selectors, a null name removes it",
+                         name_body("channels",
+                                   array_of::<ChannelSelector, ChannelSelector>(),
+                                   "name"))
+        }
+        (&Method::Get, "channel/:id") => op("Fetch the value of a channel"),
+        (&Method::Put, "channel/:id") => {
+            op_with_body("Send the body, json or binary, to a channel", Payload::schema())
+        }
+        (&Method::Get, "formats") => op("Describe the formats of the values of the channels"),
+
+        // Certificates.
+        (&Method::Get, "certificates") => op("List the installed certificates (admin)"),
+        (&Method::Put, "certificates/:hostname") => {
+            op_with_body("Install a certificate, from an object {certificate, private_key, \
+                          chain} of PEM strings where the chain is optional (admin)",
+                         any_object())
+        }
+        (&Method::Delete, "certificates/:hostname") => op("Remove a certificate (admin)"),
+
+        // Configuration.
+        (&Method::Get, "config") => op("List the settings, with secrets masked (admin)"),
+        (&Method::Put, "config") => {
+            op_with_body("Update settings, from an object {namespace: {name: value}} (admin)",
+                         any_object())
+        }
+
+        // Backups.
+        (&Method::Post, "backup") => {
+            op_with_body("Return a backup archive of the profile, encrypted when the body \
+                          is an object {passphrase} (admin)",
+                         any_object())
+        }
+        (&Method::Post, "backup/restore") => {
+            op("Restore the archive sent as the body, decrypted with the passphrase of the \
+                X-Backup-Passphrase header (admin)")
+        }
+
+        // Scenes.
+        (&Method::Get, "scenes") => op("List the scenes"),
+        (&Method::Post, "scenes") => {
+            op_with_body("Add a scene, from an object {name, selectors} capturing the current \
+                          values of the channels or {name, values}",
+                         any_object())
+        }
+        (&Method::Get, "scenes/:id") => op("Return a scene"),
+        (&Method::Put, "scenes/:id") => {
+            op_with_body("Replace the name and values of a scene", any_object())
+        }
+        (&Method::Delete, "scenes/:id") => op("Remove a scene"),
+        (&Method::Post, "scenes/:id/activate") => op("Send the values of a scene"),
+
+        // Virtual devices.
+        (&Method::Get, "virtual") => op("List the virtual devices"),
+        (&Method::Post, "virtual") => {
+            op_with_body("Add a virtual device, from an object {name, kind, value} where the \
+                          value is optional",
+                         any_object())
+        }
+        (&Method::Get, "virtual/:id") => op("Return a virtual device"),
+        (&Method::Put, "virtual/:id") => {
+            op_with_body("Rename a virtual device and/or change its value", any_object())
+        }
+        (&Method::Delete, "virtual/:id") => op("Remove a virtual device"),
+
+        // Groups.
+        (&Method::Get, "groups") => op("List the groups"),
+        (&Method::Post, "groups") => {
+            op_with_body("Add a group, from an object {name, selectors, feature, aggregate}",
+                         any_object())
+        }
+        (&Method::Get, "groups/:id") => op("Return a group, with the channels of its members"),
+        (&Method::Put, "groups/:id") => {
+            op_with_body("Replace the definition of a group", any_object())
+        }
+        (&Method::Delete, "groups/:id") => op("Remove a group"),
+
+        // Audit log.
+        (&Method::Get, "audit") => {
+            op("Query the audit log, filtered by the user, action, target, since, until and \
+                limit query parameters (admin)")
+        }
+
+        // Event stream.
+        (&Method::Get, "channels/watch") => {
+            op("Stream the events of the channels as text/event-stream, filtered by the watch \
+                query parameter, a json array of {select, range}")
+        }
+
+        // Webhooks.
+        (&Method::Get, "webhooks") => op("List the webhooks (admin)"),
+        (&Method::Post, "webhooks") => {
+            op_with_body("Add a webhook, from an object {name, url, watch, secret} where watch \
+                          and secret are optional (admin)",
+                         any_object())
+        }
+        (&Method::Get, "webhooks/:id") => op("Return a webhook, without its secret (admin)"),
+        (&Method::Put, "webhooks/:id") => {
+            op_with_body("Change the name, url, watch, secret or enabled fields of a webhook \
+                          (admin)",
+                         any_object())
+        }
+        (&Method::Delete, "webhooks/:id") => op("Remove a webhook (admin)"),
+        (&Method::Get, "webhooks/:id/deliveries") => {
+            op("Return the recent attempts to deliver events to a webhook (admin)")
+        }
+        (&Method::Get, "hooks") => op("List the incoming webhooks, with their tokens (admin)"),
+        (&Method::Post, "hooks") => {
+            op_with_body("Add an incoming webhook with a random token, from an object {name} \
+                          (admin)",
+                         any_object())
+        }
+        (&Method::Post, "hooks/:token") => {
+            op("Trigger the webhook/trigger channel of an incoming webhook with any json \
+                body, authenticated by the token instead of a session")
+        }
+        (&Method::Delete, "hooks/:token") => op("Revoke an incoming webhook (admin)"),
+
+        // This document.
+        (&Method::Get, "openapi.json") => op("Describe the REST API"),
+
+        _ => None,
+    }
+}
+
+/// Lists the endpoints that are not described, as "METHOD path".
+pub fn undescribed(endpoints: &[(Vec<Method>, String)]) -> Vec<String> {
+    let mut result = vec![];
+    for &(ref methods, ref path) in endpoints {
+        for method in methods {
+            if describe(method, path).is_none() {
+                result.push(format!("{} {}", method, path));
+            }
+        }
+    }
+    result
+}
+
+/// Turns a router path like `scenes/:id` into an OpenAPI path like `/scenes/{id}`,
+/// along with its parameters.
+fn openapi_path(path: &str) -> (String, Vec<&str>) {
+    let mut params = vec![];
+    let segments: Vec<_> = path.split('/')
+        .map(|segment| if segment.starts_with(':') {
+            params.push(&segment[1..]);
+            format!("{{{}}}", &segment[1..])
+        } else {
+            segment.to_owned()
+        })
+        .collect();
+    (format!("/{}", segments.join("/")), params)
+}
+
+/// Builds the OpenAPI document describing `endpoints`, relative to api/v1.
+pub fn document(endpoints: &[(Vec<Method>, String)]) -> JSON {
+    let mut paths = BTreeMap::new();
+    for &(ref methods, ref path) in endpoints {
+        let (openapi_path, params) = openapi_path(path);
+        let parameters: Vec<_> = params.iter()
+            .map(|name| {
+                vec![("name", name.to_json()),
+                     ("in", "path".to_json()),
+                     ("required", true.to_json()),
+                     ("schema", String::schema())]
+                    .to_json()
+            })
+            .collect();
+        let mut operations = BTreeMap::new();
+        for method in methods {
+            let operation = match describe(method, path) {
+                Some(operation) => operation,
+                None => {
+                    warn!("No description of {} {}", method, path);
+                    continue;
+                }
+            };
+            let mut fields = vec![("summary", operation.summary.to_json()),
+                                  ("parameters", parameters.to_json()),
+                                  ("responses", responses())];
+            if let Some(body) = operation.body {
+                let content = vec![("application/json", vec![("schema", body)])];
+                fields.push(("requestBody", vec![("content", content)].to_json()));
+            }
+            operations.insert(method.as_ref().to_lowercase(), fields.to_json());
+        }
+        paths.insert(openapi_path, JSON::Object(operations.into_iter().collect()));
+    }
+
+    // The selectors are recursive, so their schemas are referenced by name.
+    let mut schemas = BTreeMap::new();
+    schemas.insert(ServiceSelector::description(), ServiceSelector::schema());
+    schemas.insert(ChannelSelector::description(), ChannelSelector::schema());
+    schemas.insert("Error".to_owned(), error_schema());
+
+    vec![("openapi", "3.0.0".to_json()),
+         ("info", vec![("title", "FoxBox"), ("version", API_VERSION)].to_json()),
+         ("servers", vec![vec![("url", format!("/api/{}", API_VERSION))]].to_json()),
+         ("paths", JSON::Object(paths.into_iter().collect())),
+         ("components", vec![("schemas", JSON::Object(schemas.into_iter().collect()))].to_json())]
+        .to_json()
+}
+
+/// Serves the OpenAPI document, which is built once since the routes don't change.
+pub struct OpenApi {
+    document: String,
+}
+
+impl OpenApi {
+    pub fn new(endpoints: &[(Vec<Method>, String)]) -> Self {
+        OpenApi { document: serde_json::to_string(&document(endpoints)).unwrap() }
+    }
+}
+
+impl Handler for OpenApi {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let mut response = Response::with((Status::Ok, self.document.clone()));
+        response.headers.set(ContentType::json());
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+describe! openapi {
+    before_each {
+        use foxbox_taxonomy::manager::AdapterManager;
+        use http_server::api_routers;
+        use iron::method::Method;
+        use std::sync::Arc;
+        use stubs::controller::ControllerStub;
+
+        let taxo_manager = Arc::new(AdapterManager::new(None));
+        let mut endpoints: Vec<(Vec<Method>, String)> = vec![];
+        for (_, (_, mut router_endpoints)) in api_routers(&ControllerStub::new(),
+                                                            &taxo_manager) {
+            endpoints.append(&mut router_endpoints);
+        }
+    }
+
+    it "should describe all the endpoints" {
+        assert_eq!(undescribed(&endpoints), Vec::<String>::new());
+    }
+
+    it "should report undescribed endpoints" {
+        endpoints.push((vec![Method::Get, Method::Patch], "services".to_owned()));
+        assert_eq!(undescribed(&endpoints), vec!["PATCH services".to_owned()]);
+    }
+
+    it "should generate paths with parameters" {
+        let document = document(&endpoints);
+        let operation = document.find_path(&["paths", "/scenes/{id}", "delete"]).unwrap();
+        assert_eq!(operation.find("summary").unwrap().as_str(), Some("Remove a scene"));
+        let parameter = &operation.find("parameters").unwrap().as_array().unwrap()[0];
+        assert_eq!(parameter.find("name").unwrap().as_str(), Some("id"));
+    }
+
+    it "should describe the request bodies with the parser schemas" {
+        let document = document(&endpoints);
+        let body = document.find_path(&["paths", "/channels/set", "put", "requestBody",
+                                         "content", "application/json", "schema"])
+            .unwrap();
+        assert!(body.to_string().contains("#/components/schemas/ChannelSelector"));
+        assert!(document.find_path(&["components", "schemas", "ServiceSelector", "properties",
+                                     "channels"])
+            .is_some());
+    }
+}
@@ -4,7 +4,8 @@
 
 /// This manages registration of the foxbox with the discovery endpoint.
 /// For now it simply register itselfs every N minutes with the endpoint,
-/// after trying more aggressively at first run.
+/// after trying more aggressively at first run. It also registers again
+/// as soon as the network monitor notices that the ip address changed.
 
 extern crate get_if_addrs;
 extern crate hyper;
@@ -14,8 +15,10 @@ use self::hyper::header::Connection;
 use self::hyper::status::StatusCode;
 use self::get_if_addrs::{IfAddr, Interface};
 use foxbox_core::traits::Controller;
+use network_monitor::NetworkMonitor;
 use serde_json;
 use std::io::Read;
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::time::Duration;
 use std::thread;
 use tls::{CertificateManager, DnsRecord, get_san_cert_for, register_dns_record};
@@ -23,6 +26,10 @@ use tunnel_controller::Tunnel;
 
 const REGISTRATION_INTERVAL_IN_MINUTES: u32 = 1;
 
+// How long to wait before looking for an ip address again when we have none,
+// e.g. because we are racing with the network configuration at boot.
+const NO_IP_RETRY_INTERVAL_IN_SECONDS: u64 = 10;
+
 pub struct Registrar {
     certificate_manager: CertificateManager,
     registration_endpoint: String,
@@ -159,19 +166,11 @@ impl Registrar {
                                 iface: Option<String>,
                                 tunnel: &Option<Tunnel>,
                                 box_port: u16,
-                                controller: &T) {
+                                controller: &T,
+                                network_monitor: &NetworkMonitor) {
         info!("registration server: Starting registration with {}",
                 self.registration_endpoint);
 
-        let ip_addr = self.get_ip_addr(&iface);
-        if ip_addr == None {
-            // TODO: retry later, in case we're racing with the network
-            // configuration. https://github.com/fxbox/foxbox/issues/347
-            return;
-        }
-
-        info!("Got ip address: {}", ip_addr.clone().unwrap());
-
         let tunnel_frontend = if let Some(ref tunnel) = *tunnel {
             tunnel.get_frontend_name()
         } else {
@@ -181,6 +180,12 @@ impl Registrar {
 
         let http_scheme = if enabled_tls { "https" } else { "http" };
 
+        // Wake up the registration thread whenever the network changes.
+        let (tx, rx) = channel();
+        network_monitor.add_listener(move |_| {
+            let _ = tx.send(());
+        });
+
         // Spawn a thread to register every REGISTRATION_INTERVAL_IN_MINUTES.
         thread::Builder::new()
             .name("Registrar".to_owned())
@@ -191,19 +196,32 @@ impl Registrar {
                     self.register_certificates();
                 }
 
+                let mut registered_ip_addr: Option<String> = None;
                 loop {
-                    // TODO: If the ip address changes, we need to update the dns server and
-                    // registration server with the new IP address.
-                    // https://github.com/fxbox/foxbox/issues/348
-                    self.register_with_registration_server(ip_addr.clone().unwrap(),
-                                                           http_scheme,
-                                                           box_port,
-                                                           tunnel_configured);
-                    self.register_with_dns_server(ip_addr.clone().unwrap(),
-                                                  tunnel_frontend.clone());
-
-                    // Go to sleep.
-                    thread::sleep(Duration::from_secs(REGISTRATION_INTERVAL_IN_MINUTES as u64 * 60))
+                    let ip_addr = self.get_ip_addr(&iface);
+                    let sleep_duration = if let Some(ip_addr) = ip_addr {
+                        if registered_ip_addr.as_ref() != Some(&ip_addr) {
+                            info!("Got ip address: {}", ip_addr);
+                        }
+                        self.register_with_registration_server(ip_addr.clone(),
+                                                               http_scheme,
+                                                               box_port,
+                                                               tunnel_configured);
+                        self.register_with_dns_server(ip_addr.clone(), tunnel_frontend.clone());
+                        registered_ip_addr = Some(ip_addr);
+                        Duration::from_secs(REGISTRATION_INTERVAL_IN_MINUTES as u64 * 60)
+                    } else {
+                        warn!("registration server: No ip address yet, retrying in {}s",
+                              NO_IP_RETRY_INTERVAL_IN_SECONDS);
+                        Duration::from_secs(NO_IP_RETRY_INTERVAL_IN_SECONDS)
+                    };
+
+                    // Go to sleep, until the next registration or a network change.
+                    match rx.recv_timeout(sleep_duration) {
+                        Ok(()) => info!("registration server: Network changed, registering again"),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => thread::sleep(sleep_duration),
+                    }
                 }
             })
             .unwrap();
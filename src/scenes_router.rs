@@ -0,0 +1,296 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Endpoints to manage the scenes.
+//!
+//! GET    /api/v1/scenes               : lists the scenes.
+//! POST   /api/v1/scenes               : adds a scene. The json body is either
+//!                                      {"name": "Movie night",
+//!                                       "selectors": [{"feature": "light/is-on"}]}
+//!                                      to capture the current values of the matching
+//!                                      channels, or {"name": "...", "values": [...]} to
+//!                                      give the values explicitly.
+//! GET    /api/v1/scenes/<id>          : returns a scene, e.g.
+//!                                      {"id": "movie-night", "name": "Movie night",
+//!                                       "values": [{"channel": "...", "value": "Off"}]}
+//! PUT    /api/v1/scenes/<id>          : replaces the name and values of a scene.
+//! DELETE /api/v1/scenes/<id>          : removes a scene.
+//! POST   /api/v1/scenes/<id>/activate : sends the values of a scene.
+
+use adapters::scenes::{Scene, SceneError, SceneValue, Scenes};
+use api_error::{self, json_response, read_json};
+use auth;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::parse::{Parser, Path};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_users::AuthEndpoint;
+use iron::{Handler, IronResult, Request, Response};
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use serde_json;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+struct SceneEdit {
+    name: String,
+    values: Vec<SceneValue>,
+}
+
+pub struct ScenesRouter {
+    scenes: Scenes,
+}
+
+fn error_response(err: &SceneError) -> IronResult<Response> {
+    let status = match *err {
+        SceneError::NoSuchScene(_) => Status::NotFound,
+        SceneError::InvalidScene(_) => Status::BadRequest,
+        SceneError::Activation(_) => Status::BadGateway,
+        SceneError::Database(_) |
+        SceneError::Api(_) => Status::InternalServerError,
+    };
+    Ok(api_error::response(status, err.to_string()))
+}
+
+impl ScenesRouter {
+    pub fn new(scenes: Scenes) -> Self {
+        ScenesRouter { scenes: scenes }
+    }
+
+    fn add(&self, req: &mut Request) -> IronResult<Response> {
+        let user = match auth::user_from_request(req) {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        let json = match read_json::<serde_json::Value>(req, "scene") {
+            Ok(json) => json,
+            Err(response) => return Ok(response),
+        };
+
+        let selectors = json.find("selectors").cloned();
+        let result = match selectors {
+            Some(selectors) => {
+                let name = match json.find("name").and_then(|name| name.as_str()) {
+                    Some(name) => name.to_owned(),
+                    None => return Ok(api_error::response(Status::BadRequest, "Missing name")),
+                };
+                let selectors = match Vec::<ChannelSelector>::parse(Path::new(), &selectors) {
+                    Ok(selectors) => selectors,
+                    Err(err) => return Ok(api_error::from_parse_error(&err)),
+                };
+                self.scenes.capture(&name, selectors, &user)
+            }
+            None => {
+                let edit: SceneEdit = match serde_json::from_value(json) {
+                    Ok(edit) => edit,
+                    Err(err) => {
+                        return Ok(api_error::response(Status::BadRequest,
+                                                      format!("Invalid scene: {}", err)))
+                    }
+                };
+                self.scenes.create(&edit.name, edit.values)
+            }
+        };
+        match result {
+            Ok(scene) => json_response(Status::Created, &scene),
+            Err(err) => error_response(&err),
+        }
+    }
+
+    fn update(&self, id: &str, req: &mut Request) -> IronResult<Response> {
+        let json = match read_json::<serde_json::Value>(req, "scene") {
+            Ok(json) => json,
+            Err(response) => return Ok(response),
+        };
+        let edit: SceneEdit = match serde_json::from_value(json) {
+            Ok(edit) => edit,
+            Err(err) => {
+                return Ok(api_error::response(Status::BadRequest,
+                                              format!("Invalid scene: {}", err)))
+            }
+        };
+        let scene = Scene {
+            id: id.to_owned(),
+            name: edit.name,
+            values: edit.values,
+        };
+        match self.scenes.update(&scene) {
+            Ok(()) => json_response(Status::Ok, &scene),
+            Err(err) => error_response(&err),
+        }
+    }
+
+    fn activate(&self, id: &str, req: &mut Request) -> IronResult<Response> {
+        let user = match auth::user_from_request(req) {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        match self.scenes.activate(id, &user) {
+            Ok(()) => Ok(Response::with(Status::NoContent)),
+            Err(err) => error_response(&err),
+        }
+    }
+}
+
+impl Handler for ScenesRouter {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path: Vec<String> = req.url
+            .path()
+            .iter()
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| (*segment).to_owned())
+            .collect();
+
+        match (req.method.clone(), path.len()) {
+            (Method::Get, 0) => {
+                match self.scenes.list() {
+                    Ok(scenes) => json_response(Status::Ok, &scenes),
+                    Err(err) => error_response(&err),
+                }
+            }
+            (Method::Post, 0) => self.add(req),
+            (Method::Get, 1) => {
+                match self.scenes.get(&path[0]) {
+                    Ok(scene) => json_response(Status::Ok, &scene),
+                    Err(err) => error_response(&err),
+                }
+            }
+            (Method::Put, 1) => self.update(&path[0], req),
+            (Method::Delete, 1) => {
+                match self.scenes.remove(&path[0]) {
+                    Ok(()) => Ok(Response::with(Status::NoContent)),
+                    Err(err) => error_response(&err),
+                }
+            }
+            (Method::Post, 2) if path[1] == "activate" => self.activate(&path[0], req),
+            (_, 0) | (_, 1) => {
+                Ok(api_error::response(Status::MethodNotAllowed,
+                                       format!("Bad method: {}", req.method)))
+            }
+            _ => Ok(api_error::response(Status::NotFound, format!("Unknown url: {}", req.url))),
+        }
+    }
+}
+
+pub fn create<T>(controller: T,
+                 adapter_api: &Arc<AdapterManager>)
+                 -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    // Keep these in sync with the handle() method. They are relative to api/v1.
+    let endpoints = vec![
+        (vec![Method::Get, Method::Post], "scenes".to_owned()),
+        (vec![Method::Get, Method::Put, Method::Delete], "scenes/:id".to_owned()),
+        (vec![Method::Post], "scenes/:id/activate".to_owned()),
+    ];
+
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        endpoints.iter().map(|item| AuthEndpoint(item.0.clone(), item.1.clone())).collect()
+    } else {
+        vec![]
+    };
+
+    let db_path = controller.get_profile().path_for("scenes.sqlite");
+    let mut chain = Chain::new(ScenesRouter::new(Scenes::new(adapter_api, &db_path)));
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! scenes_router {
+    before_each {
+        use adapters::clock;
+        use adapters::scenes::SceneAdapter;
+        use foxbox_taxonomy::manager::AdapterManager;
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{request, response};
+        use mount::Mount;
+        use std::sync::Arc;
+        use stubs::controller::ControllerStub;
+
+        let controller = ControllerStub::new();
+        let taxo_manager = Arc::new(AdapterManager::new(None));
+        clock::Clock::init(controller.clone(), &taxo_manager).unwrap();
+        SceneAdapter::init(controller.clone(), &taxo_manager).unwrap();
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1/scenes", create(controller.clone(), &taxo_manager).0);
+
+        let body = r#"{"name": "Good night",
+                       "values": [{"channel": "setter:unknown@link.mozilla.org", "value": "Off"}]}"#;
+        let response = request::post("http://localhost:3000/api/v1/scenes",
+                                     Headers::new(),
+                                     body,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Created));
+    }
+
+    it "should list the scenes" {
+        let response = request::get("http://localhost:3000/api/v1/scenes",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert_eq!(body,
+                   r#"[{"id":"good-night","name":"Good night","values":[{"channel":"setter:unknown@link.mozilla.org","value":"Off"}]}]"#);
+    }
+
+    it "should edit scenes" {
+        let response = request::put("http://localhost:3000/api/v1/scenes/good-night",
+                                    Headers::new(),
+                                    r#"{"name": "Sleep", "values": []}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+
+        let response = request::get("http://localhost:3000/api/v1/scenes/good-night",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert_eq!(body, r#"{"id":"good-night","name":"Sleep","values":[]}"#);
+
+        let response = request::put("http://localhost:3000/api/v1/scenes/nope",
+                                    Headers::new(),
+                                    r#"{"name": "Sleep", "values": []}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+    }
+
+    it "should reject invalid scenes" {
+        let response = request::post("http://localhost:3000/api/v1/scenes",
+                                     Headers::new(),
+                                     r#"{"name": "", "values": []}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+
+        // The clock channels can't be sent to, so there is nothing to capture.
+        let response = request::post("http://localhost:3000/api/v1/scenes",
+                                     Headers::new(),
+                                     r#"{"name": "Clock", "selectors": [{}]}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+    }
+
+    it "should activate scenes" {
+        let response = request::post("http://localhost:3000/api/v1/scenes/good-night/activate",
+                                     Headers::new(),
+                                     "",
+                                     &mount).unwrap();
+        // Unknown channels are skipped.
+        assert_eq!(response.status, Some(Status::NoContent));
+    }
+
+    it "should remove scenes" {
+        let response = request::delete("http://localhost:3000/api/v1/scenes/good-night",
+                                       Headers::new(),
+                                       &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NoContent));
+
+        let response = request::get("http://localhost:3000/api/v1/scenes/good-night",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+    }
+}
@@ -6,6 +6,7 @@ extern crate rand;
 
 use foxbox_core::config_store::ConfigService;
 use foxbox_core::profile_service::{ProfilePath, ProfileService};
+use foxbox_core::rate_limit::RateLimiter;
 use foxbox_core::traits::Controller;
 use foxbox_core::upnp::UpnpManager;
 use foxbox_users::UsersManager;
@@ -17,7 +18,7 @@ use std::net::ToSocketAddrs;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
-use tls::{CertificateManager, CertificateRecord, SniSslContextProvider};
+use tls::{CertificateManager, CertificateRecord, SniSslContextProvider, TlsOption};
 use ws;
 
 #[derive(Clone)]
@@ -68,12 +69,18 @@ impl Controller for ControllerStub {
     fn get_users_manager(&self) -> Arc<UsersManager> {
         Arc::new(UsersManager::new(&self.profile_service.path_for("unused")))
     }
+    fn get_rate_limiter(&self) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(self.config.clone()))
+    }
     fn get_profile(&self) -> &ProfileService {
         &self.profile_service
     }
     fn get_tls_enabled(&self) -> bool {
         false
     }
+    fn get_tls_option(&self) -> TlsOption {
+        TlsOption::Disabled
+    }
 
     fn get_hostname(&self) -> String {
         String::from("localhost")
@@ -90,6 +97,10 @@ impl Controller for ControllerStub {
                                                "abcdef".to_owned())
     }
 
+    fn get_served_certificate(&self) -> Option<CertificateRecord> {
+        None
+    }
+
     fn get_certificate_manager(&self) -> CertificateManager {
         CertificateManager::new(PathBuf::from(current_dir!()),
                                 "knilxof.org",
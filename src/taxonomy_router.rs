@@ -7,18 +7,22 @@ extern crate serde_json;
 use foxbox_core::traits::Controller;
 use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::api::{API, Error, TargetMap, Targetted, User};
+use foxbox_taxonomy::audit::AuditEntry;
 use foxbox_taxonomy::channel::*;
 use foxbox_taxonomy::io::*;
 use foxbox_taxonomy::values::{format, Binary, Json, Value};
 use foxbox_taxonomy::selector::*;
 use foxbox_taxonomy::services::*;
 use foxbox_taxonomy::util::MimeTypeId;
+use foxbox_taxonomy::value_cache::CachedValue;
 
 use foxbox_users::AuthEndpoint;
-use foxbox_users::SessionToken;
+
+use api_error;
+use auth;
 
 use iron::{Handler, headers, IronResult, Request, Response};
-use iron::headers::ContentType;
+use iron::headers::{ContentType, HttpDate, LastModified};
 use iron::method::Method;
 use iron::prelude::Chain;
 use iron::request::Body;
@@ -26,6 +30,9 @@ use iron::status::Status;
 
 use std::io::{Error as IOError, Read};
 use std::sync::Arc;
+use std::time::Duration;
+
+use time;
 
 /// This is a specialized Router for the taxonomy API.
 /// It handles all the calls under the api/v1/ url space.
@@ -34,6 +41,7 @@ pub struct TaxonomyRouter {
 }
 
 type GetterResultMap = ResultMap<Id<Channel>, Option<(Payload, Arc<Format>)>, Error>;
+type CachedResultMap = ResultMap<Id<Channel>, Option<CachedValue>, Error>;
 
 impl TaxonomyRouter {
     pub fn new(adapter_api: &Arc<AdapterManager>) -> Self {
@@ -63,10 +71,7 @@ impl TaxonomyRouter {
     }
 
     fn build_parse_error(&self, obj: &ParseError) -> IronResult<Response> {
-        let mut response = Response::with(itry!(serde_json::to_string(obj)));
-        response.status = Some(Status::BadRequest);
-        response.headers.set(ContentType::plaintext()); // FIXME: Should be JSON
-        Ok(response)
+        Ok(api_error::from_parse_error(obj))
     }
 
     fn read_body_to_string<'a, 'b: 'a>(body: &mut Body<'a, 'b>) -> Result<String, IOError> {
@@ -85,63 +90,139 @@ impl TaxonomyRouter {
 
         for map_value in map.values() {
             if let Ok(Some((ref payload, _))) = *map_value {
-                if let Ok(ref data) = payload.to_value(&format::BINARY) {
-                    match data.downcast::<Binary>() {
-                        Some(data) => {
-                            return Some(Binary {
-                                mimetype: (*data).mimetype.clone(),
-                                data: (*data).data.clone(),
-                            });
-                        }
-                        None => {
-                            warn!("get_binary could not convert data labelled as format::BINARY \
-                                   to Binary {}",
-                                  data.description());
-                        }
-                    }
-                }
-                // It's not a binary, proceed as usual.
+                return self.payload_to_binary(payload);
+            }
+        }
+
+        None
+    }
+
+    // Same as get_binary(), for cached values.
+    fn get_cached_binary(&self, map: &CachedResultMap) -> Option<(Binary, CachedValue)> {
+        if map.len() != 1 {
+            return None;
+        }
+
+        for map_value in map.values() {
+            if let Ok(Some(ref value)) = *map_value {
+                return self.payload_to_binary(&value.payload).map(|binary| (binary, value.clone()));
             }
         }
 
         None
     }
+
+    fn payload_to_binary(&self, payload: &Payload) -> Option<Binary> {
+        if let Ok(ref data) = payload.to_value(&format::BINARY) {
+            match data.downcast::<Binary>() {
+                Some(data) => {
+                    return Some(Binary {
+                        mimetype: (*data).mimetype.clone(),
+                        data: (*data).data.clone(),
+                    });
+                }
+                None => {
+                    warn!("get_binary could not convert data labelled as format::BINARY to \
+                           Binary {}",
+                          data.description());
+                }
+            }
+        }
+        // It's not a binary, proceed as usual.
+        None
+    }
+
+    // Reads the optional max_age query parameter, in seconds.
+    fn get_max_age(&self, req: &Request) -> Result<Option<Duration>, Response> {
+        let query = match req.url.query() {
+            Some(query) => query,
+            None => return Ok(None),
+        };
+        for pair in query.split('&') {
+            let mut parts = pair.splitn(2, '=');
+            if parts.next() != Some("max_age") {
+                continue;
+            }
+            return match parts.next().unwrap_or("").parse::<u64>() {
+                Ok(seconds) => Ok(Some(Duration::from_secs(seconds))),
+                Err(_) => {
+                    Err(api_error::response(Status::BadRequest,
+                                            format!("Invalid max_age: {}", pair)))
+                }
+            };
+        }
+        Ok(None)
+    }
+
+    // Responds with the values of `selectors` that are at most `max_age` old,
+    // along with the time they were last updated.
+    fn cached_response(&self,
+                       selectors: Vec<ChannelSelector>,
+                       user: User,
+                       max_age: Duration)
+                       -> IronResult<Response> {
+        let res = self.api.fetch_values_cached(selectors, user, Some(max_age));
+        if let Some((payload, value)) = self.get_cached_binary(&res) {
+            let mut response = try!(self.build_binary_response(&payload));
+            let last_updated = time::at_utc(time::Timespec::new(value.last_updated.timestamp(),
+                                                                0));
+            response.headers.set(LastModified(HttpDate(last_updated)));
+            Ok(response)
+        } else {
+            self.build_response(&res)
+        }
+    }
 }
 
 impl Handler for TaxonomyRouter {
     #[allow(cyclomatic_complexity)]
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        let user: User =
-            match req.headers.clone().get::<headers::Authorization<headers::Bearer>>() {
-                Some(&headers::Authorization(headers::Bearer { ref token })) => {
-                    match SessionToken::from_string(token) {
-                        Ok(token) => User::Id(token.claims.id),
-                        Err(_) => return Ok(Response::with(Status::Unauthorized)),
-                    }
-                }
-                _ => User::None,
-            };
+        let user = match auth::user_from_request(req) {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
 
         // We are handling urls relative to the mounter set up in http_server.rs
         // That means that for a full url like http://localhost/api/v1/services
         // the req.url.path will only contain ["services"]
         let path = req.url.path();
 
+        let max_age = match self.get_max_age(req) {
+            Ok(max_age) => max_age,
+            Err(response) => return Ok(response),
+        };
+
         macro_rules! simple_response {
             ($api:ident, $arg:ident, $call:ident) => (self.build_response(&$api.$call($arg, user)))
         }
 
+        // With a max_age, fetches go through the cache of last known values.
         macro_rules! binary_response {
             ($api:ident, $arg:ident, $call:ident) => ({
-                        let res = $api.$call($arg, user);
-                        if let Some(payload) = self.get_binary(&res) {
-                            self.build_binary_response(&payload)
+                        if let Some(max_age) = max_age {
+                            self.cached_response($arg, user, max_age)
                         } else {
-                            self.build_response(&res)
+                            let res = $api.$call($arg, user);
+                            if let Some(payload) = self.get_binary(&res) {
+                                self.build_binary_response(&payload)
+                            } else {
+                                self.build_response(&res)
+                            }
                         }
                     })
         }
 
+        // Special case for GET formats
+        // This describes the standard formats used by channels, so that clients can
+        // build controls for them.
+        if req.method == Method::Get && path.len() == 1 && path[0] == "formats" {
+            let formats: Vec<_> = format::all()
+                .iter()
+                .map(|&(name, ref format)| (name, format.to_json()))
+                .collect();
+            return self.build_response(formats);
+        }
+
         // Special case for GET channel/:id
         // This will fetch the values for a ChannelSelector using the id.
         if req.method == Method::Get && path.len() == 2 && path[0] == "channel" {
@@ -213,8 +294,8 @@ impl Handler for TaxonomyRouter {
                                 Err(err) => self.build_parse_error(&err)
                             }
                         },
-                        _ => Ok(Response::with((Status::MethodNotAllowed,
-                                                format!("Bad method: {}", req.method))))
+                        _ => Ok(api_error::response(Status::MethodNotAllowed,
+                                                    format!("Bad method: {}", req.method)))
                     }
                 }
             })
@@ -242,7 +323,9 @@ impl Handler for TaxonomyRouter {
         }
 
         // Generates the code to process a given HTTP call with a json body.
-        // This version takes 2 parameters for the internal call.
+        // This version takes 2 parameters for the internal call, and records
+        // the call and the number of services or channels it matched in the
+        // audit log since all of these change the taxonomy.
         macro_rules! payload_api2 {
             ($call:ident, $name1:ident => $param1:ty, $name2:ident => $param2:ty, $path:expr, $method:expr) => (
                 if path == $path && req.method == $method {
@@ -264,7 +347,14 @@ impl Handler for TaxonomyRouter {
                             Err(err) => return self.build_parse_error(&err),
                             Ok(val) => val
                         };
-                        self.build_response(&self.api.$call(arg_1, arg_2))
+                        let result = self.api.$call(arg_1, arg_2);
+                        let mut entry = AuditEntry::new(&user, stringify!($call))
+                            .with_payload(None, json_value!({ request: json, matched: result }));
+                        if result == 0 {
+                            entry = entry.with_error("No matching service or channel".to_owned());
+                        }
+                        self.api.get_audit_log().record(entry);
+                        self.build_response(&result)
                     }
                 }
             )
@@ -302,8 +392,23 @@ impl Handler for TaxonomyRouter {
                        tags => Vec<Id<TagId>>,
                        ["channels", "tags"], Method::Delete);
 
+        // Naming services and channels and placing services in rooms.
+        // A `null` name or room removes it.
+        payload_api2!(set_service_name,
+                      services => Vec<ServiceSelector>,
+                      name => Option<String>,
+                      ["services", "name"], Method::Put);
+        payload_api2!(set_service_room,
+                      services => Vec<ServiceSelector>,
+                      room => Option<String>,
+                      ["services", "room"], Method::Put);
+        payload_api2!(set_channel_name,
+                      channels => Vec<ChannelSelector>,
+                      name => Option<String>,
+                      ["channels", "name"], Method::Put);
+
         // Fallthrough, returning a 404.
-        Ok(Response::with((Status::NotFound, format!("Unknown url: {}", req.url))))
+        Ok(api_error::response(Status::NotFound, format!("Unknown url: {}", req.url)))
     }
 }
 
@@ -320,11 +425,15 @@ pub fn create<T>(controller: T,
     let endpoints = vec![
         (vec![Method::Get, Method::Post], "services".to_owned()),
         (vec![Method::Post, Method::Delete], "services/tags".to_owned()),
+        (vec![Method::Put], "services/name".to_owned()),
+        (vec![Method::Put], "services/room".to_owned()),
         (vec![Method::Get, Method::Post], "channels".to_owned()),
         (vec![Method::Put], "channels/get".to_owned()),
         (vec![Method::Put], "channels/set".to_owned()),
         (vec![Method::Post, Method::Delete], "channels/tags".to_owned()),
+        (vec![Method::Put], "channels/name".to_owned()),
         (vec![Method::Get, Method::Put], "channel/:id".to_owned()),
+        (vec![Method::Get], "formats".to_owned()),
     ];
 
     let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
@@ -353,7 +462,7 @@ describe! taxonomy_router {
         use std::sync::Arc;
 
         let taxo_manager = Arc::new(AdapterManager::new(None));
-        clock::Clock::init(&taxo_manager).unwrap();
+        clock::Clock::init(ControllerStub::new(), &taxo_manager).unwrap();
 
         let mut mount = Mount::new();
         mount.mount("/api/v1", create(ControllerStub::new(), &taxo_manager).0);
@@ -364,7 +473,7 @@ describe! taxonomy_router {
                                     Headers::new(),
                                     &mount).unwrap();
         let body = response::extract_body_to_string(response);
-        let s = r#"[{"adapter":"clock@link.mozilla.org","channels":{"getter:interval.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-interval-seconds","id":"getter:interval.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":null,"supports_send":null,"tags":[]},"getter:timeofday.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-of-day-seconds","id":"getter:timeofday.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"Duration (s)"}},"supports_send":null,"tags":[]},"getter:timestamp.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-timestamp-rfc-3339","id":"getter:timestamp.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)"}},"supports_send":null,"tags":[]}},"id":"service:clock@link.mozilla.org","properties":{"model":"Mozilla clock v1"},"tags":[]}]"#;
+        let s = r#"[{"adapter":"clock@link.mozilla.org","channels":{"getter:civil-dawn.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/civil-dawn","id":"getter:civil-dawn.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:civil-dusk.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/civil-dusk","id":"getter:civil-dusk.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:interval.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-interval-seconds","id":"getter:interval.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":null,"supports_send":null,"supports_watch":{"accepts":{"requires":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:is-daylight.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/is-daylight","id":"getter:is-daylight.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"On/Off","schema":{"enum":["On","Off"],"type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"On/Off","schema":{"enum":["On","Off"],"type":"string"}},"returns":{"requires":"On/Off","schema":{"enum":["On","Off"],"type":"string"}}},"tags":[]},"getter:nautical-dawn.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/nautical-dawn","id":"getter:nautical-dawn.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:nautical-dusk.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/nautical-dusk","id":"getter:nautical-dusk.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:sunrise.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/sunrise","id":"getter:sunrise.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:sunset.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/sunset","id":"getter:sunset.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:timeofday.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-of-day-seconds","id":"getter:timeofday.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"Duration (s)","schema":{"type":"number","unit":"s"}}},"supports_send":null,"supports_watch":{"accepts":{"requires":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"Duration (s)","schema":{"type":"number","unit":"s"}}},"tags":[]},"getter:timestamp.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-timestamp-rfc-3339","id":"getter:timestamp.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]}},"id":"service:clock@link.mozilla.org","name":null,"properties":{"model":"Mozilla clock v1"},"room":null,"tags":[]}]"#;
 
         assert_eq!(body, s);
     }
@@ -375,21 +484,98 @@ describe! taxonomy_router {
                                     r#"[{"id":"service:clock@link.mozilla.org"}]"#,
                                     &mount).unwrap();
         let body = response::extract_body_to_string(response);
-        let s = r#"[{"adapter":"clock@link.mozilla.org","channels":{"getter:interval.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-interval-seconds","id":"getter:interval.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":null,"supports_send":null,"tags":[]},"getter:timeofday.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-of-day-seconds","id":"getter:timeofday.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"Duration (s)"}},"supports_send":null,"tags":[]},"getter:timestamp.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-timestamp-rfc-3339","id":"getter:timestamp.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)"}},"supports_send":null,"tags":[]}},"id":"service:clock@link.mozilla.org","properties":{"model":"Mozilla clock v1"},"tags":[]}]"#;
+        let s = r#"[{"adapter":"clock@link.mozilla.org","channels":{"getter:civil-dawn.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/civil-dawn","id":"getter:civil-dawn.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:civil-dusk.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/civil-dusk","id":"getter:civil-dusk.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:interval.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-interval-seconds","id":"getter:interval.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":null,"supports_send":null,"supports_watch":{"accepts":{"requires":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:is-daylight.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/is-daylight","id":"getter:is-daylight.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"On/Off","schema":{"enum":["On","Off"],"type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"On/Off","schema":{"enum":["On","Off"],"type":"string"}},"returns":{"requires":"On/Off","schema":{"enum":["On","Off"],"type":"string"}}},"tags":[]},"getter:nautical-dawn.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/nautical-dawn","id":"getter:nautical-dawn.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:nautical-dusk.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/nautical-dusk","id":"getter:nautical-dusk.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:sunrise.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/sunrise","id":"getter:sunrise.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:sunset.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/sunset","id":"getter:sunset.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"optional":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]},"getter:timeofday.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-of-day-seconds","id":"getter:timeofday.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"Duration (s)","schema":{"type":"number","unit":"s"}}},"supports_send":null,"supports_watch":{"accepts":{"requires":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"Duration (s)","schema":{"type":"number","unit":"s"}}},"tags":[]},"getter:timestamp.clock@link.mozilla.org":{"adapter":"clock@link.mozilla.org","feature":"clock/time-timestamp-rfc-3339","id":"getter:timestamp.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":{"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"supports_send":null,"supports_watch":{"accepts":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]}},"id":"service:clock@link.mozilla.org","name":null,"properties":{"model":"Mozilla clock v1"},"room":null,"tags":[]}]"#;
 
         assert_eq!(body, s);
     }
 
+    it "should name services and channels and place services in rooms" {
+        let response = request::put("http://localhost:3000/api/v1/services/name",
+                                    Headers::new(),
+                                    r#"{"services": [{"id":"service:clock@link.mozilla.org"}],
+                                        "name": "Kitchen clock"}"#,
+                                    &mount).unwrap();
+        assert_eq!(response::extract_body_to_string(response), "1");
+        let response = request::put("http://localhost:3000/api/v1/services/room",
+                                    Headers::new(),
+                                    r#"{"services": [{"id":"service:clock@link.mozilla.org"}],
+                                        "room": "Kitchen"}"#,
+                                    &mount).unwrap();
+        assert_eq!(response::extract_body_to_string(response), "1");
+        let response = request::put("http://localhost:3000/api/v1/channels/name",
+                                    Headers::new(),
+                                    r#"{"channels": [{"id":"getter:sunset.clock@link.mozilla.org"}],
+                                        "name": "Sunset"}"#,
+                                    &mount).unwrap();
+        assert_eq!(response::extract_body_to_string(response), "1");
+
+        let response = request::post("http://localhost:3000/api/v1/services",
+                                     Headers::new(),
+                                     r#"[{"id":"service:clock@link.mozilla.org"}]"#,
+                                     &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let service = &json.as_array().unwrap()[0];
+        assert_eq!(service.find("name").unwrap().as_str(), Some("Kitchen clock"));
+        assert_eq!(service.find("room").unwrap().as_str(), Some("Kitchen"));
+        assert_eq!(service.find_path(&["channels", "getter:sunset.clock@link.mozilla.org", "name"])
+                       .unwrap()
+                       .as_str(),
+                   Some("Sunset"));
+
+        // A `null` name removes the name.
+        let response = request::put("http://localhost:3000/api/v1/services/name",
+                                    Headers::new(),
+                                    r#"{"services": [{"id":"service:clock@link.mozilla.org"}],
+                                        "name": null}"#,
+                                    &mount).unwrap();
+        assert_eq!(response::extract_body_to_string(response), "1");
+        let response = request::post("http://localhost:3000/api/v1/services",
+                                     Headers::new(),
+                                     r#"[{"id":"service:clock@link.mozilla.org"}]"#,
+                                     &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.as_array().unwrap()[0].find("name"), Some(&serde_json::Value::Null));
+    }
+
+    it "should reject invalid names" {
+        use iron::status::Status;
+
+        let response = request::put("http://localhost:3000/api/v1/services/name",
+                                    Headers::new(),
+                                    r#"{"services": [{"id":"service:clock@link.mozilla.org"}],
+                                        "name": 42}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+    }
+
     it "should return the list of channels from a POST request" {
         let response = request::post("http://localhost:3000/api/v1/channels",
                                      Headers::new(),
                                      r#"[{"id":"getter:interval.clock@link.mozilla.org"}]"#,
                                      &mount).unwrap();
         let body = response::extract_body_to_string(response);
-        let s = r#"[{"adapter":"clock@link.mozilla.org","feature":"clock/time-interval-seconds","id":"getter:interval.clock@link.mozilla.org","service":"service:clock@link.mozilla.org","supports_fetch":null,"supports_send":null,"tags":[]}]"#;
+        let s = r#"[{"adapter":"clock@link.mozilla.org","feature":"clock/time-interval-seconds","id":"getter:interval.clock@link.mozilla.org","name":null,"service":"service:clock@link.mozilla.org","supports_fetch":null,"supports_send":null,"supports_watch":{"accepts":{"requires":"Duration (s)","schema":{"type":"number","unit":"s"}},"returns":{"requires":"TimeStamp (RFC 3339)","schema":{"format":"date-time","type":"string"}}},"tags":[]}]"#;
 
         assert_eq!(body, s);
     }
+
+    it "should describe the standard formats from a GET request" {
+        let response = request::get("http://localhost:3000/api/v1/formats",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.find_path(&["OnOff", "description"]).unwrap().as_str(), Some("On/Off"));
+        assert_eq!(serde_json::to_string(json.find_path(&["OnOff", "schema"]).unwrap()).unwrap(),
+                   r#"{"enum":["On","Off"],"type":"string"}"#);
+        assert_eq!(json.find_path(&["Duration", "schema", "unit"]).unwrap().as_str(), Some("s"));
+        assert_eq!(json.find_path(&["Color", "schema", "properties", "s", "maximum"])
+                       .unwrap()
+                       .as_f64(),
+                   Some(1.));
+    }
 }
 
 #[cfg(test)]
@@ -404,7 +590,7 @@ describe! binary_getter {
         use foxbox_taxonomy::services::*;
         use foxbox_taxonomy::values::{ format, Value, Json, Binary };
         use iron::Headers;
-        use iron::headers::{ ContentLength, ContentType };
+        use iron::headers::{ ContentLength, ContentType, LastModified };
         use iron::status::Status;
         use iron_test::{ request, response };
         use mount::Mount;
@@ -565,6 +751,21 @@ describe! binary_getter {
         let result = response::extract_body_to_bytes(response);
         assert_eq!(result, vec![1, 2, 3, 10, 11, 12]);
 
+// The value is now cached, and carries its last update time.
+        let response = request::get("http://localhost:3000/api/v1/channel/getter:binary@link.mozilla.org?max_age=60",
+                                    Headers::new(),
+                                    &mount).unwrap();
+
+        assert_eq!(response.status, Some(Status::Ok));
+        assert!(response.headers.get::<LastModified>().is_some());
+        let result = response::extract_body_to_bytes(response);
+        assert_eq!(result, vec![1, 2, 3, 10, 11, 12]);
+
+        let response = request::get("http://localhost:3000/api/v1/channel/getter:binary@link.mozilla.org?max_age=soon",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+
 // Send some binary data to the binary setter.
         let mut headers = Headers::new();
         headers.set(ContentType::png());
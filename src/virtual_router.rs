@@ -0,0 +1,279 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Endpoints to manage the virtual devices.
+//!
+//! GET    /api/v1/virtual      : lists the virtual devices.
+//! POST   /api/v1/virtual      : adds a device. The json body is
+//!                               {"name": "Away", "kind": "away-mode"}, with an optional
+//!                               initial "value". The kind is one of "switch", "number",
+//!                               "text" or "away-mode".
+//! GET    /api/v1/virtual/<id> : returns a device, e.g.
+//!                               {"id": "away", "name": "Away", "kind": "away-mode",
+//!                                "value": "Off"}
+//! PUT    /api/v1/virtual/<id> : renames a device and/or changes its value, e.g.
+//!                               {"name": "Holidays"} or {"value": "On"}.
+//! DELETE /api/v1/virtual/<id> : removes a device.
+//!
+//! Values can also be read and changed with the channels API, like the values of
+//! any other device.
+
+use adapters::virtual_devices::{Kind, VirtualDevices, VirtualError};
+use api_error::{self, json_response, read_json};
+use auth;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::api::{API, Targetted};
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_taxonomy::parse::{Parser, Path};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_users::AuthEndpoint;
+use iron::{Handler, IronResult, Request, Response};
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use serde_json;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+struct DeviceCreation {
+    name: String,
+    kind: Kind,
+    value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceEdit {
+    name: Option<String>,
+    value: Option<serde_json::Value>,
+}
+
+pub struct VirtualRouter {
+    manager: Arc<AdapterManager>,
+    devices: VirtualDevices,
+}
+
+fn error_response(err: &VirtualError) -> Response {
+    let status = match *err {
+        VirtualError::NoSuchDevice(_) => Status::NotFound,
+        VirtualError::InvalidDevice(_) => Status::BadRequest,
+        VirtualError::Database(_) |
+        VirtualError::Api(_) => Status::InternalServerError,
+    };
+    api_error::response(status, err.to_string())
+}
+
+impl VirtualRouter {
+    pub fn new(manager: &Arc<AdapterManager>, devices: VirtualDevices) -> Self {
+        VirtualRouter {
+            manager: manager.clone(),
+            devices: devices,
+        }
+    }
+
+    fn add(&self, req: &mut Request) -> IronResult<Response> {
+        let creation: DeviceCreation = match read_json(req, "device") {
+            Ok(creation) => creation,
+            Err(response) => return Ok(response),
+        };
+        match self.devices.create(&creation.name, creation.kind, creation.value) {
+            Ok(device) => json_response(Status::Created, &device),
+            Err(err) => Ok(error_response(&err)),
+        }
+    }
+
+    fn update(&self, id: &str, req: &mut Request) -> IronResult<Response> {
+        let user = match auth::user_from_request(req) {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+        let edit: DeviceEdit = match read_json(req, "device") {
+            Ok(edit) => edit,
+            Err(response) => return Ok(response),
+        };
+
+        if let Err(err) = self.devices.get(id) {
+            return Ok(error_response(&err));
+        }
+        if let Some(ref name) = edit.name {
+            if let Err(err) = self.devices.rename(id, name) {
+                return Ok(error_response(&err));
+            }
+        }
+        if let Some(ref value) = edit.value {
+            // Go through the taxonomy so that the watchers are notified.
+            let payload = match Payload::parse(Path::new(), value) {
+                Ok(payload) => payload,
+                Err(err) => return Ok(api_error::from_parse_error(&err)),
+            };
+            let target = Targetted {
+                select: vec![ChannelSelector::new().with_id(&VirtualDevices::channel_id(id))],
+                payload: payload,
+            };
+            for (_, result) in self.manager.send_values(vec![target], user) {
+                if let Err(err) = result {
+                    return Ok(api_error::from_api_error(&err));
+                }
+            }
+        }
+
+        match self.devices.get(id) {
+            Ok(device) => json_response(Status::Ok, &device),
+            Err(err) => Ok(error_response(&err)),
+        }
+    }
+}
+
+impl Handler for VirtualRouter {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path: Vec<String> = req.url
+            .path()
+            .iter()
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| (*segment).to_owned())
+            .collect();
+
+        match (req.method.clone(), path.len()) {
+            (Method::Get, 0) => {
+                match self.devices.list() {
+                    Ok(devices) => json_response(Status::Ok, &devices),
+                    Err(err) => Ok(error_response(&err)),
+                }
+            }
+            (Method::Post, 0) => self.add(req),
+            (Method::Get, 1) => {
+                match self.devices.get(&path[0]) {
+                    Ok(device) => json_response(Status::Ok, &device),
+                    Err(err) => Ok(error_response(&err)),
+                }
+            }
+            (Method::Put, 1) => self.update(&path[0], req),
+            (Method::Delete, 1) => {
+                match self.devices.remove(&path[0]) {
+                    Ok(()) => Ok(Response::with(Status::NoContent)),
+                    Err(err) => Ok(error_response(&err)),
+                }
+            }
+            (_, 0) | (_, 1) => {
+                Ok(api_error::response(Status::MethodNotAllowed,
+                                       format!("Bad method: {}", req.method)))
+            }
+            _ => Ok(api_error::response(Status::NotFound, format!("Unknown url: {}", req.url))),
+        }
+    }
+}
+
+pub fn create<T>(controller: T,
+                 adapter_api: &Arc<AdapterManager>)
+                 -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    // Keep these in sync with the handle() method. They are relative to api/v1.
+    let endpoints = vec![
+        (vec![Method::Get, Method::Post], "virtual".to_owned()),
+        (vec![Method::Get, Method::Put, Method::Delete], "virtual/:id".to_owned()),
+    ];
+
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        endpoints.iter().map(|item| AuthEndpoint(item.0.clone(), item.1.clone())).collect()
+    } else {
+        vec![]
+    };
+
+    let db_path = controller.get_profile().path_for("virtual_devices.sqlite");
+    let devices = VirtualDevices::new(adapter_api, &db_path);
+    let mut chain = Chain::new(VirtualRouter::new(adapter_api, devices));
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! virtual_router {
+    before_each {
+        use adapters::virtual_devices::VirtualAdapter;
+        use foxbox_taxonomy::manager::AdapterManager;
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{request, response};
+        use mount::Mount;
+        use std::sync::Arc;
+        use stubs::controller::ControllerStub;
+
+        let controller = ControllerStub::new();
+        let taxo_manager = Arc::new(AdapterManager::new(None));
+        VirtualAdapter::init(controller.clone(), &taxo_manager).unwrap();
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1/virtual", create(controller.clone(), &taxo_manager).0);
+
+        let response = request::post("http://localhost:3000/api/v1/virtual",
+                                     Headers::new(),
+                                     r#"{"name": "Away", "kind": "away-mode"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Created));
+    }
+
+    it "should list the devices" {
+        let response = request::get("http://localhost:3000/api/v1/virtual",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert_eq!(body, r#"[{"id":"away","name":"Away","kind":"away-mode","value":"Off"}]"#);
+    }
+
+    it "should change names and values" {
+        let response = request::put("http://localhost:3000/api/v1/virtual/away",
+                                    Headers::new(),
+                                    r#"{"name": "Holidays", "value": "On"}"#,
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert_eq!(body, r#"{"id":"away","name":"Holidays","kind":"away-mode","value":"On"}"#);
+
+        let response = request::put("http://localhost:3000/api/v1/virtual/away",
+                                    Headers::new(),
+                                    r#"{"value": 12}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+
+        let response = request::put("http://localhost:3000/api/v1/virtual/nope",
+                                    Headers::new(),
+                                    r#"{"value": "On"}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+    }
+
+    it "should reject invalid devices" {
+        let response = request::post("http://localhost:3000/api/v1/virtual",
+                                     Headers::new(),
+                                     r#"{"name": "Lamp", "kind": "lamp"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+
+        let response = request::post("http://localhost:3000/api/v1/virtual",
+                                     Headers::new(),
+                                     r#"{"name": "Counter", "kind": "number", "value": "ten"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+
+        let large = String::from_utf8(vec![b' '; 70000]).unwrap();
+        let response = request::post("http://localhost:3000/api/v1/virtual",
+                                     Headers::new(),
+                                     &large,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::PayloadTooLarge));
+    }
+
+    it "should remove devices" {
+        let response = request::delete("http://localhost:3000/api/v1/virtual/away",
+                                       Headers::new(),
+                                       &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NoContent));
+
+        let response = request::get("http://localhost:3000/api/v1/virtual/away",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+    }
+}
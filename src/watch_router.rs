@@ -0,0 +1,514 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Streams the events of the channels as Server-Sent Events, for the clients
+//! that can't use the websocket server.
+//!
+//! GET /api/v1/channels/watch : streams the `WatchEvent`s as `text/event-stream`. The
+//!                              optional `watch` query parameter is the json of the
+//!                              selectors and optional ranges to watch, as in
+//!                              [{"select": {"feature": "door/is-open"}, "range": ...}],
+//!                              and defaults to all the values of all the channels.
+//!
+//! As EventSource can't set headers, the session token may be passed as the `auth`
+//! query parameter. The events are named `range/enter`, `range/exit`, `channel/added`,
+//! `channel/removed` and `error`, and carry the same json data as the websocket
+//! messages. Comments are sent as heartbeats when there are no events.
+//!
+//! The watch of a stream is kept for a little while after the client disconnects, and
+//! the recent events are buffered, so that a client reconnecting with `Last-Event-ID`
+//! gets the events it missed. When that's no longer possible, the new stream starts
+//! with a `stream/reset` event.
+
+use api_error;
+use foxbox_core::rate_limit::FailureKey;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::api::{API, TargetMap, Targetted, WatchEvent};
+use foxbox_taxonomy::io::Payload;
+use foxbox_taxonomy::manager::{AdapterManager, WatchGuard};
+use foxbox_taxonomy::parse::{JSON, ParseError, Parser, Path, ToJSON};
+use foxbox_taxonomy::selector::ChannelSelector;
+use foxbox_taxonomy::util::Exactly;
+use hyper::mime::{Mime, SubLevel, TopLevel};
+use iron::{Handler, headers, IronResult, Request, Response};
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::response::{ResponseBody, WriteBody};
+use iron::status::Status;
+use rand;
+use serde_json;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant};
+use transformable_channels::mpsc::{self, Receiver};
+use url::form_urlencoded;
+
+/// Delay without events after which a heartbeat comment is sent, which keeps
+/// proxies from closing the connection and lets us notice the gone clients.
+const HEARTBEAT_S: u64 = 15;
+
+/// How long the watch of a disconnected client is kept for it to resume.
+const RESUME_WINDOW_S: u64 = 30;
+
+/// The number of recent events of a stream kept for the clients resuming it.
+const BUFFER_SIZE: usize = 64;
+
+/// Each connected stream holds one of the 8 threads of the http server, so
+/// keep some of them for the other requests.
+const MAX_STREAMS: usize = 4;
+
+/// Delay before the clients reconnect, in milliseconds.
+const RETRY_MS: u64 = 3000;
+
+header! { (LastEventId, "Last-Event-ID") => [String] }
+
+type Watch = TargetMap<ChannelSelector, Exactly<Payload>>;
+
+/// Reads the `watch` query parameter, defaulting to all the values of all the channels.
+fn parse_watch(source: Option<&str>) -> Result<Watch, ParseError> {
+    match source {
+        Some(source) => Path::new().push_str("watch", |path| Watch::from_str_at(path, source)),
+        None => {
+            Ok(vec![Targetted {
+                        select: vec![ChannelSelector::new()],
+                        payload: Exactly::Always,
+                    }])
+        }
+    }
+}
+
+/// Splits an event id like `5c3a1f0e2b9d4c67-12` into its stream and its sequence number.
+fn parse_event_id(event_id: &str) -> Option<(&str, u64)> {
+    let mut parts = event_id.rsplitn(2, '-');
+    let seq = match parts.next().map(|seq| seq.parse::<u64>()) {
+        Some(Ok(seq)) => seq,
+        _ => return None,
+    };
+    parts.next().map(|stream| (stream, seq))
+}
+
+/// Converts a watch event to the name and the data of a server-sent event.
+pub fn event_data(event: WatchEvent) -> (&'static str, JSON) {
+    let (name, data) = match event {
+        WatchEvent::EnterRange { channel, value, .. } => {
+            ("range/enter", vec![("channel", channel.to_json()), ("value", value.to_json())])
+        }
+        WatchEvent::ExitRange { channel, value, .. } => {
+            ("range/exit", vec![("channel", channel.to_json()), ("value", value.to_json())])
+        }
+        WatchEvent::ChannelAdded(id) => ("channel/added", vec![("id", id.to_json())]),
+        WatchEvent::ChannelRemoved(id) => ("channel/removed", vec![("id", id.to_json())]),
+        WatchEvent::Error { channel, error } => {
+            ("error", vec![("channel", channel.to_json()), ("error", error.to_json())])
+        }
+    };
+    (name, data.to_json())
+}
+
+#[derive(Clone)]
+struct StreamEvent {
+    seq: u64,
+    name: &'static str,
+    data: String,
+}
+
+impl StreamEvent {
+    fn write_to<W: Write>(&self, stream: &str, out: &mut W) -> io::Result<()> {
+        write!(out,
+               "id: {}-{}\nevent: {}\ndata: {}\n\n",
+               stream,
+               self.seq,
+               self.name,
+               self.data)
+    }
+}
+
+struct StreamState {
+    // Keeps the watch registered while the client may come back.
+    _guard: WatchGuard,
+
+    /// The events of the watch, `None` while a client is connected.
+    rx: Option<Receiver<WatchEvent>>,
+
+    /// The recent events, oldest first.
+    buffer: VecDeque<StreamEvent>,
+    next_seq: u64,
+    disconnected_at: Instant,
+}
+
+/// Drops the watches of the streams that can no longer be resumed.
+fn forget_expired(streams: &mut HashMap<String, StreamState>) {
+    let window = Duration::from_secs(RESUME_WINDOW_S);
+    streams.retain(|_, state| state.rx.is_none() || state.disconnected_at.elapsed() < window);
+}
+
+/// The streams that are connected or may be resumed.
+struct Streams {
+    streams: Mutex<HashMap<String, StreamState>>,
+}
+
+impl Streams {
+    fn new() -> Self {
+        Streams { streams: Mutex::new(HashMap::new()) }
+    }
+
+    /// Resumes the stream of `last_event_id` if it's still around and not connected,
+    /// or starts a new one with the watch returned by `watch`.
+    fn open<F>(streams: &Arc<Self>,
+               last_event_id: Option<&str>,
+               watch: F)
+               -> Result<EventStream, Response>
+        where F: FnOnce() -> (WatchGuard, Receiver<WatchEvent>)
+    {
+        let mut map = streams.streams.lock().unwrap();
+        forget_expired(&mut map);
+        if map.values().filter(|state| state.rx.is_none()).count() >= MAX_STREAMS {
+            return Err(api_error::with_code(Status::ServiceUnavailable,
+                                            "too_many_streams",
+                                            "Too many event streams"));
+        }
+
+        let mut reset = false;
+        if let Some((id, seq)) = last_event_id.and_then(parse_event_id) {
+            if let Some(state) = map.get_mut(id) {
+                if let Some(rx) = state.rx.take() {
+                    // Some events are lost if the oldest buffered one isn't the next one.
+                    let missed = state.buffer
+                        .front()
+                        .map_or(false, |event| event.seq > seq.saturating_add(1));
+                    let replay = state.buffer.iter().filter(|event| event.seq > seq).cloned();
+                    return Ok(EventStream {
+                        streams: streams.clone(),
+                        id: id.to_owned(),
+                        rx: Some(rx),
+                        reset: missed,
+                        replay: replay.collect(),
+                    });
+                }
+            }
+            reset = true;
+        }
+
+        let id = format!("{:016x}", rand::random::<u64>());
+        let (guard, rx) = watch();
+        map.insert(id.clone(),
+                   StreamState {
+                       _guard: guard,
+                       rx: None,
+                       buffer: VecDeque::new(),
+                       next_seq: 0,
+                       disconnected_at: Instant::now(),
+                   });
+        Ok(EventStream {
+            streams: streams.clone(),
+            id: id,
+            rx: Some(rx),
+            reset: reset,
+            replay: vec![],
+        })
+    }
+
+    /// Numbers an event of a stream and buffers it.
+    fn record(&self, id: &str, event: WatchEvent) -> StreamEvent {
+        let (name, data) = event_data(event);
+        let mut map = self.streams.lock().unwrap();
+        let state = map.get_mut(id).unwrap(); // Connected streams are never removed.
+        let event = StreamEvent {
+            seq: state.next_seq,
+            name: name,
+            data: serde_json::to_string(&data).unwrap(),
+        };
+        state.next_seq += 1;
+        state.buffer.push_back(event.clone());
+        if state.buffer.len() > BUFFER_SIZE {
+            state.buffer.pop_front();
+        }
+        event
+    }
+
+    /// Keeps the watch of a disconnected stream for it to be resumed.
+    fn release(streams: &Arc<Self>, id: &str, rx: Receiver<WatchEvent>) {
+        if let Some(state) = streams.streams.lock().unwrap().get_mut(id) {
+            state.rx = Some(rx);
+            state.disconnected_at = Instant::now();
+        }
+
+        // Drop the watch once the client can no longer resume, even if no other
+        // stream is opened in the meantime.
+        let streams = streams.clone();
+        thread::Builder::new()
+            .name("WatchStreamExpiry".to_owned())
+            .spawn(move || {
+                thread::sleep(Duration::from_secs(RESUME_WINDOW_S));
+                forget_expired(&mut streams.streams.lock().unwrap());
+            })
+            .unwrap();
+    }
+}
+
+/// The body of a streaming response, writing the events until the client goes away.
+struct EventStream {
+    streams: Arc<Streams>,
+    id: String,
+    rx: Option<Receiver<WatchEvent>>,
+    reset: bool,
+    replay: Vec<StreamEvent>,
+}
+
+impl EventStream {
+    fn run<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        try!(write!(out, "retry: {}\n\n", RETRY_MS));
+        if self.reset {
+            try!(out.write_all(b"event: stream/reset\ndata: {}\n\n"));
+        }
+        for event in self.replay.drain(..) {
+            try!(event.write_to(&self.id, out));
+        }
+        try!(out.flush());
+
+        let rx = match self.rx {
+            Some(ref rx) => rx,
+            None => return Ok(()),
+        };
+        loop {
+            match rx.recv_timeout(Duration::from_secs(HEARTBEAT_S)) {
+                Ok(event) => try!(self.streams.record(&self.id, event).write_to(&self.id, out)),
+                Err(RecvTimeoutError::Timeout) => try!(out.write_all(b": heartbeat\n\n")),
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            // Writing to a closed connection fails here at the latest.
+            try!(out.flush());
+        }
+    }
+}
+
+impl WriteBody for EventStream {
+    fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
+        let result = self.run(res);
+        if let Err(ref err) = result {
+            debug!("Event stream {} closed: {}", self.id, err);
+        }
+        result
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        if let Some(rx) = self.rx.take() {
+            Streams::release(&self.streams, &self.id, rx);
+        }
+    }
+}
+
+pub struct WatchRouter<T> {
+    controller: T,
+    api: Arc<AdapterManager>,
+    streams: Arc<Streams>,
+}
+
+impl<T: Controller> WatchRouter<T> {
+    pub fn new(controller: T, adapter_api: &Arc<AdapterManager>) -> Self {
+        WatchRouter {
+            controller: controller,
+            api: adapter_api.clone(),
+            streams: Arc::new(Streams::new()),
+        }
+    }
+
+    /// Checks the session token, which may be in the `auth` query parameter.
+    /// Always succeeds when the authentication feature is disabled, and in tests.
+    fn check_token(&self, req: &Request, query_token: Option<String>) -> Result<(), Response> {
+        if !cfg!(feature = "authentication") || cfg!(test) {
+            return Ok(());
+        }
+
+        let token = match req.headers.get::<headers::Authorization<headers::Bearer>>() {
+            Some(&headers::Authorization(headers::Bearer { ref token })) => token.clone(),
+            None => {
+                match query_token {
+                    Some(token) => token,
+                    None => {
+                        return Err(api_error::response(Status::Unauthorized,
+                                                       "Authentication required"))
+                    }
+                }
+            }
+        };
+        if self.controller.get_users_manager().verify_token(&token).is_err() {
+            // The rate limiter only sees the tokens of the Authorization header.
+            if !req.headers.has::<headers::Authorization<headers::Bearer>>() {
+                if let Some(key) = FailureKey::client(&req.remote_addr.ip()) {
+                    self.controller.get_rate_limiter().record_failure(&[key]);
+                }
+            }
+            return Err(api_error::response(Status::Unauthorized, "Invalid token"));
+        }
+        Ok(())
+    }
+}
+
+impl<T: Controller> Handler for WatchRouter<T> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if req.url.path() != vec![""] {
+            return Ok(api_error::response(Status::NotFound,
+                                          format!("Unknown url: {}", req.url)));
+        }
+        if req.method != Method::Get {
+            return Ok(api_error::response(Status::MethodNotAllowed,
+                                          format!("Bad method: {}", req.method)));
+        }
+
+        let mut watch = None;
+        let mut token = None;
+        let mut last_event_id = req.headers.get::<LastEventId>().map(|id| id.0.clone());
+        if let Some(query) = req.url.query() {
+            for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+                match &*key {
+                    "watch" => watch = Some(value.into_owned()),
+                    "auth" => token = Some(value.into_owned()),
+                    // For the EventSource polyfills that can't set headers.
+                    "last_event_id" if last_event_id.is_none() => {
+                        last_event_id = Some(value.into_owned())
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Err(response) = self.check_token(req, token) {
+            return Ok(response);
+        }
+        let watch = match parse_watch(watch.as_ref().map(|watch| watch as &str)) {
+            Ok(watch) => watch,
+            Err(err) => return Ok(api_error::from_parse_error(&err)),
+        };
+
+        let api = &self.api;
+        let stream = Streams::open(&self.streams, last_event_id.as_ref().map(|id| id as &str), || {
+            let (tx, rx) = mpsc::channel();
+            (api.watch_values(watch, Box::new(tx)), rx)
+        });
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(response) => return Ok(response),
+        };
+
+        let mut response = Response::with(Status::Ok);
+        response.headers
+            .set(headers::ContentType(Mime(TopLevel::Text,
+                                           SubLevel::Ext("event-stream".to_owned()),
+                                           vec![])));
+        response.headers.set(headers::CacheControl(vec![headers::CacheDirective::NoCache]));
+        response.body = Some(Box::new(stream));
+        Ok(response)
+    }
+}
+
+pub fn create<T>(controller: T,
+                 adapter_api: &Arc<AdapterManager>)
+                 -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    // Keep this in sync with the handle() method. It is relative to api/v1.
+    // The session token is checked by the router itself since it may be in the query.
+    let endpoints = vec![
+        (vec![Method::Get], "channels/watch".to_owned()),
+    ];
+
+    (Chain::new(WatchRouter::new(controller, adapter_api)), endpoints)
+}
+
+#[cfg(test)]
+describe! watch_router {
+    before_each {
+        use foxbox_taxonomy::api::{API, WatchEvent};
+        use foxbox_taxonomy::manager::AdapterManager;
+        use foxbox_taxonomy::util::Id;
+        use std::sync::Arc;
+        use transformable_channels::mpsc;
+
+        let taxo_manager = Arc::new(AdapterManager::new(None));
+        let streams = Arc::new(Streams::new());
+        let (tx, rx) = mpsc::channel::<WatchEvent>();
+        let mut rx = Some(rx);
+        let mut open = |last_event_id: Option<&str>| {
+            let taxo_manager = taxo_manager.clone();
+            let rx = rx.take();
+            Streams::open(&streams, last_event_id, move || {
+                let (unused_tx, unused_rx) = mpsc::channel();
+                let guard = taxo_manager.watch_values(vec![], Box::new(unused_tx));
+                (guard, rx.unwrap_or(unused_rx))
+            })
+        };
+        let run = |stream: &mut EventStream| {
+            let mut out = vec![];
+            stream.run(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+    }
+
+    it "should parse the watched selectors and ranges" {
+        assert_eq!(parse_watch(None).unwrap().len(), 1);
+        let watch = parse_watch(Some(r#"[{"select": {"id": "door"}, "range": {"Eq": true}}]"#))
+            .unwrap();
+        assert_eq!(watch.len(), 1);
+        let err = parse_watch(Some(r#"[{"range": {"Eq": true}}]"#)).unwrap_err();
+        assert_eq!(err.code(), "missing_field");
+    }
+
+    it "should parse the event ids" {
+        assert_eq!(parse_event_id("5c3a1f0e2b9d4c67-12"), Some(("5c3a1f0e2b9d4c67", 12)));
+        assert_eq!(parse_event_id("12"), None);
+        assert_eq!(parse_event_id("stream-"), None);
+    }
+
+    it "should stream the events" {
+        let mut stream = open(None).ok().unwrap();
+        tx.send(WatchEvent::ChannelAdded(Id::new("door"))).unwrap();
+        drop(tx);
+        let id = stream.id.clone();
+        assert_eq!(run(&mut stream),
+                   format!("retry: 3000\n\nid: {}-0\nevent: channel/added\n\
+                            data: {{\"id\":\"door\"}}\n\n",
+                           id));
+    }
+
+    it "should replay the missed events to resuming clients" {
+        let mut stream = open(None).ok().unwrap();
+        tx.send(WatchEvent::ChannelAdded(Id::new("door"))).unwrap();
+        tx.send(WatchEvent::ChannelRemoved(Id::new("window"))).unwrap();
+        drop(tx);
+        let id = stream.id.clone();
+        run(&mut stream);
+        drop(stream);
+
+        let mut stream = open(Some(&format!("{}-0", id))).ok().unwrap();
+        assert_eq!(stream.id, id);
+        let output = run(&mut stream);
+        assert!(!output.contains("stream/reset"));
+        assert!(!output.contains(&format!("{}-0", id)));
+        assert!(output.contains(&format!("id: {}-1\nevent: channel/removed", id)));
+    }
+
+    it "should reset the streams that can't be resumed" {
+        let mut stream = open(Some("0123456789abcdef-3")).ok().unwrap();
+        assert!(stream.id != "0123456789abcdef");
+        drop(tx);
+        assert_eq!(run(&mut stream), "retry: 3000\n\nevent: stream/reset\ndata: {}\n\n");
+    }
+
+    it "should limit the number of connected streams" {
+        let mut connected = vec![];
+        for _ in 0..MAX_STREAMS {
+            connected.push(open(None).ok().unwrap());
+        }
+        match open(None) {
+            Err(response) => assert_eq!(response.status, Some(Status::ServiceUnavailable)),
+            Ok(_) => panic!("Too many streams were accepted"),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Admin endpoints to manage the webhooks.
+//!
+//! GET    /api/v1/webhooks                 : lists the webhooks.
+//! POST   /api/v1/webhooks                 : adds a webhook. The json body is e.g.
+//!                                           {"name": "Door opened",
+//!                                            "url": "https://example.com/hooks/door",
+//!                                            "watch": [{"select": {"feature": "door/is-open"}}],
+//!                                            "secret": "s3cr3t"}
+//!                                           where "watch" and "secret" are optional.
+//! GET    /api/v1/webhooks/<id>            : returns a webhook, without its secret.
+//! PUT    /api/v1/webhooks/<id>            : changes some of "name", "url", "watch",
+//!                                           "secret" (an empty one removes it) and
+//!                                           "enabled" of a webhook.
+//! DELETE /api/v1/webhooks/<id>            : removes a webhook.
+//! GET    /api/v1/webhooks/<id>/deliveries : returns the recent attempts to deliver
+//!                                           events to a webhook, most recent first.
+
+use adapters::webhooks::{Webhooks, WebhookError};
+use api_error::{self, json_response, read_json};
+use auth;
+use foxbox_core::traits::Controller;
+use foxbox_taxonomy::manager::AdapterManager;
+use foxbox_users::AuthEndpoint;
+use iron::{Handler, IronResult, Request, Response};
+use iron::method::Method;
+use iron::prelude::Chain;
+use iron::status::Status;
+use serde_json;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+struct WebhookCreation {
+    name: String,
+    url: String,
+    watch: Option<serde_json::Value>,
+    secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookEdit {
+    name: Option<String>,
+    url: Option<String>,
+    watch: Option<serde_json::Value>,
+    secret: Option<String>,
+    enabled: Option<bool>,
+}
+
+pub struct WebhooksRouter<T> {
+    controller: T,
+    webhooks: Webhooks,
+}
+
+fn error_response(err: &WebhookError) -> IronResult<Response> {
+    let status = match *err {
+        WebhookError::NoSuchWebhook(_) => Status::NotFound,
+        WebhookError::InvalidWebhook(_) => Status::BadRequest,
+        WebhookError::Database(_) |
+        WebhookError::Api(_) => Status::InternalServerError,
+    };
+    Ok(api_error::response(status, err.to_string()))
+}
+
+impl<T: Controller> WebhooksRouter<T> {
+    pub fn new(controller: T, webhooks: Webhooks) -> Self {
+        WebhooksRouter {
+            controller: controller,
+            webhooks: webhooks,
+        }
+    }
+
+    fn add(&self, req: &mut Request) -> IronResult<Response> {
+        let creation: WebhookCreation = match read_json(req, "webhook") {
+            Ok(creation) => creation,
+            Err(response) => return Ok(response),
+        };
+        match self.webhooks
+            .create(&creation.name, &creation.url, creation.watch, creation.secret) {
+            Ok(webhook) => json_response(Status::Created, &webhook),
+            Err(err) => error_response(&err),
+        }
+    }
+
+    fn update(&self, id: &str, req: &mut Request) -> IronResult<Response> {
+        let edit: WebhookEdit = match read_json(req, "webhook") {
+            Ok(edit) => edit,
+            Err(response) => return Ok(response),
+        };
+        let mut webhook = match self.webhooks.get(id) {
+            Ok(webhook) => webhook,
+            Err(err) => return error_response(&err),
+        };
+        if let Some(name) = edit.name {
+            webhook.name = name;
+        }
+        if let Some(url) = edit.url {
+            webhook.url = url;
+        }
+        if let Some(watch) = edit.watch {
+            webhook.watch = watch;
+        }
+        if let Some(secret) = edit.secret {
+            webhook.secret = if secret.is_empty() { None } else { Some(secret) };
+        }
+        if let Some(enabled) = edit.enabled {
+            webhook.enabled = enabled;
+        }
+        match self.webhooks.update(&webhook) {
+            Ok(webhook) => json_response(Status::Ok, &webhook),
+            Err(err) => error_response(&err),
+        }
+    }
+}
+
+impl<T: Controller> Handler for WebhooksRouter<T> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if let Err(response) = auth::check_admin(req, &self.controller.get_users_manager()) {
+            return Ok(response);
+        }
+
+        let path: Vec<String> = req.url
+            .path()
+            .iter()
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| (*segment).to_owned())
+            .collect();
+
+        match (req.method.clone(), path.len()) {
+            (Method::Get, 0) => {
+                match self.webhooks.list() {
+                    Ok(webhooks) => json_response(Status::Ok, &webhooks),
+                    Err(err) => error_response(&err),
+                }
+            }
+            (Method::Post, 0) => self.add(req),
+            (Method::Get, 1) => {
+                match self.webhooks.get(&path[0]) {
+                    Ok(webhook) => json_response(Status::Ok, &webhook),
+                    Err(err) => error_response(&err),
+                }
+            }
+            (Method::Put, 1) => self.update(&path[0], req),
+            (Method::Delete, 1) => {
+                match self.webhooks.remove(&path[0]) {
+                    Ok(()) => Ok(Response::with(Status::NoContent)),
+                    Err(err) => error_response(&err),
+                }
+            }
+            (Method::Get, 2) if path[1] == "deliveries" => {
+                match self.webhooks.deliveries(&path[0]) {
+                    Ok(deliveries) => json_response(Status::Ok, &deliveries),
+                    Err(err) => error_response(&err),
+                }
+            }
+            (_, 0) | (_, 1) => {
+                Ok(api_error::response(Status::MethodNotAllowed,
+                                       format!("Bad method: {}", req.method)))
+            }
+            _ => Ok(api_error::response(Status::NotFound, format!("Unknown url: {}", req.url))),
+        }
+    }
+}
+
+pub fn create<T>(controller: T,
+                 adapter_api: &Arc<AdapterManager>)
+                 -> (Chain, Vec<(Vec<Method>, String)>)
+    where T: Controller
+{
+    // Keep these in sync with the handle() method. They are relative to api/v1.
+    let endpoints = vec![
+        (vec![Method::Get, Method::Post], "webhooks".to_owned()),
+        (vec![Method::Get, Method::Put, Method::Delete], "webhooks/:id".to_owned()),
+        (vec![Method::Get], "webhooks/:id/deliveries".to_owned()),
+    ];
+
+    let auth_endpoints = if cfg!(feature = "authentication") && !cfg!(test) {
+        endpoints.iter().map(|item| AuthEndpoint(item.0.clone(), item.1.clone())).collect()
+    } else {
+        vec![]
+    };
+
+    let db_path = controller.get_profile().path_for("webhooks.sqlite");
+    let webhooks = Webhooks::shared(adapter_api, &db_path);
+    let mut chain = Chain::new(WebhooksRouter::new(controller.clone(), webhooks));
+    chain.around(controller.get_users_manager().get_middleware(auth_endpoints));
+
+    (chain, endpoints)
+}
+
+#[cfg(test)]
+describe! webhooks_router {
+    before_each {
+        use adapters::webhooks::WebhookAdapter;
+        use foxbox_taxonomy::manager::AdapterManager;
+        use iron::Headers;
+        use iron::status::Status;
+        use iron_test::{request, response};
+        use mount::Mount;
+        use serde_json;
+        use std::sync::Arc;
+        use stubs::controller::ControllerStub;
+
+        let controller = ControllerStub::new();
+        let taxo_manager = Arc::new(AdapterManager::new(None));
+        WebhookAdapter::init(controller.clone(), &taxo_manager).unwrap();
+
+        let mut mount = Mount::new();
+        mount.mount("/api/v1/webhooks", create(controller.clone(), &taxo_manager).0);
+
+        let response = request::post("http://localhost:3000/api/v1/webhooks",
+                                     Headers::new(),
+                                     r#"{"name": "Door", "url": "http://127.0.0.1:1/door",
+                                         "secret": "s3cr3t"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::Created));
+    }
+
+    it "should list the webhooks without their secret" {
+        let response = request::get("http://localhost:3000/api/v1/webhooks",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        assert_eq!(body,
+                   r#"[{"id":"door","name":"Door","url":"http://127.0.0.1:1/door","watch":[],"#
+                       .to_owned() + r#""enabled":true,"failures":0}]"#);
+    }
+
+    it "should change webhooks" {
+        let response = request::put("http://localhost:3000/api/v1/webhooks/door",
+                                    Headers::new(),
+                                    r#"{"name": "Front door", "enabled": false}"#,
+                                    &mount).unwrap();
+        let body = response::extract_body_to_string(response);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.find("name").unwrap().as_str(), Some("Front door"));
+        assert_eq!(json.find("enabled").unwrap().as_bool(), Some(false));
+
+        let response = request::put("http://localhost:3000/api/v1/webhooks/door",
+                                    Headers::new(),
+                                    r#"{"watch": {"select": 12}}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+
+        let response = request::put("http://localhost:3000/api/v1/webhooks/window",
+                                    Headers::new(),
+                                    r#"{"enabled": true}"#,
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+    }
+
+    it "should reject invalid webhooks" {
+        let response = request::post("http://localhost:3000/api/v1/webhooks",
+                                     Headers::new(),
+                                     r#"{"name": "Door"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+
+        let response = request::post("http://localhost:3000/api/v1/webhooks",
+                                     Headers::new(),
+                                     r#"{"name": "Door", "url": "door"}"#,
+                                     &mount).unwrap();
+        assert_eq!(response.status, Some(Status::BadRequest));
+    }
+
+    it "should list the deliveries and remove webhooks" {
+        let response = request::get("http://localhost:3000/api/v1/webhooks/door/deliveries",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response::extract_body_to_string(response), "[]");
+
+        let response = request::delete("http://localhost:3000/api/v1/webhooks/door",
+                                       Headers::new(),
+                                       &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NoContent));
+
+        let response = request::get("http://localhost:3000/api/v1/webhooks/door/deliveries",
+                                    Headers::new(),
+                                    &mount).unwrap();
+        assert_eq!(response.status, Some(Status::NotFound));
+    }
+}
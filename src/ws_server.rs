@@ -4,12 +4,14 @@
 extern crate url;
 
 use self::url::Url;
+use foxbox_core::rate_limit::FailureKey;
 use foxbox_core::traits::Controller;
 use openssl::ssl::{Ssl, SslContext, SslMethod};
 use openssl::x509::X509FileType;
 use std::rc::Rc;
 use std::time::Duration;
 use std::thread;
+use tls::TlsOption;
 use ws;
 use ws::{Handler, Sender, Result, Message, Handshake, CloseCode, Error};
 
@@ -35,9 +37,12 @@ impl WsServer {
                             // This will fail when starting without a certificate, so for now just loop until we generate one.
                         loop {
                             // Get the certificate record for the remote hostname, and use its certificate and
-                            // private key files.
-                            let record =
-                                controller.get_certificate_manager().get_remote_hostname_certificate();
+                            // private key files. In self-signed mode, use the box certificate instead.
+                            let record = if controller.get_tls_option() == TlsOption::SelfSigned {
+                                controller.get_box_certificate().ok()
+                            } else {
+                                controller.get_certificate_manager().get_remote_hostname_certificate()
+                            };
                             if record.is_some() {
                                 let record = record.unwrap();
                                 context.set_certificate_file(record.full_chain
@@ -80,6 +85,20 @@ impl<T: Controller> Handler for WsHandler<T> {
     fn on_open(&mut self, handshake: Handshake) -> Result<()> {
         info!("Hello new ws connection");
 
+        let ip = handshake.peer_addr.map(|addr| addr.ip());
+        let limiter = self.controller.get_rate_limiter();
+        // Clients coming through the tunnel share the address of localhost, so
+        // only the clients whose real address is known can be locked out.
+        let failure_keys: Vec<_> = ip.iter().filter_map(FailureKey::client).collect();
+        if let Some(ref ip) = ip {
+            if limiter.check("ws", ip, None).is_err() {
+                return self.close_with_error("Too many requests");
+            }
+        }
+        if limiter.check_lockout("ws", &failure_keys).is_err() {
+            return self.close_with_error("Too many requests");
+        }
+
         let resource = &handshake.request.resource()[..];
 
         // creating a fake url to get the path and query parsed
@@ -98,6 +117,7 @@ impl<T: Controller> Handler for WsHandler<T> {
         };
 
         if self.controller.get_users_manager().verify_token(&token).is_err() {
+            limiter.record_failure(&failure_keys);
             return self.close_with_error("Authorization failed");
         }
 
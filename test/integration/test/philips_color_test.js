@@ -83,10 +83,10 @@ Prepper.makeSuite('Control lights locally', function () {
     return chakram.put(Prepper.foxboxManager.setterURL, payload)
       .then(function (cmdResponse) {
         expect(cmdResponse).to.have.status(200);
-        expect(cmdResponse.body[lights[0]].Error.ParseError.TypeError.expected)
-          .equals('a number in [0, 1]');
-        expect(cmdResponse.body[lights[0]].Error.ParseError.TypeError.name)
-          .equals('s');
+        expect(cmdResponse.body[lights[0]].error.code)
+          .equals('type_error');
+        expect(cmdResponse.body[lights[0]].error.message)
+          .equals('Invalid `s`, expected a number in [0, 1]');
       });
   });
 
@@ -105,10 +105,10 @@ Prepper.makeSuite('Control lights locally', function () {
     return chakram.put(Prepper.foxboxManager.setterURL, payload)
       .then(function (cmdResponse) {
         expect(cmdResponse).to.have.status(200);
-        expect(cmdResponse.body[lights[1]].Error.ParseError.TypeError.expected)
-          .equals('a number in [0, 1]');
-        expect(cmdResponse.body[lights[1]].Error.ParseError.TypeError.name)
-          .equals('v');
+        expect(cmdResponse.body[lights[1]].error.code)
+          .equals('type_error');
+        expect(cmdResponse.body[lights[1]].error.message)
+          .equals('Invalid `v`, expected a number in [0, 1]');
       });
   });
 });